@@ -1,10 +1,29 @@
-use anyhow::{Result, anyhow};
-use reqwest::Client;
+use anyhow::{Result, anyhow, Context as AnyhowContext};
+use colored::Colorize;
+use futures_util::StreamExt;
+use reqwest::{Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use std::collections::HashSet;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
 
-use crate::config::Config;
+use crate::cache;
+use crate::config::{fill_prompt_template, shell_suggestion_instruction, Backend, Config, GenerationOptions};
+use crate::{status, verbose};
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("Model '{0}' was not found in Ollama")]
+    ModelNotFound(String),
+    
+    #[error("Generation cancelled")]
+    Cancelled,
+    
+    #[error("Ollama error: {0}")]
+    Server(String),
+}
 
 #[derive(Debug, Clone)]
 pub struct OllamaClient {
@@ -12,6 +31,14 @@ pub struct OllamaClient {
     model: String,
     client: Client,
     config: Config,
+    project_prompt: Option<String>,
+    /// `/api/show` results by model name, shared by clones for the whole session; None
+    /// when the server couldn't tell
+    model_details: Arc<Mutex<HashMap<String, Option<ModelDetails>>>>,
+    /// Instructions for the language of the files in the context
+    language_prompt: Option<String>,
+    /// Persona whose system prompt replaces the model's
+    persona: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,12 +47,234 @@ struct OllamaRequest {
     prompt: String,
     system: String,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<GenerationOptions>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct OllamaResponse {
     model: String,
     response: String,
+    /// `length` when generation stopped at `num_predict`
+    #[serde(default)]
+    done_reason: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: Option<u64>,
+    #[serde(default)]
+    eval_count: Option<u64>,
+}
+
+/// Token counts reported by the server for one response
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokenUsage {
+    /// Tokens in the prompt, including the system prompt, history and context
+    pub prompt_tokens: u64,
+    /// Tokens generated for the response
+    pub completion_tokens: u64,
+}
+
+impl TokenUsage {
+    fn from_counts(prompt_tokens: Option<u64>, completion_tokens: Option<u64>) -> Option<Self> {
+        if prompt_tokens.is_none() && completion_tokens.is_none() {
+            return None;
+        }
+        Some(Self {
+            prompt_tokens: prompt_tokens.unwrap_or_default(),
+            completion_tokens: completion_tokens.unwrap_or_default(),
+        })
+    }
+}
+
+/// The complete text of a model response and, when reported, its token usage
+#[derive(Debug, Clone)]
+pub struct ModelResponse {
+    pub text: String,
+    pub usage: Option<TokenUsage>,
+    /// Generation stopped at the token limit (`num_predict`) instead of finishing
+    pub truncated: bool,
+    /// Served from the response cache instead of the model
+    pub cached: bool,
+}
+
+/// An installed model as listed by the server
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+    pub name: String,
+    /// Size on disk in bytes (Ollama only)
+    pub size: Option<u64>,
+    /// When the model was last pulled or changed (Ollama only)
+    pub modified_at: Option<String>,
+}
+
+/// What Ollama's `/api/show` reports about a model's context window
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModelDetails {
+    /// `num_ctx` set in the model's Modelfile parameters
+    pub num_ctx: Option<u64>,
+    /// The longest context the model was trained for
+    pub context_length: Option<u64>,
+}
+
+/// Ollama's context window when neither the request nor the Modelfile sets `num_ctx`
+const OLLAMA_DEFAULT_NUM_CTX: u64 = 2048;
+
+/// A role-tagged message for the `/api/chat` endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    fn new(role: &str, content: &str) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<GenerationOptions>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaChatResponse {
+    message: ChatMessage,
+    #[serde(default)]
+    done_reason: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: Option<u64>,
+    #[serde(default)]
+    eval_count: Option<u64>,
+}
+
+/// Request body for an OpenAI-compatible `/v1/chat/completions` endpoint
+#[derive(Debug, Serialize, Deserialize)]
+struct OpenAiChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiChatResponse {
+    choices: Vec<OpenAiChoice>,
+    #[serde(default)]
+    usage: Option<OpenAiUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiUsage {
+    #[serde(default)]
+    prompt_tokens: Option<u64>,
+    #[serde(default)]
+    completion_tokens: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiChoice {
+    message: ChatMessage,
+    /// `length` when generation stopped at `max_tokens`
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+/// A single `data:` event from a streaming OpenAI-compatible response
+#[derive(Debug, Deserialize)]
+struct OpenAiStreamChunk {
+    #[serde(default)]
+    choices: Vec<OpenAiStreamChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiStreamChoice {
+    #[serde(default)]
+    delta: OpenAiDelta,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct OpenAiDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+/// A progress update streamed by `/api/pull`
+#[derive(Debug, Deserialize)]
+pub struct PullProgress {
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub total: Option<u64>,
+    #[serde(default)]
+    pub completed: Option<u64>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Body that Ollama sends instead of a response when a request fails, e.g. when the model
+/// doesn't fit in memory
+#[derive(Debug, Deserialize)]
+struct OllamaErrorResponse {
+    error: String,
+}
+
+/// The error reported in a response body, if the body is Ollama's `{"error": "..."}`
+fn server_error(body: &str) -> Option<ApiError> {
+    serde_json::from_str::<OllamaErrorResponse>(body)
+        .ok()
+        .map(|response| ApiError::Server(response.error))
+}
+
+/// Response from `/api/embeddings`
+#[derive(Debug, Deserialize)]
+struct OllamaEmbeddingResponse {
+    embedding: Vec<f32>,
+}
+
+/// Response from an OpenAI-compatible `/v1/embeddings`
+#[derive(Debug, Deserialize)]
+struct OpenAiEmbeddingResponse {
+    data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAiEmbedding {
+    embedding: Vec<f32>,
+}
+
+/// A single newline-delimited JSON chunk from a streaming `/api/generate` or `/api/chat` call
+#[derive(Debug, Deserialize)]
+struct OllamaStreamChunk {
+    #[serde(default)]
+    response: String,
+    #[serde(default)]
+    message: Option<ChatMessage>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    done_reason: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: Option<u64>,
+    #[serde(default)]
+    eval_count: Option<u64>,
 }
 
 impl OllamaClient {
@@ -34,20 +283,133 @@ impl OllamaClient {
             api_url: api_url.to_string(),
             model: model.to_string(),
             client: Client::new(),
+            persona: config.persona.clone(),
+            config,
+            project_prompt: None,
+            model_details: Arc::default(),
+            language_prompt: None,
+        }
+    }
+    
+    /// A client for another model that keeps this one's project prompt and persona
+    pub fn with_model(&self, model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            ..self.clone()
+        }
+    }
+    
+    /// A client for a model on another server, with the config for it (see
+    /// `Config::use_profile`), that keeps this one's prompts and persona
+    pub fn with_server(&self, api_url: &str, model: &str, config: Config) -> Self {
+        Self {
+            api_url: api_url.to_string(),
+            model: model.to_string(),
             config,
+            // Another server may have different models under the same names
+            model_details: Arc::default(),
+            ..self.clone()
         }
     }
     
-    pub fn get_api_url(&self) -> &str {
-        &self.api_url
+    /// Set project-type specific instructions appended to the system prompt
+    pub fn set_project_prompt(&mut self, prompt: Option<String>) {
+        self.project_prompt = prompt;
+    }
+    
+    /// Set language-specific instructions appended to the system prompt, after the project's
+    pub fn set_language_prompt(&mut self, prompt: Option<String>) {
+        self.language_prompt = prompt;
+    }
+    
+    /// Select a persona (already checked with `Config::check_persona`), or None for the
+    /// model's own prompt
+    pub fn set_persona(&mut self, persona: Option<String>) {
+        self.persona = persona;
+    }
+    
+    pub fn get_persona(&self) -> Option<&str> {
+        self.persona.as_deref()
+    }
+    
+    /// Get the full system prompt for the active model or persona, including project and
+    /// language instructions
+    pub fn get_system_prompt(&self) -> String {
+        let mut system_prompt = self.config.get_system_prompt(&self.model, self.persona.as_deref());
+        for extra in [&self.project_prompt, &self.language_prompt].into_iter().flatten() {
+            system_prompt.push_str("\n\n");
+            system_prompt.push_str(extra);
+        }
+        if self.config.allow_shell_suggestions {
+            system_prompt.push_str("\n\n");
+            system_prompt.push_str(shell_suggestion_instruction());
+        }
+        system_prompt
+    }
+    
+    pub fn get_model(&self) -> &str {
+        &self.model
+    }
+    
+    /// URL that lists the available models
+    fn get_models_url(&self) -> String {
+        match self.config.backend {
+            Backend::Ollama => format!("{}/api/tags", self.api_url),
+            Backend::OpenAiCompatible => format!("{}/v1/models", self.api_url),
+        }
+    }
+    
+    /// Attach the configured API key, if any, as a bearer token
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match (&self.config.backend, &self.config.api_key) {
+            (Backend::OpenAiCompatible, Some(api_key)) => request.bearer_auth(api_key),
+            _ => request,
+        }
+    }
+    
+    fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.request_timeout_secs)
+    }
+    
+    /// Send a generation request, retrying with exponential backoff when the connection
+    /// fails or times out. Other errors, and any HTTP response at all, are returned as is.
+    async fn send_with_retry(&self, request_url: &str, request_body: &Value, stream: bool) -> Result<reqwest::Response> {
+        let timeout = self.request_timeout();
+        let mut attempt = 0;
+        
+        loop {
+            let mut request = self.authorize(self.client.post(request_url)).json(request_body);
+            if !stream {
+                request = request.timeout(timeout);
+            }
+            
+            // A streamed response only has to start within the timeout
+            let error = match tokio::time::timeout(timeout, request.send()).await {
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(e)) if e.is_connect() || e.is_timeout() => anyhow!(e),
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => anyhow!("no response within {} seconds", self.config.request_timeout_secs),
+            };
+            
+            if attempt >= self.config.max_retries {
+                return Err(error).with_context(|| {
+                    format!("Request to {} failed after {} attempt(s)", request_url, attempt + 1)
+                });
+            }
+            
+            attempt += 1;
+            let delay = Duration::from_secs(1 << (attempt - 1).min(6));
+            eprintln!("{}", format!("⚠️  Request failed ({}), retrying in {}s (attempt {}/{})",
+                error, delay.as_secs(), attempt, self.config.max_retries).yellow());
+            tokio::time::sleep(delay).await;
+        }
     }
     
     /// Tests if the connection to Ollama is working
     pub async fn test_connection(&self) -> Result<bool> {
-        let request_url = format!("{}/api/tags", self.api_url);
+        let request_url = self.get_models_url();
         
-        let response = self.client
-            .get(&request_url)
+        let response = self.authorize(self.client.get(&request_url))
             .send()
             .await;
             
@@ -57,12 +419,23 @@ impl OllamaClient {
         }
     }
     
-    /// Gets a list of available models from Ollama
+    /// Get the names of the installed models, sorted
     pub async fn get_available_models(&self) -> Result<Vec<String>> {
-        let request_url = format!("{}/api/tags", self.api_url);
+        let mut names: Vec<String> = self.get_model_list().await?
+            .into_iter()
+            .map(|model| model.name)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+    
+    /// Get the installed models with their size and modification time, where the server reports them
+    pub async fn get_model_list(&self) -> Result<Vec<ModelInfo>> {
+        let request_url = self.get_models_url();
         
-        let response = self.client
-            .get(&request_url)
+        let response = self.authorize(self.client.get(&request_url))
             .send()
             .await?;
             
@@ -73,76 +446,693 @@ impl OllamaClient {
         let body = response.text().await?;
         let json: Value = serde_json::from_str(&body)?;
         
-        // Parse the JSON response to extract model names
-        let models = match json.get("models") {
-            Some(models_array) => {
-                let mut model_names = HashSet::new();
-                
-                if let Some(array) = models_array.as_array() {
-                    for model_obj in array {
-                        if let Some(name) = model_obj.get("name").and_then(|n| n.as_str()) {
-                            model_names.insert(name.to_string());
-                        }
-                    }
-                }
-                
-                // Convert to sorted Vec
-                let mut model_names_vec: Vec<String> = model_names.into_iter().collect();
-                model_names_vec.sort();
-                model_names_vec
-            },
-            None => Vec::new(),
+        // Ollama lists `models[].name`, OpenAI-compatible servers list `data[].id`
+        let (list_key, name_key) = match self.config.backend {
+            Backend::Ollama => ("models", "name"),
+            Backend::OpenAiCompatible => ("data", "id"),
         };
         
+        let models = json.get(list_key)
+            .and_then(|models| models.as_array())
+            .map(|array| array.iter()
+                .filter_map(|model_obj| {
+                    let name = model_obj.get(name_key)?.as_str()?;
+                    Some(ModelInfo {
+                        name: name.to_string(),
+                        size: model_obj.get("size").and_then(|size| size.as_u64()),
+                        modified_at: model_obj.get("modified_at").and_then(|m| m.as_str()).map(str::to_string),
+                    })
+                })
+                .collect())
+            .unwrap_or_default();
+        
         Ok(models)
     }
 
-    pub async fn generate_response(
+    /// Ask Ollama about a model's context window
+    pub async fn show_model(&self, name: &str) -> Result<ModelDetails> {
+        if self.config.backend == Backend::OpenAiCompatible {
+            return Err(anyhow!("OpenAI-compatible servers don't describe their models"));
+        }
+        
+        let request_url = format!("{}/api/show", self.api_url);
+        // Older Ollama versions only know `name`
+        let request_body = serde_json::json!({ "model": name, "name": name });
+        let response = self.client.post(&request_url)
+            .json(&request_body)
+            .timeout(self.request_timeout())
+            .send()
+            .await?;
+        
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            return Err(anyhow!("Failed to describe model '{}' (Status: {}): {}", name, status, body));
+        }
+        
+        let json: Value = serde_json::from_str(&body)?;
+        
+        // Parameters come as Modelfile lines, e.g. "num_ctx 4096\nstop <|eot|>"
+        let num_ctx = json.get("parameters")
+            .and_then(Value::as_str)
+            .and_then(|parameters| parameters.lines().find_map(|line| {
+                let mut words = line.split_whitespace();
+                (words.next() == Some("num_ctx")).then(|| words.next()?.parse().ok()).flatten()
+            }));
+        
+        // The key is prefixed with the architecture, e.g. "llama.context_length"
+        let context_length = json.get("model_info")
+            .and_then(Value::as_object)
+            .and_then(|info| info.iter().find(|(key, _)| key.ends_with(".context_length")))
+            .and_then(|(_, value)| value.as_u64());
+        
+        Ok(ModelDetails { num_ctx, context_length })
+    }
+    
+    /// The active model's details, asking Ollama only the first time in a session
+    pub async fn model_details(&self) -> Option<ModelDetails> {
+        if let Some(details) = self.model_details.lock().ok()?.get(&self.model) {
+            return *details;
+        }
+        
+        let details = match self.show_model(&self.model).await {
+            Ok(details) => Some(details),
+            Err(e) => {
+                verbose!("Couldn't get the details of '{}': {:#}", self.model, e);
+                None
+            }
+        };
+        self.model_details.lock().ok()?.insert(self.model.clone(), details);
+        details
+    }
+    
+    /// The number of tokens the active model can take in: the configured `num_ctx`, then the
+    /// Modelfile's, then Ollama's default, but never more than the model was trained for.
+    /// Returns None when the server couldn't tell.
+    pub async fn context_window(&self) -> Option<u64> {
+        let details = self.model_details().await?;
+        let num_ctx = self.config.get_generation_options(&self.model)
+            .and_then(|options| options.num_ctx)
+            .map(u64::from)
+            .or(details.num_ctx)
+            .unwrap_or(OLLAMA_DEFAULT_NUM_CTX);
+        Some(details.context_length.map_or(num_ctx, |length| num_ctx.min(length)))
+    }
+    
+    /// Get the URL of the endpoint used for generation
+    pub fn get_endpoint_url(&self) -> String {
+        if self.config.backend == Backend::OpenAiCompatible {
+            format!("{}/v1/chat/completions", self.api_url)
+        } else if self.config.use_chat_api {
+            format!("{}/api/chat", self.api_url)
+        } else {
+            format!("{}/api/generate", self.api_url)
+        }
+    }
+    
+    /// Build the request body for the configured endpoint
+    fn build_body(
         &self,
         prompt: &str,
         context: &str,
         conversation_history: &[String],
-    ) -> Result<String> {
+        stream: bool,
+    ) -> Result<Value> {
+        let conversation_history = self.history_window(conversation_history, prompt);
+        let body = if self.config.backend == Backend::OpenAiCompatible {
+            serde_json::to_value(self.build_openai_request(prompt, context, conversation_history, stream))?
+        } else if self.config.use_chat_api {
+            serde_json::to_value(self.build_chat_request(prompt, context, conversation_history, stream))?
+        } else {
+            serde_json::to_value(self.build_request(prompt, context, conversation_history, stream))?
+        };
+        Ok(body)
+    }
+    
+    /// The part of the history sent with a request: the current turn plus the last
+    /// `max_history_turns` turns before it (each a user entry and the replies to it).
+    /// Older turns are left out so long sessions still fit the model's context window.
+    pub fn history_window<'a>(&self, conversation_history: &'a [String], prompt: &str) -> &'a [String] {
+        let max_turns = self.config.max_history_turns;
+        if max_turns == 0 {
+            return conversation_history;
+        }
+        
+        // The current turn is usually already in the history; it doesn't count against the limit
+        let is_current = |entry: &String| entry.strip_prefix("User: ") == Some(prompt);
+        let keep = max_turns + usize::from(conversation_history.last().is_some_and(is_current));
+        
+        let start = conversation_history.iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, entry)| entry.starts_with("User: "))
+            .nth(keep - 1)
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+        
+        if start > 0 {
+            verbose!("[verbose] Leaving the oldest {} history entries out of the request (max_history_turns = {})", start, max_turns);
+        }
+        &conversation_history[start..]
+    }
+    
+    /// Build the `/api/chat` request from the system prompt, the role-tagged history and the new turn
+    fn build_chat_request(
+        &self,
+        prompt: &str,
+        context: &str,
+        conversation_history: &[String],
+        stream: bool,
+    ) -> OllamaChatRequest {
+        OllamaChatRequest {
+            model: self.model.clone(),
+            messages: self.build_messages(prompt, context, conversation_history),
+            stream,
+            options: self.config.get_generation_options(&self.model),
+        }
+    }
+    
+    /// Build the `/v1/chat/completions` request, mapping generation options to OpenAI's names
+    fn build_openai_request(
+        &self,
+        prompt: &str,
+        context: &str,
+        conversation_history: &[String],
+        stream: bool,
+    ) -> OpenAiChatRequest {
+        let options = self.config.get_generation_options(&self.model).unwrap_or_default();
+        
+        OpenAiChatRequest {
+            model: self.model.clone(),
+            messages: self.build_messages(prompt, context, conversation_history),
+            stream,
+            temperature: options.temperature,
+            top_p: options.top_p,
+            max_tokens: options.num_predict,
+            seed: options.seed,
+        }
+    }
+    
+    /// Build role-tagged messages from the system prompt, the history and the new turn
+    fn build_messages(&self, prompt: &str, context: &str, conversation_history: &[String]) -> Vec<ChatMessage> {
+        let mut messages = vec![ChatMessage::new("system", &self.get_system_prompt())];
+        
+        // The current user turn is usually already in the history; it's re-sent below with the context
+        let mut history = conversation_history;
+        if let Some(last) = history.last() {
+            if last.strip_prefix("User: ") == Some(prompt) {
+                history = &history[..history.len() - 1];
+            }
+        }
+        
+        for entry in history {
+            if let Some(content) = entry.strip_prefix("User: ") {
+                messages.push(ChatMessage::new("user", content));
+            } else if let Some(content) = entry.strip_prefix("Assistant: ") {
+                messages.push(ChatMessage::new("assistant", content));
+            }
+        }
+        
+        // The history went in as messages above, so its placeholder is left empty
+        let user_turn = self.fill_prompt("", context, prompt);
+        messages.push(ChatMessage::new("user", user_turn.trim_start()));
+        
+        messages
+    }
+    
+    /// The prompt laid out by `prompt_template`, followed by the format reminder when
+    /// `reinforce_format` is on
+    fn fill_prompt(&self, history: &str, context: &str, prompt: &str) -> String {
+        let filled = fill_prompt_template(&self.config.prompt_template, history, context, prompt);
+        if self.config.reinforce_format {
+            format!("{}\n\n{}", filled, self.config.format_reminder)
+        } else {
+            filled
+        }
+    }
+    
+    /// Build the `/api/generate` request body from the history, context and prompt
+    fn build_request(
+        &self,
+        prompt: &str,
+        context: &str,
+        conversation_history: &[String],
+        stream: bool,
+    ) -> OllamaRequest {
         let history = conversation_history.join("\n");
         
         // Get the configured system prompt for this model
-        let system_prompt = self.config.get_system_prompt(&self.model);
+        let system_prompt = self.get_system_prompt();
+        
+        let full_prompt = self.fill_prompt(&history, context, prompt);
         
-        let full_prompt = format!(
-            "{}\n\nContext of the current directory:\n{}\n\nUser request: {}",
-            history, context, prompt
-        );
+        OllamaRequest {
+            model: self.model.clone(),
+            prompt: full_prompt,
+            system: system_prompt,
+            stream,
+            options: self.config.get_generation_options(&self.model),
+        }
+    }
 
-        let request_url = format!("{}/api/generate", self.api_url);
+    /// Generates a complete response. With `cache_responses`, an identical earlier request
+    /// is answered from the cache and new responses are stored in it.
+    pub async fn generate_response(
+        &self,
+        prompt: &str,
+        context: &str,
+        conversation_history: &[String],
+    ) -> Result<ModelResponse> {
+        let cache_key = self.cache_key(prompt, context, conversation_history)?;
+        if let Some(response) = cache_key.as_deref().and_then(cache::load) {
+            return Ok(response);
+        }
+        
+        let response = self.fetch_response(prompt, context, conversation_history).await?;
+        self.store_in_cache(cache_key.as_deref(), &response);
+        Ok(response)
+    }
+    
+    /// Like `generate_response`, calling `on_fragment` with each piece of the response as
+    /// it arrives. A cached response arrives as a single piece.
+    pub async fn generate_response_streaming<F: FnMut(&str)>(
+        &self,
+        prompt: &str,
+        context: &str,
+        conversation_history: &[String],
+        mut on_fragment: F,
+    ) -> Result<ModelResponse> {
+        let cache_key = self.cache_key(prompt, context, conversation_history)?;
+        if let Some(response) = cache_key.as_deref().and_then(cache::load) {
+            on_fragment(&response.text);
+            return Ok(response);
+        }
+        
+        let response = self.fetch_response_streaming(prompt, context, conversation_history, on_fragment).await?;
+        self.store_in_cache(cache_key.as_deref(), &response);
+        Ok(response)
+    }
+    
+    /// The response cache key for a request, or None when caching is off. Streaming and
+    /// complete requests share entries.
+    fn cache_key(&self, prompt: &str, context: &str, conversation_history: &[String]) -> Result<Option<String>> {
+        if !self.config.cache_responses {
+            return Ok(None);
+        }
         
-        let request_body = json!({
-            "model": self.model,
-            "prompt": full_prompt,
-            "system": system_prompt,
-            "stream": false
-        });
+        let body = self.build_body(prompt, context, conversation_history, false)?;
+        Ok(Some(cache::cache_key(&self.get_endpoint_url(), &body.to_string())))
+    }
+    
+    /// Save a response in the cache; failing to is only worth a warning
+    fn store_in_cache(&self, cache_key: Option<&str>, response: &ModelResponse) {
+        if let Some(cache_key) = cache_key {
+            if let Err(e) = cache::store(cache_key, response) {
+                status!("{}", format!("⚠️  {:#}", e).yellow());
+            }
+        }
+    }
+    
+    /// Sends the request and parses the complete response
+    async fn fetch_response(
+        &self,
+        prompt: &str,
+        context: &str,
+        conversation_history: &[String],
+    ) -> Result<ModelResponse> {
+        let request_url = self.get_endpoint_url();
+        let request_body = self.build_body(prompt, context, conversation_history, false)?;
+        log_request(&request_url, &request_body);
 
-        let raw_response = self.client
-            .post(&request_url)
-            .json(&request_body)
-            .send()
-            .await?;
+        let raw_response = self.send_with_retry(&request_url, &request_body, false).await?;
             
         // Store status and raw text for debugging purposes
         let status = raw_response.status();
         let body = raw_response.text().await?;
+        verbose!("[verbose] Response (status {}):\n{}", status, body);
+        
+        // Ollama answers with a 404 when the model has been removed since it was selected
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(anyhow!(ApiError::ModelNotFound(self.model.clone())));
+        }
+        
+        // Ollama reports failures such as running out of memory as `{"error": "..."}`, with any status
+        if let Some(error) = server_error(&body) {
+            return Err(anyhow!(error));
+        }
         
         // Try to deserialize
-        match serde_json::from_str::<OllamaResponse>(&body) {
-            Ok(parsed) => Ok(parsed.response),
+        let parsed = if self.config.backend == Backend::OpenAiCompatible {
+            serde_json::from_str::<OpenAiChatResponse>(&body).map_err(anyhow::Error::from).and_then(|parsed| {
+                let usage = parsed.usage.and_then(|usage| TokenUsage::from_counts(usage.prompt_tokens, usage.completion_tokens));
+                parsed.choices.into_iter().next()
+                    .map(|choice| ModelResponse {
+                        truncated: is_length_limit(&choice.finish_reason),
+                        cached: false,
+                        text: choice.message.content,
+                        usage,
+                    })
+                    .ok_or_else(|| anyhow!("response has no choices"))
+            })
+        } else if self.config.use_chat_api {
+            serde_json::from_str::<OllamaChatResponse>(&body).map(|parsed| ModelResponse {
+                text: parsed.message.content,
+                usage: TokenUsage::from_counts(parsed.prompt_eval_count, parsed.eval_count),
+                truncated: is_length_limit(&parsed.done_reason),
+                cached: false,
+            }).map_err(anyhow::Error::from)
+        } else {
+            serde_json::from_str::<OllamaResponse>(&body).map(|parsed| ModelResponse {
+                text: parsed.response,
+                usage: TokenUsage::from_counts(parsed.prompt_eval_count, parsed.eval_count),
+                truncated: is_length_limit(&parsed.done_reason),
+                cached: false,
+            }).map_err(anyhow::Error::from)
+        };
+        
+        match parsed {
+            Ok(response) => Ok(response),
             Err(e) => {
                 // Include meaningful error that shows what's happening
                 let err_msg = format!(
                     "Failed to parse response (Status: {}): {} \nRequest URL: {}\nRaw response: {}", 
                     status, e, request_url, body
                 );
-                Err(anyhow::anyhow!(err_msg))
+                Err(anyhow!(err_msg))
+            }
+        }
+    }
+    
+    /// Requests a response with streaming enabled, calling `on_fragment` with each piece
+    /// of the response as it arrives. Returns the complete response once Ollama reports done.
+    async fn fetch_response_streaming<F: FnMut(&str)>(
+        &self,
+        prompt: &str,
+        context: &str,
+        conversation_history: &[String],
+        mut on_fragment: F,
+    ) -> Result<ModelResponse> {
+        let request_url = self.get_endpoint_url();
+        let request_body = self.build_body(prompt, context, conversation_history, true)?;
+        log_request(&request_url, &request_body);
+
+        let raw_response = self.send_with_retry(&request_url, &request_body, true).await?;
+            
+        let status = raw_response.status();
+        verbose!("[verbose] Streaming response (status {})", status);
+        
+        // Ollama answers with a 404 when the model has been removed since it was selected
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(anyhow!(ApiError::ModelNotFound(self.model.clone())));
+        }
+        
+        if !status.is_success() {
+            let body = raw_response.text().await.unwrap_or_default();
+            if let Some(error) = server_error(&body) {
+                return Err(anyhow!(error));
+            }
+            return Err(anyhow!("Request failed (Status: {})\nRequest URL: {}\nRaw response: {}", status, request_url, body));
+        }
+        
+        let mut stream = raw_response.bytes_stream();
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut full_response = String::new();
+        // OpenAI-compatible servers give the finish reason before `[DONE]`
+        let mut truncated = false;
+        
+        loop {
+            // Give up if the server goes quiet for longer than the timeout
+            let chunk = match tokio::time::timeout(self.request_timeout(), stream.next()).await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(_) => return Err(anyhow!(
+                    "No data from the server for {} seconds after receiving {} characters",
+                    self.config.request_timeout_secs, full_response.len()
+                )),
+            };
+            let chunk = chunk.map_err(|e| anyhow!(
+                "Connection to Ollama dropped mid-response after receiving {} characters: {}",
+                full_response.len(), e
+            ))?;
+            buffer.extend_from_slice(&chunk);
+            
+            // Process every complete line in the buffer; a partial line waits for more bytes
+            while let Some(newline_pos) = buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=newline_pos).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                verbose!("[verbose] < {}", line);
+                
+                // OpenAI-compatible servers stream server-sent events ending with `data: [DONE]`
+                if self.config.backend == Backend::OpenAiCompatible {
+                    let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                        continue;
+                    };
+                    if data == "[DONE]" {
+                        return Ok(ModelResponse { text: full_response, usage: None, truncated, cached: false });
+                    }
+                    
+                    let parsed: OpenAiStreamChunk = serde_json::from_str(data)
+                        .map_err(|e| anyhow!("Failed to parse streamed chunk: {}\nRaw chunk: {}", e, data))?;
+                    
+                    truncated |= parsed.choices.first().is_some_and(|choice| is_length_limit(&choice.finish_reason));
+                    if let Some(fragment) = parsed.choices.first().and_then(|choice| choice.delta.content.as_deref()) {
+                        if !fragment.is_empty() {
+                            on_fragment(fragment);
+                            full_response.push_str(fragment);
+                        }
+                    }
+                    continue;
+                }
+                
+                let parsed: OllamaStreamChunk = serde_json::from_str(line)
+                    .map_err(|e| anyhow!("Failed to parse streamed chunk: {}\nRaw chunk: {}", e, line))?;
+                
+                if let Some(error) = parsed.error {
+                    return Err(anyhow!("Ollama reported an error mid-stream: {}", error));
+                }
+                
+                // `/api/generate` streams `response`, `/api/chat` streams `message.content`
+                let fragment = match &parsed.message {
+                    Some(message) => message.content.as_str(),
+                    None => parsed.response.as_str(),
+                };
+                
+                if !fragment.is_empty() {
+                    on_fragment(fragment);
+                    full_response.push_str(fragment);
+                }
+                
+                // The final chunk carries the token counts for the whole response
+                if parsed.done {
+                    return Ok(ModelResponse {
+                        text: full_response,
+                        usage: TokenUsage::from_counts(parsed.prompt_eval_count, parsed.eval_count),
+                        truncated: is_length_limit(&parsed.done_reason),
+                        cached: false,
+                    });
+                }
+            }
+        }
+        
+        Err(anyhow!(
+            "Response stream ended before Ollama reported completion ({} characters received). The connection may have been interrupted.",
+            full_response.len()
+        ))
+    }
+    
+    /// Compute the embedding of `text` with the configured `embedding_model`
+    pub async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        let model = &self.config.embedding_model;
+        let (request_url, request_body) = match self.config.backend {
+            Backend::Ollama => (
+                format!("{}/api/embeddings", self.api_url),
+                serde_json::json!({ "model": model, "prompt": text }),
+            ),
+            Backend::OpenAiCompatible => (
+                format!("{}/v1/embeddings", self.api_url),
+                serde_json::json!({ "model": model, "input": text }),
+            ),
+        };
+        verbose!("Embedding {} characters with {}", text.len(), model);
+        
+        let raw_response = self.send_with_retry(&request_url, &request_body, false).await?;
+        let status = raw_response.status();
+        let body = raw_response.text().await?;
+        if let Some(error) = server_error(&body) {
+            return Err(anyhow!(error)).with_context(|| format!("Failed to compute an embedding with '{}'", model));
+        }
+        if !status.is_success() {
+            return Err(anyhow!("Failed to compute an embedding with '{}' (Status: {}): {}", model, status, body));
+        }
+        
+        let embedding = match self.config.backend {
+            Backend::Ollama => serde_json::from_str::<OllamaEmbeddingResponse>(&body)
+                .map(|response| response.embedding),
+            Backend::OpenAiCompatible => serde_json::from_str::<OpenAiEmbeddingResponse>(&body)
+                .map(|response| response.data.into_iter().next().map(|data| data.embedding).unwrap_or_default()),
+        }.map_err(|e| anyhow!("Failed to parse embedding response: {}\nRaw response: {}", e, body))?;
+        
+        if embedding.is_empty() {
+            return Err(anyhow!("'{}' returned an empty embedding; is it an embedding model?", model));
+        }
+        
+        Ok(embedding)
+    }
+    
+    /// Download a model into Ollama, calling `on_progress` with each status update
+    pub async fn pull_model<F: FnMut(&PullProgress)>(&self, name: &str, mut on_progress: F) -> Result<()> {
+        let request_url = format!("{}/api/pull", self.api_url);
+        let request_body = serde_json::json!({ "name": name, "stream": true });
+        
+        let raw_response = self.client
+            .post(&request_url)
+            .json(&request_body)
+            .send()
+            .await?;
+        
+        let status = raw_response.status();
+        if !status.is_success() {
+            let body = raw_response.text().await.unwrap_or_default();
+            return Err(anyhow!("Failed to pull model '{}' (Status: {}): {}", name, status, body));
+        }
+        
+        let mut stream = raw_response.bytes_stream();
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut succeeded = false;
+        
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| anyhow!("Connection to Ollama dropped while pulling '{}': {}", name, e))?;
+            buffer.extend_from_slice(&chunk);
+            
+            while let Some(newline_pos) = buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=newline_pos).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                
+                let progress: PullProgress = serde_json::from_str(line)
+                    .map_err(|e| anyhow!("Failed to parse pull progress: {}\nRaw chunk: {}", e, line))?;
+                
+                if let Some(error) = progress.error {
+                    return Err(anyhow!("Ollama failed to pull '{}': {}", name, error));
+                }
+                
+                succeeded = progress.status == "success";
+                on_progress(&progress);
             }
         }
+        
+        if !succeeded {
+            return Err(anyhow!("Pulling '{}' ended before Ollama reported success", name));
+        }
+        
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// True when a done or finish reason says generation hit the token limit
+fn is_length_limit(reason: &Option<String>) -> bool {
+    reason.as_deref() == Some("length")
+}
+
+/// Log what is about to be sent: the URL, the system prompt and the prompt or messages,
+/// whichever the request body for the configured API carries
+fn log_request(request_url: &str, request_body: &Value) {
+    if !crate::utils::is_verbose() {
+        return;
+    }
+    
+    eprintln!("[verbose] POST {}", request_url);
+    if let Some(system) = request_body.get("system").and_then(Value::as_str) {
+        eprintln!("[verbose] System prompt:\n{}", system);
+    }
+    if let Some(prompt) = request_body.get("prompt").and_then(Value::as_str) {
+        eprintln!("[verbose] Full prompt:\n{}", prompt);
+    }
+    for message in request_body.get("messages").and_then(Value::as_array).into_iter().flatten() {
+        let role = message.get("role").and_then(Value::as_str).unwrap_or_default();
+        let content = message.get("content").and_then(Value::as_str).unwrap_or_default();
+        eprintln!("[verbose] {} message:\n{}", role, content);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn client(config: Config) -> OllamaClient {
+        OllamaClient::new("http://localhost:11434", "test-model", config)
+    }
+    
+    /// A history of `turns` question and answer pairs, followed by the current question
+    fn history(turns: usize) -> Vec<String> {
+        let mut history = Vec::new();
+        for turn in 1..=turns {
+            history.push(format!("User: question {}", turn));
+            history.push(format!("Assistant: answer {}", turn));
+        }
+        history.push("User: current".to_string());
+        history
+    }
+    
+    #[test]
+    fn oldest_turns_are_evicted_past_max_history_turns() {
+        let client = client(Config { max_history_turns: 2, ..Config::default() });
+        let history = history(5);
+        
+        let window = client.history_window(&history, "current");
+        assert_eq!(window, &history[6..]);
+        assert_eq!(window[0], "User: question 4");
+        
+        let body = client.build_body("current", "", &history, false).unwrap();
+        let prompt = body["prompt"].as_str().unwrap();
+        assert!(prompt.contains("answer 4") && prompt.contains("answer 5"));
+        assert!(!prompt.contains("question 3"));
+    }
+    
+    #[test]
+    fn history_within_the_limit_is_kept_whole() {
+        let short = history(2);
+        assert_eq!(client(Config { max_history_turns: 2, ..Config::default() }).history_window(&short, "current"), &short[..]);
+        // 0 turns off the limit
+        let long = history(50);
+        assert_eq!(client(Config { max_history_turns: 0, ..Config::default() }).history_window(&long, "current"), &long[..]);
+    }
+    
+    #[test]
+    fn reinforce_format_appends_the_reminder_to_the_prompt() {
+        let config = Config { reinforce_format: true, ..Config::default() };
+        let reminder = config.format_reminder.clone();
+        let history = history(1);
+        
+        let request = client(config).build_request("current", "fn main() {}", &history, false);
+        assert!(!reminder.is_empty());
+        assert!(request.prompt.ends_with(&reminder));
+        assert!(request.prompt.contains("current"));
+        
+        let request = client(Config { reinforce_format: false, ..Config::default() }).build_request("current", "", &history, false);
+        assert!(!request.prompt.contains(&reminder));
+    }
+    
+    #[test]
+    fn seed_is_sent_in_the_options() {
+        let options = GenerationOptions { seed: Some(42), temperature: Some(0.0), ..GenerationOptions::default() };
+        let config = Config { default_options: options, ..Config::default() };
+        
+        let body = client(config.clone()).build_body("current", "", &[], false).unwrap();
+        assert_eq!(body["options"]["seed"], 42);
+        assert_eq!(body["options"]["temperature"], 0.0);
+        
+        let openai = client(Config { backend: Backend::OpenAiCompatible, ..config });
+        assert_eq!(openai.build_body("current", "", &[], false).unwrap()["seed"], 42);
+        
+        let body = client(Config::default()).build_body("current", "", &[], false).unwrap();
+        assert!(body["options"].get("seed").is_none());
+    }
+}
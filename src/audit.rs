@@ -0,0 +1,75 @@
+use anyhow::{Context as AnyhowContext, Result};
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::diff::FileDiff;
+use crate::utils::ensure_directory_exists;
+
+/// What happened to a proposed change
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Applied,
+    Rejected,
+}
+
+/// One line of the audit log
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    /// Seconds since the Unix epoch
+    timestamp: u64,
+    action: AuditAction,
+    file: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rename_from: Option<&'a Path>,
+    is_new_file: bool,
+    is_deletion: bool,
+    prompt: &'a str,
+    model: &'a str,
+    old_bytes: usize,
+    new_bytes: usize,
+}
+
+/// Append a JSON line for each diff to the audit log at `path`. When the log has grown
+/// past `max_size_kb`, it's first moved to `<path>.1`, replacing an older one.
+pub fn record(path: &Path, max_size_kb: u64, action: AuditAction, diffs: &[FileDiff], prompt: &str, model: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        ensure_directory_exists(parent)?;
+    }
+
+    if fs::metadata(path).is_ok_and(|metadata| metadata.len() > max_size_kb * 1024) {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        fs::rename(path, &rotated)
+            .with_context(|| format!("Failed to rotate the audit log {}", path.display()))?;
+    }
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let mut lines = String::new();
+    for diff in diffs {
+        let entry = AuditEntry {
+            timestamp,
+            action,
+            file: diff.get_file_path(),
+            rename_from: diff.rename_from().map(|path| path.as_path()),
+            is_new_file: diff.is_new_file(),
+            is_deletion: diff.is_deletion(),
+            prompt,
+            model,
+            old_bytes: diff.old_content().len(),
+            new_bytes: diff.new_content().len(),
+        };
+        lines.push_str(&serde_json::to_string(&entry)?);
+        lines.push('\n');
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(lines.as_bytes()))
+        .with_context(|| format!("Failed to write to the audit log {}", path.display()))
+}
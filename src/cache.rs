@@ -0,0 +1,69 @@
+use anyhow::{Context as AnyhowContext, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::api::ModelResponse;
+use crate::config::get_config_dir;
+use crate::utils::ensure_directory_exists;
+
+/// A response as stored in the cache
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedResponse {
+    text: String,
+    #[serde(default)]
+    truncated: bool,
+}
+
+/// Directory holding the cached responses, `<config dir>/cache`
+pub fn cache_dir() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("cache"))
+}
+
+/// The cache key for a request: a hash of the endpoint and the request body, which holds
+/// the model, system prompt, context, history, prompt and generation options
+pub fn cache_key(url: &str, body: &str) -> String {
+    format!("{:016x}", fnv1a(url.as_bytes().iter().chain([0].iter()).chain(body.as_bytes())))
+}
+
+/// The cached response for `key`, if there is one. An unreadable entry counts as a miss.
+pub fn load(key: &str) -> Option<ModelResponse> {
+    let json = fs::read_to_string(cache_dir().ok()?.join(format!("{}.json", key))).ok()?;
+    let cached: CachedResponse = serde_json::from_str(&json).ok()?;
+    Some(ModelResponse { text: cached.text, usage: None, truncated: cached.truncated, cached: true })
+}
+
+/// Store a response under `key`
+pub fn store(key: &str, response: &ModelResponse) -> Result<()> {
+    let dir = cache_dir()?;
+    ensure_directory_exists(&dir)?;
+
+    let path = dir.join(format!("{}.json", key));
+    let json = serde_json::to_string(&CachedResponse { text: response.text.clone(), truncated: response.truncated })?;
+    fs::write(&path, json)
+        .with_context(|| format!("Failed to write cached response: {}", path.display()))
+}
+
+/// Delete every cached response, returning how many there were
+pub fn clear() -> Result<usize> {
+    let dir = cache_dir()?;
+    if !dir.exists() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read cache directory: {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to delete cached response: {}", path.display()))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` gives the same keys across builds
+pub(crate) fn fnv1a<'a>(bytes: impl Iterator<Item = &'a u8>) -> u64 {
+    bytes.fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
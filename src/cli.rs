@@ -1,21 +1,36 @@
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, Context as AnyhowContext};
 use clap::{Parser, Subcommand};
 use colored::Colorize;
-use dialoguer::{theme::ColorfulTheme, Select};
+use dialoguer::{theme::ColorfulTheme, Confirm, MultiSelect, Select};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use notify::event::ModifyKind;
+use notify::{EventKind, RecursiveMode, Watcher};
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 use std::thread;
-use std::time::Duration;
-use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use std::io::{self, IsTerminal, Read, Write};
 
-use crate::api::OllamaClient;
-use crate::config::{load_config, get_config_dir, get_config_path};
-use crate::context::ContextManager;
-use crate::diff::{DiffGenerator, DiffAction};
+use crate::api::{ApiError, ModelInfo, ModelResponse, OllamaClient};
+use crate::audit::{self, AuditAction};
+use crate::cache;
+use crate::config::{back_up_broken_config, load_config, set_global_value, Config, ConfigError, get_config_dir, get_config_path, get_local_config_path, continuation_prompt, repair_diff_prompt, strict_diff_instruction, Backend, ContextMode, HistoryScope, ModelNotFoundAction};
+use crate::context::{ContextManager, ContextProgress, ContextResult};
+use crate::diff::{DiffBlockFilter, DiffError, DiffGenerator, DiffAction, DiffExtraction, FileDiff, HunkStatus, SkippedBlock, has_unterminated_fence, is_read_only, save_patches, set_read_only, undo_last, UndoStep};
+use crate::editorconfig::EditorConfig;
+use crate::git;
+use crate::highlight::{self, LineKind};
+use crate::hooks::{run_hook, PostApplyHooks};
+use crate::project::{resolve_project_type, ResolvedProjectType};
+use crate::session::{Session, LAST_SESSION};
+use crate::shell::{read_clipboard, ShellCommand, ShellCommandExtractor};
+use crate::{status, verbose};
+use crate::utils::{file_role, is_verbose, language_for_path, print_paged, FileRole, read_text_file, set_pager, show_in_pager, set_status_to_stderr, set_verbose};
 
 #[derive(Parser)]
 #[clap(author, version, about)]
@@ -24,18 +39,137 @@ pub struct Cli {
     command: Option<Commands>,
 
     /// The model to use for code suggestions
-    #[clap(short, long)]
+    #[clap(short, long, env = "CODE_LLM_MODEL")]
     model: Option<String>,
 
-    /// Ollama API endpoint URL
-    #[clap(long, default_value = "http://localhost:11434")]
-    api_url: String,
+    /// Ollama API endpoint URL [default: http://localhost:11434, or the profile's server]
+    #[clap(long, env = "CODE_LLM_API_URL")]
+    api_url: Option<String>,
+
+    /// Bearer token for an OpenAI-compatible backend (overrides `api_key` in the config)
+    #[clap(long, env = "CODE_LLM_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Use a server from `profiles` in the config, with its model and key (switch with /profile)
+    #[clap(long, env = "CODE_LLM_PROFILE")]
+    profile: Option<String>,
+
+    /// Log requests, raw responses and diff parsing details to stderr
+    #[clap(short, long, alias = "debug")]
+    verbose: bool,
+
+    /// Never write to disk: diffs are shown but can't be applied (overrides `read_only` in the config)
+    #[clap(long, env = "CODE_LLM_READ_ONLY")]
+    read_only: bool,
+
+    /// Only print responses, without looking for changes in them; implies --read-only
+    /// (overrides `explain_only` in the config)
+    #[clap(long, env = "CODE_LLM_EXPLAIN_ONLY")]
+    explain_only: bool,
+
+    /// Show suggested changes but never write them to disk
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Don't check the connection or list models at startup; use the configured model as is
+    #[clap(long)]
+    offline: bool,
+
+    /// Send requests without the project's files, for general questions (toggle with /nocontext)
+    #[clap(long)]
+    no_context: bool,
+
+    /// List the files sent as context when an interactive session starts (see /context)
+    #[clap(long)]
+    show_context: bool,
+
+    /// Don't run the `post_apply_hooks` from the config on applied files
+    #[clap(long)]
+    no_hooks: bool,
+
+    /// Reuse stored responses for identical requests, and store new ones (see `cache clear`)
+    #[clap(long)]
+    cache: bool,
+
+    /// Stop generating after this many tokens (overrides `num_predict` in the config)
+    #[clap(long, value_name = "N")]
+    max_tokens: Option<i32>,
+
+    /// Random seed for generation (overrides `seed` in the config); with temperature 0,
+    /// the same request gets the same response
+    #[clap(long, value_name = "N")]
+    seed: Option<i64>,
+
+    /// Force the project type instead of detecting it (e.g. rust, node, python, go, java)
+    #[clap(long)]
+    project_type: Option<String>,
+
+    /// How much of each file to send as context (overrides `context_mode` in the config)
+    #[clap(long, value_enum)]
+    context_mode: Option<ContextMode>,
+
+    /// Don't syntax-highlight code in diff previews
+    #[clap(long)]
+    no_highlight: bool,
+
+    /// Don't use colors; diffs are printed as plain patches
+    #[clap(long)]
+    no_color: bool,
+
+    /// Show responses and diffs taller than the terminal in a pager (`PAGER`, or `less`)
+    #[clap(long)]
+    pager: bool,
+
+    /// Use a named system prompt from `personas` in the config (e.g. explain, production)
+    #[clap(long)]
+    persona: Option<String>,
+
+    /// Use the contents of this file as the system prompt, instead of any in the config
+    #[clap(long, value_name = "PATH", conflicts_with = "persona")]
+    system_prompt_file: Option<PathBuf>,
+
+    /// Only include files matching this glob in the context (repeatable, e.g. '**/*.rs')
+    #[clap(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Leave files matching this glob out of the context (repeatable, e.g. 'vendor/**')
+    #[clap(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Add a file from outside the project to the context as reference (repeatable); it's
+    /// sent whatever the ignore and size rules, and never changed
+    #[clap(long, value_name = "PATH")]
+    include_external: Vec<PathBuf>,
+
+    /// Unchanged lines to show around each change in diff previews (overrides `diff_context_lines` in the config)
+    #[clap(long, value_name = "N")]
+    context_lines: Option<usize>,
+
+    /// Ask before reviewing a response with more than N changes one by one (overrides `max_diffs_per_response` in the config; 0 for no limit)
+    #[clap(long, value_name = "N")]
+    max_diffs: Option<usize>,
+
+    /// Project directory to work on, instead of the current directory
+    #[clap(long, value_name = "DIR")]
+    project_root: Option<PathBuf>,
+
+    /// Write applied changes to this directory instead of the working tree (overrides `output_root` in the config)
+    #[clap(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Initialize a new context
-    Init,
+    Init {
+        /// Use this model without asking; fails if it isn't installed
+        #[clap(long)]
+        model: Option<String>,
+        
+        /// Overwrite an existing local config without asking
+        #[clap(short, long)]
+        force: bool,
+    },
     
     /// Edit the configuration
     Config {
@@ -46,27 +180,287 @@ enum Commands {
         /// Open the configuration file in the default editor
         #[clap(short, long)]
         edit: bool,
+        
+        #[clap(subcommand)]
+        action: Option<ConfigAction>,
+    },
+    
+    /// Ask a single question and exit, without entering interactive mode
+    Ask {
+        /// The request to send to the model; `-` (or leaving it out with input piped in) reads it from stdin
+        prompt: Option<String>,
+        
+        /// Apply suggested changes without asking for confirmation
+        #[clap(short, long, conflicts_with = "dry_run")]
+        yes: bool,
+        
+        /// Show suggested changes but never apply them
+        #[clap(long)]
+        dry_run: bool,
+        
+        /// Print suggested changes as colored diffs (text) or as a JSON array for scripts (json)
+        #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        
+        /// Also write each suggested change to a `.patch` file in this directory
+        #[clap(long, value_name = "DIR")]
+        save_patch: Option<PathBuf>,
+    },
+    
+    /// Ask a question, then ask it again every time a project file changes, until Ctrl+C.
+    /// Suggestions are printed but never applied.
+    Watch {
+        /// The request to send to the model
+        prompt: String,
+    },
+    
+    /// Inspect the system prompt sent to the model
+    Prompt {
+        #[clap(subcommand)]
+        action: PromptAction,
+    },
+    
+    /// Save or resume conversations
+    Session {
+        #[clap(subcommand)]
+        action: SessionAction,
+    },
+    
+    /// Work with patch files without asking the model
+    Diff {
+        #[clap(subcommand)]
+        action: PatchAction,
+    },
+    
+    /// Manage the response cache used with --cache
+    Cache {
+        #[clap(subcommand)]
+        action: CacheAction,
+    },
+    
+    /// List the installed models with their sizes, largest first
+    Models,
+    
+    /// Check the setup (Ollama, models, configuration, permissions) and suggest fixes
+    Doctor,
+}
+
+/// How `ask` prints its results
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Change a setting in the global configuration
+    Set {
+        /// Dotted key of the setting (e.g. model, default_options.temperature)
+        key: String,
+        
+        /// The new value, as TOML (plain words are taken as strings)
+        #[clap(allow_hyphen_values = true)]
+        value: String,
+    },
+    
+    /// Print the effective value of a setting
+    Get {
+        /// Dotted key of the setting (e.g. model, default_options.temperature)
+        key: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionAction {
+    /// Save the most recent conversation under a name
+    Save {
+        /// The name to save the session as
+        name: String,
+    },
+    
+    /// Start interactive mode with a saved conversation
+    Load {
+        /// The name of the session to resume
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum PatchAction {
+    /// Review and apply the changes in a patch file, like a model's suggestions
+    Apply {
+        /// A unified diff (e.g. from `git diff` or --save-patch) or a saved response with fenced diffs
+        patch: PathBuf,
+        
+        /// Apply every change without asking for confirmation
+        #[clap(short, long, conflicts_with = "dry_run")]
+        yes: bool,
+        
+        /// Show the changes but never apply them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    
+    /// Review and apply a unified diff copied to the clipboard, e.g. from a web chat
+    Paste {
+        /// Apply every change without asking for confirmation
+        #[clap(short, long, conflicts_with = "dry_run")]
+        yes: bool,
+        
+        /// Show the changes but never apply them
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheAction {
+    /// Delete all cached responses
+    Clear,
+}
+
+#[derive(Subcommand)]
+enum PromptAction {
+    /// Print the effective system prompt for a model
+    Show {
+        /// The model to resolve the prompt for (defaults to --model)
+        #[clap(short, long)]
+        model: Option<String>,
     },
 }
 
 pub async fn run_cli() -> Result<()> {
     let cli = Cli::parse();
-    let model_opt = cli.model;
-    let api_url = cli.api_url;
     
-    // Load configuration
-    let config = load_config()?;
+    set_verbose(cli.verbose);
+    
+    // Color only when writing to a terminal, and never with --no-color or NO_COLOR set
+    let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    colored::control::set_override(!cli.no_color && !no_color_env && io::stdout().is_terminal());
+    
+    // When stdout is piped it holds only the output itself (the response, patches or JSON);
+    // progress, warnings and hunk reports go to stderr
+    if !io::stdout().is_terminal() {
+        set_status_to_stderr(true);
+    }
+    
+    // Settings resolve as: command-line flag > CODE_LLM_* environment variable (both via
+    // clap) > local config > global config > built-in default
+    
+    // The context, diffs, backups and local config are all relative to the project root
+    let project_root = resolve_project_root(cli.project_root.as_deref())?;
+    
+    // The doctor reports a broken configuration instead of failing on it
+    if let Some(Commands::Doctor) = cli.command {
+        return run_doctor(&project_root, &resolve_api_url(cli.api_url, None), cli.model, cli.api_key).await;
+    }
+    
+    // Showing or editing the config file doesn't need it to load, so a broken one can be fixed
+    if let Some(Commands::Config { path, edit, action: None }) = &cli.command {
+        return run_config_file(*path, *edit, &project_root);
+    }
+    
+    // Load configuration (global, with the project-local config layered on top)
+    let read_only_command = matches!(&cli.command,
+        Some(Commands::Prompt { .. }) | Some(Commands::Config { action: Some(ConfigAction::Get { .. }), .. }));
+    let mut config = load_config_or_recover(&project_root, read_only_command)?;
+    config.explain_only |= cli.explain_only;
+    set_read_only(cli.read_only || config.read_only || config.explain_only);
+    // A profile picks the server unless --api-url is given, and the model unless --model is
+    let mut profile_model = None;
+    let mut profile_api_url = None;
+    if let Some(name) = &cli.profile {
+        let profile = config.use_profile(name)?;
+        profile_api_url = Some(profile.api_url);
+        profile_model = profile.model;
+    }
+    let api_url = resolve_api_url(cli.api_url, profile_api_url);
+    if let Some(api_key) = cli.api_key {
+        config.api_key = Some(api_key);
+    }
+    if let Some(context_mode) = cli.context_mode {
+        config.context_mode = context_mode;
+    }
+    if cli.offline {
+        config.offline = true;
+    }
+    if cli.no_context {
+        config.no_context = true;
+    }
+    if cli.show_context {
+        config.show_context = true;
+    }
+    if cli.no_hooks {
+        config.post_apply_hooks.clear();
+    }
+    if cli.cache {
+        config.cache_responses = true;
+    }
+    if let Some(max_tokens) = cli.max_tokens {
+        // Model-specific options would otherwise win over the default
+        config.default_options.num_predict = Some(max_tokens);
+        for options in config.options.values_mut() {
+            options.num_predict = Some(max_tokens);
+        }
+    }
+    if let Some(seed) = cli.seed {
+        config.default_options.seed = Some(seed);
+        for options in config.options.values_mut() {
+            options.seed = Some(seed);
+        }
+    }
+    if let Some(output_dir) = cli.output_dir {
+        // A relative --output-dir is where the user is, not under the project root
+        config.output_root = Some(std::env::current_dir()?.join(output_dir));
+    }
+    config.include_globs.extend(cli.include);
+    config.exclude_globs.extend(cli.exclude);
+    // Relative to where the user is, like --output-dir
+    let current_dir = std::env::current_dir()?;
+    config.external_files.extend(cli.include_external.into_iter().map(|path| current_dir.join(path)));
+    if let Some(context_lines) = cli.context_lines {
+        config.diff_context_lines = context_lines;
+    }
+    if let Some(max_diffs) = cli.max_diffs {
+        config.max_diffs_per_response = max_diffs;
+    }
+    if let Some(persona) = cli.persona {
+        config.persona = Some(persona);
+    }
+    if let Some(persona) = &config.persona {
+        config.check_persona(persona)?;
+    }
+    if let Some(path) = &cli.system_prompt_file {
+        // Replaces the default and model-specific prompts, and a persona set in the config
+        config.default_system_prompt = read_system_prompt_file(path)?;
+        config.model_prompts.clear();
+        config.persona = None;
+    }
+    
+    highlight::set_enabled(config.syntax_highlighting && !cli.no_highlight);
+    set_pager(config.pager || cli.pager);
+    
+    // An explicit --model (or CODE_LLM_MODEL) wins over the profile's model and the configured one
+    let model_opt = cli.model.or(profile_model).or_else(|| config.model.clone());
+    
+    // Resolve the project type: --project-type, then config, then detection
+    let project_type_opt = cli.project_type.clone().or_else(|| config.project_type.clone());
+    let project = resolve_project_type(project_type_opt.as_deref(), &config.project_types, &project_root)?;
 
     match &cli.command {
-        Some(Commands::Init) => {
+        Some(Commands::Init { model, force }) => {
             println!("{}", "Initializing new context...".green());
             
             // Create local .code-llm directory path
-            let local_config_dir = PathBuf::from(".code-llm");
-            let local_config_path = local_config_dir.join("config.toml");
+            let local_config_path = get_local_config_path(&project_root);
+            let local_config_dir = local_config_path.parent().unwrap_or(Path::new(".")).to_path_buf();
             
             // Check if local config already exists
-            let should_proceed = if local_config_path.exists() {
+            let should_proceed = if local_config_path.exists() && *force {
+                println!("{}", format!("Overwriting the existing config at {}", local_config_path.display()).yellow());
+                true
+            } else if local_config_path.exists() {
                 println!("{}", format!("⚠️  Warning: Local config file already exists at {}", local_config_path.display()).yellow());
                 println!("{}", "Initializing will overwrite the existing configuration.".yellow());
                 
@@ -88,8 +482,11 @@ pub async fn run_cli() -> Result<()> {
                 return Ok(());
             }
             
-            // Check if Ollama is running and select a model
-            let selected_model = initialize_with_model_selection(model_opt, &api_url, &config).await?;
+            // Check if Ollama is running and select a model; a model given to init must already be installed
+            let selected_model = match model {
+                Some(model) => initialize_with_model_selection(Some(model.clone()), &api_url, &config, false).await?,
+                None => initialize_with_model_selection(model_opt, &api_url, &config, true).await?,
+            };
             
             // Create directory if needed
             if !local_config_dir.exists() {
@@ -115,316 +512,2815 @@ model = "{}"
             
             return Ok(());
         }
-        Some(Commands::Config { path, edit }) => {
-            let config_path = get_config_path()?;
-            
-            if *path {
-                // Just show the path to the config file
-                println!("{}", config_path.to_string_lossy());
-                return Ok(());
+        Some(Commands::Config { action: Some(ConfigAction::Set { key, value }), .. }) => {
+            // Only the global file's own keys are edited, so neither project-local values
+            // nor the ones it extends are copied into it
+            let config_path = set_global_value(key, value)?;
+            println!("{}", format!("✅ Set {} in {}", key, config_path.display()).green());
+            return Ok(());
+        }
+        Some(Commands::Config { action: Some(ConfigAction::Get { key }), .. }) => {
+            match config.get_value(key)? {
+                Some(toml::Value::String(value)) => println!("{}", value),
+                Some(toml::Value::Table(table)) => print!("{}", toml::to_string_pretty(&table)?),
+                Some(value) => println!("{}", value),
+                None => return Err(anyhow!("{} is not set", key)),
             }
+            return Ok(());
+        }
+        Some(Commands::Prompt { action: PromptAction::Show { model } }) => {
+            let model = model.clone().or(model_opt).unwrap_or_default();
             
-            if *edit {
-                // Try to open the default editor
-                #[cfg(target_os = "windows")]
-                {
-                    std::process::Command::new("notepad")
-                        .arg(&config_path)
-                        .spawn()?;
-                }
-                
-                #[cfg(not(target_os = "windows"))]
-                {
-                    // Try to get the default editor from environment variables
-                    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
-                    std::process::Command::new(editor)
-                        .arg(&config_path)
-                        .spawn()?
-                        .wait()?;
-                }
-                
-                println!("{}", format!("Edited configuration at {}", config_path.display()).green());
-                return Ok(());
+            // Let the user know which prompt was resolved without polluting the prompt output
+            if let Some(path) = &cli.system_prompt_file {
+                eprintln!("{}", format!("Using system prompt from {}", path.display()).blue());
+            } else if let Some(persona) = &config.persona {
+                eprintln!("{}", format!("Using persona '{}'", persona).blue());
+            } else if config.model_prompts.contains_key(&model) {
+                eprintln!("{}", format!("Using model-specific system prompt for '{}'", model).blue());
+            } else {
+                eprintln!("{}", "Using default system prompt".blue());
             }
             
-            // Default behavior: print the config file contents
-            if config_path.exists() {
-                let config_content = fs::read_to_string(&config_path)?;
-                println!("{}", config_content);
-            } else {
-                println!("{}", "Configuration file does not exist yet. It will be created when you first run the tool.".yellow());
+            let mut client = OllamaClient::new(&api_url, &model, config.clone());
+            if let Some(project) = &project {
+                eprintln!("{}", format!("Including instructions for project type '{}'", project.name).blue());
+                client.set_project_prompt(project.bundle.prompt.clone());
             }
+            
+            println!("{}", client.get_system_prompt());
             return Ok(());
         }
+        Some(Commands::Ask { prompt, yes, dry_run, format, save_patch }) => {
+            let prompt = resolve_ask_prompt(prompt.as_deref())?;
+            let options = AskOptions {
+                apply: *yes && !*dry_run && !cli.dry_run,
+                format: *format,
+                save_patch: save_patch.as_deref(),
+            };
+            run_ask(&prompt, options, model_opt, &api_url, config, project, &project_root).await?;
+        }
+        Some(Commands::Watch { prompt }) => {
+            run_watch(prompt, model_opt, &api_url, config, project, &project_root).await?;
+        }
+        Some(Commands::Diff { action: PatchAction::Apply { patch, yes, dry_run } }) => {
+            run_diff_apply(patch, *yes, *dry_run || cli.dry_run, &config, &project_root)?;
+        }
+        Some(Commands::Diff { action: PatchAction::Paste { yes, dry_run } }) => {
+            run_diff_paste(*yes, *dry_run || cli.dry_run, &config, &project_root)?;
+        }
+        Some(Commands::Cache { action: CacheAction::Clear }) => {
+            let removed = cache::clear()?;
+            println!("{}", format!("✅ Deleted {} cached response(s) from {}", removed, cache::cache_dir()?.display()).green());
+        }
+        Some(Commands::Models) => {
+            let client = OllamaClient::new(&api_url, "", config.clone());
+            let mut models = client.get_model_list().await
+                .with_context(|| format!("Failed to list models from {}", api_url))?;
+            print_model_table(&mut models);
+        }
+        Some(Commands::Doctor) => unreachable!("the doctor runs before the configuration is loaded"),
+        Some(Commands::Config { action: None, .. }) => unreachable!("the config file is shown before the configuration is loaded"),
+        Some(Commands::Session { action: SessionAction::Save { name } }) => {
+            let session = Session::load(LAST_SESSION)
+                .map_err(|e| anyhow!("No conversation to save: {:#}", e))?;
+            let path = session.save(name)?;
+            println!("{}", format!("✅ Saved session '{}' to {}", name, path.display()).green());
+        }
+        Some(Commands::Session { action: SessionAction::Load { name } }) => {
+            let session = Session::load_or_default(name);
+            // Resume with the session's model unless --model was given
+            let model_opt = model_opt.or_else(|| session.model.clone());
+            run_interactive_mode(model_opt, &api_url, config, project, cli.dry_run, session, &project_root).await?;
+        }
         None => {
             // Interactive mode
-            run_interactive_mode(model_opt, &api_url, config).await?;
+            run_interactive_mode(model_opt, &api_url, config, project, cli.dry_run, Session::default(), &project_root).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a system prompt from a file, which must exist and have some text in it
+fn read_system_prompt_file(path: &Path) -> Result<String> {
+    let prompt = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read system prompt file {}", path.display()))?;
+    if prompt.trim().is_empty() {
+        return Err(anyhow!("System prompt file {} is empty", path.display()));
+    }
+    Ok(prompt)
+}
+
+/// Print the global config file, its path with `path`, or open it in the editor with `edit`
+fn run_config_file(path: bool, edit: bool, root: &Path) -> Result<()> {
+    let config_path = get_config_path()?;
+    
+    if path {
+        // Just show the path to the config file
+        println!("{}", config_path.to_string_lossy());
+        return Ok(());
+    }
+    
+    if edit {
+        open_in_editor(&config_path)?;
+        println!("{}", format!("Edited configuration at {}", config_path.display()).green());
+        if let Err(e) = load_config(root) {
+            println!("{}", format!("⚠️  The configuration still doesn't load: {:#}", e).yellow());
+        }
+        return Ok(());
+    }
+    
+    // Default behavior: print the config file contents
+    if config_path.exists() {
+        let config_content = fs::read_to_string(&config_path)?;
+        println!("{}", config_content);
+    } else {
+        println!("{}", "Configuration file does not exist yet. It will be created when you first run the tool.".yellow());
+    }
+    Ok(())
+}
+
+/// Load the configuration. When a config file is broken, say where, and in a terminal offer
+/// to move it aside and start from the defaults, or to use the defaults for this run.
+/// Otherwise only `read_only_command`s go on, with the defaults.
+fn load_config_or_recover(root: &Path, read_only_command: bool) -> Result<Config> {
+    loop {
+        let error = match load_config(root) {
+            Ok(config) => return Ok(config),
+            Err(e) => e,
+        };
+        let Some(config_error) = error.downcast_ref::<ConfigError>() else {
+            return Err(error);
+        };
+        
+        eprintln!("{}", format!("❌ The configuration couldn't be loaded: {}", config_error).red());
+        let broken_path = match config_error {
+            ConfigError::Syntax { path, column, source_line, .. } => {
+                eprintln!("    {}", source_line);
+                eprintln!("    {}{}", " ".repeat(column - 1), "^".red());
+                Some(path.clone())
+            },
+            ConfigError::Invalid(_) => None,
+        };
+        
+        // Only the global and the project config are ours to move aside; a file they
+        // extend is left to the user
+        let is_own_config = |path: &Path| [get_config_path().ok(), Some(get_local_config_path(root))]
+            .into_iter()
+            .flatten()
+            .filter_map(|own| fs::canonicalize(own).ok())
+            .any(|own| own == path);
+        let replaceable = broken_path.filter(|path| is_own_config(path));
+        
+        if !io::stdin().is_terminal() {
+            if read_only_command {
+                eprintln!("{}", "Continuing with the default settings.".yellow());
+                return Ok(Config::default());
+            }
+            return Err(anyhow!("Fix the configuration, e.g. with `code-llm config --edit`, and try again"));
+        }
+        
+        let mut options = Vec::new();
+        if replaceable.is_some() {
+            options.push("Back up the broken file and start from the defaults");
         }
+        options.push("Continue with the default settings for this run");
+        options.push("Quit to fix it (`code-llm config --edit` opens the global config)");
+        
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("What now?")
+            .default(0)
+            .items(&options)
+            .interact()?;
+        
+        match (options[selection], &replaceable) {
+            (option, Some(path)) if option.starts_with("Back up") => {
+                let backup_path = back_up_broken_config(path)?;
+                println!("{}", format!("Moved the broken config to {}", backup_path.display()).green());
+                // Recreate the global file; a project config is simply gone, so the global one applies
+                if get_config_path().is_ok_and(|global| global == *path) {
+                    Config::default().save()?;
+                    println!("{}", format!("Wrote the default configuration to {}", path.display()).green());
+                }
+            },
+            (option, _) if option.starts_with("Continue") => return Ok(Config::default()),
+            _ => return Err(anyhow!("Fix the configuration, e.g. with `code-llm config --edit`, and try again")),
+        }
+    }
+}
+
+/// The directory code-llm works on: --project-root if given, otherwise the current directory
+/// Ollama's address when neither --api-url nor a profile names a server
+const DEFAULT_API_URL: &str = "http://localhost:11434";
+
+/// The server to talk to: --api-url (or CODE_LLM_API_URL), then the profile's, then the default
+fn resolve_api_url(cli_api_url: Option<String>, profile_api_url: Option<String>) -> String {
+    cli_api_url.or(profile_api_url).unwrap_or_else(|| DEFAULT_API_URL.to_string())
+}
+
+fn resolve_project_root(project_root: Option<&Path>) -> Result<PathBuf> {
+    let path = project_root.unwrap_or(Path::new("."));
+    let root = fs::canonicalize(path)
+        .with_context(|| format!("Project root {} doesn't exist", path.display()))?;
+    if !root.is_dir() {
+        return Err(anyhow!("Project root {} isn't a directory", path.display()));
     }
+    Ok(root)
+}
 
+/// Run each setup check, printing ✅ or ❌ with a hint on how to fix failures.
+/// Fails if any check failed, so scripts can tell.
+async fn run_doctor(root: &Path, api_url: &str, model_opt: Option<String>, api_key: Option<String>) -> Result<()> {
+    let mut failures = 0;
+    let mut check = |ok: bool, message: String, hint: &str| {
+        if ok {
+            println!("{} {}", "✅".green(), message);
+        } else {
+            failures += 1;
+            println!("{} {}", "❌".red(), message);
+            println!("   {}", hint.yellow());
+        }
+    };
+    
+    // Configuration
+    let mut config = match load_config(root) {
+        Ok(config) => {
+            check(true, "Configuration loads".to_string(), "");
+            config
+        },
+        Err(e) => {
+            check(false, format!("Configuration doesn't load: {:#}", e),
+                "Fix the file named above, or open the global one with `code-llm config --edit`");
+            crate::config::Config::default()
+        }
+    };
+    if let Some(api_key) = api_key {
+        config.api_key = Some(api_key);
+    }
+    
+    // Server and models
+    let client = OllamaClient::new(api_url, "", config.clone());
+    let reachable = client.test_connection().await.unwrap_or(false);
+    let message = if reachable { "Server reachable at" } else { "Server not reachable at" };
+    check(reachable, format!("{} {}", message, api_url),
+        "Start Ollama with `ollama serve`, or point code-llm at it with --api-url or CODE_LLM_API_URL");
+    
+    if reachable {
+        match client.get_available_models().await {
+            Ok(models) => {
+                check(!models.is_empty(), format!("{} model(s) installed", models.len()),
+                    "Install a model, e.g. `ollama pull llama3`");
+                
+                if let Some(model) = model_opt.or_else(|| config.model.clone()) {
+                    let installed = models.contains(&model);
+                    let message = if installed { "is installed" } else { "isn't installed" };
+                    check(installed, format!("Model '{}' {}", model, message),
+                        &format!("Install it with `ollama pull {}`, or pick one from `code-llm models`", model));
+                }
+            },
+            Err(e) => check(false, format!("Couldn't list models: {:#}", e),
+                "Check that --api-url points at an Ollama server (or set `backend` for an OpenAI-compatible one)"),
+        }
+    }
+    
+    // Changes are written to the project directory
+    let probe = root.join(format!(".code-llm-doctor-{}", std::process::id()));
+    let writable = fs::write(&probe, "").is_ok();
+    let _ = fs::remove_file(&probe);
+    let message = if writable { "Project directory is writable" } else { "Project directory isn't writable" };
+    check(writable, message.to_string(),
+        "Accepted changes can't be applied here; use --output-dir to write them elsewhere, or --read-only");
+    
+    // Editor for "Edit" and `config --edit`
+    match configured_editor() {
+        Some(editor) => check(true, format!("Editor is set ({})", editor), ""),
+        None => check(false, "Neither $VISUAL nor $EDITOR is set".to_string(),
+            &format!("Set one (e.g. `export EDITOR=nano`) to edit suggestions and the config; {} is used otherwise", DEFAULT_EDITOR)),
+    }
+    
+    if failures > 0 {
+        return Err(anyhow!("{} check(s) failed", failures));
+    }
+    
+    println!("{}", "Everything looks good.".green());
     Ok(())
 }
 
+/// A running "Thinking..." animation
+struct ThinkingAnimation {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
 /// Starts an animated "Thinking..." prompt with cycling dots in a separate thread.
 /// Returns a handle to the animation that can be used to stop it.
-fn start_thinking_animation() -> Arc<AtomicBool> {
-    let running = Arc::new(AtomicBool::new(true));
-    let running_clone = running.clone();
+fn start_thinking_animation() -> ThinkingAnimation {
+    let (stop, stopped) = mpsc::channel();
     
-    thread::spawn(move || {
+    let thread = thread::spawn(move || {
         let mut state = 0;
         let states = [".", "..", "...", "....", "....."];
+        let started = Instant::now();
         
-        while running_clone.load(Ordering::SeqCst) {
-            // Clear the line and print the current state
-            print!("\r{}{:<5}", "Thinking".yellow(), states[state].yellow());
-            io::stdout().flush().unwrap();
+        loop {
+            // Clear the line and print the current state, with how long we've been waiting
+            let elapsed = started.elapsed().as_secs();
+            print!("\r{}{:<5} {}", "Thinking".yellow(), states[state].yellow(),
+                format!("{:02}:{:02}", elapsed / 60, elapsed % 60).dimmed());
+            io::stdout().flush().ok();
             
             // Cycle through states
             state = (state + 1) % states.len();
             
-            // Wait a bit before updating
-            thread::sleep(Duration::from_millis(300));
+            // Wait before updating, waking up as soon as the animation is stopped
+            match stopped.recv_timeout(Duration::from_millis(300)) {
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                _ => break,
+            }
         }
         
         // Clear the line when done
-        print!("\r{:<15}\r", "");
-        io::stdout().flush().unwrap();
+        print!("\r{:<25}\r", "");
+        io::stdout().flush().ok();
     });
     
-    running
+    ThinkingAnimation { stop, thread }
 }
 
-/// Stops the thinking animation thread
-fn stop_thinking_animation(handle: Arc<AtomicBool>) {
-    handle.store(false, Ordering::SeqCst);
-    // Small delay to ensure the thread has time to clean up
-    thread::sleep(Duration::from_millis(50));
+/// Stops the thinking animation, returning once its line has been cleared so nothing
+/// printed afterwards can be overwritten
+fn stop_thinking_animation(animation: ThinkingAnimation) {
+    let _ = animation.stop.send(());
+    let _ = animation.thread.join();
 }
 
-/// Handles Ollama connectivity check and model selection
-/// Returns the selected model name
-async fn initialize_with_model_selection(model_opt: Option<String>, api_url: &str, config: &crate::config::Config) -> Result<String> {
-    // Create a temporary client for testing connection and getting models
-    let temp_client = OllamaClient::new(api_url, "", config.clone());
-    
-    // Test connection to Ollama on startup
-    println!("{}", "Testing connection to Ollama...".yellow());
-    match temp_client.test_connection().await {
-        Ok(true) => println!("{}", "✅ Connected to Ollama successfully!".green()),
-        Ok(false) => {
-            println!("{}", format!("❌ Failed to connect to Ollama at {}. Is Ollama running?", api_url).red());
-            println!("{}", "Please start Ollama and try again.".yellow());
-            return Err(anyhow!("Could not connect to Ollama"));
+/// Get the prompt for `ask`: the argument itself, or all of stdin when the argument is `-`
+/// or missing and input is piped in
+fn resolve_ask_prompt(prompt: Option<&str>) -> Result<String> {
+    match prompt {
+        Some(prompt) if prompt != "-" => return Ok(prompt.to_string()),
+        None if io::stdin().is_terminal() => {
+            return Err(anyhow!("No prompt given. Pass it as an argument, or pipe it in with `ask -`"));
         },
-        Err(e) => {
-            println!("{}", format!("❌ Error testing connection to Ollama: {}", e).red());
-            println!("{}", "Please check that Ollama is running and try again.".yellow());
-            return Err(anyhow!("Error testing connection to Ollama"));
-        }
+        _ => {},
     }
     
-    // Get available models
-    let available_models = match temp_client.get_available_models().await {
-        Ok(models) => models,
-        Err(e) => {
-            println!("{}", format!("❌ Error getting available models: {}", e).red());
-            return Err(anyhow!("Error getting available models"));
-        }
-    };
-    
-    if available_models.is_empty() {
-        println!("{}", "❌ No models found in Ollama. Please pull a model first.".red());
-        println!("{}", "Example: ollama pull llama3".yellow());
-        return Err(anyhow!("No models available"));
+    let mut prompt = String::new();
+    io::stdin().read_to_string(&mut prompt)?;
+    if prompt.trim().is_empty() {
+        return Err(anyhow!("The prompt read from stdin is empty"));
     }
-    
-    // Determine which model to use
-    let selected_model = match model_opt {
-        Some(model) => {
-            // Check if the specified model exists
-            println!("{}", format!("Checking if model '{}' is available...", model).yellow());
-            
-            if available_models.contains(&model) {
-                println!("{}", "✅ Model found!".green());
-                model
-            } else {
-                println!("{}", format!("⚠️ Model '{}' not found!", model).yellow());
-                select_model_from_list(&available_models)?
-            }
-        },
-        None => {
-            // No model specified, ask user to select one
-            println!("{}", "No model specified. Please select from available models:".blue());
-            select_model_from_list(&available_models)?
-        }
-    };
-    
-    Ok(selected_model)
+    Ok(prompt)
 }
 
-async fn run_interactive_mode(model_opt: Option<String>, api_url: &str, config: crate::config::Config) -> Result<()> {
-    // Check connectivity and select model
-    let selected_model = initialize_with_model_selection(model_opt, api_url, &config).await?;
+/// What `ask` does with the suggested changes
+struct AskOptions<'a> {
+    /// Apply the changes instead of only printing them
+    apply: bool,
+    format: OutputFormat,
+    /// Directory to write the changes to as `.patch` files
+    save_patch: Option<&'a Path>,
+}
+
+/// Runs a single non-interactive request against the current directory context.
+/// Suggested changes are printed, and applied only when `options.apply` is set.
+async fn run_ask(
+    prompt: &str,
+    options: AskOptions<'_>,
+    model_opt: Option<String>,
+    api_url: &str,
+    config: crate::config::Config,
+    project: Option<ResolvedProjectType>,
+    root: &Path,
+) -> Result<()> {
+    // JSON goes to stdout on its own: no colors, and status messages go to stderr
+    let json = options.format == OutputFormat::Json;
+    if json {
+        colored::control::set_override(false);
+        set_status_to_stderr(true);
+    }
     
-    // Create the client with the selected model
-    let client = OllamaClient::new(api_url, &selected_model, config.clone());
+    // Fail fast instead of prompting when no usable model is configured
+    let selected_model = initialize_with_model_selection(model_opt, api_url, &config, false).await?;
     
-    let context_manager = ContextManager::new(".")?;
-    let diff_generator = DiffGenerator::new();
+    let mut client = OllamaClient::new(api_url, &selected_model, config.clone());
+    client.set_project_prompt(project.as_ref().and_then(|p| p.bundle.prompt.clone()));
     
-    println!("{}", format!("Welcome to code-llm! Using model: {}", selected_model).green());
-    println!("{}", "Type your questions/requests or 'exit' to quit.".blue());
+    let mut context_manager = ContextManager::new(root, &config)?;
+    if let Some(project) = &project {
+        context_manager.apply_project_type(project)?;
+    }
+    let context = if config.no_context {
+        ContextResult::default()
+    } else if config.rank_by_relevance {
+        load_ranked_context(&mut context_manager, &client, prompt).await?
+    } else {
+        load_context(&mut context_manager)?
+    };
     
-    let mut conversation_history = Vec::new();
-    let mut current_context = context_manager.get_context()?;
+    apply_language_prompt(&mut client, &config, &context.files_included);
+    warn_if_over_context_window(&client, prompt, &context.text, &[]).await;
     
-    // Set up rustyline for history
-    let history_path = get_history_file_path()?;
-    let mut rl = DefaultEditor::new()?;
+    let response = client.generate_response(prompt, &context.text, &[]).await?;
+    note_if_cached(&response);
+    warn_if_truncated(&response);
+    let mut response = response.text;
     
-    // Load history if the file exists
-    if history_path.exists() {
-        if let Err(err) = rl.load_history(&history_path) {
-            println!("{}", format!("Warning: Failed to load history: {}", err).yellow());
+    // A response that stops inside a code block was cut off, so ask the model to go on
+    for continuation in 1..=config.max_continuations {
+        if !has_unterminated_fence(&response) {
+            break;
+        }
+        status!("{}", format!("The response stopped inside a code block. Asking the model to continue ({}/{})...",
+            continuation, config.max_continuations).yellow());
+        
+        let history = [format!("User: {}", prompt), format!("Assistant: {}", response)];
+        let more = client.generate_response(continuation_prompt(), &context.text, &history).await?;
+        warn_if_truncated(&more);
+        response.push_str(&more.text);
+    }
+    if !json {
+        print_ask_response(&config, &response);
+    }
+    
+    let diff_generator = DiffGenerator::new(root, &config);
+    let mut extraction = diff_generator.extract_diffs(&response);
+    use_whole_file_blocks(&config, &diff_generator, &response, &mut extraction);
+    let DiffExtraction { mut diffs, mut skipped } = extraction;
+    
+    // Retry once with a stricter diff instruction if a change was requested but none came back
+    if config.auto_reprompt_on_no_diff && diffs.is_empty() && looks_like_change_request(prompt) {
+        status!("{}", "No diffs found. Retrying once with a stricter diff format instruction...".yellow());
+        
+        let retry_prompt = format!("{}\n\n{}", prompt, strict_diff_instruction());
+        let history = [format!("User: {}", prompt), format!("Assistant: {}", response)];
+        let retry = client.generate_response(&retry_prompt, &context.text, &history).await?;
+        warn_if_truncated(&retry);
+        if !json {
+            print_ask_response(&config, &retry.text);
+        }
+        DiffExtraction { diffs, skipped } = diff_generator.extract_diffs(&retry.text);
+    }
+    report_skipped_blocks(&skipped);
+    
+    if config.respect_editorconfig {
+        if let Some(editorconfig) = EditorConfig::load(root)? {
+            for diff in diffs.iter_mut() {
+                diff.apply_editorconfig(&editorconfig);
+            }
+        }
+    }
+    
+    if json {
+        let changes: Vec<_> = diffs.iter().map(FileDiff::to_proposed_change).collect();
+        println!("{}", serde_json::to_string_pretty(&changes)?);
+    } else {
+        for diff in &diffs {
+            println!("{}", diff.display_diff());
+        }
+    }
+    
+    if diffs.is_empty() {
+        return Ok(());
+    }
+    
+    if let Some(dir) = options.save_patch {
+        let patches: Vec<_> = diffs.iter().map(|diff| (diff.get_file_path().clone(), diff.render_diff())).collect();
+        report_saved_patches(&save_patches(&patches, dir)?);
+    }
+    
+    if !options.apply {
+        status!("{}", "Changes were not applied. Re-run with --yes to apply them.".yellow());
+    } else if is_read_only() {
+        status!("{}", "🔒 Read-only mode: changes were not written.".yellow());
+    } else {
+        check_no_conflicts(&diffs)?;
+        let mut isolation = SessionIsolation::default();
+        isolation.isolate(&config, root)?;
+        // All-or-nothing, so a failed change never leaves the tree half-updated
+        diff_generator.apply_all(&diffs)?;
+        for diff in &diffs {
+            status!("{}", format!("✅ Changes successfully applied to {}", diff.get_file_path().display()).green());
+        }
+        run_post_apply_hooks(&config, root, &diffs);
+        audit_changes(root, &config, AuditAction::Applied, &diffs, prompt, client.get_model());
+        if config.auto_commit && config.output_root.is_none() {
+            commit_applied_changes(root, &diffs, prompt);
+        }
+        isolation.report();
+    }
+    
+    Ok(())
+}
+
+/// Print a response to `ask`; its diffs are printed again with the changes
+fn print_ask_response(config: &crate::config::Config, text: &str) {
+    if config.dedupe_diff_output {
+        println!("{}", DiffBlockFilter::filter_all(text, config.strict_diffs));
+    } else {
+        println!("{}", text);
+    }
+}
+
+/// How long the project has to be quiet after a change before `watch` asks again, so that
+/// saving several files (or an editor's write-and-rename) leads to one request
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Runs `prompt` like `ask` without applying anything, then again with a fresh context each
+/// time files that are part of the context change, until Ctrl+C
+async fn run_watch(
+    prompt: &str,
+    model_opt: Option<String>,
+    api_url: &str,
+    config: crate::config::Config,
+    project: Option<ResolvedProjectType>,
+    root: &Path,
+) -> Result<()> {
+    // Decides which changed paths matter, by the same rules as the context
+    let context_manager = ContextManager::new(root, &config)?;
+    let root = fs::canonicalize(root)?;
+    
+    let (sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let _ = sender.send(event);
+        }
+    }).context("Failed to start watching for file changes")?;
+    watcher.watch(&root, RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch {}", root.display()))?;
+    
+    loop {
+        let options = AskOptions {
+            apply: false,
+            format: OutputFormat::Text,
+            save_patch: None,
+        };
+        tokio::select! {
+            result = run_ask(prompt, options, model_opt.clone(), api_url, config.clone(), project.clone(), &root) => {
+                // A failed request doesn't end the loop; the next change may fix it
+                if let Err(e) = result {
+                    println!("{}", format!("❌ {:#}", e).red());
+                }
+            },
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+        
+        status!("{}", "\n👀 Watching for changes (Ctrl+C to stop)...".blue());
+        let mut changed = Vec::new();
+        while changed.is_empty() {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(event) => collect_watched_paths(&event, &context_manager, &mut changed),
+                    None => return Ok(()),
+                },
+                _ = tokio::signal::ctrl_c() => return Ok(()),
+            }
+        }
+        // Wait for the burst of changes to settle
+        while let Ok(Some(event)) = tokio::time::timeout(WATCH_DEBOUNCE, events.recv()).await {
+            collect_watched_paths(&event, &context_manager, &mut changed);
+        }
+        
+        let names: Vec<String> = changed.iter()
+            .map(|path| path.strip_prefix(&root).unwrap_or(path).display().to_string())
+            .collect();
+        status!("{}", format!("🔄 Changed: {}. Asking again...", names.join(", ")).blue());
+    }
+}
+
+/// Add the paths of a file system event that are part of the context to `changed`
+fn collect_watched_paths(event: &notify::Event, context_manager: &ContextManager, changed: &mut Vec<PathBuf>) {
+    // Reading files, which building the context does, isn't a change
+    let is_change = matches!(event.kind, EventKind::Create(_) | EventKind::Remove(_))
+        || matches!(event.kind, EventKind::Modify(kind) if !matches!(kind, ModifyKind::Metadata(_)));
+    if !is_change {
+        return;
+    }
+    for path in &event.paths {
+        if context_manager.is_watched(path) && !changed.contains(path) {
+            changed.push(path.clone());
+        }
+    }
+}
+
+/// Applies the changes in a patch file with the same review as the model's suggestions,
+/// or all of them at once with `yes`
+fn run_diff_apply(patch: &Path, yes: bool, dry_run: bool, config: &crate::config::Config, root: &Path) -> Result<()> {
+    let text = fs::read_to_string(patch)
+        .with_context(|| format!("Failed to read patch file {}", patch.display()))?;
+    apply_patch_text(&text, &patch.display().to_string(), &format!("Apply {}", patch.display()), yes, dry_run, config, root)
+}
+
+/// Applies the changes in a diff on the clipboard like `run_diff_apply` does for a file
+fn run_diff_paste(yes: bool, dry_run: bool, config: &crate::config::Config, root: &Path) -> Result<()> {
+    let text = read_clipboard()?;
+    if text.trim().is_empty() {
+        return Err(anyhow!("The clipboard is empty. Copy a unified diff first."));
+    }
+    apply_patch_text(&text, "the clipboard", "Apply a diff from the clipboard", yes, dry_run, config, root)
+}
+
+/// Reviews and applies the changes in a patch, read from `source`. `request` is recorded as
+/// the request that led to the changes, in the audit log and commit message.
+fn apply_patch_text(text: &str, source: &str, request: &str, yes: bool, dry_run: bool, config: &crate::config::Config, root: &Path) -> Result<()> {
+    let diff_generator = DiffGenerator::new(root, config);
+    let DiffExtraction { mut diffs, skipped } = diff_generator.extract_patch_diffs(text);
+    report_skipped_blocks(&skipped);
+    if diffs.is_empty() {
+        return Err(anyhow!("No changes found in {}", source));
+    }
+    
+    if config.respect_editorconfig {
+        if let Some(editorconfig) = EditorConfig::load(root)? {
+            for diff in diffs.iter_mut() {
+                diff.apply_editorconfig(&editorconfig);
+            }
+        }
+    }
+    
+    order_for_review(&mut diffs);
+    let accepted = if yes || dry_run || is_read_only() {
+        for diff in &diffs {
+            println!("{}", diff.display_diff());
+        }
+        if dry_run {
+            status!("{}", "Dry run: changes were not applied.".yellow());
+            return Ok(());
+        }
+        if is_read_only() {
+            status!("{}", "🔒 Read-only mode: changes were not written.".yellow());
+            return Ok(());
+        }
+        check_no_conflicts(&diffs)?;
+        diffs
+    } else {
+        let mut review = review_diffs(diffs, false, None)?;
+        if config.confirm_before_write {
+            confirm_writes(&mut review)?;
+        }
+        if !review.rejected.is_empty() {
+            audit_changes(root, config, AuditAction::Rejected, &review.rejected, request, "");
+        }
+        review.accepted
+    };
+    
+    let mut isolation = SessionIsolation::default();
+    if !accepted.is_empty() {
+        isolation.isolate(config, root)?;
+    }
+    let applied = apply_reviewed(&diff_generator, accepted, false)?;
+    run_post_apply_hooks(config, root, &applied);
+    if config.show_after_apply {
+        show_applied(&applied);
+    }
+    if !applied.is_empty() {
+        audit_changes(root, config, AuditAction::Applied, &applied, request, "");
+        if config.auto_commit && config.output_root.is_none() {
+            commit_applied_changes(root, &applied, request);
+        }
+    }
+    isolation.report();
+    
+    Ok(())
+}
+
+/// Gets a response from the model and displays it. When streaming is enabled the
+/// fragments are printed as they arrive; otherwise the "Thinking..." animation runs
+/// until the whole response is ready.
+async fn request_response(
+    client: &OllamaClient,
+    config: &crate::config::Config,
+    prompt: &str,
+    context: &str,
+    conversation_history: &[String],
+) -> Result<String> {
+    let sent_history = client.history_window(conversation_history, prompt);
+    print_prompt_size(client, context, sent_history);
+    if sent_history.len() < conversation_history.len() {
+        println!("{}", "Older turns of the conversation are left out (max_history_turns).".dimmed());
+    }
+    warn_if_over_context_window(client, prompt, context, sent_history).await;
+    
+    let mut thinking_handle = Some(start_thinking_animation());
+    
+    // Diffs are shown again for review, except in explain-only mode
+    let mut diff_filter = (config.dedupe_diff_output && !config.explain_only).then(|| DiffBlockFilter::new(config.strict_diffs));
+    
+    if !config.stream_responses {
+        let result = cancellable(client.generate_response(prompt, context, conversation_history)).await;
+        if let Some(handle) = thinking_handle.take() {
+            stop_thinking_animation(handle);
+        }
+        
+        let response = report_cancellation(result)?;
+        if diff_filter.is_some() {
+            print_paged(&format!("{}: {}", "Assistant".bright_blue(), DiffBlockFilter::filter_all(&response.text, config.strict_diffs)));
+        } else {
+            print_paged(&format!("{}: {}", "Assistant".bright_blue(), response.text));
+        }
+        print_token_usage(&response, None);
+        note_if_cached(&response);
+        warn_if_truncated(&response);
+        return Ok(response.text);
+    }
+    
+    // Generation speed is measured from the first fragment, after the prompt was processed
+    let mut first_fragment_at = None;
+    let result = cancellable(client.generate_response_streaming(prompt, context, conversation_history, |fragment| {
+        // Replace the animation with the response once the first fragment arrives
+        if let Some(handle) = thinking_handle.take() {
+            stop_thinking_animation(handle);
+            print!("{}: ", "Assistant".bright_blue());
+            first_fragment_at = Some(Instant::now());
+        }
+        match &mut diff_filter {
+            Some(filter) => print!("{}", filter.push(fragment)),
+            None => print!("{}", fragment),
+        }
+        io::stdout().flush().ok();
+    })).await;
+    
+    if let Some(filter) = &mut diff_filter {
+        print!("{}", filter.finish());
+    }
+    match thinking_handle.take() {
+        Some(handle) => stop_thinking_animation(handle),
+        None => println!(),
+    }
+    
+    let response = report_cancellation(result)?;
+    print_token_usage(&response, first_fragment_at.map(|at| at.elapsed()));
+    note_if_cached(&response);
+    warn_if_truncated(&response);
+    Ok(response.text)
+}
+
+/// Run a request until it completes or Ctrl+C is pressed. Dropping the request on Ctrl+C
+/// closes its connection, which also stops the server from generating.
+async fn cancellable<T>(request: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+    tokio::select! {
+        result = request => result,
+        _ = tokio::signal::ctrl_c() => Err(anyhow!(ApiError::Cancelled)),
+    }
+}
+
+/// Tell the user a request was cancelled; the caller just returns to the prompt
+fn report_cancellation<T>(result: Result<T>) -> Result<T> {
+    if result.as_ref().is_err_and(is_cancelled) {
+        println!("{}", "Generation cancelled".yellow());
+    }
+    result
+}
+
+fn is_cancelled(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<ApiError>(), Some(ApiError::Cancelled))
+}
+
+fn is_changed_on_disk(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<DiffError>(), Some(DiffError::ChangedOnDisk(_)))
+}
+
+/// Rough token estimate for sizing prompts, at about 4 characters per token
+fn estimate_tokens(bytes: usize) -> usize {
+    bytes.div_ceil(4)
+}
+
+/// Show how much of the prompt each part takes up, so the context window isn't overrun unnoticed
+fn print_prompt_size(client: &OllamaClient, context: &str, conversation_history: &[String]) {
+    let history_bytes: usize = conversation_history.iter().map(|entry| entry.len() + 1).sum();
+    let parts = [
+        ("context", context.len()),
+        ("history", history_bytes),
+        ("system prompt", client.get_system_prompt().len()),
+    ];
+    
+    let summary: Vec<String> = parts
+        .iter()
+        .map(|(name, bytes)| format!("{} {:.1} KB (~{} tokens)", name, *bytes as f64 / 1024.0, estimate_tokens(*bytes)))
+        .collect();
+    println!("{}", format!("Prompt size: {}", summary.join(", ")).dimmed());
+}
+
+/// Warn when the prompt probably doesn't fit in the model's context window, since Ollama
+/// then cuts part of it off without saying so
+async fn warn_if_over_context_window(client: &OllamaClient, prompt: &str, context: &str, conversation_history: &[String]) {
+    let Some(window) = client.context_window().await else {
+        return;
+    };
+    
+    let history_bytes: usize = conversation_history.iter().map(|entry| entry.len() + 1).sum();
+    let tokens = estimate_tokens(prompt.len() + context.len() + history_bytes + client.get_system_prompt().len()) as u64;
+    if tokens <= window {
+        return;
+    }
+    
+    let model = client.get_model();
+    let mut advice = format!("raise `num_ctx` under [options.\"{}\"]", model);
+    if let Some(length) = client.model_details().await.and_then(|details| details.context_length).filter(|&length| length > window) {
+        advice.push_str(&format!(" (the model supports up to {})", length));
+    }
+    status!("{}", format!(
+        "⚠️  The prompt (~{} tokens) is larger than the context window of '{}' ({} tokens), so part of it will be cut off. \
+        Send less context (--context-mode tree-only, @file mentions or --include), {}, or switch to a model with a larger window.",
+        tokens, model, window, advice
+    ).yellow());
+}
+
+/// Show the token counts reported by the server for a response
+fn print_token_usage(response: &ModelResponse, generation_time: Option<Duration>) {
+    let Some(usage) = response.usage else {
+        return;
+    };
+    
+    let mut line = format!("Tokens: {} prompt, {} generated", usage.prompt_tokens, usage.completion_tokens);
+    // A streamed response's generation speed, unless it was too quick to measure
+    if let Some(seconds) = generation_time.map(|time| time.as_secs_f64()).filter(|&seconds| seconds >= 0.1) {
+        line.push_str(&format!(" ({:.1} tokens/s)", usage.completion_tokens as f64 / seconds));
+    }
+    println!("{}", line.dimmed());
+}
+
+/// Point out a response that came from the cache, since it wasn't generated for this run
+fn note_if_cached(response: &ModelResponse) {
+    if response.cached {
+        status!("{}", "Cached response (--cache); run `code-llm cache clear` to ask the model again.".dimmed());
+    }
+}
+
+/// Warn that the response stopped at the token limit, so a diff at its end may be cut off
+fn warn_if_truncated(response: &ModelResponse) {
+    if response.truncated {
+        status!("{}", "⚠️  The response was cut off at the token limit (num_predict / --max-tokens); a diff at its end may be incomplete.".yellow());
+    }
+}
+
+/// Handles Ollama connectivity check and model selection
+/// Returns the selected model name. When `interactive` is false, a missing or
+/// unknown model is an error instead of prompting the user to pick one.
+async fn initialize_with_model_selection(
+    model_opt: Option<String>,
+    api_url: &str,
+    config: &crate::config::Config,
+    interactive: bool,
+) -> Result<String> {
+    // Trust the model without talking to the server; a bad model or URL fails the first request
+    if config.offline {
+        let model = model_opt.ok_or_else(|| {
+            anyhow!("No model specified. Offline mode can't list models, so pass --model or set `model` in the config")
+        })?;
+        status!("{}", format!("Offline mode: using model '{}' without checking that it's available", model).yellow());
+        return Ok(model);
+    }
+    
+    // Create a temporary client for testing connection and getting models
+    let temp_client = OllamaClient::new(api_url, "", config.clone());
+    
+    // Test connection to Ollama on startup
+    status!("{}", "Testing connection to Ollama...".yellow());
+    match temp_client.test_connection().await {
+        Ok(true) => status!("{}", "✅ Connected to Ollama successfully!".green()),
+        Ok(false) => {
+            status!("{}", format!("❌ Failed to connect to Ollama at {}. Is Ollama running?", api_url).red());
+            status!("{}", "Please start Ollama and try again.".yellow());
+            return Err(anyhow!("Could not connect to Ollama"));
+        },
+        Err(e) => {
+            status!("{}", format!("❌ Error testing connection to Ollama: {}", e).red());
+            status!("{}", "Please check that Ollama is running and try again.".yellow());
+            return Err(anyhow!("Error testing connection to Ollama"));
+        }
+    }
+    
+    // Get available models
+    let available_models = match temp_client.get_available_models().await {
+        Ok(models) => models,
+        Err(e) => {
+            status!("{}", format!("❌ Error getting available models: {}", e).red());
+            return Err(anyhow!("Error getting available models"));
+        }
+    };
+    
+    if available_models.is_empty() && model_opt.is_none() {
+        status!("{}", "❌ No models found in Ollama. Please pull a model first.".red());
+        status!("{}", "Example: ollama pull llama3".yellow());
+        return Err(anyhow!("No models available"));
+    }
+    
+    // Determine which model to use
+    let selected_model = match model_opt {
+        Some(model) => {
+            // Check if the specified model exists
+            status!("{}", format!("Checking if model '{}' is available...", model).yellow());
+            
+            if available_models.contains(&model) {
+                status!("{}", "✅ Model found!".green());
+                model
+            } else {
+                status!("{}", format!("⚠️ Model '{}' not found!", model).yellow());
+                if !interactive {
+                    if config.backend == Backend::Ollama {
+                        status!("{}", format!("Install it with: ollama pull {}", model).yellow());
+                    }
+                    return Err(anyhow!("Model '{}' is not available in Ollama", model));
+                }
+                
+                // Offer to download the requested model before falling back to the list
+                let can_pull = config.backend == Backend::Ollama;
+                if can_pull && Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!("Pull '{}' now?", model))
+                    .default(true)
+                    .interact()?
+                {
+                    pull_model_with_progress(&temp_client, &model).await?;
+                    status!("{}", format!("✅ Pulled model '{}'", model).green());
+                    model
+                } else if available_models.is_empty() {
+                    return Err(anyhow!("No models available"));
+                } else {
+                    select_model_from_list(&available_models)?
+                }
+            }
+        },
+        None if !interactive => {
+            return Err(anyhow!("No model specified. Pass --model to choose one of: {}", available_models.join(", ")));
+        },
+        None => {
+            // No model specified, ask user to select one
+            status!("{}", "No model specified. Please select from available models:".blue());
+            select_model_from_list(&available_models)?
+        }
+    };
+    
+    Ok(selected_model)
+}
+
+/// Branch that the conversation starts on
+const DEFAULT_BRANCH: &str = "main";
+
+async fn run_interactive_mode(
+    model_opt: Option<String>,
+    api_url: &str,
+    mut config: crate::config::Config,
+    project: Option<ResolvedProjectType>,
+    dry_run: bool,
+    session: Session,
+    root: &Path,
+) -> Result<()> {
+    // Check connectivity and select model
+    let selected_model = initialize_with_model_selection(model_opt, api_url, &config, true).await?;
+    
+    // Create the client with the selected model
+    let mut client = OllamaClient::new(api_url, &selected_model, config.clone());
+    // The server changes with /profile
+    let mut api_url = api_url.to_string();
+    client.set_project_prompt(project.as_ref().and_then(|p| p.bundle.prompt.clone()));
+    
+    let mut context_manager = ContextManager::new(root, &config)?;
+    if let Some(project) = &project {
+        println!("{}", format!("Project type: {}", project.name).blue());
+        context_manager.apply_project_type(project)?;
+    }
+    let diff_generator = DiffGenerator::new(root, &config);
+    
+    // Load .editorconfig so new files match the project's formatting conventions
+    let editorconfig = if config.respect_editorconfig {
+        EditorConfig::load(root)?
+    } else {
+        None
+    };
+    
+    println!("{}", format!("Welcome to code-llm! Using model: {}", selected_model).green());
+    println!("{}", "Type your questions/requests or 'exit' to quit.".blue());
+    
+    if config.explain_only {
+        println!("{}", "🔒 Explain-only mode: responses are only printed, and files are never changed.".yellow());
+    } else if is_read_only() {
+        println!("{}", "🔒 Read-only mode: suggested changes will be shown but never written to disk.".yellow());
+    } else if dry_run {
+        println!("{}", "Dry run: accepted changes will be reported but not written to disk.".yellow());
+    } else if let Some(output_root) = &config.output_root {
+        println!("{}", format!("Accepted changes will be written to {} instead of the working tree.", output_root.display()).yellow());
+    }
+    
+    // A loaded session seeds the history so the model keeps its earlier context
+    let mut conversation_history = session.conversation_history;
+    
+    // The changes proposed in the most recent response, as (file path, plain patch)
+    let mut last_patches: Vec<(PathBuf, String)> = Vec::new();
+    
+    // Histories of the branches that aren't active; `conversation_history` is the active one's
+    let mut branches: HashMap<String, Vec<String>> = HashMap::new();
+    let mut current_branch = DEFAULT_BRANCH.to_string();
+    
+    // Why the user rejected changes, to be sent to the model in place of the next input
+    let mut pending_feedback: Option<String> = None;
+    
+    let mut isolation = SessionIsolation::default();
+    let mut stats = SessionStats::new();
+    if !conversation_history.is_empty() {
+        println!("{}", format!("Resumed session with {} message(s).", conversation_history.len()).blue());
+    }
+    // Off with --no-context or /nocontext: requests go without the project's files
+    let mut no_context = config.no_context;
+    let mut current_context = reload_context(&mut context_manager, no_context)?;
+    // What's sent with every request, so nothing leaves the machine unnoticed
+    if !no_context {
+        print_context_summary(&current_context, config.show_context);
+    }
+    
+    // Subdirectory the context is gathered from (see /root); empty for the whole project
+    let mut context_scope = PathBuf::new();
+    
+    // Files chosen with /pick, sent in place of the whole context until the choice changes
+    let mut picked_files: Vec<PathBuf> = Vec::new();
+    
+    // Set up rustyline for history
+    // History is skipped if the config directory isn't available
+    let history_path = get_history_file_path(config.history, root).ok();
+    let mut rl = DefaultEditor::new()?;
+    
+    // Load history if the file exists
+    if let Some(history_path) = history_path.as_ref().filter(|p| p.exists()) {
+        if let Err(err) = rl.load_history(history_path) {
+            println!("{}", format!("Warning: Failed to load history: {}", err).yellow());
+        }
+    }
+    
+    loop {
+        // Feedback on rejected changes is sent as the next request without waiting for input
+        let user_input = if let Some(feedback) = pending_feedback.take() {
+            println!("{}", "Sending your feedback to the model...".blue());
+            feedback
+        } else {
+            // Get user input with history support
+            match rl.readline("You> ") {
+                Ok(line) => {
+                    // Add valid input to history
+                    if !line.trim().is_empty() {
+                        rl.add_history_entry(&line)?;
+                        
+                        // Append rather than rewrite, so sessions in other terminals sharing
+                        // the file don't lose their entries
+                        if let Some(history_path) = &history_path {
+                            if let Err(err) = rl.append_history(history_path) {
+                                println!("{}", format!("Warning: Failed to save history: {}", err).yellow());
+                            }
+                        }
+                    }
+                    line
+                },
+                Err(ReadlineError::Interrupted) => {
+                    println!("{}", "Interrupted (Ctrl+C)".blue());
+                    continue;
+                },
+                Err(ReadlineError::Eof) => {
+                    println!("{}", "Exiting due to Ctrl+D".blue());
+                    break;
+                },
+                Err(err) => {
+                    return Err(anyhow!("Error reading input: {}", err));
+                }
+            }
+        };
+        
+        // Collect multi-line input as a single prompt
+        let user_input = if user_input.trim() == "/multi" {
+            println!("{}", "Multi-line mode: enter your request and finish with /end on its own line.".blue());
+            match read_multiline_block(&mut rl)? {
+                Some(block) => block,
+                None => continue,
+            }
+        } else if user_input.ends_with('\\') {
+            match read_continuation_lines(&mut rl, &user_input)? {
+                Some(block) => block,
+                None => continue,
+            }
+        } else {
+            user_input
+        };
+        
+        if user_input.trim().is_empty() {
+            // Skip empty inputs
+            continue;
+        }
+        
+        if user_input.trim().to_lowercase() == "exit" || user_input.trim().to_lowercase() == "quit" {
+            break;
+        }
+        
+        // Slash commands control the session and are never sent to the model
+        if let Some(command) = user_input.trim().strip_prefix('/') {
+            let (name, arg) = command
+                .split_once(char::is_whitespace)
+                .map(|(name, arg)| (name, arg.trim()))
+                .unwrap_or((command, ""));
+            
+            match name {
+                "undo" if is_read_only() => println!("{}", "🔒 Read-only mode: /undo is disabled.".yellow()),
+                "undo" => match undo_last(root) {
+                    Ok(Some(steps)) => {
+                        for step in &steps {
+                            match step {
+                                UndoStep::Restore { path, .. } => println!("{}", format!("↩️  Restored {}", path.display()).green()),
+                                UndoStep::Remove { path } => println!("{}", format!("↩️  Removed {}", path.display()).green()),
+                            }
+                            context_manager.invalidate(step.path());
+                        }
+                        current_context = reload_context(&mut context_manager, no_context)?;
+                    },
+                    Ok(None) => println!("{}", "No backups to restore.".yellow()),
+                    Err(e) => println!("{}", format!("❌ Undo failed: {:#}", e).red()),
+                },
+                "context" => {
+                    let context = context_manager.get_context()?;
+                    print_context_summary(&context, true);
+                    current_context = context;
+                },
+                "nocontext" => {
+                    no_context = !no_context;
+                    current_context = reload_context(&mut context_manager, no_context)?;
+                    if no_context {
+                        println!("{}", "Context off: requests are sent without the project's files.".blue());
+                    } else {
+                        println!("{}", format!("Context on: {} file(s) in context.", current_context.files_included.len()).green());
+                    }
+                },
+                "clear" => {
+                    conversation_history.clear();
+                    println!("{}", "Conversation history cleared.".blue());
+                },
+                "branch" if arg.is_empty() => {
+                    let mut names: Vec<&String> = branches.keys().chain([&current_branch]).collect();
+                    names.sort();
+                    for name in names {
+                        let marker = if *name == current_branch { "*" } else { " " };
+                        println!("{} {}", marker, name);
+                    }
+                },
+                "branch" if *arg == current_branch || branches.contains_key(arg) => {
+                    println!("{}", format!("Branch '{}' already exists; use /switch {} to go to it.", arg, arg).yellow());
+                },
+                "branch" => {
+                    // The new branch starts from the conversation so far
+                    branches.insert(current_branch.clone(), conversation_history.clone());
+                    current_branch = arg.to_string();
+                    println!("{}", format!("Created branch '{}' from the current conversation and switched to it.", arg).green());
+                },
+                "switch" if arg.is_empty() => println!("{}", "Usage: /switch <branch>".yellow()),
+                "switch" if *arg == current_branch => println!("{}", format!("Already on branch '{}'.", arg).blue()),
+                "switch" => match branches.remove(arg) {
+                    Some(history) => {
+                        branches.insert(current_branch.clone(), std::mem::replace(&mut conversation_history, history));
+                        current_branch = arg.to_string();
+                        println!("{}", format!("Switched to branch '{}' ({} message(s)).", arg, conversation_history.len()).green());
+                    },
+                    None => println!("{}", format!("No branch named '{}'; create it with /branch {}.", arg, arg).yellow()),
+                },
+                "model" if arg.is_empty() => {
+                    println!("{}", format!("Current model: {}", client.get_model()).blue());
+                },
+                "model" => match initialize_with_model_selection(Some(arg.to_string()), &api_url, &config, true).await {
+                    Ok(new_model) => {
+                        client = client.with_model(&new_model);
+                        println!("{}", format!("Switched to model: {}", new_model).green());
+                    },
+                    Err(e) => println!("{}", format!("❌ Couldn't switch models: {:#}", e).red()),
+                },
+                "persona" if arg.is_empty() => {
+                    match client.get_persona() {
+                        Some(persona) => println!("{}", format!("Current persona: {}", persona).blue()),
+                        None => println!("{}", "No persona selected; using the model's system prompt.".blue()),
+                    }
+                    let mut names: Vec<&String> = config.personas.keys().collect();
+                    names.sort();
+                    for name in names {
+                        println!("  {}", name);
+                    }
+                },
+                "persona" if arg == "none" => {
+                    client.set_persona(None);
+                    println!("{}", "Persona cleared; using the model's system prompt.".green());
+                },
+                "persona" => match config.check_persona(arg) {
+                    Ok(()) => {
+                        client.set_persona(Some(arg.to_string()));
+                        println!("{}", format!("Switched to persona: {}", arg).green());
+                    },
+                    Err(e) => println!("{}", format!("❌ {}", e).red()),
+                },
+                "profile" if arg.is_empty() => {
+                    println!("{}", format!("Current server: {} (model {})", api_url, client.get_model()).blue());
+                    let mut names: Vec<&String> = config.profiles.keys().collect();
+                    names.sort();
+                    if names.is_empty() {
+                        println!("{}", "No profiles are configured; add them under [profiles] in the config.".yellow());
+                    }
+                    for name in names {
+                        let profile = &config.profiles[name];
+                        println!("  {} - {}{}", name, profile.api_url,
+                            profile.model.as_ref().map(|model| format!(" ({})", model)).unwrap_or_default());
+                    }
+                },
+                "profile" => {
+                    let mut profile_config = config.clone();
+                    match profile_config.use_profile(arg) {
+                        // Checks the connection to the profile's server before switching to it
+                        Ok(profile) => match initialize_with_model_selection(profile.model.clone(), &profile.api_url, &profile_config, true).await {
+                            Ok(new_model) => {
+                                client = client.with_server(&profile.api_url, &new_model, profile_config.clone());
+                                config = profile_config;
+                                api_url = profile.api_url;
+                                println!("{}", format!("Switched to profile {}: {} at {}", arg, new_model, api_url).green());
+                            },
+                            Err(e) => println!("{}", format!("❌ Couldn't switch to profile '{}': {:#}", arg, e).red()),
+                        },
+                        Err(e) => println!("{}", format!("❌ {}", e).red()),
+                    }
+                },
+                "root" if arg.is_empty() => {
+                    println!("{}", format!("Context root: {}", describe_scope(&context_scope)).blue());
+                    println!("{}", "Usage: /root <dir> to gather context from a subdirectory, /root . for the whole project".yellow());
+                },
+                "root" => match context_manager.set_scope(arg) {
+                    Ok(scope) => {
+                        context_scope = scope;
+                        current_context = reload_context(&mut context_manager, no_context)?;
+                        println!("{}", format!("Context root set to {}: {} file(s) in context.",
+                            describe_scope(&context_scope), current_context.files_included.len()).green());
+                    },
+                    Err(e) => println!("{}", format!("❌ Couldn't change the context root: {:#}", e).red()),
+                },
+                "pick" if arg == "clear" => {
+                    picked_files.clear();
+                    println!("{}", "Cleared the picked files; requests get the whole context again.".blue());
+                },
+                "pick" => match pick_context_files(&context_manager, &picked_files, config.max_context_size_kb) {
+                    Ok(picked) => picked_files = picked,
+                    Err(e) => println!("{}", format!("❌ {:#}", e).red()),
+                },
+                "show" if arg.is_empty() => println!("{}", "Usage: /show <file>".yellow()),
+                "show" => if let Err(e) = show_file(&root.join(arg), arg) {
+                    println!("{}", format!("❌ {:#}", e).red());
+                },
+                "save-patch" if arg.is_empty() => println!("{}", "Usage: /save-patch <dir>".yellow()),
+                "save-patch" if last_patches.is_empty() => println!("{}", "The last response had no changes to save.".yellow()),
+                "save-patch" => match save_patches(&last_patches, Path::new(arg)) {
+                    Ok(written) => report_saved_patches(&written),
+                    Err(e) => println!("{}", format!("❌ Couldn't save patches: {:#}", e).red()),
+                },
+                "history" => {
+                    if conversation_history.is_empty() {
+                        println!("{}", "No conversation yet.".blue());
+                    }
+                    for entry in &conversation_history {
+                        println!("{}\n", entry);
+                    }
+                },
+                _ => print_slash_command_help(),
+            }
+            continue;
+        }
+        
+        conversation_history.push(format!("User: {}", user_input));
+        last_patches.clear();
+        
+        // `@path` mentions scope this turn's context to just those files
+        let mentioned_files: Vec<PathBuf> = extract_file_mentions(&user_input)
+            .into_iter()
+            .filter_map(|path| match context_manager.check_mentioned_file(&path) {
+                Ok(rel_path) => Some(rel_path),
+                Err(e) => {
+                    println!("{}", format!("⚠️  Ignoring mentioned file {}: {:#}", path.display(), e).yellow());
+                    None
+                }
+            })
+            .collect();
+        let turn_context = if !mentioned_files.is_empty() {
+            let names: Vec<String> = mentioned_files.iter().map(|path| path.display().to_string()).collect();
+            println!("{}", format!("Context limited to: {}", names.join(", ")).blue());
+            apply_language_prompt(&mut client, &config, &names);
+            context_manager.get_context_for_files(&mentioned_files)?
+        } else if !picked_files.is_empty() {
+            // Picked files may have been deleted since
+            picked_files.retain(|path| root.join(path).is_file());
+            let names: Vec<String> = picked_files.iter().map(|path| path.display().to_string()).collect();
+            println!("{}", format!("Context limited to {} picked file(s) (/pick clear for all of them)", names.len()).blue());
+            apply_language_prompt(&mut client, &config, &names);
+            context_manager.get_context_for_files(&picked_files)?
+        } else if no_context {
+            String::new()
+        } else if config.rank_by_relevance {
+            let context = load_ranked_context(&mut context_manager, &client, &user_input).await?;
+            apply_language_prompt(&mut client, &config, &context.files_included);
+            context.text
+        } else {
+            apply_language_prompt(&mut client, &config, &current_context.files_included);
+            current_context.text.clone()
+        };
+        
+        // Get response from Ollama, displaying it as it arrives
+        stats.record_prompt(client.get_model(), active_profile(&config, &api_url, client.get_model()));
+        let mut response = match request_response(&client, &config, &user_input, &turn_context, &conversation_history).await {
+            // Some quantized models answer with nothing, which would otherwise look like no reply at all
+            Ok(response) if response.trim().is_empty() => {
+                report_empty_response(&config);
+                conversation_history.pop();
+                continue;
+            },
+            Ok(response) => {
+                conversation_history.push(format!("Assistant: {}", response));
+                response
+            },
+            Err(e) => {
+                // Ctrl+C: forget the unanswered request and go back to the prompt
+                if is_cancelled(&e) {
+                    conversation_history.pop();
+                    continue;
+                }
+                
+                // The model may have been removed externally; offer to switch to another one
+                if let Some(ApiError::ModelNotFound(model)) = e.downcast_ref::<ApiError>() {
+                    println!("{}", format!("❌ Model '{}' is no longer available in Ollama.", model).red());
+                    conversation_history.pop();
+                    
+                    // Offline there's no model list to choose from
+                    if config.on_model_not_found == ModelNotFoundAction::Prompt && !config.offline {
+                        let options = vec!["Select a different model", "Keep the current model"];
+                        let selection = Select::with_theme(&ColorfulTheme::default())
+                            .with_prompt("What would you like to do?")
+                            .default(0)
+                            .items(&options)
+                            .interact()?;
+                        
+                        if selection == 0 {
+                            let new_model = initialize_with_model_selection(None, &api_url, &config, true).await?;
+                            client = client.with_model(&new_model);
+                            println!("{}", format!("Switched to model: {}. Please resend your request.", client.get_model()).green());
+                        }
+                    }
+                    continue;
+                }
+                
+                // Ollama explained what went wrong, so the response format isn't the problem
+                if let Some(error @ ApiError::Server(_)) = e.downcast_ref::<ApiError>() {
+                    println!("{}", format!("❌ {}", error).red());
+                    conversation_history.pop();
+                    continue;
+                }
+                
+                println!("{}", format!("Error: {}", e).red());
+                println!("{}", format!("API URL: {}", client.get_endpoint_url()).yellow());
+                println!("{}", "Couldn't process API response. The model may have returned an unexpected format.".yellow());
+                continue;
+            }
+        };
+        
+        // A response that stops inside a code block was cut off, so ask the model to go on
+        let mut continuations = 0;
+        while continuations < config.max_continuations && has_unterminated_fence(&response) {
+            continuations += 1;
+            println!("{}", format!("The response stopped inside a code block. Asking the model to continue ({}/{})...",
+                continuations, config.max_continuations).yellow());
+            
+            match request_response(&client, &config, continuation_prompt(), &turn_context, &conversation_history).await {
+                Ok(continuation) => {
+                    // The history keeps one complete answer rather than the pieces
+                    response.push_str(&continuation);
+                    conversation_history.pop();
+                    conversation_history.push(format!("Assistant: {}", response));
+                },
+                Err(e) => {
+                    if !is_cancelled(&e) {
+                        println!("{}", format!("Error: {}", e).red());
+                    }
+                    break;
+                }
+            }
+        }
+        
+        // Nothing in the response is actionable in explain-only mode
+        if config.explain_only {
+            continue;
+        }
+        
+        // Setup commands come before the code changes that may depend on them
+        if config.allow_shell_suggestions {
+            let commands = ShellCommandExtractor::new().extract(&response);
+            if offer_shell_commands(&commands, root, dry_run)? {
+                // The commands may have changed any file
+                context_manager.force_refresh();
+            }
+        }
+        
+        // Check if response contains code suggestions
+        println!("{}", "Analyzing response for code suggestions...".yellow());
+        let mut extraction = parse_response_diffs(&diff_generator, &response);
+        let mut whole_files = use_whole_file_blocks(&config, &diff_generator, &response, &mut extraction);
+        
+        // Retry once with a stricter diff instruction if a change was requested but none came back
+        if config.auto_reprompt_on_no_diff
+            && looks_like_change_request(&user_input)
+            && extraction.diffs.is_empty()
+        {
+            println!("{}", "No diffs found. Retrying once with a stricter diff format instruction...".yellow());
+            
+            // The retry is its own turn, so the history keeps alternating between user and model
+            let retry_prompt = format!("{}\n\n{}", user_input, strict_diff_instruction());
+            conversation_history.push(format!("User: {}", retry_prompt));
+            
+            match request_response(&client, &config, &retry_prompt, &turn_context, &conversation_history).await {
+                Ok(retry_response) => {
+                    conversation_history.push(format!("Assistant: {}", retry_response));
+                    response = retry_response;
+                    extraction = parse_response_diffs(&diff_generator, &response);
+                    whole_files = use_whole_file_blocks(&config, &diff_generator, &response, &mut extraction);
+                },
+                Err(e) => {
+                    conversation_history.pop();
+                    if !is_cancelled(&e) {
+                        println!("{}", format!("Error: {}", e).red());
+                    }
+                    continue;
+                }
+            }
+        }
+        
+        // Then check for diffs separately
+        if !response.contains("```") {
+            // No code blocks found at all
+            continue;
+        }
+        
+        let DiffExtraction { mut diffs, mut skipped } = extraction;
+        if whole_files {
+            println!("{}", format!("Found {} code block(s) for named files, offered as whole files:", diffs.len()).green());
+        } else {
+            // Extract and print diff blocks (before parsing)
+            let diff_blocks = diff_generator.extract_raw_diff_blocks(&response);
+            if diff_blocks.is_empty() {
+                // No diff suggestions found, just continue
+                continue;
+            }
+
+            // Check if the code block was explicitly marked as a diff
+            let has_explicit_diff = response.contains("```diff");
+            
+            if has_explicit_diff {
+                println!("{}", format!("Found {} explicit diff suggestion(s):", diff_blocks.len()).green());
+            } else {
+                println!("{}", format!("Found {} code suggestion(s) that look like diffs:", diff_blocks.len()).green());
+            }
+            report_skipped_blocks(&skipped);
+
+            // Ask the model once to reformat blocks that couldn't be parsed, then parse its answer
+            if diffs.is_empty() && config.auto_repair_diffs {
+                println!("{}", "Couldn't parse the suggested diff(s). Asking the model to repair them...".yellow());
+                
+                // Recorded as its own turn, like the stricter retry
+                let repair_prompt = repair_diff_prompt(&diff_blocks);
+                conversation_history.push(format!("User: {}", repair_prompt));
+                match request_response(&client, &config, &repair_prompt, &turn_context, &conversation_history).await {
+                    Ok(repaired) => {
+                        conversation_history.push(format!("Assistant: {}", repaired));
+                        DiffExtraction { diffs, skipped } = parse_response_diffs(&diff_generator, &repaired);
+                        if diffs.is_empty() {
+                            println!("{}", "❌ Repair failed: the reformatted response still has no valid diffs.".red());
+                            report_skipped_blocks(&skipped);
+                        } else {
+                            println!("{}", format!("✅ Repair succeeded: parsed {} diff(s).", diffs.len()).green());
+                        }
+                    },
+                    Err(e) => {
+                        conversation_history.pop();
+                        if !is_cancelled(&e) {
+                            println!("{}", format!("❌ Repair failed: {}", e).red());
+                        }
+                    },
+                }
+            }
+        }
+        
+        if let Some(editorconfig) = &editorconfig {
+            for diff in diffs.iter_mut() {
+                diff.apply_editorconfig(editorconfig);
+            }
+        }
+        
+        // Keep the changes as proposed, before any review edits, for /save-patch
+        order_for_review(&mut diffs);
+        last_patches = diffs.iter().map(|diff| (diff.get_file_path().clone(), diff.render_diff())).collect();
+        if !diffs.is_empty() {
+            print_diff_stat(&diffs);
+        }
+        stats.suggested += diffs.len();
+        let diffs = limit_review(diffs, config.max_diffs_per_response)?;
+        
+        if !diffs.is_empty() {
+            let mut review = review_diffs(diffs, dry_run, Some(&mut rl))?;
+            if config.confirm_before_write {
+                confirm_writes(&mut review)?;
+            }
+            if !review.rejections.is_empty() {
+                pending_feedback = Some(review.rejections.join("\n"));
+            }
+            if !review.rejected.is_empty() {
+                audit_changes(root, &config, AuditAction::Rejected, &review.rejected, &user_input, client.get_model());
+            }
+            stats.rejected += review.rejected.len();
+            
+            let any_accepted = !review.accepted.is_empty();
+            if any_accepted {
+                if let Err(e) = isolation.isolate(&config, root) {
+                    println!("{}", format!("❌ {:#}", e).red());
+                    println!("{}", "No files were changed.".yellow());
+                    continue;
+                }
+            }
+            let applied = apply_reviewed(&diff_generator, review.accepted, true)?;
+            run_post_apply_hooks(&config, root, &applied);
+            if applied.is_empty() && any_accepted {
+                // Files may have been written and rolled back, so don't trust the cache
+                context_manager.force_refresh();
+            }
+            for diff in &applied {
+                context_manager.invalidate(diff.get_file_path());
+                if let Some(rename_from) = diff.rename_from() {
+                    context_manager.invalidate(rename_from);
+                }
+            }
+            if config.show_after_apply {
+                show_applied(&applied);
+            }
+            stats.record_applied(&applied);
+            if !applied.is_empty() {
+                audit_changes(root, &config, AuditAction::Applied, &applied, &user_input, client.get_model());
+                if config.auto_commit && config.output_root.is_none() {
+                    commit_applied_changes(root, &applied, &user_input);
+                }
+            }
+            
+            // Update context after changes
+            current_context = reload_context(&mut context_manager, no_context)?;
+        } else {
+            // No valid diffs could be parsed; the skipped blocks were listed with their reasons
+            if skipped.is_empty() {
+                println!("{}", "Found code block(s) but couldn't parse valid diff(s).".yellow());
+            }
+            println!("{}: {}", "Assistant".bright_blue(), response);
+        }
+    }
+    
+    // Keep the conversation so it can be saved under a name or resumed later
+    if !conversation_history.is_empty() {
+        if let Err(e) = Session::new(client.get_model(), &conversation_history).save(LAST_SESSION) {
+            println!("{}", format!("Warning: Failed to save session: {:#}", e).yellow());
+        }
+    }
+    
+    isolation.report();
+    if config.session_summary {
+        stats.print();
+    }
+    println!("{}", "Thank you for using code-llm!".green());
+    Ok(())
+}
+
+/// What happened in an interactive session, for `session_summary`. Nothing leaves the machine.
+struct SessionStats {
+    started: Instant,
+    prompts: usize,
+    /// Changes suggested by the model, counting each file once per response
+    suggested: usize,
+    accepted: usize,
+    rejected: usize,
+    /// Files changed by applied suggestions, in the order they were first changed
+    files_modified: Vec<PathBuf>,
+    /// Models prompted, with the profile each was used through, in the order they were first used
+    models: Vec<String>,
+}
+
+impl SessionStats {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            prompts: 0,
+            suggested: 0,
+            accepted: 0,
+            rejected: 0,
+            files_modified: Vec::new(),
+            models: Vec::new(),
+        }
+    }
+    
+    fn record_prompt(&mut self, model: &str, profile: Option<&str>) {
+        self.prompts += 1;
+        let model = match profile {
+            Some(profile) => format!("{} (profile {})", model, profile),
+            None => model.to_string(),
+        };
+        if !self.models.contains(&model) {
+            self.models.push(model);
+        }
+    }
+    
+    fn record_applied(&mut self, applied: &[FileDiff]) {
+        self.accepted += applied.len();
+        for diff in applied {
+            if !self.files_modified.contains(diff.get_file_path()) {
+                self.files_modified.push(diff.get_file_path().clone());
+            }
+        }
+    }
+    
+    fn print(&self) {
+        let elapsed = self.started.elapsed().as_secs();
+        println!("{}", format!("Session summary ({}h {:02}m {:02}s):", elapsed / 3600, elapsed / 60 % 60, elapsed % 60).bright_blue());
+        if !self.models.is_empty() {
+            println!("  Model{}: {}", if self.models.len() == 1 { "" } else { "s" }, self.models.join(", "));
+        }
+        println!("  Prompts sent: {}", self.prompts);
+        println!("  Changes suggested: {} ({} accepted, {} rejected)", self.suggested, self.accepted, self.rejected);
+        println!("  Files modified: {}", self.files_modified.len());
+        for file in &self.files_modified {
+            println!("    {}", file.display());
+        }
+    }
+}
+
+/// The profile for the server in use, if it's one of the configured ones; when several are
+/// for the same server, the one for the model in use
+fn active_profile<'a>(config: &'a crate::config::Config, api_url: &str, model: &str) -> Option<&'a str> {
+    config.profiles.iter()
+        .filter(|(_, profile)| profile.api_url == api_url)
+        .min_by_key(|(name, profile)| (profile.model.as_deref() != Some(model), name.as_str()))
+        .map(|(name, _)| name.as_str())
+}
+
+/// What the user decided about each suggested change
+struct Review {
+    /// Changes to apply, after any edits or picked hunks
+    accepted: Vec<FileDiff>,
+    rejected: Vec<FileDiff>,
+    /// Why the user rejected individual changes, to send to the model
+    rejections: Vec<String>,
+}
+
+/// Put changes in the order they're reviewed in: configuration files first, then source
+/// files, then markup, styles and other assets, so a large scaffold reads from the
+/// manifest down. Changes of the same kind keep the order the model gave them in.
+fn order_for_review(diffs: &mut [FileDiff]) {
+    diffs.sort_by_key(|diff| file_role(diff.get_file_path()));
+}
+
+/// Show each change and ask whether to accept, reject, edit or pick hunks from it. With
+/// `dry_run`, accepted changes are only reported. A reason for each rejection is asked
+/// for when `rl` is given. When the response creates several new files, they are offered
+/// together first as a scaffold.
+fn review_diffs(diffs: Vec<FileDiff>, dry_run: bool, mut rl: Option<&mut DefaultEditor>) -> Result<Review> {
+    let mut accepted = Vec::new();
+    
+    // Reasons given for rejecting individual changes
+    let mut rejections = Vec::new();
+    let mut rejected = Vec::new();
+    
+    let diffs = match review_scaffold(diffs)? {
+        Scaffold::Create(new_files, rest) => {
+            if dry_run {
+                for diff in &new_files {
+                    println!("{}", format!("Would create {}", diff.get_file_path().display()).blue());
+                }
+            } else if is_read_only() {
+                for diff in &new_files {
+                    println!("{}", format!("🔒 Read-only mode: {} was not created.", diff.get_file_path().display()).yellow());
+                }
+            } else {
+                accepted.extend(new_files);
+            }
+            rest
+        },
+        Scaffold::Skip(new_files, rest) => {
+            println!("{}", format!("Skipped creating {} new files.", new_files.len()).yellow());
+            if let Some(reason) = rl.as_deref_mut().and_then(read_rejection_reason) {
+                let paths: Vec<String> = new_files.iter().map(|diff| diff.get_file_path().display().to_string()).collect();
+                rejections.push(format!("The user declined to create {} because: {}. Please revise.",
+                    paths.join(", "), reason.trim_end_matches('.')));
+            }
+            rejected.extend(new_files);
+            rest
+        },
+        Scaffold::Review(diffs) => diffs,
+    };
+    let total = diffs.len();
+    
+    // Changes to several kinds of files are reviewed under a heading for each kind
+    let roles: Vec<FileRole> = diffs.iter().map(|diff| file_role(diff.get_file_path())).collect();
+    let grouped = roles.iter().any(|&role| role != roles[0]);
+    
+    // Set once the user accepts or rejects everything that is left
+    let mut remaining_choice: Option<&str> = None;
+    
+    for (i, mut diff) in diffs.into_iter().enumerate() {
+        let choice = match remaining_choice {
+            Some(choice) => choice,
+            None => {
+                if grouped && (i == 0 || roles[i] != roles[i - 1]) {
+                    let count = roles.iter().filter(|&&role| role == roles[i]).count();
+                    println!("\n{}", format!("── {} ({}) ──", roles[i].heading(), count).bold());
+                }
+                let language = language_for_path(diff.get_file_path())
+                    .map(|language| format!(", {}", language))
+                    .unwrap_or_default();
+                if diff.block_count() > 1 {
+                    println!("\n{} {} of {} ({}{}, {} diff blocks combined):", "Suggestion".bright_green(), i + 1, total,
+                        diff.get_file_path().display(), language, diff.block_count());
+                } else {
+                    println!("\n{} {} of {} ({}{}):", "Suggestion".bright_green(), i + 1, total, diff.get_file_path().display(), language);
+                }
+                // Print directly without further formatting to preserve ANSI colors
+                print_paged(&diff.display_preview());
+                print_apply_report(&diff);
+                if diff.has_conflicts() {
+                    println!("{}", "⚠️  The file changed where this suggestion goes, and merging it left conflict markers. Choose Edit to resolve them.".yellow());
+                }
+                
+                let prompt = if diff.is_deletion() {
+                    format!("⚠️  Delete {}?", diff.get_file_path().display())
+                } else if let Some(rename_from) = diff.rename_from() {
+                    format!("Accept or reject moving {} to {}?", rename_from.display(), diff.get_file_path().display())
+                } else {
+                    format!("Accept or reject all changes to {}?", diff.get_file_path().display())
+                };
+                
+                // Deletions have no content to edit
+                let mut options = vec!["Accept", "Reject"];
+                if !diff.is_deletion() {
+                    options.push("Edit");
+                }
+                if diff.hunk_count() > 1 {
+                    options.push("Pick hunks");
+                }
+                // Only part of a long new file is previewed
+                if diff.preview_truncated() {
+                    options.push("Show the whole file");
+                }
+                if i + 1 < total {
+                    options.push("Accept all remaining");
+                    options.push("Reject all remaining");
+                }
+                
+                // Conflicts have to be resolved by hand, so Edit comes first for them
+                let default = if diff.has_conflicts() {
+                    options.iter().position(|&option| option == "Edit").unwrap_or(0)
+                } else {
+                    0
+                };
+                let selection = loop {
+                    let selection = Select::with_theme(&ColorfulTheme::default())
+                        .with_prompt(&prompt)
+                        .default(default)
+                        .items(&options)
+                        .interact()?;
+                    if options[selection] != "Show the whole file" {
+                        break selection;
+                    }
+                    show_in_pager(&diff.display_diff());
+                };
+                
+                match options[selection] {
+                    "Accept all remaining" => {
+                        remaining_choice = Some("Accept");
+                        "Accept"
+                    },
+                    "Reject all remaining" => {
+                        remaining_choice = Some("Reject");
+                        "Reject"
+                    },
+                    // Only the hunks the user keeps are applied
+                    "Pick hunks" => if pick_hunks(&mut diff)? { "Accept" } else { "Reject" },
+                    // Editing replaces the proposed content and then accepts the result
+                    "Edit" => match edit_diff(&mut diff) {
+                        Ok(()) => {
+                            println!("{}", diff.display_diff());
+                            if diff.has_conflicts() {
+                                println!("{}", format!("⚠️  {} still has conflict markers.", diff.get_file_path().display()).yellow());
+                            }
+                            "Accept"
+                        },
+                        Err(e) => {
+                            println!("{}", format!("❌ Failed to edit {}: {:#}", diff.get_file_path().display(), e).red());
+                            "Reject"
+                        }
+                    },
+                    choice => choice,
+                }
+            }
+        };
+        
+        match choice {
+            "Accept" if dry_run => {
+                // Report what would happen without touching the file
+                println!("{}", format!("Would apply to {}", diff.get_file_path().display()).blue());
+            },
+            "Accept" if is_read_only() => {
+                // Never write in read-only mode
+                println!("{}", format!("🔒 Read-only mode: changes to {} were not written.", diff.get_file_path().display()).yellow());
+            },
+            "Accept" => {
+                // Accept the diff; accepted diffs are applied together below
+                accepted.push(diff);
+            },
+            _ => {
+                // Reject the diff
+                println!("{}", format!("Changes to {} rejected.", diff.get_file_path().display()).yellow());
+                
+                // Only ask when this change was rejected on its own
+                if remaining_choice.is_none() {
+                    if let Some(reason) = rl.as_deref_mut().and_then(read_rejection_reason) {
+                        rejections.push(format!("The user rejected the change to {} because: {}. Please revise.",
+                            diff.get_file_path().display(), reason.trim_end_matches('.')));
+                    }
+                }
+                rejected.push(diff);
+            },
+        }
+    }
+    
+    Ok(Review { accepted, rejected, rejections })
+}
+
+/// Most `+` and `-` signs in a line of the change summary; bigger changes are scaled down
+const DIFF_STAT_BAR_WIDTH: usize = 40;
+
+/// Summarize the changes like `git diff --stat`: each file with its number of changed lines
+/// and a bar of `+` and `-`, then the totals
+fn print_diff_stat(diffs: &[FileDiff]) {
+    let rows: Vec<(String, usize, usize)> = diffs.iter()
+        .map(|diff| {
+            let name = match diff.rename_from() {
+                Some(rename_from) => format!("{} → {}", rename_from.display(), diff.get_file_path().display()),
+                None => diff.get_file_path().display().to_string(),
+            };
+            let (added, removed) = diff.line_stats();
+            (name, added, removed)
+        })
+        .collect();
+    
+    let name_width = rows.iter().map(|(name, _, _)| name.chars().count()).max().unwrap_or(0);
+    let count_width = rows.iter().map(|(_, added, removed)| (added + removed).to_string().len()).max().unwrap_or(1);
+    let largest = rows.iter().map(|(_, added, removed)| added + removed).max().unwrap_or(0);
+    
+    println!();
+    for (name, added, removed) in &rows {
+        // Scale the bar so the largest change fits, keeping at least one sign for any change
+        let (plus, minus) = if largest <= DIFF_STAT_BAR_WIDTH {
+            (*added, *removed)
+        } else {
+            let scale = |count: usize| if count == 0 { 0 } else { (count * DIFF_STAT_BAR_WIDTH / largest).max(1) };
+            (scale(*added), scale(*removed))
+        };
+        println!(" {:<name_width$} | {:>count_width$} {}{}", name, added + removed,
+            "+".repeat(plus).green(), "-".repeat(minus).red());
+    }
+    
+    let total_added: usize = rows.iter().map(|(_, added, _)| added).sum();
+    let total_removed: usize = rows.iter().map(|(_, _, removed)| removed).sum();
+    println!(" {} file{} changed, {} insertion{}(+), {} deletion{}(-)",
+        rows.len(), if rows.len() == 1 { "" } else { "s" },
+        total_added, if total_added == 1 { "" } else { "s" },
+        total_removed, if total_removed == 1 { "" } else { "s" });
+}
+
+/// When a response has more than `max` changes, typically a runaway one, ask whether to
+/// review all of them, only the first `max`, or none, before going through them one by one
+fn limit_review(mut diffs: Vec<FileDiff>, max: usize) -> Result<Vec<FileDiff>> {
+    if max == 0 || diffs.len() <= max {
+        return Ok(diffs);
+    }
+    
+    let total = diffs.len();
+    let options = [
+        format!("Review all {}", total),
+        format!("Review the first {}", max),
+        "Cancel".to_string(),
+    ];
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!("The response contains {} suggested changes", total))
+        .default(1)
+        .items(&options)
+        .interact()?;
+    
+    match selection {
+        0 => {},
+        1 => {
+            diffs.truncate(max);
+            println!("{}", format!("Skipping the other {} change(s); /save-patch still writes all of them.", total - max).yellow());
+        },
+        _ => {
+            diffs.clear();
+            println!("{}", "Review cancelled: no changes were applied.".yellow());
+        },
+    }
+    Ok(diffs)
+}
+
+/// Show each command the model suggested and ask whether to run it, copy it, or skip it.
+/// Returns whether any command was run.
+fn offer_shell_commands(commands: &[ShellCommand], root: &Path, dry_run: bool) -> Result<bool> {
+    if commands.is_empty() {
+        return Ok(false);
+    }
+    println!("\n{} {} suggested command(s), run from {}:", "Commands".bright_green(), commands.len(), root.display());
+    
+    let mut ran_any = false;
+    for command in commands {
+        println!("\n  {} {}", "$".dimmed(), command.command.bold());
+        
+        // Skipping is the default, so Enter never runs anything by accident
+        let options = ["Run", "Copy", "Skip"];
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Run this command?")
+            .default(2)
+            .items(&options)
+            .interact()?;
+        
+        match options[selection] {
+            "Run" if dry_run => println!("{}", format!("Would run: {}", command.command).blue()),
+            "Run" => {
+                ran_any = true;
+                match command.run(root) {
+                    Ok(status) if status.success() => println!("{}", "✅ Command finished".green()),
+                    Ok(status) => println!("{}", format!("❌ Command failed ({})", status).red()),
+                    Err(e) => println!("{}", format!("❌ {:#}", e).red()),
+                }
+            },
+            "Copy" => match command.copy_to_clipboard() {
+                Ok(()) => println!("{}", "📋 Copied to the clipboard".green()),
+                Err(e) => println!("{}", format!("❌ {:#}", e).red()),
+            },
+            _ => {},
+        }
+    }
+    Ok(ran_any)
+}
+
+/// What the user decided about the new files in a response
+enum Scaffold {
+    /// Create all of the new files; the other changes are still reviewed one at a time
+    Create(Vec<FileDiff>, Vec<FileDiff>),
+    /// Create none of the new files
+    Skip(Vec<FileDiff>, Vec<FileDiff>),
+    /// Review every change on its own, as usual
+    Review(Vec<FileDiff>),
+}
+
+/// When the changes create more than one new file, typically a new project, show them as
+/// a tree and ask whether to create them all at once
+fn review_scaffold(diffs: Vec<FileDiff>) -> Result<Scaffold> {
+    if diffs.iter().filter(|diff| diff.is_new_file()).count() < 2 {
+        return Ok(Scaffold::Review(diffs));
+    }
+    
+    let (new_files, rest): (Vec<FileDiff>, Vec<FileDiff>) = diffs.into_iter().partition(|diff| diff.is_new_file());
+    
+    println!("\n{} {} new files:", "Scaffold".bright_green(), new_files.len());
+    let entries: Vec<(&Path, usize)> = new_files.iter()
+        .map(|diff| (diff.get_file_path().as_path(), diff.new_content().lines().count()))
+        .collect();
+    print!("{}", format_file_tree(&entries));
+    if !rest.is_empty() {
+        println!("{}", format!("{} other change(s) will be reviewed afterwards.", rest.len()).blue());
+    }
+    
+    let options = [
+        format!("Create all {} files", new_files.len()),
+        "Review each file".to_string(),
+        "Skip all new files".to_string(),
+    ];
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!("Scaffold these {} files?", new_files.len()))
+        .default(0)
+        .items(&options)
+        .interact()?;
+    
+    Ok(match selection {
+        0 => Scaffold::Create(new_files, rest),
+        2 => Scaffold::Skip(new_files, rest),
+        _ => Scaffold::Review(new_files.into_iter().chain(rest).collect()),
+    })
+}
+
+/// Render paths as an indented tree, with the line count of each file
+fn format_file_tree(entries: &[(&Path, usize)]) -> String {
+    #[derive(Default)]
+    struct Node {
+        children: BTreeMap<String, Node>,
+        lines: Option<usize>,
+    }
+    
+    fn render(node: &Node, prefix: &str, out: &mut String) {
+        let count = node.children.len();
+        for (i, (name, child)) in node.children.iter().enumerate() {
+            let last = i + 1 == count;
+            let branch = if last { "└── " } else { "├── " };
+            match child.lines {
+                Some(lines) => out.push_str(&format!("{}{}{} {}\n", prefix, branch, name,
+                    format!("({} line{})", lines, if lines == 1 { "" } else { "s" }).dimmed())),
+                None => out.push_str(&format!("{}{}{}/\n", prefix, branch, name.bright_blue())),
+            }
+            let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+            render(child, &child_prefix, out);
+        }
+    }
+    
+    let mut root = Node::default();
+    for (path, lines) in entries {
+        let mut node = &mut root;
+        for component in path.components() {
+            node = node.children.entry(component.as_os_str().to_string_lossy().to_string()).or_default();
+        }
+        node.lines = Some(*lines);
+    }
+    
+    let mut out = String::new();
+    render(&root, "  ", &mut out);
+    out
+}
+
+/// Summarize how a change's hunks apply to the file, e.g. "2 hunks clean, 1 applied with
+/// offset +3 at line 40", so the user knows whether to trust where they landed
+fn print_apply_report(diff: &FileDiff) {
+    let report = diff.apply_report();
+    if let Some(HunkStatus::Failed(reason)) = report.first() {
+        println!("{}", format!("❌ Won't apply: {}", reason).red());
+        return;
+    }
+    
+    let clean = report.iter().filter(|status| matches!(status, HunkStatus::CleanAtLine(_))).count();
+    let mut parts = Vec::new();
+    if clean > 0 {
+        parts.push(format!("{} hunk{} clean", clean, if clean == 1 { "" } else { "s" }));
+    }
+    for status in &report {
+        match status {
+            HunkStatus::FuzzyAtLine(line, 0) => parts.push(format!("1 matched loosely at line {}", line)),
+            HunkStatus::FuzzyAtLine(line, offset) => parts.push(format!("1 applied with offset {:+} at line {}", offset, line)),
+            HunkStatus::MergedAtLine(line, 0) => parts.push(format!("1 merged at line {}", line)),
+            HunkStatus::MergedAtLine(line, conflicts) => parts.push(format!("1 merged at line {} with {} conflict{}",
+                line, conflicts, if *conflicts == 1 { "" } else { "s" })),
+            _ => {},
+        }
+    }
+    
+    if parts.is_empty() {
+        return;
+    }
+    if clean == report.len() {
+        println!("{}", parts.join(", ").green());
+    } else {
+        println!("{}", parts.join(", ").yellow());
+    }
+}
+
+/// Apply the accepted changes at once, so either every file is written or none is. When a
+/// file changed on disk since the diff was made, offers to re-parse the changes on top of
+/// it. Returns the changes that were applied. `undo_hint` mentions /undo next to backups.
+fn apply_reviewed(diff_generator: &DiffGenerator, mut accepted: Vec<FileDiff>, undo_hint: bool) -> Result<Vec<FileDiff>> {
+    while !accepted.is_empty() {
+        println!("{}", "Applying changes...".green());
+        match diff_generator.apply_all(&accepted) {
+            Ok(backup_paths) => {
+                for (diff, backup_path) in accepted.iter().zip(backup_paths) {
+                    println!("{}", format!("✅ Changes successfully applied to {}", diff.get_file_path().display()).green());
+                    match backup_path {
+                        Some(backup_path) if undo_hint => println!("{}", format!("Backup saved to {} (type /undo to restore)", backup_path.display()).blue()),
+                        Some(backup_path) => println!("{}", format!("Backup saved to {}", backup_path.display()).blue()),
+                        None => {},
+                    }
+                }
+                return Ok(accepted);
+            },
+            Err(e) => {
+                println!("{}", format!("❌ {:#}", e).red());
+                println!("{}", "No files were changed.".yellow());
+                
+                // A file was edited since the diff was made: offer to redo the changes on top
+                if !is_changed_on_disk(&e) || !Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Re-parse the accepted changes against the files as they are now?")
+                    .default(true)
+                    .interact()?
+                {
+                    break;
+                }
+                accepted = reparse_accepted(diff_generator, &accepted)?;
+            }
+        }
+    }
+    
+    Ok(Vec::new())
+}
+
+/// Run the matching `post_apply_hooks` command, like a formatter, on each applied file.
+/// A hook that fails is reported, but the changes stay applied.
+fn run_post_apply_hooks(config: &crate::config::Config, root: &Path, applied: &[FileDiff]) {
+    let hooks = match PostApplyHooks::new(&config.post_apply_hooks) {
+        Ok(hooks) if hooks.is_empty() => return,
+        Ok(hooks) => hooks,
+        Err(e) => {
+            status!("{}", format!("❌ Not running post_apply_hooks: {:#}", e).red());
+            return;
+        }
+    };
+    
+    for diff in applied {
+        let (Some(path), Some(command)) = (diff.written_path(), hooks.command_for(diff.get_file_path())) else {
+            continue;
+        };
+        match run_hook(command, &path, root) {
+            Ok(output) => {
+                status!("{}", format!("🔧 Ran `{}` on {}", command, diff.get_file_path().display()).green());
+                verbose!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+            },
+            Err(e) => status!("{}", format!("⚠️  Hook on {} failed; the changes are still applied: {:#}",
+                diff.get_file_path().display(), e).yellow()),
+        }
+    }
+}
+
+/// Print each applied file as it now is on disk
+fn show_applied(applied: &[FileDiff]) {
+    for diff in applied {
+        let Some(path) = diff.written_path() else {
+            continue;
+        };
+        if let Err(e) = show_file(&path, &diff.get_file_path().to_string_lossy()) {
+            println!("{}", format!("❌ {:#}", e).red());
+        }
+    }
+}
+
+/// Explain an empty response from the model and what to try instead
+fn report_empty_response(config: &crate::config::Config) {
+    let suggestion = if config.use_chat_api || config.backend == Backend::OpenAiCompatible {
+        "try a different model"
+    } else {
+        "try a different model or the /api/chat endpoint (use_chat_api = true)"
+    };
+    println!("{}", format!("⚠️  The model returned an empty response. It may not support this prompt format; {}.", suggestion).yellow());
+    if !is_verbose() {
+        println!("{}", "Run with --verbose to see the raw response from the server.".dimmed());
+    }
+}
+
+/// Let the user check the files to send with the next requests, from the ones the context
+/// could include, with each file's size. The files picked before start out checked. An
+/// empty choice means the whole context again.
+fn pick_context_files(context_manager: &ContextManager, picked: &[PathBuf], max_context_size_kb: usize) -> Result<Vec<PathBuf>> {
+    let files = context_manager.context_files()?;
+    if files.is_empty() {
+        return Err(anyhow!("There are no files to pick from"));
+    }
+    
+    let width = files.iter().map(|(path, _)| path.len()).max().unwrap_or(0);
+    let items: Vec<String> = files.iter()
+        .map(|(path, size)| format!("{:<width$}  {:>8.1} KB", path, *size as f64 / 1024.0))
+        .collect();
+    let checked: Vec<bool> = files.iter().map(|(path, _)| picked.iter().any(|picked| picked == Path::new(path))).collect();
+    let selection = MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Files to send with requests (space to check, enter to confirm)")
+        .items(&items)
+        .defaults(&checked)
+        .interact()?;
+    
+    if selection.is_empty() {
+        println!("{}", "No files picked; requests get the whole context.".blue());
+        return Ok(Vec::new());
+    }
+    let total: u64 = selection.iter().map(|&i| files[i].1).sum();
+    let summary = format!("Picked {} file(s), {:.1} KB, for the next requests (/pick clear for all files).", selection.len(), total as f64 / 1024.0);
+    if total / 1024 > max_context_size_kb as u64 {
+        println!("{}", format!("{} That's more than max_context_size_kb ({} KB).", summary, max_context_size_kb).yellow());
+    } else {
+        println!("{}", summary.green());
+    }
+    Ok(selection.into_iter().map(|i| PathBuf::from(&files[i].0)).collect())
+}
+
+/// Print a file with line numbers, syntax highlighted when that's enabled
+fn show_file(path: &Path, name: &str) -> Result<()> {
+    let content = read_text_file(path)?;
+    println!("\n{} {}", "File".bright_green(), name);
+    print!("{}", render_numbered(&content, path));
+    Ok(())
+}
+
+/// Content with line numbers, syntax highlighted for the file at `path` when that's enabled
+fn render_numbered(content: &str, path: &Path) -> String {
+    let highlighted = highlight::highlight_content(content, path);
+    let width = content.lines().count().max(1).to_string().len();
+    
+    let mut rendered = String::new();
+    for (i, line) in content.lines().enumerate() {
+        let spans = highlighted.as_ref().and_then(|lines| lines.get(i));
+        rendered.push_str(&format!("{} {}\n", format!("{:>width$} │", i + 1).dimmed(), highlight::render_line(LineKind::Context, line, spans)));
+    }
+    rendered
+}
+
+/// With `confirm_before_write`, show the whole of each accepted change to an existing file
+/// as it will be written and ask once more. Hunks that didn't match exactly can land
+/// somewhere other than the diff suggested, and this is the last chance to see that.
+/// Declined changes move to the rejected ones.
+fn confirm_writes(review: &mut Review) -> Result<()> {
+    for diff in std::mem::take(&mut review.accepted) {
+        // New files were shown whole already, and deletions and moves write no new content
+        if diff.is_new_file() || diff.is_deletion() || diff.new_content() == diff.old_content() {
+            review.accepted.push(diff);
+            continue;
+        }
+        
+        let content = diff.new_content();
+        println!("\n{} {} ({} lines, {} bytes)", "Final contents of".bright_green(), diff.get_file_path().display(),
+            content.lines().count(), content.len());
+        print_paged(render_numbered(content, diff.get_file_path()).trim_end_matches('\n'));
+        let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Write these exact contents to {}?", diff.get_file_path().display()))
+            .default(true)
+            .interact()?;
+        if confirmed {
+            review.accepted.push(diff);
+        } else {
+            println!("{}", format!("Changes to {} rejected.", diff.get_file_path().display()).yellow());
+            review.rejected.push(diff);
         }
     }
-    
-    loop {
-        // Get user input with history support
-        let user_input = match rl.readline("You> ") {
-            Ok(line) => {
-                // Add valid input to history
-                if !line.trim().is_empty() {
-                    rl.add_history_entry(&line)?;
-                    
-                    // Save history after each command
-                    if let Err(err) = rl.save_history(&history_path) {
-                        println!("{}", format!("Warning: Failed to save history: {}", err).yellow());
-                    }
-                }
-                line
-            },
-            Err(ReadlineError::Interrupted) => {
-                println!("{}", "Interrupted (Ctrl+C)".blue());
-                continue;
+    Ok(())
+}
+
+/// Parse the accepted changes again against the files as they are now and show the
+/// result. Returns the changes to apply, which is none if the user declines them.
+fn reparse_accepted(diff_generator: &DiffGenerator, accepted: &[FileDiff]) -> Result<Vec<FileDiff>> {
+    let mut reparsed = Vec::new();
+    for diff in accepted {
+        match diff_generator.reparse(diff) {
+            Ok(diff) => {
+                println!("{}", diff.display_diff());
+                reparsed.push(diff);
             },
-            Err(ReadlineError::Eof) => {
-                println!("{}", "Exiting due to Ctrl+D".blue());
-                return Ok(());
+            Err(e) => println!("{}", format!("❌ {:#}", e).red()),
+        }
+    }
+    
+    if reparsed.is_empty() {
+        return Ok(reparsed);
+    }
+    
+    let apply = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Apply these changes?")
+        .default(true)
+        .interact()?;
+    Ok(if apply { reparsed } else { Vec::new() })
+}
+
+/// List the patch files that were written
+fn report_saved_patches(written: &[PathBuf]) {
+    for path in written {
+        status!("{}", format!("💾 Saved patch to {}", path.display()).green());
+    }
+}
+
+/// Commit the files changed by applied diffs, reporting the outcome. Failures are only
+/// reported, since the changes themselves were applied successfully.
+fn commit_applied_changes(root: &Path, diffs: &[FileDiff], prompt: &str) {
+    match git::commit_applied(root, diffs, &git::commit_message(prompt)) {
+        Ok(Some(hash)) => status!("{}", format!("📝 Committed changes as {}", hash).green()),
+        Ok(None) => status!("{}", "Not a git repository; changes were not committed.".yellow()),
+        Err(e) => status!("{}", format!("⚠️  Failed to commit changes: {:#}", e).yellow()),
+    }
+}
+
+/// Whether a session's changes have been moved onto a branch of their own yet, for
+/// `isolate_in_git_branch`
+#[derive(Default)]
+struct SessionIsolation {
+    checked: bool,
+    isolation: Option<git::Isolation>,
+}
+
+impl SessionIsolation {
+    /// Move onto the session's branch before changes are first applied; later calls keep
+    /// it. An error means the changes shouldn't be applied, since they wouldn't be isolated.
+    fn isolate(&mut self, config: &crate::config::Config, root: &Path) -> Result<()> {
+        if self.checked || !config.isolate_in_git_branch || config.output_root.is_some() {
+            return Ok(());
+        }
+        match git::isolate_in_branch(root).context("Failed to isolate the changes in a git branch")? {
+            Some(isolation) => {
+                status!("{}", format!("🌿 Applying changes on a new branch, {}", isolation.branch).green());
+                self.isolation = Some(isolation);
             },
-            Err(err) => {
-                return Err(anyhow!("Error reading input: {}", err));
-            }
+            None => status!("{}", "Not a git repository; changes are applied without a branch of their own.".yellow()),
+        }
+        self.checked = true;
+        Ok(())
+    }
+    
+    /// Say where the session's changes and the user's earlier work ended up
+    fn report(&self) {
+        let Some(isolation) = &self.isolation else {
+            return;
         };
-        
-        if user_input.trim().is_empty() {
-            // Skip empty inputs
-            continue;
+        status!("{}", format!("🌿 This session's changes are on the branch {}.", isolation.branch).green());
+        if let Some(stash) = &isolation.stash {
+            status!("{}", format!("Your uncommitted work from before the session is saved in the stash \"{}\".", stash).blue());
         }
-        
-        if user_input.trim().to_lowercase() == "exit" || user_input.trim().to_lowercase() == "quit" {
-            break;
+    }
+}
+
+/// Refuse to apply changes without review when merging left conflict markers in them,
+/// since the markers would be written into the files
+fn check_no_conflicts(diffs: &[FileDiff]) -> Result<()> {
+    match diffs.iter().find(|diff| diff.has_conflicts()) {
+        Some(diff) => Err(anyhow!("Not applying any changes: merging the changes to {} left conflicts. Review them without --yes to resolve the conflicts.",
+            diff.get_file_path().display())),
+        None => Ok(()),
+    }
+}
+
+/// Record what happened to the changes in the audit log, if one is configured. A failed
+/// write is only a warning, since the changes themselves already went through.
+fn audit_changes(root: &Path, config: &crate::config::Config, action: AuditAction, diffs: &[FileDiff], prompt: &str, model: &str) {
+    let Some(audit_log) = &config.audit_log else {
+        return;
+    };
+    if let Err(e) = audit::record(&root.join(audit_log), config.audit_log_max_kb, action, diffs, prompt, model) {
+        status!("{}", format!("⚠️  {:#}", e).yellow());
+    }
+}
+
+/// Parse the diffs in a response, showing progress when there are several blocks so a
+/// long response doesn't look hung. Responses without code blocks have nothing to parse.
+fn parse_response_diffs(diff_generator: &DiffGenerator, response: &str) -> DiffExtraction {
+    if !response.contains("```") {
+        return DiffExtraction::default();
+    }
+    
+    diff_generator.extract_diffs_with_progress(response, |current, total| {
+        if total > 1 {
+            println!("{}", format!("Parsing block {}/{}...", current, total).dimmed());
         }
-        
-        conversation_history.push(format!("User: {}", user_input));
-        
-        // Start the animated "Thinking..." prompt
-        let thinking_handle = start_thinking_animation();
-        
-        // Get response from Ollama
-        let response = match client.generate_response(&user_input, &current_context, &conversation_history).await {
-            Ok(response) => {
-                // Stop the thinking animation
-                stop_thinking_animation(thinking_handle);
-                
-                conversation_history.push(format!("Assistant: {}", response));
-                response
-            },
-            Err(e) => {
-                // Stop the thinking animation
-                stop_thinking_animation(thinking_handle);
-                
-                println!("{}", format!("Error: {}", e).red());
-                println!("{}", format!("API URL: {}/api/generate", client.get_api_url()).yellow());
-                println!("{}", "Couldn't process API response. The model may have returned an unexpected format.".yellow());
-                continue;
+    })
+}
+
+/// Small models often answer with whole files instead of diffs. Without a fenced diff,
+/// anything parsed came from reading the whole response as one, so offer the code blocks
+/// for named files in its place. Returns whether the extraction was replaced.
+fn use_whole_file_blocks(config: &Config, diff_generator: &DiffGenerator, response: &str, extraction: &mut DiffExtraction) -> bool {
+    if !config.write_code_blocks || diff_generator.has_diff_blocks(response) {
+        return false;
+    }
+    
+    let whole_files = diff_generator.extract_file_blocks(response);
+    if whole_files.is_empty() {
+        return false;
+    }
+    extraction.diffs = whole_files;
+    extraction.skipped.clear();
+    true
+}
+
+/// List the diff blocks of a response that were left out of its changes, and why, so a
+/// missing change can be traced to its block
+fn report_skipped_blocks(skipped: &[SkippedBlock]) {
+    if skipped.is_empty() {
+        return;
+    }
+    status!("{}", format!("⚠️  Skipped {} code block(s):", skipped.len()).yellow());
+    for skipped in skipped {
+        status!("{}", format!("  block {}: {}", skipped.block, skipped.reason).yellow());
+    }
+}
+
+/// The number of files in the context and its size, and with `list_files` the files in it
+/// and the ones left out
+fn print_context_summary(context: &ContextResult, list_files: bool) {
+    let external = if context.files_external.is_empty() {
+        String::new()
+    } else {
+        format!(" and {} external file(s)", context.files_external.len())
+    };
+    println!("{}", format!("Context: {} file(s){}, {:.1} KB", context.files_included.len(), external, context.text.len() as f64 / 1024.0).blue());
+    if !list_files {
+        return;
+    }
+    for file in &context.files_included {
+        if context.files_truncated.contains(file) {
+            println!("  {} {}", file, "(truncated)".dimmed());
+        } else {
+            println!("  {}", file);
+        }
+    }
+    for file in &context.files_external {
+        println!("  {} {}", file, "(external)".dimmed());
+    }
+    if context.truncated {
+        println!("{}", format!("Left out to stay under {}: {} file(s)", context.limit_description(), context.files_skipped.len()).yellow());
+        for file in &context.files_skipped {
+            println!("  {}", file);
+        }
+    }
+}
+
+/// Build the project context like `load_context`, or an empty one without scanning the
+/// project when context is turned off
+fn reload_context(context_manager: &mut ContextManager, no_context: bool) -> Result<ContextResult> {
+    if no_context {
+        return Ok(ContextResult::default());
+    }
+    load_context(context_manager)
+}
+
+/// Build the project context, warning when files had to be left out because of its size limit
+fn load_context(context_manager: &mut ContextManager) -> Result<ContextResult> {
+    // Ctrl+C stops gathering and keeps the files read so far
+    let cancel = Arc::new(AtomicBool::new(false));
+    let watcher = tokio::spawn({
+        let cancel = Arc::clone(&cancel);
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                cancel.store(true, Ordering::SeqCst);
             }
+        }
+    });
+    
+    // Progress only appears when gathering is slow, and goes to stderr like other progress
+    let started = Instant::now();
+    let mut last_shown: Option<Instant> = None;
+    let result = tokio::task::block_in_place(|| context_manager.get_context_with_progress(|progress| {
+        let now = Instant::now();
+        if now - started < CONTEXT_PROGRESS_DELAY || last_shown.is_some_and(|shown| now - shown < CONTEXT_PROGRESS_INTERVAL) {
+            return;
+        }
+        last_shown = Some(now);
+        let message = match progress {
+            ContextProgress::Scanning(found) => format!("Scanning the project: {} files found (Ctrl+C to stop)", found),
+            ContextProgress::Reading(read, total) => format!("Scanning {}/{} files... (Ctrl+C to stop)", read, total),
         };
-        
-        // Check if response contains code suggestions
-        println!("{}", "Analyzing response for code suggestions...".yellow());
+        eprint!("\r\x1b[K{}", message.dimmed());
+    }, &cancel));
+    watcher.abort();
+    if last_shown.is_some() {
+        eprint!("\r\x1b[K");
+    }
+    
+    Ok(report_truncation(result?))
+}
 
-        // Always display the response first so the user sees what the AI said
-        println!("{}: {}", "Assistant".bright_blue(), response);
-        
-        // Then check for diffs separately
-        if !response.contains("```") {
-            // No code blocks found at all
-            continue;
+/// How long gathering the context may take before progress is shown
+const CONTEXT_PROGRESS_DELAY: Duration = Duration::from_millis(500);
+
+/// Time between updates of the context progress line
+const CONTEXT_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Build the project context with the files most relevant to the prompt first, falling
+/// back to the usual order when the embeddings can't be computed
+async fn load_ranked_context(context_manager: &mut ContextManager, client: &OllamaClient, prompt: &str) -> Result<ContextResult> {
+    // Progress goes to stderr so it never mixes with `ask --format json` output
+    let ranked = context_manager.get_ranked_context(client, prompt, |done, total| {
+        eprint!("\r{}", format!("Ranking files by relevance: {}/{} embedded", done, total).dimmed());
+        if done == total {
+            eprintln!();
         }
-        
-        // Extract and print diff blocks (before parsing)
-        let diff_blocks = diff_generator.extract_raw_diff_blocks(&response);
-        if diff_blocks.is_empty() {
-            // No diff suggestions found, just continue
-            continue;
+    }).await;
+    
+    match ranked {
+        Ok(context) => Ok(report_truncation(context)),
+        Err(e) => {
+            status!("{}", format!("⚠️  Couldn't rank files by relevance ({:#}); using the usual order.", e).yellow());
+            load_context(context_manager)
         }
+    }
+}
 
-        // Check if the code block was explicitly marked as a diff
-        let has_explicit_diff = response.contains("```diff");
-        
-        if has_explicit_diff {
-            println!("{}", format!("Found {} explicit diff suggestion(s):", diff_blocks.len()).green());
-        } else {
-            println!("{}", format!("Found {} code suggestion(s) that look like diffs:", diff_blocks.len()).green());
+/// Add the configured instructions for the language that most of `files` are written in
+/// to the system prompt, replacing those of an earlier turn
+fn apply_language_prompt<S: AsRef<str>>(client: &mut OllamaClient, config: &crate::config::Config, files: &[S]) {
+    let language_prompt = config.language_prompt(files);
+    if let Some((language, _)) = language_prompt {
+        verbose!("Adding the instructions for {} to the system prompt", language);
+    }
+    client.set_language_prompt(language_prompt.map(|(_, prompt)| prompt.to_string()));
+}
+
+/// Warn when files had to be left out of the context, and pass it on
+fn report_truncation(context: ContextResult) -> ContextResult {
+    if context.cancelled {
+        status!("{}", format!("⚠️  Stopped gathering context: only {} file(s) are included.", context.files_included.len()).yellow());
+    } else if context.truncated {
+        // Name a few of the dropped files so it's clear why the model doesn't know them
+        const SHOWN: usize = 5;
+        let mut names = context.files_skipped.iter().take(SHOWN).cloned().collect::<Vec<_>>().join(", ");
+        if context.files_skipped.len() > SHOWN {
+            names.push_str(&format!(" and {} more", context.files_skipped.len() - SHOWN));
         }
+        status!("{}", format!("⚠️  Context truncated: {} file(s) left out to stay under {} ({}).",
+            context.files_skipped.len(), context.limit_description(), names).yellow());
+    }
+    
+    context
+}
 
-        // Parse diffs from the extracted blocks
-        let diffs = diff_generator.extract_diffs(&response);
-        
-        if !diffs.is_empty() {
-            for (i, diff) in diffs.iter().enumerate() {
-                println!("\n{} {}:", "Suggestion".bright_green(), i + 1);
-                // Print directly without further formatting to preserve ANSI colors
-                println!("{}", diff.display_diff());
-                
-                let options = vec!["Accept", "Reject"];
-                let selection = Select::with_theme(&ColorfulTheme::default())
-                    .with_prompt("Accept or reject this change?")
-                    .default(0)
-                    .items(&options)
-                    .interact()?;
-                
-                match selection {
-                    0 => {
-                        // Accept the diff
-                        println!("{}", "Applying changes...".green());
-                        diff.apply()?;
-                        println!("{}", format!("✅ Changes successfully applied to {}", diff.get_file_path().display()).green());
-                    },
-                    1 => {
-                        // Reject the diff
-                        println!("{}", "Changes rejected.".yellow());
-                    },
-                    _ => unreachable!(),
+/// Ask about each hunk of a diff in turn and keep only the accepted ones. Returns false
+/// when every hunk was rejected.
+fn pick_hunks(diff: &mut FileDiff) -> Result<bool> {
+    let count = diff.hunk_count();
+    let mut keep = Vec::with_capacity(count);
+    for i in 0..count {
+        println!("\n{} {}/{}:", "Hunk".bright_green(), i + 1, count);
+        println!("{}", diff.render_hunk_colored(i));
+        keep.push(Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Apply hunk {}?", i + 1))
+            .default(true)
+            .interact()?);
+    }
+    
+    let kept = keep.iter().filter(|&&keep| keep).count();
+    if kept == 0 {
+        return Ok(false);
+    }
+    if kept < count {
+        diff.keep_hunks(&keep);
+        println!("{}", format!("Keeping {} of {} hunks of {}.", kept, count, diff.get_file_path().display()).blue());
+    }
+    Ok(true)
+}
+
+/// Editor used when neither `VISUAL` nor `EDITOR` is set
+#[cfg(target_os = "windows")]
+const DEFAULT_EDITOR: &str = "notepad";
+#[cfg(not(target_os = "windows"))]
+const DEFAULT_EDITOR: &str = "vi";
+
+/// GUI editors that return as soon as the file is open unless told to wait for it to close
+const DETACHING_EDITORS: &[&str] = &["code", "code-insiders", "codium", "cursor", "subl", "atom", "zed", "gedit", "mate"];
+
+/// The editor command from `VISUAL`, then `EDITOR`, ignoring empty values
+fn configured_editor() -> Option<String> {
+    ["VISUAL", "EDITOR"]
+        .into_iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .map(|editor| editor.trim().to_string())
+        .find(|editor| !editor.is_empty())
+}
+
+/// Open a file in the user's editor and wait for it to close. The editor command can
+/// carry arguments, like `code --wait`.
+fn open_in_editor(path: &Path) -> Result<()> {
+    let editor = configured_editor().unwrap_or_else(|| DEFAULT_EDITOR.to_string());
+    
+    // A path to the editor may contain spaces (`C:\Program Files\...`); otherwise the
+    // command is split into the program and its arguments
+    let (program, args): (&str, Vec<&str>) = if Path::new(&editor).is_file() {
+        (&editor, Vec::new())
+    } else {
+        let mut words = editor.split_whitespace();
+        (words.next().unwrap_or(DEFAULT_EDITOR), words.collect())
+    };
+    
+    let name = Path::new(program).file_stem().map(|stem| stem.to_string_lossy().to_lowercase()).unwrap_or_default();
+    if DETACHING_EDITORS.contains(&name.as_str()) && !args.iter().any(|arg| matches!(*arg, "--wait" | "-w")) {
+        println!("{}", format!("'{}' may return before you close the file; set EDITOR=\"{} --wait\" if your edits are lost.", program, program).yellow());
+    }
+    
+    // Editors like `code` are `.cmd` scripts on Windows, which only the shell finds
+    #[cfg(target_os = "windows")]
+    let mut command = {
+        let mut command = std::process::Command::new("cmd");
+        command.arg("/C").arg(program);
+        command
+    };
+    #[cfg(not(target_os = "windows"))]
+    let mut command = std::process::Command::new(program);
+    
+    command.args(&args)
+        .arg(path)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to start editor '{}': {}", editor, e))?
+        .wait()?;
+    
+    Ok(())
+}
+
+/// Let the user edit a diff's proposed content in their editor, replacing it with what they save
+fn edit_diff(diff: &mut FileDiff) -> Result<()> {
+    let file_name = diff.get_file_path()
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    
+    // Keep the file name so the editor picks the right syntax highlighting
+    let temp_path = std::env::temp_dir().join(format!("code-llm-{}-{}", timestamp, file_name));
+    fs::write(&temp_path, diff.new_content())?;
+    
+    let result = open_in_editor(&temp_path).and_then(|_| Ok(fs::read_to_string(&temp_path)?));
+    let _ = fs::remove_file(&temp_path);
+    
+    diff.set_new_content(result?);
+    Ok(())
+}
+
+/// Extract `@path` mentions from a request, ignoring trailing punctuation
+fn extract_file_mentions(input: &str) -> Vec<PathBuf> {
+    input
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|path| path.trim_end_matches([',', ';', ':', '?', '!', ')', '.']))
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// A context scope for messages: the subdirectory, or the project root when it's empty
+fn describe_scope(scope: &Path) -> String {
+    if scope.as_os_str().is_empty() {
+        "the project root".to_string()
+    } else {
+        format!("{}/", scope.display())
+    }
+}
+
+/// List the commands understood by the interactive prompt
+fn print_slash_command_help() {
+    println!("{}", "Available commands:".blue());
+    println!("  /context        show the context size and the files it includes");
+    println!("  /clear          forget the conversation so far");
+    println!("  /model [name]   show the current model or switch to another one");
+    println!("  /persona [name] show the personas or switch to one (`none` for the model's prompt)");
+    println!("  /profile [name] show the profiles or switch to one's server and model");
+    println!("  /history        print the conversation so far");
+    println!("  /branch [name]  list the branches, or start a new one from the current conversation");
+    println!("  /switch name    continue another branch's conversation");
+    println!("  /undo           undo the most recently applied changes, restoring their backups");
+    println!("  /save-patch dir write the last response's changes to .patch files in dir");
+    println!("  /root [dir]     gather context only from dir, relative to the project (`.` for all of it)");
+    println!("  /nocontext      stop or start sending the project's files with requests");
+    println!("  /pick [clear]   choose the files to send with requests from a list, or go back to all of them");
+    println!("  /show file      print a file with line numbers, e.g. to check changes after applying");
+    println!("  /multi          enter a multi-line request, finished by /end");
+    println!("  exit, quit      leave code-llm");
+}
+
+/// Ask why a change was rejected. Returns None when the user just presses Enter (or Ctrl+C).
+fn read_rejection_reason(rl: &mut DefaultEditor) -> Option<String> {
+    let reason = rl.readline("Why? (press Enter to skip) ").ok()?;
+    let reason = reason.trim();
+    (!reason.is_empty()).then(|| reason.to_string())
+}
+
+/// Reads lines until a line containing only `/end`, returning them as one prompt.
+/// Returns None if the user cancels with Ctrl+C.
+fn read_multiline_block(rl: &mut DefaultEditor) -> Result<Option<String>> {
+    let mut lines = Vec::new();
+    
+    loop {
+        match rl.readline("...> ") {
+            Ok(line) if line.trim() == "/end" => break,
+            Ok(line) => lines.push(line),
+            Err(ReadlineError::Interrupted) => {
+                println!("{}", "Multi-line input cancelled.".blue());
+                return Ok(None);
+            },
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(anyhow!("Error reading input: {}", err)),
+        }
+    }
+    
+    Ok(Some(lines.join("\n")))
+}
+
+/// Keeps reading lines while they end with a backslash, joining them into one prompt.
+/// Returns None if the user cancels with Ctrl+C.
+fn read_continuation_lines(rl: &mut DefaultEditor, first_line: &str) -> Result<Option<String>> {
+    let mut lines = vec![first_line.trim_end_matches('\\').to_string()];
+    
+    loop {
+        match rl.readline("...> ") {
+            Ok(line) => {
+                let continues = line.ends_with('\\');
+                lines.push(line.trim_end_matches('\\').to_string());
+                if !continues {
+                    break;
                 }
-            }
-            
-            // Update context after changes
-            current_context = context_manager.get_context()?;
+            },
+            Err(ReadlineError::Interrupted) => {
+                println!("{}", "Multi-line input cancelled.".blue());
+                return Ok(None);
+            },
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(anyhow!("Error reading input: {}", err)),
+        }
+    }
+    
+    Ok(Some(lines.join("\n")))
+}
+
+/// Heuristically determines whether the user asked for a code change rather than a question
+fn looks_like_change_request(input: &str) -> bool {
+    let change_verbs = [
+        "add", "change", "fix", "implement", "refactor", "update", "remove", "delete",
+        "rename", "modify", "create", "write", "replace", "move", "convert", "make",
+    ];
+    
+    input
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| change_verbs.contains(&word))
+}
+
+/// Pull a model while drawing a progress bar, with its speed, for each layer being
+/// downloaded. Without a terminal, only the status changes are printed.
+async fn pull_model_with_progress(client: &OllamaClient, model: &str) -> Result<()> {
+    let multi = MultiProgress::new();
+    let style = ProgressStyle::with_template("{prefix:.blue} [{bar:30}] {percent:>3}% ({bytes}/{total_bytes}, {bytes_per_sec}, {eta} left)")?
+        .progress_chars("#>-");
+    
+    // Ollama reports each layer under its own status ("pulling <digest>"), with `completed`
+    // starting again from 0, so each status gets its own bar
+    let mut layers: HashMap<String, ProgressBar> = HashMap::new();
+    let mut last_status = String::new();
+    
+    let result = client.pull_model(model, |progress| {
+        let new_status = progress.status != last_status;
+        match progress.total {
+            Some(total) if total > 0 && !multi.is_hidden() => {
+                let bar = layers.entry(progress.status.clone()).or_insert_with(|| {
+                    multi.add(ProgressBar::new(total).with_style(style.clone()).with_prefix(progress.status.clone()))
+                });
+                bar.set_length(total);
+                bar.set_position(progress.completed.unwrap_or(0).min(total));
+            },
+            // Steps without a size, like "verifying sha256 digest", are printed above the bars
+            _ if new_status && multi.is_hidden() => status!("{}", progress.status.blue()),
+            _ if new_status => {
+                let _ = multi.println(progress.status.blue().to_string());
+            },
+            _ => {},
+        }
+        last_status = progress.status.clone();
+    }).await;
+    
+    for bar in layers.values() {
+        if result.is_ok() {
+            bar.finish();
         } else {
-            // No valid diffs could be parsed
-            println!("{}", "Found code block(s) but couldn't parse valid diff(s).".yellow());
-            println!("{}: {}", "Assistant".bright_blue(), response);
+            bar.abandon();
         }
     }
+    result
+}
+
+/// Print models as a table sorted by size, largest first
+fn print_model_table(models: &mut [ModelInfo]) {
+    if models.is_empty() {
+        println!("{}", "No models installed. Example: ollama pull llama3".yellow());
+        return;
+    }
     
-    println!("{}", "Thank you for using code-llm!".green());
-    Ok(())
+    models.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+    
+    let name_width = models.iter().map(|model| model.name.len()).max().unwrap_or(0).max("NAME".len());
+    println!("{}", format!("{:<name_width$}  {:>9}  {}", "NAME", "SIZE", "MODIFIED").bold());
+    for model in models.iter() {
+        let size = model.size.map(format_size).unwrap_or_else(|| "-".to_string());
+        // Ollama reports RFC 3339 timestamps; the date and minute are enough here
+        let modified = model.modified_at.as_deref()
+            .map(|m| m.get(..16).unwrap_or(m).replace('T', " "))
+            .unwrap_or_else(|| "-".to_string());
+        println!("{:<name_width$}  {:>9}  {}", model.name, size, modified);
+    }
+}
+
+/// Format a byte count with a binary unit, e.g. `4.7 GB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
 }
 
-/// Get the path to the history file in the config directory
 fn select_model_from_list(available_models: &[String]) -> Result<String> {
     // Create a list of available models for selection
     let model_choices: Vec<&str> = available_models.iter().map(AsRef::as_ref).collect();
@@ -442,11 +3338,144 @@ fn select_model_from_list(available_models: &[String]) -> Result<String> {
     Ok(selected)
 }
 
-fn get_history_file_path() -> Result<PathBuf> {
+/// Get the path to the history file in the config directory: the shared one, or with
+/// `history = "project"` one named after the project root and a hash of its full path
+fn get_history_file_path(scope: HistoryScope, root: &Path) -> Result<PathBuf> {
     let mut path = get_config_dir()?;
     
-    // Add the history file name
-    path.push("history");
+    match scope {
+        HistoryScope::Global => path.push("history"),
+        HistoryScope::Project => {
+            let root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+            let name = root.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            let hash = crate::cache::fnv1a(root.as_os_str().as_encoded_bytes().iter());
+            path.push("histories");
+            fs::create_dir_all(&path)
+                .with_context(|| format!("Failed to create history directory: {}", path.display()))?;
+            path.push(format!("{}-{:016x}", name, hash));
+        },
+    }
     
     Ok(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn file_mentions_drop_trailing_punctuation() {
+        assert_eq!(extract_file_mentions("explain @src/api.rs."), vec![PathBuf::from("src/api.rs")]);
+        assert_eq!(extract_file_mentions("compare @a.rs, @b/c.toml?"), vec![PathBuf::from("a.rs"), PathBuf::from("b/c.toml")]);
+        assert_eq!(extract_file_mentions("email me@ or @ alone"), Vec::<PathBuf>::new());
+    }
+    
+    /// Parse the arguments with the given `CODE_LLM_*` variables set (or unset, for None)
+    fn parse_with_env(args: &[&str], vars: &[(&str, Option<&str>)]) -> Cli {
+        let _guard = crate::config::tests::ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let saved: Vec<_> = vars.iter().map(|(name, _)| (*name, std::env::var_os(name))).collect();
+        for (name, value) in vars {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+        let cli = Cli::try_parse_from(args);
+        for (name, value) in saved {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+        cli.unwrap()
+    }
+    
+    const ENV_VARS: [&str; 3] = ["CODE_LLM_MODEL", "CODE_LLM_API_URL", "CODE_LLM_API_KEY"];
+    
+    #[test]
+    fn environment_variables_set_model_url_and_key() {
+        let cli = parse_with_env(&["code-llm"], &[
+            (ENV_VARS[0], Some("env-model")),
+            (ENV_VARS[1], Some("http://ollama:11434")),
+            (ENV_VARS[2], Some("secret")),
+        ]);
+        assert_eq!(cli.model.as_deref(), Some("env-model"));
+        assert_eq!(cli.api_url.as_deref(), Some("http://ollama:11434"));
+        assert_eq!(cli.api_key.as_deref(), Some("secret"));
+    }
+    
+    #[test]
+    fn flags_win_over_environment_variables() {
+        let cli = parse_with_env(&["code-llm", "--model", "flag-model", "--api-url", "http://flag:1"], &[
+            (ENV_VARS[0], Some("env-model")),
+            (ENV_VARS[1], Some("http://ollama:11434")),
+            (ENV_VARS[2], None),
+        ]);
+        assert_eq!(cli.model.as_deref(), Some("flag-model"));
+        assert_eq!(cli.api_url.as_deref(), Some("http://flag:1"));
+        assert_eq!(cli.api_key, None);
+    }
+    
+    #[test]
+    fn api_url_defaults_without_flag_or_environment_variable() {
+        let cli = parse_with_env(&["code-llm"], &ENV_VARS.map(|name| (name, None)));
+        assert_eq!(cli.model, None);
+        assert_eq!(resolve_api_url(cli.api_url, None), "http://localhost:11434");
+    }
+    
+    #[test]
+    fn explicit_api_url_wins_over_the_profile() {
+        let profile_url = || Some("http://gpu:11434".to_string());
+        let cli = parse_with_env(&["code-llm", "--api-url", "http://flag:1"], &ENV_VARS.map(|name| (name, None)));
+        assert_eq!(resolve_api_url(cli.api_url, profile_url()), "http://flag:1");
+        
+        let cli = parse_with_env(&["code-llm"], &[(ENV_VARS[1], Some("http://ollama:11434"))]);
+        assert_eq!(resolve_api_url(cli.api_url, profile_url()), "http://ollama:11434");
+        
+        let cli = parse_with_env(&["code-llm"], &ENV_VARS.map(|name| (name, None)));
+        assert_eq!(resolve_api_url(cli.api_url, profile_url()), "http://gpu:11434");
+    }
+    
+    #[test]
+    fn thinking_animation_stops_when_asked() {
+        let animation = start_thinking_animation();
+        thread::sleep(Duration::from_millis(50));
+        
+        // Stopping returns only after the thread has ended, well before the next frame is due
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            stop_thinking_animation(animation);
+            let _ = done.send(());
+        });
+        assert!(finished.recv_timeout(Duration::from_secs(2)).is_ok(), "the animation thread didn't stop");
+    }
+    
+    #[test]
+    fn thinking_animation_stops_when_its_handle_is_dropped() {
+        let ThinkingAnimation { stop, thread: handle } = start_thinking_animation();
+        drop(stop);
+        
+        let started = Instant::now();
+        while !handle.is_finished() {
+            assert!(started.elapsed() < Duration::from_secs(2), "the animation thread didn't stop");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    
+    #[test]
+    fn changes_are_reviewed_config_first_then_source_then_assets() {
+        let dir = tempfile::tempdir().unwrap();
+        let generator = DiffGenerator::new(dir.path(), &Config::default());
+        let patch: String = ["README.md", "src/main.rs", "Cargo.toml", "index.html", "src/lib.rs", ".gitignore"]
+            .iter()
+            .map(|path| format!("--- /dev/null\n+++ b/{}\n@@ -0,0 +1 @@\n+x\n", path))
+            .collect();
+        let mut diffs = generator.extract_patch_diffs(&patch).diffs;
+        assert_eq!(diffs.len(), 6);
+        
+        order_for_review(&mut diffs);
+        let order: Vec<&Path> = diffs.iter().map(|diff| diff.get_file_path().as_path()).collect();
+        // Within a role the model's order is kept
+        assert_eq!(order, ["Cargo.toml", ".gitignore", "src/main.rs", "src/lib.rs", "README.md", "index.html"].map(Path::new));
+    }
+}
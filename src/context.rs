@@ -1,79 +1,445 @@
-use anyhow::{Result, Context as AnyhowContext};
+use anyhow::{anyhow, Result, Context as AnyhowContext};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+use ignore::WalkBuilder;
 use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use std::fs::Metadata;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use crate::api::OllamaClient;
+use crate::config::{Config, ContextMode};
+use crate::project::ResolvedProjectType;
+use crate::secrets::find_secret;
+use crate::status;
 
 pub struct ContextManager {
     root_dir: PathBuf,
+    /// Directory the files are gathered from: the root, or a subdirectory of it set with
+    /// `set_scope`. Paths in the context stay relative to the root.
+    scope_dir: PathBuf,
+    /// Ignore files in the directories between the root and `scope_dir`, which the walk
+    /// from `scope_dir` doesn't see on its own
+    scope_ignores: Vec<Gitignore>,
     ignore_patterns: Vec<Regex>,
+    /// When set, only files matching these globs are included
+    include_globs: Option<GlobSet>,
+    exclude_globs: GlobSet,
     max_file_size_kb: usize,
+    /// Include the start and end of files over `max_file_size_kb` instead of leaving them out
+    truncate_large_files: bool,
+    large_file_head_kb: usize,
+    large_file_tail_kb: usize,
     max_context_size_kb: usize,
+    /// Files (relative to the root) always included first and in full, whatever the limits
+    pinned_files: Vec<String>,
+    /// Absolute paths of files added at the end of the context as reference, whatever the
+    /// ignore and size rules
+    external_files: Vec<PathBuf>,
+    /// Maximum number of files whose contents are included; 0 for no limit
+    max_files: usize,
+    /// Leave out files whose contents look like credentials
+    scan_for_secrets: bool,
+    project_header: Option<String>,
+    mode: ContextMode,
+    head_lines: usize,
+    /// Line before each file's content, with `{path}` standing for its relative path
+    file_header: String,
+    /// Put each file's content in a code fence tagged with its language
+    fence_files: bool,
+    /// File contents from earlier calls, reused while the file's mtime and size are unchanged
+    cache: HashMap<PathBuf, CachedFile>,
+    /// Embeddings of files ranked by relevance, reused the same way
+    embeddings: HashMap<PathBuf, CachedEmbedding>,
+}
+
+/// A context built from the project's files, and which files made it in
+#[derive(Default)]
+pub struct ContextResult {
+    pub text: String,
+    /// The size limit or the file count limit was reached before every file could be included
+    pub truncated: bool,
+    /// The file count limit, when it was the limit that was reached
+    pub file_limit: Option<usize>,
+    /// Relative paths of the files whose contents are in the context
+    pub files_included: Vec<String>,
+    /// Relative paths of the files left out because of the size or file count limit
+    pub files_skipped: Vec<String>,
+    /// Relative paths of the included files over `max_file_size_kb`, of which only the start
+    /// and end are in the context
+    pub files_truncated: Vec<String>,
+    /// Absolute paths of the `external_files` in the context
+    pub files_external: Vec<String>,
+    /// Gathering was stopped early, so only the files read by then are included
+    pub cancelled: bool,
+}
+
+/// How far building the context has got, for progress displays
+#[derive(Debug, Clone, Copy)]
+pub enum ContextProgress {
+    /// Walking the project; this many files found so far
+    Scanning(usize),
+    /// Reading the files found: (read, total)
+    Reading(usize, usize),
+}
+
+impl ContextResult {
+    /// Describe the limit that left files out, for warnings
+    pub fn limit_description(&self) -> String {
+        match self.file_limit {
+            Some(max_files) => format!("max_files = {}", max_files),
+            None => "max_context_size_kb".to_string(),
+        }
+    }
+}
+
+struct CachedFile {
+    modified: SystemTime,
+    len: u64,
+    /// None for binary files and files with credentials, which are left out of the context
+    content: Option<String>,
 }
 
+struct CachedEmbedding {
+    modified: SystemTime,
+    len: u64,
+    /// None for binary files, which can't be ranked, and files with credentials
+    embedding: Option<Vec<f32>>,
+}
+
+/// A file that may go into the context: its path, its path relative to the root, and its metadata
+type Candidate = (PathBuf, String, Metadata);
+
+/// Characters of a file that are embedded; the start of a file says the most about it,
+/// and embedding models only take a few thousand tokens
+const EMBEDDING_MAX_CHARS: usize = 8000;
+
 impl ContextManager {
-    pub fn new<P: AsRef<Path>>(root_dir: P) -> Result<Self> {
+    pub fn new<P: AsRef<Path>>(root_dir: P, config: &Config) -> Result<Self> {
         let root_dir = fs::canonicalize(root_dir)?;
         
         // Default ignore patterns
-        let ignore_patterns = vec![
+        let mut ignore_patterns = vec![
             Regex::new(r"\.git/")?,
             Regex::new(r"\.gitignore")?,
+            Regex::new(r"\.code-llmignore")?,
             Regex::new(r"node_modules/")?,
             Regex::new(r"target/")?,
             Regex::new(r"\.DS_Store")?,
             Regex::new(r"\.vscode/")?,
             Regex::new(r"\.idea/")?,
+            Regex::new(r"\.code-llm-backup/")?,
+            // The project config may hold an API key, which must not be sent to the model
+            Regex::new(r"(^|/)\.code-llm/")?,
+            // Files that usually hold secrets: dotenv files, private keys, cloud and SSH credentials
+            Regex::new(r"(^|/)\.env(\.[^/]*)?$")?,
+            Regex::new(r"(^|/)id_(rsa|dsa|ecdsa|ed25519)$")?,
+            Regex::new(r"\.(pem|key|p12|pfx|jks|keystore)$")?,
+            Regex::new(r"(^|/)\.(aws|ssh|gnupg)/")?,
+            Regex::new(r"(^|/)(\.netrc|\.pgpass|\.npmrc|\.pypirc|credentials\.json)$")?,
+            Regex::new(r"(^|/)secrets?\.[^/]*$")?,
             Regex::new(r"\.(png|jpe?g|gif|svg|woff|woff2|ttf|eot|mp4|mp3|avi|mov|webm|pdf|zip|tar|gz|rar)$")?,
         ];
         
+        // User-configured ignore patterns
+        for pattern in &config.extra_ignore_patterns {
+            ignore_patterns.push(Regex::new(pattern).with_context(|| {
+                format!("Invalid pattern in extra_ignore_patterns: {}", pattern)
+            })?);
+        }
+        
+        // Changes written to an output directory inside the project aren't part of it
+        if let Some(output_root) = &config.output_root {
+            // The directory may not exist yet, so don't canonicalize it
+            let output_root = root_dir.join(output_root);
+            if let Ok(rel_path) = output_root.strip_prefix(&root_dir) {
+                let pattern = format!("^{}/", regex::escape(&rel_path.to_string_lossy()));
+                ignore_patterns.push(Regex::new(&pattern)?);
+            }
+        }
+        
+        let include_globs = if config.include_globs.is_empty() {
+            None
+        } else {
+            Some(build_glob_set(&config.include_globs, "include")?)
+        };
+        let exclude_globs = build_glob_set(&config.exclude_globs, "exclude")?;
+        
+        // Pinned files are included whatever the limits, but only from inside the project
+        for pinned in &config.pinned_files {
+            let path = root_dir.join(pinned);
+            if !path.starts_with(&root_dir) || Path::new(pinned).components().any(|c| c == Component::ParentDir) {
+                return Err(anyhow!("Pinned file {} is outside the project", pinned));
+            }
+            match fs::metadata(&path) {
+                Err(_) => status!("⚠️  Pinned file {} doesn't exist", pinned),
+                Ok(metadata) if metadata.len() as usize / 1024 > config.max_file_size_kb => {
+                    status!("⚠️  Pinned file {} is larger than max_file_size_kb ({} KB), but is included anyway",
+                        pinned, metadata.len() / 1024);
+                },
+                Ok(_) => {},
+            }
+        }
+        
+        let external_files = config.resolved_external_files(&root_dir);
+        for path in &external_files {
+            if !path.is_file() {
+                status!("⚠️  External file {} doesn't exist", path.display());
+            }
+        }
+        
         Ok(Self {
+            scope_dir: root_dir.clone(),
+            scope_ignores: Vec::new(),
             root_dir,
             ignore_patterns,
-            max_file_size_kb: 100, // 100KB max file size
-            max_context_size_kb: 8000, // 8MB max context size
+            include_globs,
+            exclude_globs,
+            max_file_size_kb: config.max_file_size_kb,
+            truncate_large_files: config.truncate_large_files,
+            large_file_head_kb: config.large_file_head_kb,
+            large_file_tail_kb: config.large_file_tail_kb,
+            max_context_size_kb: config.max_context_size_kb,
+            pinned_files: config.pinned_files.clone(),
+            external_files,
+            max_files: config.max_files,
+            scan_for_secrets: config.scan_for_secrets,
+            project_header: None,
+            mode: config.context_mode,
+            head_lines: config.context_head_lines,
+            file_header: config.context_file_header.clone(),
+            fence_files: config.fence_files,
+            cache: HashMap::new(),
+            embeddings: HashMap::new(),
         })
     }
     
-    pub fn get_context(&self) -> Result<String> {
-        let mut context = String::new();
-        let mut total_size = 0;
-        
-        // Check if .gitignore exists and add its patterns
-        let gitignore_path = self.root_dir.join(".gitignore");
-        let mut gitignore_patterns = Vec::new();
-        
-        if gitignore_path.exists() {
-            let gitignore_content = fs::read_to_string(&gitignore_path)?;
-            for line in gitignore_content.lines() {
-                let line = line.trim();
-                if !line.is_empty() && !line.starts_with('#') {
-                    // Convert gitignore pattern to regex
-                    // This is a simplified conversion and might not handle all gitignore syntax
-                    let pattern = line
-                        .replace(".", "\\.")
-                        .replace("*", ".*")
-                        .replace("?", ".");
-                    
-                    gitignore_patterns.push(Regex::new(&format!("^{}$", pattern))?);
+    /// Add the ignore set and entry files of a project type to the context
+    pub fn apply_project_type(&mut self, project: &ResolvedProjectType) -> Result<()> {
+        for pattern in &project.bundle.ignore {
+            self.ignore_patterns.push(Regex::new(pattern).with_context(|| {
+                format!("Invalid ignore pattern for project type '{}': {}", project.name, pattern)
+            })?);
+        }
+        
+        let mut header = format!("Project type: {}\n", project.name);
+        let entry_files = project.existing_entry_files(&self.root_dir);
+        if !entry_files.is_empty() {
+            header.push_str(&format!("Entry points: {}\n", entry_files.join(", ")));
+        }
+        self.project_header = Some(header);
+        
+        Ok(())
+    }
+    
+    /// Gather files only from `dir`, relative to the project root; `.` widens the context
+    /// to the whole project again. Returns the scope relative to the root.
+    pub fn set_scope<P: AsRef<Path>>(&mut self, dir: P) -> Result<PathBuf> {
+        let dir = dir.as_ref();
+        let scope_dir = fs::canonicalize(self.root_dir.join(dir))
+            .with_context(|| format!("{} doesn't exist", dir.display()))?;
+        if !scope_dir.is_dir() {
+            return Err(anyhow!("{} isn't a directory", dir.display()));
+        }
+        let rel_scope = scope_dir.strip_prefix(&self.root_dir)
+            .map_err(|_| anyhow!("{} is outside the project", dir.display()))?
+            .to_path_buf();
+        
+        // Patterns from .gitignore files above the scope still apply inside it
+        let mut scope_ignores = Vec::new();
+        for ancestor in scope_dir.ancestors().skip(1) {
+            if !ancestor.starts_with(&self.root_dir) {
+                break;
+            }
+            for name in [".gitignore", ".code-llmignore"] {
+                let path = ancestor.join(name);
+                if path.is_file() {
+                    scope_ignores.push(Gitignore::new(&path).0);
                 }
             }
         }
         
-        // Collect files recursively
-        for entry in WalkDir::new(&self.root_dir)
-            .into_iter()
+        self.scope_dir = scope_dir;
+        self.scope_ignores = scope_ignores;
+        Ok(rel_scope)
+    }
+    
+    /// Whether a change to `path` could change the context: it's inside the project and not
+    /// left out by the ignore patterns, globs or .gitignore/.code-llmignore files
+    pub fn is_watched(&self, path: &Path) -> bool {
+        let Ok(rel_path) = path.strip_prefix(&self.root_dir) else {
+            return false;
+        };
+        let is_dir = path.is_dir();
+        let mut rel_str = rel_path.to_string_lossy().to_string();
+        if is_dir {
+            rel_str.push('/');
+        }
+        if rel_str.is_empty() || self.should_ignore(&rel_str) {
+            return false;
+        }
+        
+        // Ignore files apply to everything below the directory they're in
+        for dir in path.ancestors().skip(1) {
+            if !dir.starts_with(&self.root_dir) {
+                break;
+            }
+            for name in [".gitignore", ".code-llmignore"] {
+                let ignore_file = dir.join(name);
+                if ignore_file.is_file()
+                    && Gitignore::new(&ignore_file).0.matched_path_or_any_parents(path, is_dir).is_ignore() {
+                    return false;
+                }
+            }
+        }
+        
+        true
+    }
+    
+    /// Forget a file's cached content, e.g. right after writing it (its mtime may not have
+    /// changed if the write happened within the filesystem's timestamp resolution)
+    pub fn invalidate<P: AsRef<Path>>(&mut self, rel_path: P) {
+        let path = self.root_dir.join(rel_path);
+        self.cache.remove(&path);
+        self.embeddings.remove(&path);
+    }
+    
+    /// Drop all cached file contents and embeddings so the next context is read entirely from disk
+    pub fn force_refresh(&mut self) {
+        self.cache.clear();
+        self.embeddings.clear();
+    }
+    
+    /// Build the context, recording which files it includes and which didn't fit
+    pub fn get_context(&mut self) -> Result<ContextResult> {
+        self.get_context_with_progress(|_| {}, &AtomicBool::new(false))
+    }
+    
+    /// Build the context like `get_context`, calling `progress` as files are found and read.
+    /// Setting `cancel` stops early with the files read so far.
+    pub fn get_context_with_progress(&mut self, mut progress: impl FnMut(ContextProgress), cancel: &AtomicBool) -> Result<ContextResult> {
+        let mut context = self.project_header.clone().unwrap_or_default();
+        
+        // Just the structure: no file contents at all
+        if self.mode == ContextMode::TreeOnly {
+            // Except for the pinned files, which are always there
+            let (pinned_text, pinned_files) = self.pinned_entries()?;
+            context.push_str(&pinned_text);
+            context.push_str(&Self::format_tree(&self.file_tree()));
+            let (external_text, files_external) = self.external_entries()?;
+            context.push_str(&external_text);
+            return Ok(ContextResult { text: context, files_included: pinned_files, files_external, ..ContextResult::default() });
+        }
+        
+        let candidates = self.candidates(&mut progress, cancel)?;
+        
+        // When there are more files than the limit allows, the most recently modified ones
+        // are the likeliest to matter, so they get the places
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        if self.max_files > 0 && candidates.len() > self.max_files {
+            order.sort_by_key(|&i| std::cmp::Reverse(candidates[i].2.modified().ok()));
+        }
+        
+        self.build_context(context, &candidates, order, &mut progress, cancel)
+    }
+    
+    /// Build the context with files included in order of relevance to `prompt`, so the
+    /// files that matter most are the ones that fit. Embeddings are only computed when
+    /// not every file fits, and are cached until a file changes; `progress` is called
+    /// with (embedded, to embed) as files are embedded.
+    pub async fn get_ranked_context(
+        &mut self,
+        client: &OllamaClient,
+        prompt: &str,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<ContextResult> {
+        if self.mode == ContextMode::TreeOnly {
+            return self.get_context();
+        }
+        
+        let context = self.project_header.clone().unwrap_or_default();
+        let candidates = self.candidates(&mut |_| {}, &AtomicBool::new(false))?;
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        
+        let total_size_kb: usize = candidates.iter().map(|(_, _, metadata)| metadata.len() as usize / 1024).sum();
+        let fits = total_size_kb <= self.max_context_size_kb
+            && (self.max_files == 0 || candidates.len() <= self.max_files);
+        if fits {
+            return self.build_context(context, &candidates, order, &mut |_| {}, &AtomicBool::new(false));
+        }
+        
+        let prompt_embedding = client.embed(prompt).await
+            .context("Failed to embed the prompt for relevance ranking")?;
+        
+        // Files whose embedding is missing or stale
+        let stale: Vec<usize> = (0..candidates.len())
+            .filter(|&i| {
+                let (path, _, metadata) = &candidates[i];
+                !self.embeddings.get(path).is_some_and(|cached| {
+                    metadata.modified().ok() == Some(cached.modified) && cached.len == metadata.len()
+                })
+            })
+            .collect();
+        
+        for (done, &i) in stale.iter().enumerate() {
+            progress(done + 1, stale.len());
+            let (path, rel_path_str, metadata) = &candidates[i];
+            let embedding = if crate::utils::is_binary_file(path)? {
+                None
+            } else {
+                // The path says something about the file too
+                let content = crate::utils::read_text_file(path)?;
+                // The embedding server mustn't see credentials either; the warning comes when the context is built
+                if self.contains_secret(&content).is_some() {
+                    self.embeddings.insert(path.clone(), CachedEmbedding { modified: metadata.modified()?, len: metadata.len(), embedding: None });
+                    continue;
+                }
+                let text: String = format!("{}\n{}", rel_path_str, content).chars().take(EMBEDDING_MAX_CHARS).collect();
+                Some(client.embed(&text).await.with_context(|| format!("Failed to embed {}", rel_path_str))?)
+            };
+            self.embeddings.insert(path.clone(), CachedEmbedding { modified: metadata.modified()?, len: metadata.len(), embedding });
+        }
+        
+        let scores: Vec<f32> = candidates.iter()
+            .map(|(path, _, _)| {
+                self.embeddings.get(path)
+                    .and_then(|cached| cached.embedding.as_deref())
+                    .map_or(f32::MIN, |embedding| cosine_similarity(&prompt_embedding, embedding))
+            })
+            .collect();
+        order.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+        
+        // Drop embeddings of files that were deleted or are no longer included
+        let paths: HashSet<&PathBuf> = candidates.iter().map(|(path, _, _)| path).collect();
+        self.embeddings.retain(|path, _| paths.contains(path));
+        
+        self.build_context(context, &candidates, order, &mut |_| {}, &AtomicBool::new(false))
+    }
+    
+    /// Collect the files that may go into the context, in walk order, stopping early
+    /// when `cancel` is set
+    fn candidates(&self, progress: &mut dyn FnMut(ContextProgress), cancel: &AtomicBool) -> Result<Vec<Candidate>> {
+        let mut candidates = Vec::new();
+        for entry in self.walker()
             .filter_map(Result::ok)
-            .filter(|e| e.file_type().is_file())
+            .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
         {
+            if cancel.load(Ordering::SeqCst) {
+                break;
+            }
+            progress(ContextProgress::Scanning(candidates.len()));
+            
             let path = entry.path();
             let rel_path = path.strip_prefix(&self.root_dir).with_context(|| {
                 format!("Failed to strip prefix from path: {:?}", path)
             })?;
             
             // Check if file should be ignored
-            let rel_path_str = rel_path.to_string_lossy();
-            if self.should_ignore(&rel_path_str, &gitignore_patterns) {
+            let rel_path_str = rel_path.to_string_lossy().to_string();
+            if self.should_ignore(&rel_path_str) {
                 continue;
             }
             
@@ -81,45 +447,366 @@ impl ContextManager {
             let metadata = fs::metadata(path)?;
             let file_size_kb = metadata.len() as usize / 1024;
             
-            if file_size_kb > self.max_file_size_kb {
+            if file_size_kb > self.max_file_size_kb && !self.truncate_large_files {
                 continue;
             }
             
-            // Skip binary files
-            if crate::utils::is_binary_file(path)? {
+            candidates.push((path.to_path_buf(), rel_path_str, metadata));
+        }
+        
+        Ok(candidates)
+    }
+    
+    /// Add the candidates to `context` in the order of preference given by `order` until
+    /// a limit is reached or `cancel` is set. The included files keep their walk order in
+    /// the context.
+    fn build_context(
+        &mut self,
+        mut context: String,
+        candidates: &[Candidate],
+        order: Vec<usize>,
+        progress: &mut dyn FnMut(ContextProgress),
+        cancel: &AtomicBool,
+    ) -> Result<ContextResult> {
+        // Pinned files come first, and what's left of the size limit is shared by the rest
+        let (pinned_text, mut files) = self.pinned_entries()?;
+        context.push_str(&pinned_text);
+        
+        let mut entries = Vec::new();
+        let mut skipped = Vec::new();
+        let mut files_truncated = Vec::new();
+        let mut truncated = false;
+        let mut file_limit = None;
+        let mut total_size = pinned_text.len() / 1024;
+        let mut cache = std::mem::take(&mut self.cache);
+        let mut seen = HashSet::new();
+        let mut cancelled = false;
+        
+        for (done, i) in order.into_iter().enumerate() {
+            if cancel.load(Ordering::SeqCst) {
+                cancelled = true;
+                break;
+            }
+            progress(ContextProgress::Reading(done + 1, candidates.len()));
+            
+            let (path, rel_path_str, metadata) = &candidates[i];
+            if files.contains(rel_path_str) {
+                continue;
+            }
+            
+            // Once the context is full, just note the remaining files
+            if truncated {
+                skipped.push(rel_path_str.clone());
                 continue;
             }
             
-            // Add file to context
-            let content = fs::read_to_string(path)
-                .with_context(|| format!("Failed to read file: {:?}", path))?;
+            // Reuse the cached content unless the file changed since it was read
+            let modified = metadata.modified()?;
+            let cached = cache.get(path)
+                .filter(|cached| cached.modified == modified && cached.len == metadata.len());
+            let content = match cached {
+                Some(cached) => cached.content.clone(),
+                None => {
+                    // Skip binary files, and files that look like they hold credentials
+                    let content = if crate::utils::is_binary_file(path)? {
+                        None
+                    } else {
+                        let content = crate::utils::read_text_file(path)?;
+                        match self.contains_secret(&content) {
+                            Some(reason) => {
+                                warn_secret(rel_path_str, reason);
+                                None
+                            },
+                            None => Some(content),
+                        }
+                    };
+                    cache.insert(path.clone(), CachedFile { modified, len: metadata.len(), content: content.clone() });
+                    content
+                }
+            };
+            seen.insert(path.clone());
+            
+            let Some(content) = content else {
+                continue;
+            };
             
-            let file_entry = format!("--- {}\n{}\n", rel_path_str, content);
+            let file_entry = match self.mode {
+                ContextMode::TreeWithHeads => {
+                    let line_count = content.lines().count();
+                    let head: Vec<&str> = content.lines().take(self.head_lines).collect();
+                    let mut entry = self.file_entry(rel_path_str, &head.join("\n"));
+                    if line_count > self.head_lines {
+                        entry.push_str(&format!("... ({} more lines)\n", line_count - self.head_lines));
+                    }
+                    entry
+                },
+                // Only large files that `truncate_large_files` lets through are over the limit
+                _ if metadata.len() as usize / 1024 > self.max_file_size_kb => {
+                    let kept = truncate_middle(&content, self.large_file_head_kb * 1024, self.large_file_tail_kb * 1024);
+                    self.file_entry(rel_path_str, &kept)
+                },
+                _ => self.file_entry(rel_path_str, &content),
+            };
             
             // Check if adding this file would exceed max context size
             let file_entry_size_kb = file_entry.len() / 1024;
             if total_size + file_entry_size_kb > self.max_context_size_kb {
-                context.push_str(&format!("Note: Context truncated due to size limits\n"));
-                break;
+                truncated = true;
+                skipped.push(rel_path_str.clone());
+                continue;
             }
             
-            context.push_str(&file_entry);
             total_size += file_entry_size_kb;
+            entries.push((i, file_entry));
+            if self.mode == ContextMode::Full && metadata.len() as usize / 1024 > self.max_file_size_kb {
+                files_truncated.push(rel_path_str.clone());
+            }
+            
+            if entries.len() == self.max_files {
+                truncated = true;
+                file_limit = Some(self.max_files);
+            }
         }
         
-        Ok(context)
+        // Keep the walk order in the context, whichever files were picked
+        entries.sort_by_key(|(i, _)| *i);
+        for (i, file_entry) in entries {
+            context.push_str(&file_entry);
+            files.push(candidates[i].1.clone());
+        }
+        let (external_text, files_external) = self.external_entries()?;
+        context.push_str(&external_text);
+        
+        // Files only counted once the limit was reached are left out too
+        if file_limit.is_some() && skipped.is_empty() {
+            truncated = false;
+            file_limit = None;
+        }
+        if cancelled {
+            context.push_str("Note: Context incomplete; reading the files was stopped early\n");
+        } else if truncated {
+            context.push_str(match file_limit {
+                Some(_) => "Note: Context truncated due to the file count limit\n",
+                None => "Note: Context truncated due to size limits\n",
+            });
+        }
+        
+        // Drop entries for files that were deleted or are no longer included; after stopping
+        // early, unread files may still be in the cache and are kept
+        if !cancelled {
+            cache.retain(|path, _| seen.contains(path));
+        }
+        self.cache = cache;
+        
+        Ok(ContextResult {
+            text: context,
+            truncated,
+            file_limit,
+            files_included: files,
+            files_skipped: skipped,
+            files_truncated,
+            files_external,
+            cancelled,
+        })
     }
     
-    fn should_ignore(&self, rel_path: &str, gitignore_patterns: &[Regex]) -> bool {
-        // Check built-in ignore patterns
-        for pattern in &self.ignore_patterns {
-            if pattern.is_match(rel_path) {
-                return true;
+    /// The entries of the pinned files that exist, in the configured order, and their
+    /// relative paths. They're read in full on every call, so they're never stale.
+    fn pinned_entries(&self) -> Result<(String, Vec<String>)> {
+        let mut text = String::new();
+        let mut files = Vec::new();
+        for pinned in &self.pinned_files {
+            let path = self.root_dir.join(pinned);
+            if !path.is_file() || crate::utils::is_binary_file(&path)? {
+                continue;
+            }
+            let content = crate::utils::read_text_file(&path)?;
+            // Pinning a file doesn't send its credentials to the model
+            if let Some(reason) = self.contains_secret(&content) {
+                warn_secret(pinned, reason);
+                continue;
+            }
+            text.push_str(&self.file_entry(pinned, &content));
+            files.push(pinned.clone());
+        }
+        Ok((text, files))
+    }
+    
+    /// The entries of the external files that exist and aren't binary, headed
+    /// `[external] <path>`, and their paths. Like pinned files, they're read on every call.
+    fn external_entries(&self) -> Result<(String, Vec<String>)> {
+        let mut text = String::new();
+        let mut files = Vec::new();
+        for path in &self.external_files {
+            if !path.is_file() || crate::utils::is_binary_file(path)? {
+                continue;
             }
+            let name = path.display().to_string();
+            let content = crate::utils::read_text_file(path)?;
+            if let Some(reason) = self.contains_secret(&content) {
+                warn_secret(&name, reason);
+                continue;
+            }
+            text.push_str(&self.file_entry(&format!("[external] {}", name), &content));
+            files.push(name);
+        }
+        Ok((text, files))
+    }
+    
+    /// The files that may go into the context, relative to the root and with their sizes in
+    /// bytes, in walk order. Ignored files and (unless they're truncated) oversized files
+    /// aren't among them.
+    pub fn context_files(&self) -> Result<Vec<(String, u64)>> {
+        let candidates = self.candidates(&mut |_| {}, &AtomicBool::new(false))?;
+        Ok(candidates.into_iter().map(|(_, rel_path, metadata)| (rel_path, metadata.len())).collect())
+    }
+    
+    /// Check a file named in an `@path` mention and return its path relative to the root.
+    /// It's held to the same rules as the files gathered into the context: it must be inside
+    /// the project (after following `..` and symlinks), not ignored, not binary, and within
+    /// `max_file_size_kb` unless large files are truncated.
+    pub fn check_mentioned_file(&self, path: &Path) -> Result<PathBuf> {
+        let full_path = fs::canonicalize(self.root_dir.join(path))
+            .map_err(|_| anyhow!("it doesn't exist"))?;
+        let rel_path = full_path.strip_prefix(&self.root_dir)
+            .map_err(|_| anyhow!("it is outside the project"))?
+            .to_path_buf();
+        
+        let metadata = fs::metadata(&full_path)?;
+        if !metadata.is_file() {
+            return Err(anyhow!("it isn't a file"));
+        }
+        if !self.is_watched(&full_path) {
+            return Err(anyhow!("it is ignored"));
+        }
+        if crate::utils::is_binary_file(&full_path)? {
+            return Err(anyhow!("it is a binary file"));
+        }
+        let size_kb = metadata.len() as usize / 1024;
+        if size_kb > self.max_file_size_kb && !self.truncate_large_files {
+            return Err(anyhow!("it is {} KB, over max_file_size_kb ({} KB)", size_kb, self.max_file_size_kb));
         }
         
-        // Check gitignore patterns
-        for pattern in gitignore_patterns {
+        Ok(rel_path)
+    }
+    
+    /// Build a context from only the given files (relative to the root, and checked with
+    /// `check_mentioned_file` or picked from `context_files`), preceded by a listing of the
+    /// project's files so the model still knows the layout. A file that can't be read is
+    /// left out with a warning.
+    pub fn get_context_for_files(&self, files: &[PathBuf]) -> Result<String> {
+        let mut context = self.project_header.clone().unwrap_or_default();
+        
+        context.push_str(&Self::format_tree(&self.file_tree()));
+        context.push('\n');
+        
+        let (pinned_text, pinned_files) = self.pinned_entries()?;
+        context.push_str(&pinned_text);
+        
+        for file in files {
+            if pinned_files.iter().any(|pinned| Path::new(pinned) == file) {
+                continue;
+            }
+            let path = self.root_dir.join(file);
+            let content = match crate::utils::read_text_file(&path) {
+                Ok(content) => content,
+                Err(e) => {
+                    status!("⚠️  Leaving {} out of the context: {:#}", file.display(), e);
+                    continue;
+                }
+            };
+            if let Some(reason) = self.contains_secret(&content) {
+                warn_secret(&file.to_string_lossy(), reason);
+                continue;
+            }
+            let content = if content.len() / 1024 > self.max_file_size_kb {
+                truncate_middle(&content, self.large_file_head_kb * 1024, self.large_file_tail_kb * 1024)
+            } else {
+                content
+            };
+            context.push_str(&self.file_entry(&file.to_string_lossy(), &content));
+        }
+        context.push_str(&self.external_entries()?.0);
+        
+        Ok(context)
+    }
+    
+    /// A file's entry in the context: the header line, then the content, fenced with the
+    /// file's language when `fence_files` is on
+    fn file_entry(&self, rel_path: &str, content: &str) -> String {
+        let header = self.file_header.replace("{path}", rel_path);
+        if !self.fence_files {
+            return format!("{}\n{}\n", header, content);
+        }
+        
+        // A fence longer than any run of backticks in the content can't be closed early
+        let longest_run = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+        let fence = "`".repeat(longest_run.max(2) + 1);
+        let language = crate::utils::language_for_path(rel_path).unwrap_or_default();
+        format!("{}\n{}{}\n{}\n{}\n", header, fence, language, content.trim_end_matches('\n'), fence)
+    }
+    
+    /// Relative paths of all files that aren't ignored, in walk order
+    fn file_tree(&self) -> Vec<String> {
+        self.walker()
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
+            .filter_map(|e| {
+                e.path()
+                    .strip_prefix(&self.root_dir)
+                    .ok()
+                    .map(|rel_path| rel_path.to_string_lossy().to_string())
+            })
+            .filter(|rel_path| !self.should_ignore(rel_path))
+            .collect()
+    }
+    
+    fn format_tree(files: &[String]) -> String {
+        let mut tree = String::from("Project files:\n");
+        for file in files {
+            tree.push_str(&format!("  {}\n", file));
+        }
+        tree
+    }
+    
+    /// Walk the project, honoring .gitignore and .code-llmignore files (including nested
+    /// ones) with real gitignore semantics. Hidden files are included, as before.
+    fn walker(&self) -> ignore::Walk {
+        let scope_ignores = self.scope_ignores.clone();
+        WalkBuilder::new(&self.scope_dir)
+            .hidden(false)
+            .git_ignore(true)
+            .add_custom_ignore_filename(".code-llmignore")
+            .require_git(false)
+            .parents(false)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .filter_entry(move |entry| {
+                let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                !scope_ignores.iter().any(|ignore| ignore.matched_path_or_any_parents(entry.path(), is_dir).is_ignore())
+            })
+            .build()
+    }
+    
+    /// What looks like a credential in the content, when `scan_for_secrets` is on
+    fn contains_secret(&self, content: &str) -> Option<&'static str> {
+        if self.scan_for_secrets {
+            find_secret(content)
+        } else {
+            None
+        }
+    }
+    
+    fn should_ignore(&self, rel_path: &str) -> bool {
+        // Apply the include/exclude globs on top of the other rules
+        if self.exclude_globs.is_match(rel_path) {
+            return true;
+        }
+        if self.include_globs.as_ref().is_some_and(|include| !include.is_match(rel_path)) {
+            return true;
+        }
+        
+        // Check built-in ignore patterns; .gitignore rules are applied by the walker
+        for pattern in &self.ignore_patterns {
             if pattern.is_match(rel_path) {
                 return true;
             }
@@ -127,4 +814,185 @@ impl ContextManager {
         
         false
     }
-}
\ No newline at end of file
+}
+
+/// Tell the user a file was left out of the context because of what looks like a credential
+fn warn_secret(rel_path: &str, reason: &str) {
+    status!("⚠️  Leaving {} out of the context: it seems to contain {} (set scan_for_secrets = false to include it)", rel_path, reason);
+}
+
+/// The first `head_bytes` and last `tail_bytes` of a file's content, on whole lines, with
+/// a marker saying how many lines were left out in between
+fn truncate_middle(content: &str, head_bytes: usize, tail_bytes: usize) -> String {
+    let mut head_end = head_bytes.min(content.len());
+    while !content.is_char_boundary(head_end) {
+        head_end -= 1;
+    }
+    if head_end < content.len() {
+        head_end = content[..head_end].rfind('\n').map_or(0, |i| i + 1);
+    }
+    
+    let mut tail_start = content.len().saturating_sub(tail_bytes);
+    while !content.is_char_boundary(tail_start) {
+        tail_start += 1;
+    }
+    if tail_start > 0 && !content[..tail_start].ends_with('\n') {
+        tail_start = content[tail_start..].find('\n').map_or(content.len(), |i| tail_start + i + 1);
+    }
+    
+    if tail_start <= head_end {
+        return content.to_string();
+    }
+    let left_out = content[head_end..tail_start].lines().count();
+    format!("{}... (truncated {} lines) ...\n{}", &content[..head_end], left_out, &content[tail_start..])
+}
+
+/// Cosine similarity of two embeddings: 1 for the same direction, 0 for unrelated
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// Compile globs (matched against paths relative to the project root) into one set
+fn build_glob_set(globs: &[String], kind: &str) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).with_context(|| format!("Invalid --{} glob: {}", kind, glob))?);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// A project with a source file, ignored files, a binary file and a large file
+    fn project() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/api.rs"), "fn retry() {}\n").unwrap();
+        fs::write(dir.path().join(".env"), "TOKEN=abc\n").unwrap();
+        fs::write(dir.path().join(".gitignore"), "build/\n").unwrap();
+        fs::create_dir(dir.path().join("build")).unwrap();
+        fs::write(dir.path().join("build/out.txt"), "generated\n").unwrap();
+        fs::write(dir.path().join("app.wasm"), [0, b'a', b's', b'm', 1, 0, 0, 0, 0x01, 0x07, 0x01, 0x60]).unwrap();
+        fs::write(dir.path().join("big.txt"), "x".repeat(200 * 1024)).unwrap();
+        dir
+    }
+    
+    fn rejection(manager: &ContextManager, path: &str) -> String {
+        format!("{:#}", manager.check_mentioned_file(Path::new(path)).unwrap_err())
+    }
+    
+    #[test]
+    fn mentioned_project_file_is_accepted() {
+        let dir = project();
+        let manager = ContextManager::new(dir.path(), &Config::default()).unwrap();
+        assert_eq!(manager.check_mentioned_file(Path::new("src/api.rs")).unwrap(), PathBuf::from("src/api.rs"));
+        assert_eq!(manager.check_mentioned_file(Path::new("./src/../src/api.rs")).unwrap(), PathBuf::from("src/api.rs"));
+    }
+    
+    #[test]
+    fn mentioned_files_outside_the_project_are_refused() {
+        let dir = project();
+        let outside = tempfile::NamedTempFile::new().unwrap();
+        let manager = ContextManager::new(dir.path(), &Config::default()).unwrap();
+        
+        assert_eq!(rejection(&manager, &outside.path().display().to_string()), "it is outside the project");
+        let name = outside.path().file_name().unwrap().to_string_lossy();
+        assert_eq!(rejection(&manager, &format!("../{}", name)), "it is outside the project");
+        assert_eq!(rejection(&manager, "missing.rs"), "it doesn't exist");
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn mentioned_symlink_out_of_the_project_is_refused() {
+        let dir = project();
+        let outside = tempfile::NamedTempFile::new().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link.txt")).unwrap();
+        let manager = ContextManager::new(dir.path(), &Config::default()).unwrap();
+        assert_eq!(rejection(&manager, "link.txt"), "it is outside the project");
+    }
+    
+    #[test]
+    fn mentioned_files_follow_the_context_rules() {
+        let dir = project();
+        let manager = ContextManager::new(dir.path(), &Config::default()).unwrap();
+        
+        assert_eq!(rejection(&manager, ".env"), "it is ignored");
+        assert_eq!(rejection(&manager, "build/out.txt"), "it is ignored");
+        assert_eq!(rejection(&manager, "app.wasm"), "it is a binary file");
+        assert!(rejection(&manager, "big.txt").contains("over max_file_size_kb"));
+    }
+    
+    #[test]
+    fn context_for_mentioned_files_skips_unreadable_ones() {
+        let dir = project();
+        let manager = ContextManager::new(dir.path(), &Config::default()).unwrap();
+        
+        let context = manager.get_context_for_files(&[PathBuf::from("gone.rs"), PathBuf::from("src/api.rs")]).unwrap();
+        assert!(context.contains("fn retry() {}"));
+    }
+    
+    #[test]
+    fn include_globs_restrict_the_context_to_matching_files() {
+        let dir = project();
+        fs::write(dir.path().join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(dir.path().join("notes.md"), "# Notes\n").unwrap();
+        let config = Config { include_globs: vec!["**/*.rs".to_string()], ..Config::default() };
+        let mut manager = ContextManager::new(dir.path(), &config).unwrap();
+        
+        let mut files = manager.get_context().unwrap().files_included;
+        files.sort();
+        assert_eq!(files, ["main.rs", "src/api.rs"]);
+    }
+    
+    #[test]
+    fn exclude_globs_remove_matching_files() {
+        let dir = project();
+        fs::write(dir.path().join("notes.md"), "# Notes\n").unwrap();
+        let config = Config { exclude_globs: vec!["src/**".to_string()], ..Config::default() };
+        let mut manager = ContextManager::new(dir.path(), &config).unwrap();
+        
+        let files = manager.get_context().unwrap().files_included;
+        assert!(files.contains(&"notes.md".to_string()));
+        assert!(!files.iter().any(|file| file.starts_with("src/")));
+    }
+    
+    #[test]
+    fn fenced_files_are_tagged_with_their_language() {
+        let dir = project();
+        let config = Config { fence_files: true, ..Config::default() };
+        let manager = ContextManager::new(dir.path(), &config).unwrap();
+        
+        assert!(manager.file_entry("src/api.rs", "fn retry() {}\n").ends_with("\n```rust\nfn retry() {}\n```\n"));
+        // A fence inside the file doesn't end the one around it
+        assert!(manager.file_entry("README.md", "Run:\n```sh\ncargo run\n```\n").ends_with("\n````markdown\nRun:\n```sh\ncargo run\n```\n````\n"));
+        
+        let unfenced = ContextManager::new(dir.path(), &Config::default()).unwrap();
+        assert!(!unfenced.file_entry("src/api.rs", "fn retry() {}\n").contains("```"));
+    }
+    
+    #[test]
+    fn external_files_are_in_the_context() {
+        let dir = project();
+        let elsewhere = tempfile::tempdir().unwrap();
+        let shared = elsewhere.path().join("shared.proto");
+        fs::write(&shared, "message Ping {}\n").unwrap();
+        let config = Config { external_files: vec![shared.clone()], ..Config::default() };
+        let mut manager = ContextManager::new(dir.path(), &config).unwrap();
+        
+        let context = manager.get_context().unwrap();
+        let name = fs::canonicalize(&shared).unwrap().display().to_string();
+        assert_eq!(context.files_external.len(), 1);
+        assert_eq!(context.files_external[0], name);
+        assert!(context.text.contains(&format!("[external] {}", name)));
+        assert!(context.text.contains("message Ping {}"));
+        assert!(!context.files_included.contains(&name));
+    }
+}
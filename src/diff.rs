@@ -1,11 +1,20 @@
 use anyhow::{anyhow, Context as AnyhowContext, Result};
 use colored::Colorize;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use thiserror::Error;
-use similar::{ChangeTag, TextDiff};
+use similar::{Change, ChangeTag, DiffOp, TextDiff};
 
+use crate::config::{Config, LineEnding};
+use crate::editorconfig::EditorConfig;
+use crate::highlight::{highlight_content, render_line, render_line_with_changes, HighlightedLine, LineKind};
+use crate::{status, verbose};
 use crate::utils::ensure_directory_exists;
 
 #[derive(Error, Debug)]
@@ -15,10 +24,56 @@ pub enum DiffError {
     
     #[error("File not found: {0}")]
     FileNotFound(String),
+    
+    #[error("Read-only mode is enabled, refusing to write: {0}")]
+    ReadOnly(String),
+    
+    #[error("{0} changed on disk since the diff was generated")]
+    ChangedOnDisk(String),
+    
+    #[error("Refusing to touch {0}: it is outside the project")]
+    OutsideProject(String),
+    
+    #[error("Refusing to touch {0}: it was only given as reference (--include-external)")]
+    ExternalFile(String),
+    
+    #[error("Hunk {hunk} starts at line {line}, past the end of the file ({len} lines), and its lines don't appear elsewhere in it")]
+    PastEndOfFile { hunk: usize, line: usize, len: usize },
+}
+
+/// Global kill-switch for file writes
+#[cfg(not(test))]
+fn with_read_only_switch<T>(f: impl FnOnce(&AtomicBool) -> T) -> T {
+    static READ_ONLY: AtomicBool = AtomicBool::new(false);
+    f(&READ_ONLY)
+}
+
+/// Tests run on parallel threads, so each gets a switch of its own
+#[cfg(test)]
+fn with_read_only_switch<T>(f: impl FnOnce(&AtomicBool) -> T) -> T {
+    thread_local! {
+        static READ_ONLY: AtomicBool = const { AtomicBool::new(false) };
+    }
+    READ_ONLY.with(f)
+}
+
+/// Enable or disable read-only mode for all diff application
+pub fn set_read_only(read_only: bool) {
+    with_read_only_switch(|switch| switch.store(read_only, Ordering::SeqCst));
 }
 
+/// Returns true if file writes are globally disabled
+pub fn is_read_only() -> bool {
+    with_read_only_switch(|switch| switch.load(Ordering::SeqCst))
+}
+
+/// Directory (relative to the project root) where originals are saved before applying diffs
+pub const BACKUP_DIR: &str = ".code-llm-backup";
+
 pub trait DiffAction {
-    fn apply(&self) -> Result<()>;
+    /// Apply the change, recording in `backups` what `/undo` needs to reverse it, and
+    /// return the path of the backup taken of an existing file
+    fn apply(&self, backups: &mut BackupSet) -> Result<Option<PathBuf>>;
     fn display_diff(&self) -> String;
 }
 
@@ -28,34 +83,219 @@ pub struct FileDiff {
     old_content: String,
     new_content: String,
     is_new_file: bool,
+    /// The diff removes the file (`+++ /dev/null`)
+    is_deletion: bool,
+    /// Original path when the diff moves the file to `file_path`
+    rename_from: Option<PathBuf>,
+    /// Number of diff blocks from the response that were merged into this file's changes
+    block_count: usize,
+    /// Where each hunk landed when it was spliced into the file
+    hunk_reports: Vec<HunkReport>,
+    /// The proposed content was replaced by the user's own edit
+    edited: bool,
+    /// Write the result under this directory instead of changing the working tree
+    output_root: Option<PathBuf>,
+    /// Unchanged lines shown around each change in the preview
+    context_lines: usize,
+    /// Most lines of a new file shown in the review preview; 0 shows all of them
+    preview_lines: usize,
+    /// The hunks were applied with `git apply` rather than the built-in hunk matching
+    patched_with_git: bool,
+    /// Write through a symlink to the file it points to instead of refusing
+    follow_symlinks: bool,
+    /// Project root that the diff's paths are relative to
+    root: PathBuf,
+}
+
+/// A proposed change in a form that can be serialized for other programs
+#[derive(Debug, Serialize)]
+pub struct ProposedChange<'a> {
+    pub file_path: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rename_from: Option<&'a Path>,
+    pub is_new_file: bool,
+    pub is_deletion: bool,
+    pub old_content: &'a str,
+    pub new_content: &'a str,
+    /// The change as a plain unified diff
+    pub diff: String,
+}
+
+/// The changes found in a response, along with the diff blocks that couldn't be parsed
+#[derive(Debug, Default)]
+pub struct DiffExtraction {
+    pub diffs: Vec<FileDiff>,
+    pub skipped: Vec<SkippedBlock>,
+}
+
+/// A diff block that was left out of the changes, and why
+#[derive(Debug, Clone)]
+pub struct SkippedBlock {
+    /// Position of the block among the response's diff blocks, from 1
+    pub block: usize,
+    pub reason: String,
+}
+
+/// Records how a single hunk was mapped onto the real file
+#[derive(Debug, Clone)]
+pub struct HunkReport {
+    /// Start line (1-based) from the hunk header
+    old_start: usize,
+    /// Number of lines that were replaced
+    old_count: usize,
+    /// Start line (1-based) where the hunk was actually applied
+    new_start: usize,
+    /// Number of lines that were written in their place
+    new_count: usize,
+    /// Shift from the header's line number, from earlier hunks or a wrong header
+    offset: isize,
+    /// The lines only matched with whitespace ignored, or there was no header to place them by
+    loose: bool,
+    /// The hunk didn't match and was merged into the file, leaving this many conflicts
+    merged: Option<usize>,
+}
+
+/// Whether a hunk applies to the file as it is now, and how
+#[derive(Debug, Clone, PartialEq)]
+pub enum HunkStatus {
+    /// Matches exactly at the line its header gives
+    CleanAtLine(usize),
+    /// Matches at the line only with the offset from its header, with whitespace ignored, or
+    /// without a header to go by
+    FuzzyAtLine(usize, isize),
+    /// Didn't match, so it was merged into the file at the line, with this many conflicts
+    MergedAtLine(usize, usize),
+    /// Doesn't apply, for the given reason
+    Failed(String),
+}
+
+impl std::fmt::Display for HunkReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "replaced {} line(s) at line {} with {} line(s)",
+            self.old_count, self.new_start, self.new_count)?;
+        
+        if self.offset != 0 {
+            write!(f, " (header said line {}, offset {:+})", self.old_start, self.offset)?;
+        }
+        match self.merged {
+            Some(0) => write!(f, " (merged)")?,
+            Some(conflicts) => write!(f, " (merged with {} conflict(s))", conflicts)?,
+            None if self.loose => write!(f, " (matched loosely)")?,
+            None => {},
+        }
+        
+        Ok(())
+    }
 }
 
 impl FileDiff {
     pub fn get_file_path(&self) -> &PathBuf {
         &self.file_path
     }
+    
+    pub fn is_new_file(&self) -> bool {
+        self.is_new_file
+    }
+    
+    pub fn is_deletion(&self) -> bool {
+        self.is_deletion
+    }
+    
+    pub fn rename_from(&self) -> Option<&PathBuf> {
+        self.rename_from.as_ref()
+    }
+    
+    pub fn block_count(&self) -> usize {
+        self.block_count
+    }
+    
+    pub fn old_content(&self) -> &str {
+        &self.old_content
+    }
+    
+    pub fn new_content(&self) -> &str {
+        &self.new_content
+    }
+    
+    /// The change as a serializable summary
+    pub fn to_proposed_change(&self) -> ProposedChange<'_> {
+        ProposedChange {
+            file_path: &self.file_path,
+            rename_from: self.rename_from.as_deref(),
+            is_new_file: self.is_new_file,
+            is_deletion: self.is_deletion,
+            old_content: &self.old_content,
+            new_content: &self.new_content,
+            diff: self.render_diff(),
+        }
+    }
+    
+    /// Replace the proposed content with a user-edited version
+    pub fn set_new_content(&mut self, content: String) {
+        self.new_content = content;
+        self.hunk_reports.clear();
+        self.edited = true;
+    }
+    
+    /// How each hunk applies to the file as it is on disk, without writing anything. The
+    /// hunks were matched against the file when the diff was parsed, so this checks the file
+    /// is unchanged since then and reports where each one landed. Empty when there are no
+    /// hunks to report on: for new files, deletions, edited changes and `git apply`.
+    pub fn apply_report(&self) -> Vec<HunkStatus> {
+        if let Err(e) = self.check_applicable(&self.root) {
+            let reason = format!("{:#}", e);
+            return vec![HunkStatus::Failed(reason); self.hunk_reports.len().max(1)];
+        }
+        
+        self.hunk_reports.iter()
+            .map(|report| match report.merged {
+                Some(conflicts) => HunkStatus::MergedAtLine(report.new_start, conflicts),
+                None if report.offset == 0 && !report.loose => HunkStatus::CleanAtLine(report.new_start),
+                None => HunkStatus::FuzzyAtLine(report.new_start, report.offset),
+            })
+            .collect()
+    }
+    
+    /// Whether the proposed content still has conflict markers from merging a hunk that
+    /// didn't match the file, which need resolving before the change is applied
+    pub fn has_conflicts(&self) -> bool {
+        self.new_content.lines().any(|line| line == CONFLICT_END)
+    }
+    
+    /// Check that this diff still applies to the file as it is on disk: the file must be
+    /// unchanged since the diff was parsed (so every hunk's context and removed lines still
+    /// match) and the change must actually do something
+    pub fn validate(&self) -> Result<()> {
+        self.check_applicable(&self.root)
+    }
+    
+    /// Normalize the content of a new file to the matching `.editorconfig` settings
+    pub fn apply_editorconfig(&mut self, editorconfig: &EditorConfig) {
+        if !self.is_new_file {
+            return;
+        }
+        
+        let settings = editorconfig.settings_for(&self.file_path);
+        self.new_content = settings.normalize(&self.new_content);
+    }
 }
 
 impl DiffAction for FileDiff {
-    fn apply(&self) -> Result<()> {
-        // Get current directory
-        let current_dir = std::env::current_dir()
-            .map_err(|_| anyhow!("Failed to get current directory"))?;
-        
-        // Convert the file path to a sanitized path relative to current directory
-        // We need to handle both absolute paths and paths relative to project root
-        let target_path = if self.file_path.is_absolute() {
-            // If it's an absolute path, try to make it relative to current directory
-            match self.file_path.strip_prefix("/") {
-                Ok(rel_path) => current_dir.join(rel_path),
-                Err(_) => self.file_path.clone() // Keep as is if we can't strip prefix
-            }
-        } else {
-            // It's already a relative path, join with current directory
-            current_dir.join(&self.file_path)
-        };
+    fn apply(&self, backups: &mut BackupSet) -> Result<Option<PathBuf>> {
+        if is_read_only() {
+            return Err(anyhow!(DiffError::ReadOnly(self.file_path.display().to_string())));
+        }
+        self.check_confined()?;
+        
+        let root = self.root.as_path();
+        
+        if let Some(output_root) = &self.output_root {
+            return self.apply_to_output_root(&root.join(output_root), backups);
+        }
+        
+        let target_path = self.target_path(root);
         
-        println!("Applying changes to: {}", target_path.display());
+        verbose!("Applying changes to: {}", target_path.display());
         
         if self.is_new_file {
             // For new files, create directories if needed and write the content
@@ -63,522 +303,3010 @@ impl DiffAction for FileDiff {
                 ensure_directory_exists(parent)?;
             }
             
+            backups.record_created(&target_path)?;
             fs::write(&target_path, &self.new_content)
                 .with_context(|| format!("Failed to write to new file: {:?}", target_path))?;
+            
+            Ok(None)
+        } else if self.is_deletion {
+            let actual_path = Self::find_actual_file_path(&target_path, root)?;
+            
+            // Save the original so the deletion can be undone
+            let backup_path = backups.back_up(&actual_path)?;
+            
+            fs::remove_file(&actual_path)
+                .with_context(|| format!("Failed to delete file: {:?}", actual_path))?;
+            
+            Ok(Some(backup_path))
+        } else if let Some(rename_from) = &self.rename_from {
+            let source_path = Self::find_actual_file_path(&Self::rooted_path(rename_from, root), root)?;
+            
+            // Save the original so the move can be undone
+            let backup_path = backups.back_up(&source_path)?;
+            
+            if let Some(parent) = target_path.parent() {
+                ensure_directory_exists(parent)?;
+            }
+            
+            // Undoing the move takes the file away from its new path again
+            backups.record_created(&target_path)?;
+            fs::rename(&source_path, &target_path)
+                .with_context(|| format!("Failed to move {:?} to {:?}", source_path, target_path))?;
+            
+            // Apply any content changes at the new location
+            if self.new_content != self.old_content {
+                fs::write(&target_path, &self.new_content)
+                    .with_context(|| format!("Failed to write to file: {:?}", target_path))?;
+            }
+            
+            Ok(Some(backup_path))
         } else {
             // For existing files, verify they exist and handle fallbacks
-            let actual_path = Self::find_actual_file_path(&target_path, &current_dir)?;
+            let actual_path = Self::find_actual_file_path(&target_path, root)?;
+            
+            // Save the original so the change can be undone
+            let backup_path = backups.back_up(&actual_path)?;
+            
+            // Write the new content to the file, restoring the original if the write fails
+            if let Err(e) = write_keeping_permissions(&actual_path, &self.new_content) {
+                fs::copy(&backup_path, &actual_path).with_context(|| {
+                    format!("Failed to write to {:?} ({}) and failed to restore it from backup {:?}", actual_path, e, backup_path)
+                })?;
+                return Err(anyhow!(e)).with_context(|| format!("Failed to write to file: {:?} (original restored)", actual_path));
+            }
             
-            // Write the new content to the file
-            fs::write(&actual_path, &self.new_content)
-                .with_context(|| format!("Failed to write to file: {:?}", actual_path))?;
+            // Report where each hunk landed so the user can verify the mapping
+            if self.edited {
+                status!("  Applied your edited version");
+            } else if self.patched_with_git {
+                status!("  Applied with git apply");
+            }
+            for (i, report) in self.hunk_reports.iter().enumerate() {
+                status!("  Hunk {}: {}", i + 1, report);
+            }
+            
+            Ok(Some(backup_path))
         }
-
-        Ok(())
     }
 
     fn display_diff(&self) -> String {
+        // Without colors, print something that can be saved and applied as a patch
+        if !colored::control::SHOULD_COLORIZE.should_colorize() {
+            return self.render_diff();
+        }
+        
+        self.render_diff_colored()
+    }
+}
+
+impl FileDiff {
+    /// Render the change as a plain unified diff, without colors or line numbers, that
+    /// applies with `patch -p0` from the project root
+    pub fn render_diff(&self) -> String {
+        self.unified_patch(self.context_lines)
+    }
+    
+    /// Render the change for review: a unified diff with line numbers, syntax
+    /// highlighting and colored added and removed lines
+    pub fn render_diff_colored(&self) -> String {
+        self.render_colored(0)
+    }
+    
+    /// Render the change for review. A new file longer than `max_preview_lines` is cut
+    /// short with a note of how many lines are left out; the whole file is still applied.
+    pub fn display_preview(&self) -> String {
+        if !colored::control::SHOULD_COLORIZE.should_colorize() {
+            return self.render_diff();
+        }
+        
+        self.render_colored(self.preview_lines)
+    }
+    
+    /// Whether `display_preview` leaves out part of the file
+    pub fn preview_truncated(&self) -> bool {
+        self.is_new_file && self.preview_lines > 0 && self.new_content.lines().count() > self.preview_lines
+    }
+    
+    /// Render with colors, showing at most `max_new_lines` lines of a new file (0 for all)
+    fn render_colored(&self, max_new_lines: usize) -> String {
         // Get the full file path for display
         let file_path_str = self.file_path
             .to_string_lossy()
             .to_string();
 
-        if self.is_new_file {
+        if self.is_deletion {
+            // For deletions, show every original line as removed
+            let mut diff_output = format!("--- {}\n+++ /dev/null\n", file_path_str);
+            diff_output.push_str(&format!("@@ -1,{} +0,0 @@\n", self.old_content.lines().count()));
+            
+            let highlighted = highlight_content(&self.old_content, &self.file_path);
+            for (i, line) in self.old_content.lines().enumerate() {
+                let spans = highlighted.as_ref().and_then(|lines| lines.get(i));
+                diff_output.push_str(&render_line(LineKind::Removed, line, spans));
+                diff_output.push('\n');
+            }
+            
+            diff_output
+        } else if self.is_new_file {
             // For new files, use standard unified diff format
             let mut diff_output = format!("--- /dev/null\n+++ {}\n", file_path_str);
             diff_output.push_str("@@ -0,0 +1,");
             let new_lines_count = self.new_content.lines().count();
             diff_output.push_str(&format!("{} @@\n", new_lines_count));
             
-            // Add each line prefixed with + and with green background
-            for line in self.new_content.lines() {
-                let display_line = format!("+{}", line);
-                diff_output.push_str(&display_line.white().on_green().bold().to_string());
+            // Add each line prefixed with its number and + with green background
+            let highlighted = highlight_content(&self.new_content, &self.file_path);
+            let width = Self::gutter_width(&self.new_content);
+            let shown = if max_new_lines > 0 { max_new_lines.min(new_lines_count) } else { new_lines_count };
+            for (i, line) in self.new_content.lines().take(shown).enumerate() {
+                let spans = highlighted.as_ref().and_then(|lines| lines.get(i));
+                diff_output.push_str(&gutter(Some(i + 1), width));
+                diff_output.push_str(&render_line(LineKind::Added, line, spans));
                 diff_output.push('\n');
             }
+            if shown < new_lines_count {
+                diff_output.push_str(&format!("...({} more lines)...\n", new_lines_count - shown).dimmed().to_string());
+            }
             
             diff_output
         } else {
             // Use similar crate to generate accurate line-by-line differences
             let diff = TextDiff::from_lines(&self.old_content, &self.new_content);
             
-            // Start with the standard diff header
-            let mut diff_output = format!("--- {}\n+++ {}\n", file_path_str, file_path_str);
+            // Highlight both versions so each line is colored in its own file's context
+            let old_highlighted = highlight_content(&self.old_content, &self.file_path);
+            let new_highlighted = highlight_content(&self.new_content, &self.file_path);
             
-            // Track the current position in the file
-            let mut old_line_num = 1;
-            let mut new_line_num = 1;
+            // Start with the standard diff header, noting a move if there is one
+            let mut diff_output = match &self.rename_from {
+                Some(rename_from) => {
+                    let old_path_str = rename_from.to_string_lossy();
+                    let mut header = format!("rename: {} → {}\n", old_path_str, file_path_str).bold().to_string();
+                    header.push_str(&format!("--- {}\n+++ {}\n", old_path_str, file_path_str));
+                    header
+                },
+                None => format!("--- {}\n+++ {}\n", file_path_str, file_path_str),
+            };
             
-            // Process the diff operations
-            for op in diff.ops() {
-                // Track start position for this hunk
-                let hunk_old_start = old_line_num;
-                let hunk_new_start = new_line_num;
-                
-                // Get the changes for context display
-                let changes: Vec<_> = diff.iter_changes(op).collect();
-                let mut old_count = 0;
-                let mut new_count = 0;
-                
-                // Count the number of lines in this hunk
-                for change in &changes {
-                    match change.tag() {
-                        ChangeTag::Delete => {
-                            old_count += 1;
-                        },
-                        ChangeTag::Insert => {
-                            new_count += 1;
-                        },
-                        ChangeTag::Equal => {
-                            old_count += 1;
-                            new_count += 1;
-                        },
-                    }
-                }
-                
-                // Only show output if there are actual changes
-                if old_count > 0 || new_count > 0 {
-                    // Add the hunk header with line numbers
-                    diff_output.push_str(&format!("@@ -{},{} +{},{} @@\n", 
-                        hunk_old_start, old_count, hunk_new_start, new_count));
-                    
-                    // Output the change lines with appropriate prefixes
-                    for change in changes {
-                        match change.tag() {
-                            ChangeTag::Delete => {
-                                // Removed line with - prefix and red background
-                                let value = change.value();
-                                let display_value = format!("-{}", value);
-                                diff_output.push_str(&display_value.white().on_red().bold().to_string());
-                                diff_output.push('\n');
-                                
-                                // Increment the old line counter
-                                old_line_num += 1;
-                            },
-                            ChangeTag::Insert => {
-                                // Added line with + prefix and green background
-                                let value = change.value();
-                                let display_value = format!("+{}", value);
-                                diff_output.push_str(&display_value.white().on_green().bold().to_string());
-                                diff_output.push('\n');
-                                
-                                // Increment the new line counter
-                                new_line_num += 1;
-                            },
-                            ChangeTag::Equal => {
-                                // Context line with space prefix (no background)
-                                let value = change.value();
-                                let display_value = format!(" {}", value);
-                                diff_output.push_str(&display_value);
-                                diff_output.push('\n');
-                                
-                                // Increment both counters for unchanged lines
-                                old_line_num += 1;
-                                new_line_num += 1;
-                            },
-                        };
-                    }
-                }
+            // Show each group of changes with `context_lines` unchanged lines around it, and
+            // number every line by its position in the new file
+            let width = Self::gutter_width(&self.new_content);
+            for group in diff.grouped_ops(self.context_lines) {
+                diff_output.push_str(&Self::render_hunk_lines(&diff, &group, &old_highlighted, &new_highlighted, width));
             }
 
             diff_output
         }
     }
-}
-
-impl FileDiff {
-    // Helper to find the actual file path, with fallbacks
-    fn find_actual_file_path(target_path: &Path, current_dir: &Path) -> Result<PathBuf> {
-        if target_path.exists() {
-            return Ok(target_path.to_path_buf());
-        }
+    
+    /// Render one hunk of a modified file, as `render_diff_colored` shows it
+    pub fn render_hunk_colored(&self, index: usize) -> String {
+        let diff = TextDiff::from_lines(&self.old_content, &self.new_content);
+        let Some(group) = diff.grouped_ops(self.context_lines).into_iter().nth(index) else {
+            return String::new();
+        };
         
-        // Fallback to just using the filename
-        if let Some(file_name) = target_path.file_name() {
-            let fallback_path = current_dir.join(file_name);
+        let old_highlighted = highlight_content(&self.old_content, &self.file_path);
+        let new_highlighted = highlight_content(&self.new_content, &self.file_path);
+        Self::render_hunk_lines(&diff, &group, &old_highlighted, &new_highlighted, Self::gutter_width(&self.new_content))
+    }
+    
+    /// Render a hunk's header and lines, numbering each line by its position in the new file
+    fn render_hunk_lines(
+        diff: &TextDiff<'_, '_, '_, str>,
+        group: &[DiffOp],
+        old_highlighted: &Option<Vec<HighlightedLine>>,
+        new_highlighted: &Option<Vec<HighlightedLine>>,
+        width: usize,
+    ) -> String {
+        let (Some(first), Some(last)) = (group.first(), group.last()) else {
+            return String::new();
+        };
+        let old_range = first.old_range().start..last.old_range().end;
+        let new_range = first.new_range().start..last.new_range().end;
+        let mut output = format!("@@ -{},{} +{},{} @@\n",
+            hunk_start(&old_range), old_range.len(), hunk_start(&new_range), new_range.len());
+        
+        let changes: Vec<Change<&str>> = group.iter().flat_map(|op| diff.iter_changes(op)).collect();
+        let emphasis = inline_changes(&changes);
+        
+        for (change, changed) in changes.iter().zip(emphasis) {
+            // Style the line without its ending so colors stop at the end of the line
+            let value = change.value().trim_end_matches(['\r', '\n']);
             
-            if fallback_path.exists() {
-                println!("Using fallback path: {}", fallback_path.display());
-                return Ok(fallback_path);
-            }
+            // Removed lines aren't in the new file, so they get an empty gutter
+            let (kind, highlighted, index, line_num) = match change.tag() {
+                ChangeTag::Delete => (LineKind::Removed, old_highlighted, change.old_index(), None),
+                ChangeTag::Insert => (LineKind::Added, new_highlighted, change.new_index(), change.new_index()),
+                ChangeTag::Equal => (LineKind::Context, new_highlighted, change.new_index(), change.new_index()),
+            };
+            let spans = highlighted.as_ref().and_then(|lines| lines.get(index?));
             
-            // If even the fallback doesn't exist, return an error with both paths we tried
-            return Err(anyhow!(DiffError::FileNotFound(
-                format!("Tried: {} and {}", 
-                    target_path.to_string_lossy(), 
-                    fallback_path.to_string_lossy())
-            )));
+            output.push_str(&gutter(line_num.map(|i| i + 1), width));
+            match changed {
+                Some(changed) => output.push_str(&render_line_with_changes(kind, value, spans, &changed)),
+                None => output.push_str(&render_line(kind, value, spans)),
+            }
+            output.push('\n');
         }
         
-        Err(anyhow!("Invalid file path"))
+        output
     }
-}
-
-pub struct DiffGenerator {
-    diff_regex: Regex,
-}
-
-impl DiffGenerator {
-    pub fn new() -> Self {
-        // Match any code block with optional language tag
-        let diff_regex = Regex::new(r"```(?:[a-zA-Z0-9_\-+.]*)?(?:\s*\n|\s)((?:.|\n)*?)```").unwrap();
-        Self { diff_regex }
+    
+    /// The old and new line ranges of each hunk of a modified file, grouped as the
+    /// preview shows them
+    fn hunk_ranges(&self) -> Vec<(Range<usize>, Range<usize>)> {
+        TextDiff::from_lines(&self.old_content, &self.new_content)
+            .grouped_ops(self.context_lines)
+            .iter()
+            .filter_map(|group| {
+                let (first, last) = (group.first()?, group.last()?);
+                Some((first.old_range().start..last.old_range().end, first.new_range().start..last.new_range().end))
+            })
+            .collect()
     }
     
-    pub fn extract_raw_diff_blocks(&self, text: &str) -> Vec<String> {
-        // First try to extract code blocks with triple backticks
-        let markdown_blocks = self.extract_code_blocks(text);
-        if !markdown_blocks.is_empty() {
-            return markdown_blocks;
+    /// Lines added and removed by the change, as (added, removed)
+    pub fn line_stats(&self) -> (usize, usize) {
+        TextDiff::from_lines(&self.old_content, &self.new_content)
+            .iter_all_changes()
+            .fold((0, 0), |(added, removed), change| match change.tag() {
+                ChangeTag::Insert => (added + 1, removed),
+                ChangeTag::Delete => (added, removed + 1),
+                ChangeTag::Equal => (added, removed),
+            })
+    }
+    
+    /// Number of hunks that can be accepted separately; new and deleted files are one change
+    pub fn hunk_count(&self) -> usize {
+        if self.is_new_file || self.is_deletion {
+            return 1;
         }
-        
-        // Try to parse as raw diff text if no code blocks were found
-        vec![text.to_string()]
+        self.hunk_ranges().len()
     }
     
-    // Extract code blocks with triple backticks
-    fn extract_code_blocks(&self, text: &str) -> Vec<String> {
-        let mut blocks = Vec::new();
-        
-        for captures in self.diff_regex.captures_iter(text) {
-            if let Some(block_match) = captures.get(1) {
-                let block = block_match.as_str().to_string();
-                
-                // Only include the block if it looks like a diff
-                if self.is_likely_diff(&block) {
-                    blocks.push(block);
+    /// Keep only the hunks whose entry in `keep` is true; the others go back to the
+    /// original lines
+    pub fn keep_hunks(&mut self, keep: &[bool]) {
+        let content = {
+            let diff = TextDiff::from_lines(&self.old_content, &self.new_content);
+            let old_lines = diff.old_slices();
+            let new_lines = diff.new_slices();
+            
+            let mut content = String::new();
+            let mut old_pos = 0;
+            for ((old_range, new_range), &keep) in self.hunk_ranges().into_iter().zip(keep) {
+                content.extend(old_lines[old_pos..old_range.start].iter().copied());
+                if keep {
+                    content.extend(new_lines[new_range].iter().copied());
+                } else {
+                    content.extend(old_lines[old_range.clone()].iter().copied());
                 }
+                old_pos = old_range.end;
             }
+            content.extend(old_lines[old_pos..].iter().copied());
+            content
+        };
+        
+        self.new_content = content;
+        // Where the kept hunks landed no longer matches the reports
+        self.hunk_reports.clear();
+    }
+    
+    /// The change as a unified diff with `context_radius` unchanged lines around each hunk
+    fn unified_patch(&self, context_radius: usize) -> String {
+        let new_path = self.file_path.to_string_lossy().to_string();
+        let old_path = match &self.rename_from {
+            Some(rename_from) => rename_from.to_string_lossy().to_string(),
+            None => new_path.clone(),
+        };
+        let (old_header, new_header) = if self.is_new_file {
+            ("/dev/null".to_string(), new_path)
+        } else if self.is_deletion {
+            (old_path, "/dev/null".to_string())
+        } else {
+            (old_path, new_path)
+        };
+        
+        // A unified diff of identical content is empty, so a pure move is written the way git does
+        if self.rename_from.is_some() && self.old_content == self.new_content {
+            return format!("diff --git a/{0} b/{1}\nsimilarity index 100%\nrename from {0}\nrename to {1}\n", old_header, new_header);
         }
         
-        blocks
+        TextDiff::from_lines(&self.old_content, &self.new_content)
+            .unified_diff()
+            .context_radius(context_radius)
+            .header(&old_header, &new_header)
+            .to_string()
     }
     
-    // Check if a block is likely a diff
-    fn is_likely_diff(&self, text: &str) -> bool {
-        let lines: Vec<&str> = text.lines().collect();
-        if lines.is_empty() {
-            return false;
+    /// Digits needed for the largest line number of the content
+    fn gutter_width(content: &str) -> usize {
+        content.lines().count().max(1).to_string().len()
+    }
+    
+    /// Write the changed file under `output_root`, creating the mirrored directory structure.
+    /// The working tree is left untouched; only a copy an earlier change left there is backed up.
+    fn apply_to_output_root(&self, output_root: &Path, backups: &mut BackupSet) -> Result<Option<PathBuf>> {
+        let target_path = self.target_path(output_root);
+        
+        if self.is_deletion {
+            // There's nothing to write; just drop a copy left by an earlier change
+            status!("Skipping deletion of {} (writing to {})", self.file_path.display(), output_root.display());
+            if target_path.exists() {
+                backups.back_up(&target_path)?;
+                fs::remove_file(&target_path)
+                    .with_context(|| format!("Failed to delete file: {:?}", target_path))?;
+            }
+            return Ok(None);
         }
         
-        // Check for common diff markers
-        let has_diff_header = lines.iter().any(|line| line.starts_with("--- ") || line.starts_with("+++ "));
-        let has_hunk_header = lines.iter().any(|line| line.starts_with("@@ -"));
-        let has_plus_minus = lines.iter().any(|line| line.starts_with('+') || line.starts_with('-'));
+        verbose!("Writing changes to: {}", target_path.display());
         
-        // Check if it's an explicitly marked diff block
-        let is_diff_format = text.trim().starts_with("diff ") || 
-                             (text.contains("--- ") && text.contains("+++ "));
+        if let Some(parent) = target_path.parent() {
+            ensure_directory_exists(parent)?;
+        }
+        
+        // A copy left by an earlier change is kept, so undoing this one brings it back
+        if target_path.exists() {
+            backups.back_up(&target_path)?;
+        } else {
+            backups.record_created(&target_path)?;
+        }
+        fs::write(&target_path, &self.new_content)
+            .with_context(|| format!("Failed to write to file: {:?}", target_path))?;
         
-        has_diff_header || has_hunk_header || (has_plus_minus && lines.len() > 2) || is_diff_format
+        Ok(None)
     }
     
-    pub fn extract_diffs(&self, text: &str) -> Vec<FileDiff> {
-        let mut diffs = Vec::new();
-        
-        // Get all potential diff blocks
-        let diff_blocks = self.extract_raw_diff_blocks(text);
+    /// Convert the file path to a sanitized path relative to the current directory.
+    /// We need to handle both absolute paths and paths relative to project root.
+    fn target_path(&self, root: &Path) -> PathBuf {
+        Self::rooted_path(&self.file_path, root)
+    }
+    
+    /// The working tree files this diff changes once applied: the target, plus the
+    /// original path of a move. Empty when changes go to an output directory.
+    pub fn touched_paths(&self, root: &Path) -> Vec<PathBuf> {
+        if self.output_root.is_some() {
+            return Vec::new();
+        }
+
+        let target_path = self.target_path(root);
+        match &self.rename_from {
+            Some(rename_from) => vec![Self::rooted_path(rename_from, root), target_path],
+            None if self.is_new_file || self.is_deletion => vec![target_path],
+            // Edits may have landed on the fallback path
+            None => vec![Self::find_actual_file_path(&target_path, root).unwrap_or(target_path)],
+        }
+    }
+
+    /// Where the changed file is once the diff is applied (in the output directory when
+    /// writing there), or None for a deletion
+    pub fn written_path(&self) -> Option<PathBuf> {
+        if self.is_deletion {
+            return None;
+        }
         
-        // Try to parse each block as a diff
-        for block in diff_blocks {
-            if let Ok(diff) = self.parse_diff(&block) {
-                diffs.push(diff);
+        match &self.output_root {
+            Some(output_root) => Some(self.target_path(&self.root.join(output_root))),
+            // The target comes after the original path of a move
+            None => self.touched_paths(&self.root).pop(),
+        }
+    }
+    
+    fn rooted_path(path: &Path, root: &Path) -> PathBuf {
+        if path.starts_with(root) {
+            // An absolute path into the project already points at the right file
+            path.to_path_buf()
+        } else if path.is_absolute() {
+            // If it's an absolute path, try to make it relative to current directory
+            match path.strip_prefix("/") {
+                Ok(rel_path) => root.join(rel_path),
+                Err(_) => path.to_path_buf() // Keep as is if we can't strip prefix
             }
+        } else {
+            // It's already a relative path, join with current directory
+            root.join(path)
         }
-        
-        diffs
     }
     
-    fn parse_diff(&self, diff_text: &str) -> Result<FileDiff> {
-        // Extract file path and content from the diff
-        let lines: Vec<&str> = diff_text.lines().collect();
-        
-        if lines.is_empty() {
-            return Err(anyhow!(DiffError::InvalidFormat("Diff is empty".to_string())));
+    /// Resolve the path the diff reads from: the target itself, or the original of a move
+    fn resolved_path(&self, root: &Path) -> Result<PathBuf> {
+        let target_path = self.target_path(root);
+        if self.is_new_file {
+            Ok(target_path)
+        } else if let Some(rename_from) = &self.rename_from {
+            Self::find_actual_file_path(&Self::rooted_path(rename_from, root), root)
+        } else {
+            Self::find_actual_file_path(&target_path, root)
+        }
+    }
+    
+    /// Check that every path the diff writes, after following `..` and symlinks, is inside
+    /// the project (or the output directory, when writing there)
+    fn check_confined(&self) -> Result<()> {
+        let base = match &self.output_root {
+            Some(output_root) => self.root.join(output_root),
+            None => self.root.clone(),
+        };
+        check_confined(&self.target_path(&base), &base)?;
+        if let Some(rename_from) = &self.rename_from {
+            check_confined(&Self::rooted_path(rename_from, &self.root), &self.root)?;
         }
+        Ok(())
+    }
+    
+    /// Check that the diff can be applied to the file as it currently is on disk
+    fn check_applicable(&self, root: &Path) -> Result<()> {
+        self.check_confined()?;
+        let path = self.resolved_path(root)?;
         
-        // Extract file paths from unified diff headers
-        let mut file_path = PathBuf::new();
-        let mut is_new_file = false;
+        if self.is_new_file {
+            if path.exists() {
+                return Err(anyhow!("{} already exists", self.file_path.display()));
+            }
+            return check_directories_creatable(&path, root);
+        }
         
-        for line in &lines {
-            if line.starts_with("--- ") {
-                let source_path = line.trim_start_matches("--- ");
-                if source_path == "/dev/null" {
-                    is_new_file = true;
-                }
-            } else if line.starts_with("+++ ") {
-                let path_part = line.trim_start_matches("+++ ");
-                
-                // Sanitize the path
-                let clean_path = path_part.trim()
-                    .trim_matches('"')
-                    .trim_matches('\'')
-                    .trim();
-                
-                if clean_path != "/dev/null" {
-                    // Clean up common prefixes (a/, b/, etc.)
-                    let final_path = clean_path
-                        .trim_start_matches("a/")
-                        .trim_start_matches("b/")
-                        .trim_start_matches("./");
-                        
-                    file_path = PathBuf::from(final_path);
-                    break;
-                }
+        if self.rename_from.is_some() {
+            if self.target_path(root).exists() {
+                return Err(anyhow!("Can't move to {}: it already exists", self.file_path.display()));
             }
+            check_directories_creatable(&self.target_path(root), root)?;
         }
         
-        // If we couldn't find a path in headers, try the first line or look for filenames
-        if file_path.as_os_str().is_empty() {
-            let first_line = lines[0].trim();
-            
-            if !first_line.starts_with('+') && !first_line.starts_with('-') && 
-               !first_line.starts_with('@') && !first_line.contains(" ") {
-                // First line might be a filename
-                file_path = PathBuf::from(first_line);
-            } else {
-                // Search for a filename in the first few lines
-                for line in lines.iter().take(5) {
-                    let cleaned = line.trim();
-                    if (cleaned.contains('.') || cleaned.contains('/')) && 
-                       !cleaned.starts_with('+') && !cleaned.starts_with('-') && 
-                       !cleaned.starts_with('@') && !cleaned.contains(" ") {
-                        file_path = PathBuf::from(cleaned);
-                        break;
-                    }
-                }
-            }
+        // Writing to a symlink would change the file it points to, which may be outside the project
+        let is_symlink = fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink());
+        if is_symlink && !self.follow_symlinks && !self.is_deletion && self.rename_from.is_none() && self.output_root.is_none() {
+            return Err(anyhow!("{} is a symlink; set follow_symlinks = true to change the file it points to",
+                self.file_path.display()));
         }
         
-        if file_path.as_os_str().is_empty() {
-            return Err(anyhow!(DiffError::InvalidFormat("Could not determine file path from diff".to_string())));
+        let on_disk = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read file: {:?}", path))?;
+        if on_disk != self.old_content {
+            return Err(anyhow!(DiffError::ChangedOnDisk(self.file_path.display().to_string())));
         }
         
-        println!("Parsed file path: {}", file_path.display());
+        if !self.is_deletion && self.rename_from.is_none() && self.new_content == self.old_content {
+            return Err(anyhow!(DiffError::InvalidFormat(
+                format!("no hunks could be applied to {}", self.file_path.display())
+            )));
+        }
         
-        // Check if the file exists if we're not sure it's a new file
-        if !is_new_file {
-            let current_dir = std::env::current_dir()
-                .unwrap_or_else(|_| PathBuf::from("."));
-                
-            let full_path = current_dir.join(&file_path);
+        Ok(())
+    }
+    
+    // Helper to find the actual file path, with fallbacks
+    fn find_actual_file_path(target_path: &Path, root: &Path) -> Result<PathBuf> {
+        if target_path.exists() {
+            return Ok(target_path.to_path_buf());
+        }
+        
+        // Fallback to just using the filename
+        if let Some(file_name) = target_path.file_name() {
+            let fallback_path = root.join(file_name);
             
-            // If the path doesn't exist, check just the filename
-            if !full_path.exists() {
-                let file_name_only = file_path.file_name().unwrap_or_default();
-                let file_name_path = current_dir.join(file_name_only);
-                
-                is_new_file = !file_name_path.exists();
-            } else {
-                is_new_file = false;
+            if fallback_path.exists() {
+                status!("Using fallback path: {}", fallback_path.display());
+                return Ok(fallback_path);
             }
+            
+            // If even the fallback doesn't exist, return an error with both paths we tried
+            return Err(anyhow!(DiffError::FileNotFound(
+                format!("Tried: {} and {}", 
+                    target_path.to_string_lossy(), 
+                    fallback_path.to_string_lossy())
+            )));
         }
         
-        // Get old content for existing files
-        let old_content = if is_new_file {
-            String::new()
-        } else {
-            let current_dir = std::env::current_dir()
-                .map_err(|_| anyhow!("Failed to get current directory"))?;
-                
-            let target_path = current_dir.join(&file_path);
-            
-            // Try to read the file with fallbacks
-            match fs::read_to_string(&target_path) {
-                Ok(content) => content,
-                Err(_) => {
-                    // Try just the filename
-                    if let Some(file_name) = file_path.file_name() {
-                        let fallback_path = current_dir.join(file_name);
-                        
-                        match fs::read_to_string(&fallback_path) {
-                            Ok(content) => content,
-                            Err(_) => {
-                                return Err(anyhow!(DiffError::FileNotFound(
-                                    format!("Could not find file at any of: {}, {}", 
-                                        target_path.display(), fallback_path.display())
-                                )));
-                            }
-                        }
-                    } else {
-                        return Err(anyhow!(DiffError::FileNotFound(
-                            format!("Invalid file path: {}", file_path.display())
-                        )));
-                    }
-                }
+        Err(anyhow!("Invalid file path"))
+    }
+}
+
+/// Refuse a new file whose directories can't be created, because part of the way to it is
+/// a file rather than a directory
+fn check_directories_creatable(path: &Path, root: &Path) -> Result<()> {
+    match path.ancestors().skip(1).find(|dir| dir.exists()) {
+        Some(existing) if !existing.is_dir() => Err(anyhow!("Can't create {}: {} is a file, not a directory",
+            path.strip_prefix(root).unwrap_or(path).display(), existing.strip_prefix(root).unwrap_or(existing).display())),
+        _ => Ok(()),
+    }
+}
+
+/// Resolve `.` and `..` components without touching the filesystem
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Refuse a path that leaves `base`, whether through `..` components or through a
+/// symlink (in the path's directories or the file itself) that points outside it
+fn check_confined(path: &Path, base: &Path) -> Result<()> {
+    let outside = || anyhow!(DiffError::OutsideProject(path.display().to_string()));
+    
+    let normalized = normalize_lexically(path);
+    if !normalized.starts_with(normalize_lexically(base)) {
+        return Err(outside());
+    }
+    
+    // Nothing can be followed out of a base that doesn't exist yet, such as a new output directory
+    let Ok(canonical_base) = fs::canonicalize(base) else {
+        return Ok(());
+    };
+    
+    // The deepest part of the path that exists decides where the write really goes; a
+    // dangling symlink can't be resolved, so where it leads is unknown
+    if let Some(existing) = normalized.ancestors().find(|ancestor| fs::symlink_metadata(ancestor).is_ok()) {
+        let canonical = fs::canonicalize(existing).map_err(|_| outside())?;
+        if !canonical.starts_with(&canonical_base) {
+            return Err(outside());
+        }
+    }
+    
+    Ok(())
+}
+
+/// Format a preview line's number gutter, leaving it blank for lines without a number
+fn gutter(line_num: Option<usize>, width: usize) -> String {
+    let number = line_num.map(|n| n.to_string()).unwrap_or_default();
+    format!("{:>width$} │ ", number, width = width).dimmed().to_string()
+}
+
+/// Byte ranges of a line to emphasize in the preview
+type ChangedRanges = Vec<Range<usize>>;
+
+/// Pair each run of removed lines with the added lines right after it, and find the
+/// words that changed within each pair of similar lines. Returns, for every change, the
+/// byte ranges to emphasize, or None when the whole line is new.
+fn inline_changes(changes: &[Change<&str>]) -> Vec<Option<ChangedRanges>> {
+    let mut emphasis = vec![None; changes.len()];
+    let mut i = 0;
+    while i < changes.len() {
+        if changes[i].tag() != ChangeTag::Delete {
+            i += 1;
+            continue;
+        }
+        
+        let deleted = i;
+        while i < changes.len() && changes[i].tag() == ChangeTag::Delete {
+            i += 1;
+        }
+        let inserted = i;
+        while i < changes.len() && changes[i].tag() == ChangeTag::Insert {
+            i += 1;
+        }
+        
+        for (old, new) in (deleted..inserted).zip(inserted..i) {
+            let old_line = changes[old].value().trim_end_matches(['\r', '\n']);
+            let new_line = changes[new].value().trim_end_matches(['\r', '\n']);
+            if let Some((old_changed, new_changed)) = changed_words(old_line, new_line) {
+                emphasis[old] = Some(old_changed);
+                emphasis[new] = Some(new_changed);
             }
+        }
+    }
+    emphasis
+}
+
+/// Lines sharing less than this fraction of their words are shown as wholly replaced
+const MIN_INLINE_SIMILARITY: f32 = 0.5;
+
+/// The byte ranges of the words that differ between two versions of a line, or None
+/// when the lines are too different for the comparison to help
+fn changed_words(old: &str, new: &str) -> Option<(ChangedRanges, ChangedRanges)> {
+    let diff = TextDiff::from_words(old, new);
+    if diff.ratio() < MIN_INLINE_SIMILARITY {
+        return None;
+    }
+    
+    let (mut old_changed, mut new_changed): (ChangedRanges, ChangedRanges) = (Vec::new(), Vec::new());
+    let (mut old_pos, mut new_pos) = (0, 0);
+    for change in diff.iter_all_changes() {
+        let len = change.value().len();
+        let (changed, pos) = match change.tag() {
+            ChangeTag::Equal => {
+                old_pos += len;
+                new_pos += len;
+                continue;
+            },
+            ChangeTag::Delete => (&mut old_changed, &mut old_pos),
+            ChangeTag::Insert => (&mut new_changed, &mut new_pos),
         };
+        // Neighboring changed words are emphasized as one span
+        match changed.last_mut() {
+            Some(last) if last.end == *pos => last.end += len,
+            _ => changed.push(*pos..*pos + len),
+        }
+        *pos += len;
+    }
+    Some((old_changed, new_changed))
+}
+
+/// The 1-based start line of a hunk header; empty ranges refer to the line before them
+fn hunk_start(range: &std::ops::Range<usize>) -> usize {
+    if range.is_empty() {
+        range.start
+    } else {
+        range.start + 1
+    }
+}
+
+/// Return the dominant line ending of the content, defaulting to `\n`
+fn detect_line_ending(content: &str) -> &'static str {
+    let crlf_count = content.matches("\r\n").count();
+    let lf_count = content.matches('\n').count() - crlf_count;
+    
+    if crlf_count > lf_count {
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+/// Replace a file's content, keeping its permissions (such as the executable bit) even
+/// if writing would otherwise reset them
+fn write_keeping_permissions(path: &Path, content: &str) -> std::io::Result<()> {
+    let permissions = fs::metadata(path)?.permissions();
+    fs::write(path, content)?;
+    fs::set_permissions(path, permissions)
+}
+
+/// Apply the hunks in a diff block to the content with `git apply`, working on a copy in a
+/// temporary directory. `--recount` tolerates the wrong hunk line counts models often write,
+/// and git finds hunks whose line numbers are off. `--3way` isn't used, since it fails
+/// outside a repository and for untracked files. Returns None if git isn't installed or the
+/// block has no hunks for it to apply.
+fn git_apply(content: &str, lines: &[&str]) -> Result<Option<String>> {
+    let hunks: String = lines.iter()
+        .skip_while(|line| !line.starts_with("@@ -"))
+        .map(|line| format!("{}\n", line.trim_end_matches('\r')))
+        .collect();
+    if hunks.is_empty() {
+        return Ok(None);
+    }
+    
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let work_dir = std::env::temp_dir().join(format!("code-llm-patch-{}-{}", std::process::id(), nanos));
+    ensure_directory_exists(&work_dir)?;
+    
+    // git works on `\n` lines; the original line endings are restored afterwards
+    let line_ending = detect_line_ending(content);
+    let result = run_git_apply(&work_dir, &content.replace("\r\n", "\n"), &hunks);
+    let _ = fs::remove_dir_all(&work_dir);
+    
+    Ok(result?.map(|patched| {
+        if line_ending == "\r\n" {
+            patched.replace('\n', "\r\n")
+        } else {
+            patched
+        }
+    }))
+}
+
+/// Write the content to `work_dir` and patch it there with `git apply`
+fn run_git_apply(work_dir: &Path, content: &str, hunks: &str) -> Result<Option<String>> {
+    let file_path = work_dir.join("file");
+    fs::write(&file_path, content)
+        .with_context(|| format!("Failed to write to file: {:?}", file_path))?;
+    
+    let mut child = match Command::new("git")
+        .args(["apply", "--recount", "--whitespace=nowarn", "-p1", "-"])
+        .current_dir(work_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow!("Failed to run git apply: {}", e)),
+    };
+    
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(format!("--- a/file\n+++ b/file\n{}", hunks).as_bytes())?;
+    }
+    
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("git apply failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    
+    Ok(Some(fs::read_to_string(&file_path)?))
+}
+
+/// Name of the file in each backup set that lists its steps
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Something applying changes did that `/undo` reverses
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum UndoStep {
+    /// A file was changed, deleted or moved away; its original was saved to `backup`
+    Restore { path: PathBuf, backup: PathBuf },
+    /// A file was created, so undoing removes it
+    Remove { path: PathBuf },
+}
+
+/// The backups taken while applying one set of changes, in `.code-llm-backup/<timestamp>/`,
+/// with a manifest of every step so `/undo` reverses them all together. Paths are relative
+/// to the project root, or absolute for files outside it (such as a followed symlink's).
+pub struct BackupSet {
+    root: PathBuf,
+    dir: PathBuf,
+    steps: Vec<UndoStep>,
+}
+
+impl BackupSet {
+    /// Start a new, empty set of backups. Sets made in the same millisecond get later
+    /// timestamps, so the newest set is always the last one.
+    pub fn create(root: &Path) -> Result<Self> {
+        let backup_root = root.join(BACKUP_DIR);
+        ensure_directory_exists(&backup_root)?;
         
-        // Extract new content from the diff
-        let new_content = if is_new_file {
-            // For new files, extract all lines that start with +
-            let mut content = String::new();
-            let mut in_hunk = false;
-            
-            for line in &lines {
-                if line.starts_with("@@ ") {
-                    in_hunk = true;
-                    continue;
-                }
-                
-                if (in_hunk || !line.starts_with("---") && !line.starts_with("+++")) && 
-                   line.starts_with('+') && !line.starts_with("+++ ") {
-                    // Remove the + prefix
-                    content.push_str(&line[1..]);
-                    content.push('\n');
-                }
+        let mut timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        loop {
+            let dir = backup_root.join(timestamp.to_string());
+            match fs::create_dir(&dir) {
+                Ok(()) => return Ok(Self { root: root.to_path_buf(), dir, steps: Vec::new() }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => timestamp += 1,
+                Err(e) => return Err(anyhow!(e)).with_context(|| format!("Failed to create backup directory: {:?}", dir)),
             }
-            
-            content
+        }
+    }
+    
+    /// Copy a file into the set before it's changed and return the backup path. A file
+    /// already in the set keeps its first backup, which is what undoing returns it to.
+    pub fn back_up(&mut self, path: &Path) -> Result<PathBuf> {
+        let path = self.step_path(path);
+        if let Some(step) = self.steps.iter().find(|step| step.path() == path) {
+            return Ok(match step {
+                UndoStep::Restore { backup, .. } => self.dir.join(backup),
+                UndoStep::Remove { .. } => PathBuf::new(),
+            });
+        }
+        
+        // Files outside the project are numbered, since only their names are kept
+        let backup = if path.is_absolute() {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            PathBuf::from("outside").join(format!("{}-{}", self.steps.len(), name))
         } else {
-            // For existing files, apply the diff to the original content
-            let old_lines: Vec<&str> = old_content.lines().collect();
-            let mut new_lines = old_lines.iter().map(|&s| s.to_string()).collect::<Vec<String>>();
-            
-            // Process hunks with line numbers
-            let mut i = 0;
-            while i < lines.len() {
-                let line = lines[i];
-                
-                // Look for hunk headers
-                if line.starts_with("@@ -") && line.contains(" @@") {
-                    // Parse the hunk header
-                    let header_parts: Vec<&str> = line
-                        .trim_matches(|c| c == '@' || c == ' ')
-                        .split(' ')
-                        .collect();
-                    
-                    if header_parts.len() >= 2 {
-                        let old_info = header_parts[0].trim_start_matches('-');
-                        let _new_info = header_parts[1].trim_start_matches('+');
-                        
-                        // Parse old line numbers: -X,Y where X = start line (1-based), Y = line count
-                        let old_parts: Vec<&str> = old_info.split(',').collect();
-                        if old_parts.len() >= 1 {
-                            let old_start = old_parts[0].parse::<usize>().unwrap_or(1);
-                            let old_count = if old_parts.len() >= 2 {
-                                old_parts[1].parse::<usize>().unwrap_or(0)
-                            } else {
-                                0
-                            };
-                            
-                            // Collect hunk content
-                            let mut old_hunk_content = Vec::new();
-                            let mut new_hunk_content = Vec::new();
-                            
-                            // Move to content lines
-                            i += 1;
-                            while i < lines.len() {
-                                let hunk_line = lines[i];
-                                
-                                if hunk_line.starts_with('-') {
-                                    old_hunk_content.push(&hunk_line[1..]);
-                                } else if hunk_line.starts_with('+') {
-                                    new_hunk_content.push(&hunk_line[1..]);
-                                } else if hunk_line.starts_with(' ') {
-                                    // Context lines are the same in both
-                                    old_hunk_content.push(&hunk_line[1..]);
-                                    new_hunk_content.push(&hunk_line[1..]);
-                                } else if hunk_line.starts_with("@@ ") {
-                                    // Next hunk header
-                                    i -= 1;
-                                    break;
-                                } else if hunk_line.is_empty() {
-                                    // Skip empty lines but continue
-                                } else {
-                                    // End of hunk
-                                    break;
-                                }
-                                
-                                i += 1;
-                            }
-                            
-                            // Apply changes to new_lines
-                            let old_start_idx = old_start.saturating_sub(1); // Convert to 0-based
-                            let old_range_end = old_start_idx + old_count;
-                            
-                            if old_start_idx < new_lines.len() {
-                                let capped_range_end = std::cmp::min(old_range_end, new_lines.len());
-                                
-                                // Replace the old lines with new lines
-                                new_lines.splice(
-                                    old_start_idx..capped_range_end,
-                                    new_hunk_content.iter().map(|&s| s.to_string())
-                                );
-                            }
-                        }
-                    }
-                }
-                
-                i += 1;
-            }
-            
-            // If standard hunk parsing failed, try simpler approach
-            if new_lines.iter().map(|s| s.as_str()).collect::<Vec<&str>>() == old_lines {
-                // Collect removed and added lines
-                let mut removed_lines = Vec::new();
-                let mut added_lines = Vec::new();
-                
-                for line in &lines {
-                    if line.starts_with('-') && !line.starts_with("--- ") {
-                        removed_lines.push(&line[1..]);
-                    } else if line.starts_with('+') && !line.starts_with("+++ ") {
-                        added_lines.push(&line[1..]);
-                    }
-                }
-                
-                // Apply the changes
-                if !removed_lines.is_empty() || !added_lines.is_empty() {
-                    let mut result = Vec::new();
-                    let mut i = 0;
-                    
-                    while i < old_lines.len() {
-                        // Try to find a sequence of removed lines at this position
-                        if i <= old_lines.len() - removed_lines.len() {
-                            let mut matched = true;
-                            for (j, &removed) in removed_lines.iter().enumerate() {
-                                if i + j >= old_lines.len() || old_lines[i + j] != removed {
-                                    matched = false;
-                                    break;
-                                }
-                            }
-                            
-                            if matched {
-                                // Replace removed lines with added lines
-                                for &added in &added_lines {
-                                    result.push(added.to_string());
-                                }
-                                i += removed_lines.len();
-                                continue;
-                            }
-                        }
-                        
-                        // No match, keep original line
-                        result.push(old_lines[i].to_string());
-                        i += 1;
-                    }
-                    
-                    new_lines = result;
-                }
-            }
-            
-            // Combine the lines
-            let mut content = new_lines.join("\n");
-            
-            // Add trailing newline if original had one
-            if old_content.ends_with('\n') {
-                content.push('\n');
-            }
-            
-            content
+            path.clone()
         };
+        let backup_path = self.dir.join(&backup);
+        if let Some(parent) = backup_path.parent() {
+            ensure_directory_exists(parent)?;
+        }
+        fs::copy(self.root.join(&path), &backup_path)
+            .with_context(|| format!("Failed to back up {:?} to {:?}", path, backup_path))?;
         
-        Ok(FileDiff {
-            file_path,
-            old_content,
-            new_content,
-            is_new_file,
-        })
+        self.steps.push(UndoStep::Restore { path, backup });
+        self.save()?;
+        Ok(backup_path)
+    }
+    
+    /// Note that a file is about to be created, so undoing removes it
+    pub fn record_created(&mut self, path: &Path) -> Result<()> {
+        let path = self.step_path(path);
+        if self.steps.iter().any(|step| step.path() == path) {
+            return Ok(());
+        }
+        self.steps.push(UndoStep::Remove { path });
+        self.save()
+    }
+    
+    /// Reverse every step, newest first, and delete the set. Files that are already gone
+    /// aren't an error. On failure the set is kept, so the undo can be tried again.
+    pub fn undo(self) -> Result<Vec<UndoStep>> {
+        for step in self.steps.iter().rev() {
+            let result = match step {
+                UndoStep::Restore { path, backup } => {
+                    let path = self.root.join(path);
+                    path.parent().map_or(Ok(()), ensure_directory_exists)
+                        .and_then(|_| fs::copy(self.dir.join(backup), &path)
+                            .with_context(|| format!("Failed to restore {:?}", path)))
+                        .map(|_| ())
+                },
+                UndoStep::Remove { path } => match fs::remove_file(self.root.join(path)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        Err(anyhow!(e)).with_context(|| format!("Failed to remove {:?}", path))
+                    },
+                    _ => Ok(()),
+                },
+            };
+            result.with_context(|| format!("Couldn't finish undoing the changes backed up in {:?}", self.dir))?;
+        }
+        
+        fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("Failed to remove backup directory: {:?}", self.dir))?;
+        Ok(self.steps)
+    }
+    
+    /// Load the newest set in the project's backup directory, if there is one
+    fn latest(root: &Path) -> Result<Option<Self>> {
+        let backup_root = root.join(BACKUP_DIR);
+        let Ok(entries) = fs::read_dir(&backup_root) else {
+            return Ok(None);
+        };
+        
+        // Backups from before sets had manifests have nothing to undo them by
+        let latest = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| Some((entry.file_name().to_str()?.parse::<u128>().ok()?, entry.path())))
+            .filter(|(_, dir)| dir.join(MANIFEST_FILE).is_file())
+            .max_by_key(|(timestamp, _)| *timestamp);
+        let Some((_, dir)) = latest else {
+            return Ok(None);
+        };
+        
+        let manifest_path = dir.join(MANIFEST_FILE);
+        let manifest = fs::read_to_string(&manifest_path)
+            .with_context(|| format!("Failed to read {:?}", manifest_path))?;
+        let steps = serde_json::from_str(&manifest)
+            .with_context(|| format!("Failed to parse {:?}", manifest_path))?;
+        Ok(Some(Self { root: root.to_path_buf(), dir, steps }))
+    }
+    
+    /// A path as it's kept in the manifest: relative to the root when it's inside it
+    fn step_path(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root).map(Path::to_path_buf).unwrap_or_else(|_| path.to_path_buf())
+    }
+    
+    /// Write the manifest, after every step so it's complete even if applying stops midway
+    fn save(&self) -> Result<()> {
+        let manifest_path = self.dir.join(MANIFEST_FILE);
+        fs::write(&manifest_path, serde_json::to_string_pretty(&self.steps)?)
+            .with_context(|| format!("Failed to write {:?}", manifest_path))
     }
-}
\ No newline at end of file
+}
+
+impl UndoStep {
+    /// The file the step puts back, relative to the project root when it's inside it
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Restore { path, .. } | Self::Remove { path } => path,
+        }
+    }
+}
+
+/// Whether a code block's content looks like a diff. With `strict`, it needs file headers
+/// and a hunk, or a move.
+fn is_likely_diff(text: &str, strict: bool) -> bool {
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.is_empty() {
+        return false;
+    }
+    
+    // Check for common diff markers
+    let has_diff_header = lines.iter().any(|line| line.starts_with("--- ") || line.starts_with("+++ "));
+    let has_hunk_header = lines.iter().any(|line| line.starts_with("@@ -"));
+    
+    if strict {
+        // Both file headers and a hunk are required, so example output that merely has
+        // +/- lines isn't offered for application. A pure move has no hunks.
+        let has_file_headers = lines.iter().any(|line| line.starts_with("--- "))
+            && lines.iter().any(|line| line.starts_with("+++ "));
+        let is_move = lines.iter().any(|line| line.starts_with("rename from "))
+            && lines.iter().any(|line| line.starts_with("rename to "));
+        return (has_file_headers && has_hunk_header) || is_move;
+    }
+    
+    let has_plus_minus = lines.iter().any(|line| line.starts_with('+') || line.starts_with('-'));
+    
+    // Check if it's an explicitly marked diff block
+    let is_diff_format = text.trim().starts_with("diff ") || 
+                         (text.contains("--- ") && text.contains("+++ "));
+    
+    has_diff_header || has_hunk_header || (has_plus_minus && lines.len() > 2) || is_diff_format
+}
+
+/// Whether the text ends inside a fenced code block, as when a response is cut off in the
+/// middle of a diff
+pub fn has_unterminated_fence(text: &str) -> bool {
+    text.lines().filter(|line| line.trim_start().starts_with("```")).count() % 2 == 1
+}
+
+/// Hides fenced diff blocks in a response as it streams in, leaving a placeholder, for when
+/// the diffs are shown again for review. Other text passes through, and a code block that
+/// isn't a diff is printed once it's complete.
+pub struct DiffBlockFilter {
+    /// Require file headers and a hunk for a block to count as a diff
+    strict: bool,
+    /// The current line, minus any part already passed through
+    line: String,
+    /// Part of the current line was passed through, so it can't open a code block
+    line_passed: bool,
+    /// Lines of the open code block, starting with its fence
+    block: Option<Vec<String>>,
+}
+
+impl DiffBlockFilter {
+    pub fn new(strict: bool) -> Self {
+        Self { strict, line: String::new(), line_passed: false, block: None }
+    }
+    
+    /// Filter a whole response at once
+    pub fn filter_all(text: &str, strict: bool) -> String {
+        let mut filter = Self::new(strict);
+        let mut output = filter.push(text);
+        output.push_str(&filter.finish());
+        output
+    }
+    
+    /// Take the next piece of the response, returning the text that can be shown so far
+    pub fn push(&mut self, fragment: &str) -> String {
+        let mut output = String::new();
+        for c in fragment.chars() {
+            if c == '\n' {
+                self.end_line(&mut output);
+            } else {
+                self.line.push(c);
+            }
+        }
+        
+        // Show a partial line right away unless it may turn out to be a fence
+        let start = self.line.trim_start();
+        let may_be_fence = start.starts_with("```") || "```".starts_with(start);
+        if self.block.is_none() && !self.line.is_empty() && (self.line_passed || !may_be_fence) {
+            output.push_str(&std::mem::take(&mut self.line));
+            self.line_passed = true;
+        }
+        output
+    }
+    
+    /// The rest of the response, including a code block that was never closed
+    pub fn finish(&mut self) -> String {
+        let mut output = self.block.take().map(|lines| lines.join("\n") + "\n").unwrap_or_default();
+        output.push_str(&std::mem::take(&mut self.line));
+        output
+    }
+    
+    fn end_line(&mut self, output: &mut String) {
+        let line = std::mem::take(&mut self.line);
+        let is_fence = line.trim_start().starts_with("```");
+        
+        if let Some(block) = &mut self.block {
+            block.push(line);
+            if is_fence {
+                let block = self.block.take().unwrap_or_default();
+                let content = block[1..block.len() - 1].join("\n");
+                if is_likely_diff(&content, self.strict) {
+                    output.push_str(&format!("{}\n", "[diff shown below]".dimmed()));
+                } else {
+                    output.push_str(&block.join("\n"));
+                    output.push('\n');
+                }
+            }
+        } else if is_fence && !self.line_passed {
+            self.block = Some(vec![line]);
+        } else {
+            output.push_str(&line);
+            output.push('\n');
+        }
+        self.line_passed = false;
+    }
+}
+
+/// The file a code block is meant for: a path in the fence info string (`js:src/index.js`
+/// or just `src/index.js`), else a path on the last non-empty line before the block, such
+/// as `` `index.js`: ``, `**src/app.py**` or `File: main.go`
+fn code_block_file_name<'a>(info: &'a str, preceding: &'a str) -> Option<&'a str> {
+    let info = info.trim();
+    let from_info = info.rsplit_once(':').map_or(info, |(_, path)| path).trim();
+    if looks_like_path(from_info) {
+        return Some(from_info);
+    }
+    
+    let line = preceding.lines().rev().map(str::trim).find(|line| !line.is_empty())?;
+    
+    // A quoted path wins over other words on the line
+    let quoted = line.split('`').skip(1).step_by(2).filter(|span| looks_like_path(span)).last();
+    if quoted.is_some() {
+        return quoted;
+    }
+    
+    let line = line.trim_start_matches(['#', '*', '-', ' '])
+        .trim_end_matches([':', '*', ' ']);
+    let line = ["File:", "file:", "Filename:", "filename:"].iter()
+        .find_map(|label| line.strip_prefix(label))
+        .unwrap_or(line)
+        .trim()
+        .trim_matches('*');
+    looks_like_path(line).then_some(line)
+}
+
+/// Whether a word could be a relative file path: no spaces, and a directory or extension
+fn looks_like_path(word: &str) -> bool {
+    !word.is_empty()
+        && !word.contains(char::is_whitespace)
+        && !word.contains("://")
+        && (word.contains('/') || word.contains('.'))
+        && !word.ends_with(['.', '/'])
+        && word.chars().all(|c| c.is_alphanumeric() || "._-/+@".contains(c))
+}
+
+/// Which sides of a hunk end without a final newline, from `\ No newline at end of file`
+#[derive(Debug, Default, Clone, Copy)]
+struct MissingNewline {
+    old: bool,
+    new: bool,
+}
+
+/// The old and new paths from the rest of a `diff --git a/<old> b/<new>` line, without the
+/// prefixes. Paths with spaces are split where both halves are the same path, unless
+/// they're quoted.
+fn parse_git_header(rest: &str) -> Option<(String, String)> {
+    let rest = rest.trim();
+    
+    // Git quotes paths with unusual characters: "a/x y" "b/x y"
+    if rest.starts_with('"') {
+        let mut quoted = rest.split('"').filter(|part| !part.trim().is_empty());
+        let old_path = quoted.next()?.strip_prefix("a/")?;
+        let new_path = quoted.next()?.strip_prefix("b/")?;
+        return Some((old_path.to_string(), new_path.to_string()));
+    }
+    
+    let rest = rest.strip_prefix("a/")?;
+    // The same path on both sides, which is the usual case, even if it has spaces
+    let half = rest.len().saturating_sub(3) / 2;
+    if rest.is_char_boundary(half) && rest[half..].starts_with(" b/") && rest[..half] == rest[half + 3..] {
+        return Some((rest[..half].to_string(), rest[half + 3..].to_string()));
+    }
+    let (old_path, new_path) = rest.split_once(" b/")?;
+    Some((old_path.to_string(), new_path.to_string()))
+}
+
+/// Whether every hunk in a diff block starts from nothing (`@@ -0,0 +1,N @@`), as the diff
+/// of a new file does
+fn only_adds_to_empty_file(lines: &[&str]) -> bool {
+    let mut old_ranges = lines.iter()
+        .filter(|line| line.starts_with("@@ -"))
+        .filter_map(|line| line[4..].split_whitespace().next())
+        .peekable();
+    old_ranges.peek().is_some() && old_ranges.all(|range| range == "0,0" || range == "0")
+}
+
+/// Whether a diff block changes any lines, rather than only having headers (or hunk
+/// headers) as a pure rename does
+fn has_content_changes(lines: &[&str]) -> bool {
+    lines.iter().any(|line| (line.starts_with('+') && !line.starts_with("+++ "))
+        || (line.starts_with('-') && !line.starts_with("--- ")))
+}
+
+/// The diff of each file in a block that has several `---`/`+++` (or `diff`) headers, or
+/// the block as it is when it has at most one
+fn split_block_files(block: String) -> Vec<String> {
+    let files = split_patch_files(&block);
+    if files.len() > 1 {
+        files
+    } else {
+        vec![block]
+    }
+}
+
+/// Split a plain unified diff into one section per file. A file starts at a `diff` line, or
+/// at a `---` header right before a `+++` one that doesn't follow a `diff` line. Anything
+/// before the first file, like a commit message, is dropped.
+fn split_patch_files(text: &str) -> Vec<String> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut sections: Vec<String> = Vec::new();
+    let mut in_git_header = false;
+    
+    for (i, line) in lines.iter().enumerate() {
+        let starts_file = if line.starts_with("diff ") {
+            in_git_header = true;
+            true
+        } else if line.starts_with("--- ") && lines.get(i + 1).is_some_and(|next| next.starts_with("+++ ")) {
+            !std::mem::take(&mut in_git_header)
+        } else {
+            if line.starts_with("@@ ") {
+                in_git_header = false;
+            }
+            false
+        };
+        
+        if starts_file {
+            sections.push(String::new());
+        }
+        if let Some(section) = sections.last_mut() {
+            section.push_str(line);
+            section.push('\n');
+        }
+    }
+    
+    sections
+}
+
+/// Write each (file path, patch) pair to `<dir>/<path with / replaced by _>.patch`,
+/// returning the files written
+pub fn save_patches(patches: &[(PathBuf, String)], dir: &Path) -> Result<Vec<PathBuf>> {
+    ensure_directory_exists(dir)?;
+    
+    let mut written = Vec::new();
+    for (file_path, patch) in patches {
+        let name = file_path.to_string_lossy().trim_start_matches('/').replace(['/', '\\'], "_");
+        let patch_path = dir.join(format!("{}.patch", name));
+        fs::write(&patch_path, patch)
+            .with_context(|| format!("Failed to write patch file: {:?}", patch_path))?;
+        written.push(patch_path);
+    }
+    
+    Ok(written)
+}
+
+/// Undo the most recent set of applied changes: restore every file it changed, deleted or
+/// moved, and remove the files it created. Returns what was undone, or None if there are
+/// no backups.
+pub fn undo_last(root: &Path) -> Result<Option<Vec<UndoStep>>> {
+    match BackupSet::latest(root)? {
+        Some(backups) => Ok(Some(backups.undo()?)),
+        None => Ok(None),
+    }
+}
+
+pub struct DiffGenerator {
+    diff_regex: Regex,
+    /// Project root that paths in diffs are relative to
+    root: PathBuf,
+    /// How many lines around a hunk header's position to search for the hunk's content
+    hunk_search_window: usize,
+    /// Merge hunks that don't match into the file, with conflict markers, instead of failing
+    merge_on_conflict: bool,
+    /// Directory that applied changes are written to instead of the working tree
+    output_root: Option<PathBuf>,
+    /// Unchanged lines shown around each change in diff previews
+    diff_context_lines: usize,
+    /// Most lines of a new file shown in the review preview
+    max_preview_lines: usize,
+    /// Only treat blocks with file headers and hunk headers as diffs
+    strict_diffs: bool,
+    /// Apply hunks with `git apply` when git is available
+    use_external_patch: bool,
+    /// Write through symlinks instead of refusing to change them
+    follow_symlinks: bool,
+    /// Match hunk lines to the file regardless of indentation when nothing matches exactly
+    ignore_whitespace_in_match: bool,
+    /// Line endings that new files are written with
+    new_file_line_ending: LineEnding,
+    /// Absolute paths of the files sent only as reference, which are never changed
+    external_files: Vec<PathBuf>,
+}
+
+impl DiffGenerator {
+    pub fn new<P: AsRef<Path>>(root: P, config: &Config) -> Self {
+        // Match any code block with optional language tag
+        let diff_regex = Regex::new(r"```(?:[a-zA-Z0-9_\-+.]*)?(?:\s*\n|\s)((?:.|\n)*?)```").unwrap();
+        Self {
+            diff_regex,
+            root: root.as_ref().to_path_buf(),
+            hunk_search_window: config.hunk_search_window,
+            merge_on_conflict: config.merge_on_conflict,
+            output_root: config.output_root.clone(),
+            diff_context_lines: config.diff_context_lines,
+            max_preview_lines: config.max_preview_lines,
+            strict_diffs: config.strict_diffs,
+            use_external_patch: config.use_external_patch,
+            follow_symlinks: config.follow_symlinks,
+            ignore_whitespace_in_match: config.ignore_whitespace_in_match,
+            new_file_line_ending: config.new_file_line_ending,
+            external_files: config.resolved_external_files(root.as_ref()),
+        }
+    }
+    
+    /// Apply all diffs with all-or-nothing semantics: every diff is validated against the
+    /// files on disk before anything is written, and if a write still fails, the changes
+    /// already made are rolled back. The backups form one set, so `/undo` reverses all of
+    /// the changes at once. Returns the backup paths of the changed files.
+    pub fn apply_all(&self, diffs: &[FileDiff]) -> Result<Vec<Option<PathBuf>>> {
+        // Checked before anything else, so not even the backup directory is created
+        if is_read_only() {
+            let paths: Vec<String> = diffs.iter().map(|diff| diff.file_path.display().to_string()).collect();
+            return Err(anyhow!(DiffError::ReadOnly(paths.join(", "))));
+        }
+        
+        for diff in diffs {
+            diff.validate().with_context(|| {
+                format!("Not applying any changes: {} can't be applied", diff.file_path.display())
+            })?;
+        }
+        
+        let mut backups = BackupSet::create(&self.root)?;
+        let mut backup_paths = Vec::new();
+        for diff in diffs {
+            match diff.apply(&mut backups) {
+                Ok(backup_path) => backup_paths.push(backup_path),
+                Err(e) => {
+                    // Undoing the set restores the backups and removes newly created files
+                    let message = match backups.undo() {
+                        Ok(_) => "earlier changes were rolled back".to_string(),
+                        Err(rollback_error) => format!("rolling back earlier changes failed: {:#}", rollback_error),
+                    };
+                    return Err(e.context(format!("Failed to apply changes to {}; {}", diff.file_path.display(), message)));
+                }
+            }
+        }
+        
+        Ok(backup_paths)
+    }
+    
+    /// Parse a diff again against its file as it is on disk now, e.g. after the file was
+    /// edited while the diff waited for review. The change is carried over as a patch of
+    /// the old and new content, so an edit made during review is kept.
+    pub fn reparse(&self, diff: &FileDiff) -> Result<FileDiff> {
+        // Enough context to find the hunks again, whatever the preview shows
+        self.parse_diff(&diff.unified_patch(3))
+            .with_context(|| format!("The changes to {} no longer apply to the file", diff.file_path.display()))
+    }
+    
+    pub fn extract_raw_diff_blocks(&self, text: &str) -> Vec<String> {
+        // First try to extract code blocks with triple backticks
+        let markdown_blocks = self.extract_code_blocks(text);
+        if !markdown_blocks.is_empty() {
+            return markdown_blocks;
+        }
+        
+        // Try to parse as raw diff text if no code blocks were found. In strict mode prose
+        // only qualifies if it really contains a diff.
+        if self.strict_diffs && !self.is_likely_diff(text) {
+            return Vec::new();
+        }
+        split_block_files(text.to_string())
+    }
+    
+    // Extract code blocks with triple backticks
+    fn extract_code_blocks(&self, text: &str) -> Vec<String> {
+        let mut blocks = Vec::new();
+        
+        for captures in self.diff_regex.captures_iter(text) {
+            if let Some(block_match) = captures.get(1) {
+                let block = block_match.as_str().to_string();
+                
+                // Only include the block if it looks like a diff. Models sometimes put the
+                // diffs of several files in one block, which are parsed one file at a time.
+                if self.is_likely_diff(&block) {
+                    blocks.extend(split_block_files(block));
+                }
+            }
+        }
+        
+        blocks
+    }
+    
+    /// Whether any fenced code block in the text looks like a diff
+    pub fn has_diff_blocks(&self, text: &str) -> bool {
+        !self.extract_code_blocks(text).is_empty()
+    }
+    
+    // Check if a block is likely a diff
+    fn is_likely_diff(&self, text: &str) -> bool {
+        is_likely_diff(text, self.strict_diffs)
+    }
+    
+    /// The changes in a response's diff blocks, and the blocks that looked like diffs but
+    /// couldn't be parsed or applied to their file
+    pub fn extract_diffs(&self, text: &str) -> DiffExtraction {
+        self.extract_diffs_with_progress(text, |_, _| {})
+    }
+    
+    /// Like `extract_diffs`, calling `progress(block number, block count)` before each block
+    /// is parsed so callers can show that work is being done
+    pub fn extract_diffs_with_progress(&self, text: &str, progress: impl FnMut(usize, usize)) -> DiffExtraction {
+        // Get all potential diff blocks
+        let diff_blocks = self.extract_raw_diff_blocks(text);
+        self.parse_diff_blocks(diff_blocks, progress)
+    }
+    
+    /// Parse the changes in a patch file: either a saved response with fenced diffs, or a
+    /// plain unified diff (such as `git diff` output), which may touch several files
+    pub fn extract_patch_diffs(&self, text: &str) -> DiffExtraction {
+        if text.contains("```") {
+            return self.extract_diffs(text);
+        }
+        
+        self.parse_diff_blocks(split_patch_files(text), |_, _| {})
+    }
+    
+    /// Fenced code blocks that aren't diffs but name a file, either in the fence info string
+    /// (```` ```js:src/index.js ````) or on the line before the block (`` `index.js`: ``),
+    /// as changes that write the block as the whole file. A file named twice gets the last block.
+    pub fn extract_file_blocks(&self, text: &str) -> Vec<FileDiff> {
+        let fence_regex = Regex::new(r"(?ms)^[ \t]*```([^\n`]*)\n(.*?)^[ \t]*```[ \t]*$").unwrap();
+        let mut diffs: Vec<FileDiff> = Vec::new();
+        let mut previous_end = 0;
+        
+        for captures in fence_regex.captures_iter(text) {
+            let (Some(fence), Some(info), Some(body)) = (captures.get(0), captures.get(1), captures.get(2)) else {
+                continue;
+            };
+            let preceding = &text[previous_end..fence.start()];
+            previous_end = fence.end();
+            
+            if self.is_likely_diff(body.as_str()) {
+                continue;
+            }
+            let Some(name) = code_block_file_name(info.as_str(), preceding) else {
+                continue;
+            };
+            
+            match self.whole_file_diff(Path::new(name), body.as_str()) {
+                Ok(diff) => {
+                    diffs.retain(|d| d.file_path != diff.file_path);
+                    diffs.push(diff);
+                },
+                Err(e) => status!("{}", format!("⚠️  Skipping code block for {}: {:#}", name, e).yellow()),
+            }
+        }
+        
+        diffs
+    }
+    
+    /// A change that replaces the file at `path` with `content`, or creates it
+    fn whole_file_diff(&self, path: &Path, content: &str) -> Result<FileDiff> {
+        let file_path = self.project_path(path)?;
+        let target_path = self.root.join(&file_path);
+        let is_new_file = !target_path.exists();
+        let old_content = if is_new_file {
+            String::new()
+        } else {
+            fs::read_to_string(&target_path)
+                .with_context(|| format!("Failed to read file: {}", target_path.display()))?
+        };
+        
+        if old_content == content {
+            return Err(anyhow!("the block is the same as the file"));
+        }
+        
+        let new_content = if is_new_file {
+            self.with_new_file_line_endings(content, &file_path)
+        } else {
+            content.to_string()
+        };
+        
+        Ok(FileDiff {
+            file_path,
+            old_content,
+            new_content,
+            is_new_file,
+            is_deletion: false,
+            rename_from: None,
+            block_count: 1,
+            hunk_reports: Vec::new(),
+            edited: false,
+            output_root: self.output_root.clone(),
+            context_lines: self.diff_context_lines,
+            preview_lines: self.max_preview_lines,
+            patched_with_git: false,
+            follow_symlinks: self.follow_symlinks,
+            root: self.root.clone(),
+        })
+    }
+    
+    fn parse_diff_blocks(&self, diff_blocks: Vec<String>, mut progress: impl FnMut(usize, usize)) -> DiffExtraction {
+        let mut diffs: Vec<FileDiff> = Vec::new();
+        let mut skipped = Vec::new();
+        let total = diff_blocks.len();
+        
+        // Try to parse each block as a diff, grouping blocks that touch the same file
+        // so all changes to one file are reviewed and applied together
+        for (i, block) in diff_blocks.into_iter().enumerate() {
+            progress(i + 1, total);
+            let diff = match self.parse_diff(&block) {
+                Ok(diff) => diff,
+                Err(e) => {
+                    // Plain prose that merely fell through to raw-diff parsing isn't worth a mention
+                    if self.is_likely_diff(&block) {
+                        skipped.push(SkippedBlock { block: i + 1, reason: format!("{:#}", e) });
+                    }
+                    continue;
+                }
+            };
+            
+            if let Some(existing) = diffs.iter_mut().find(|d| d.file_path == diff.file_path) {
+                // Apply this block on top of the earlier blocks for the same file. Its hunk
+                // headers refer to the original file, so they're shifted by the earlier hunks.
+                let lines: Vec<&str> = block.lines().collect();
+                match self.patch_content(&existing.new_content, &lines, &existing.hunk_reports) {
+                    Ok((content, reports, patched_with_git)) => {
+                        existing.new_content = content;
+                        existing.hunk_reports.extend(reports);
+                        existing.patched_with_git |= patched_with_git;
+                        existing.block_count += 1;
+                    },
+                    Err(e) => {
+                        let reason = format!("doesn't apply after the earlier changes to {}: {:#}", diff.file_path.display(), e);
+                        skipped.push(SkippedBlock { block: i + 1, reason });
+                    }
+                }
+            } else {
+                diffs.push(diff);
+            }
+        }
+        
+        DiffExtraction { diffs, skipped }
+    }
+    
+    /// A path from a diff as a path relative to the project root. Absolute paths into the
+    /// project are made relative, other absolute paths are read as relative to the root
+    /// (models often write `/src/main.rs`), and paths that leave the root are refused.
+    fn project_path(&self, path: &Path) -> Result<PathBuf> {
+        // External files keep their absolute paths in the context, so a diff may name one
+        let full_path = fs::canonicalize(self.root.join(path)).unwrap_or_else(|_| self.root.join(path));
+        if self.external_files.contains(&full_path) {
+            return Err(anyhow!(DiffError::ExternalFile(path.display().to_string())));
+        }
+        
+        let relative = path.strip_prefix(&self.root)
+            .or_else(|_| path.strip_prefix("/"))
+            .unwrap_or(path);
+        
+        let mut normalized = PathBuf::new();
+        for component in relative.components() {
+            match component {
+                Component::CurDir => {},
+                // Climbing above the root leaves the project
+                Component::ParentDir => {
+                    if !normalized.pop() {
+                        return Err(anyhow!(DiffError::OutsideProject(path.display().to_string())));
+                    }
+                },
+                component => normalized.push(component),
+            }
+        }
+        
+        check_confined(&self.root.join(&normalized), &self.root)
+            .map_err(|_| anyhow!(DiffError::OutsideProject(path.display().to_string())))?;
+        Ok(normalized)
+    }
+    
+    /// Sanitize a path from a `---`/`+++` header, returning None for `/dev/null`. Git's
+    /// `a/` and `b/` prefixes are left for `strip_git_prefix`.
+    fn clean_header_path(path_part: &str) -> Option<String> {
+        let clean_path = path_part.trim()
+            .trim_matches('"')
+            .trim_matches('\'')
+            .trim();
+        
+        if clean_path == "/dev/null" {
+            return None;
+        }
+        
+        Some(clean_path.strip_prefix("./").unwrap_or(clean_path).to_string())
+    }
+    
+    /// Remove git's `a/` or `b/` prefix from a header path. In a diff known to be in git's
+    /// format it's always there; otherwise it's only taken for one when the project has no
+    /// directory of that name, so `a/` in a real `a/...` path is kept.
+    fn strip_git_prefix(&self, path: String, prefix: &str, git_format: bool) -> String {
+        match path.strip_prefix(prefix) {
+            Some(rest) if git_format || !self.root.join(&prefix[..1]).is_dir() => rest.to_string(),
+            _ => path,
+        }
+    }
+    
+    fn parse_diff(&self, diff_text: &str) -> Result<FileDiff> {
+        // Extract file path and content from the diff
+        let lines: Vec<&str> = diff_text.lines().collect();
+        
+        if lines.is_empty() {
+            return Err(anyhow!(DiffError::InvalidFormat("Diff is empty".to_string())));
+        }
+        
+        // Extract file paths from unified diff headers
+        let mut git_paths: Option<(String, String)> = None;
+        let mut old_header: Option<String> = None;
+        let mut new_header: Option<String> = None;
+        let mut rename_from_line: Option<String> = None;
+        let mut is_new_file = false;
+        let mut is_deletion = false;
+        let mut rename_to: Option<String> = None;
+        
+        for line in &lines {
+            if let Some(rest) = line.strip_prefix("diff --git ") {
+                git_paths = parse_git_header(rest);
+            } else if let Some(path_part) = line.strip_prefix("rename from ") {
+                rename_from_line = Self::clean_header_path(path_part);
+            } else if let Some(path_part) = line.strip_prefix("rename to ") {
+                rename_to = Self::clean_header_path(path_part);
+            } else if line.starts_with("@@ ") {
+                // Headers end at the first hunk
+                break;
+            } else if let Some(path_part) = line.strip_prefix("--- ") {
+                match Self::clean_header_path(path_part) {
+                    Some(path) => old_header = Some(path),
+                    None => is_new_file = true,
+                }
+            } else if let Some(path_part) = line.strip_prefix("+++ ") {
+                match Self::clean_header_path(path_part) {
+                    Some(path) => {
+                        new_header = Some(path);
+                        break;
+                    },
+                    None => {
+                        // `+++ /dev/null` marks the file from the `---` header for deletion
+                        if old_header.is_some() {
+                            is_deletion = true;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        
+        // A `diff --git` line, or `a/` and `b/` on both headers, shows the prefixes are git's
+        let git_format = git_paths.is_some()
+            || (old_header.as_deref().is_some_and(|path| path.starts_with("a/"))
+                && new_header.as_deref().is_some_and(|path| path.starts_with("b/")));
+        let old_header = old_header.map(|path| self.strip_git_prefix(path, "a/", git_format));
+        let new_header = new_header.map(|path| self.strip_git_prefix(path, "b/", git_format));
+        
+        // Unlike a `---` header, `rename from` always means a move
+        let explicit_rename = rename_from_line.is_some();
+        let source_path = rename_from_line.or(old_header);
+        let mut file_path = match (is_deletion, new_header) {
+            (true, _) => source_path.as_deref().map(PathBuf::from).unwrap_or_default(),
+            (false, Some(path)) => PathBuf::from(path),
+            (false, None) => PathBuf::new(),
+        };
+        
+        // A pure git-style move has `rename to` but no `+++` header
+        if file_path.as_os_str().is_empty() {
+            if let Some(rename_to) = &rename_to {
+                file_path = PathBuf::from(rename_to);
+            }
+        }
+        
+        // Otherwise the `diff --git` line names the file, e.g. for a mode change
+        if file_path.as_os_str().is_empty() {
+            if let Some((_, new_path)) = &git_paths {
+                file_path = PathBuf::from(new_path);
+            }
+        }
+        
+        // If we couldn't find a path in headers, try the first line or look for filenames
+        if file_path.as_os_str().is_empty() {
+            let first_line = lines[0].trim();
+            
+            if !first_line.starts_with('+') && !first_line.starts_with('-') && 
+               !first_line.starts_with('@') && !first_line.contains(" ") {
+                // First line might be a filename
+                file_path = PathBuf::from(first_line);
+            } else {
+                // Search for a filename in the first few lines
+                for line in lines.iter().take(5) {
+                    let cleaned = line.trim();
+                    if (cleaned.contains('.') || cleaned.contains('/')) && 
+                       !cleaned.starts_with('+') && !cleaned.starts_with('-') && 
+                       !cleaned.starts_with('@') && !cleaned.contains(" ") {
+                        file_path = PathBuf::from(cleaned);
+                        break;
+                    }
+                }
+            }
+        }
+        
+        if file_path.as_os_str().is_empty() {
+            return Err(anyhow!(DiffError::InvalidFormat("Could not determine file path from diff".to_string())));
+        }
+        
+        let file_path = self.project_path(&file_path)?;
+        let source_path = source_path
+            .map(|source| self.project_path(Path::new(&source)).map(|path| path.to_string_lossy().to_string()))
+            .transpose()?;
+        verbose!("Parsed file path: {}", file_path.display());
+        
+        // Check whether the file exists, either at the given path or by its filename alone
+        let root = &self.root;
+        let exists_on_disk = |path: &Path| root.join(path).exists()
+            || path.file_name().is_some_and(|name| root.join(name).exists());
+        
+        // Hunks that only add to an empty file (`@@ -0,0`) create the file when it's missing,
+        // whatever the `---` header says: models often put the path there instead of /dev/null
+        let creates_file = !is_deletion && only_adds_to_empty_file(&lines) && !root.join(&file_path).exists();
+        
+        // Differing `---` and `+++` paths (or `rename from`/`rename to`) describe a move.
+        // A `---` path that doesn't exist is treated as a sloppy header rather than a move.
+        let rename_from = match &source_path {
+            Some(source) if !is_new_file && !is_deletion && !creates_file && Path::new(source) != file_path
+                && exists_on_disk(Path::new(source)) => Some(PathBuf::from(source)),
+            _ => None,
+        };
+        if rename_from.is_some() {
+            is_new_file = false;
+        } else if explicit_rename && !is_new_file && !is_deletion && !creates_file {
+            if let Some(source) = source_path.as_deref().filter(|source| Path::new(source) != file_path) {
+                return Err(anyhow!(DiffError::FileNotFound(
+                    format!("Cannot move {} to {}: it doesn't exist", source, file_path.display())
+                )));
+            }
+        }
+        let read_path = rename_from.clone().unwrap_or_else(|| file_path.clone());
+        // Nor is another file with the same name used in place of the missing one
+        let exists_on_disk = !creates_file && exists_on_disk(&read_path);
+        
+        if is_deletion && !exists_on_disk {
+            return Err(anyhow!(DiffError::FileNotFound(
+                format!("Cannot delete {}: it doesn't exist", file_path.display())
+            )));
+        }
+        
+        if is_new_file && exists_on_disk {
+            // Writing a "new file" diff over an existing file would replace it with only the
+            // added lines, so apply it as a change to the existing content instead
+            status!("{}", format!("⚠️  Diff marks {} as a new file, but it already exists. Treating it as a change to the existing file.",
+                file_path.display()).yellow());
+            is_new_file = false;
+        } else if !is_new_file {
+            is_new_file = !exists_on_disk;
+        }
+        
+        // Get old content for existing files
+        let old_content = if is_new_file {
+            String::new()
+        } else {
+            let target_path = root.join(&read_path);
+            
+            // Try to read the file with fallbacks
+            match fs::read_to_string(&target_path) {
+                Ok(content) => content,
+                Err(_) => {
+                    // Try just the filename
+                    if let Some(file_name) = read_path.file_name() {
+                        let fallback_path = root.join(file_name);
+                        
+                        match fs::read_to_string(&fallback_path) {
+                            Ok(content) => content,
+                            Err(_) => {
+                                return Err(anyhow!(DiffError::FileNotFound(
+                                    format!("Could not find file at any of: {}, {}", 
+                                        target_path.display(), fallback_path.display())
+                                )));
+                            }
+                        }
+                    } else {
+                        return Err(anyhow!(DiffError::FileNotFound(
+                            format!("Invalid file path: {}", read_path.display())
+                        )));
+                    }
+                }
+            }
+        };
+        
+        // Extract new content from the diff
+        let mut hunk_reports = Vec::new();
+        let mut patched_with_git = false;
+        let new_content = if is_deletion {
+            String::new()
+        } else if is_new_file {
+            // For new files, extract all lines that start with +
+            let mut content = String::new();
+            let mut in_hunk = false;
+            
+            for line in &lines {
+                if line.starts_with("@@ ") {
+                    in_hunk = true;
+                    continue;
+                }
+                
+                // `\ No newline at end of file` after the last line
+                if in_hunk && line.starts_with('\\') {
+                    if content.ends_with('\n') {
+                        content.pop();
+                    }
+                    continue;
+                }
+                
+                if (in_hunk || !line.starts_with("---") && !line.starts_with("+++")) && 
+                   line.starts_with('+') && !line.starts_with("+++ ") {
+                    // Remove the + prefix
+                    content.push_str(&line[1..]);
+                    content.push('\n');
+                }
+            }
+            
+            self.with_new_file_line_endings(&content, &file_path)
+        } else if rename_from.is_some() && !has_content_changes(&lines) {
+            // A pure move, like git's `similarity index 100%` renames
+            old_content.clone()
+        } else {
+            // For existing files, apply the diff to the original content
+            let (content, reports, with_git) = self.patch_content(&old_content, &lines, &[])
+                .with_context(|| format!("Can't apply diff to {}", file_path.display()))?;
+            hunk_reports = reports;
+            patched_with_git = with_git;
+            content
+        };
+        
+        Ok(FileDiff {
+            file_path,
+            old_content,
+            new_content,
+            is_new_file,
+            is_deletion,
+            rename_from,
+            block_count: 1,
+            hunk_reports,
+            edited: false,
+            output_root: self.output_root.clone(),
+            context_lines: self.diff_context_lines,
+            preview_lines: self.max_preview_lines,
+            patched_with_git,
+            follow_symlinks: self.follow_symlinks,
+            root: self.root.clone(),
+        })
+    }
+
+    /// The content of a new file with the line endings `new_file_line_ending` asks for
+    fn with_new_file_line_endings(&self, content: &str, file_path: &Path) -> String {
+        let line_ending = match self.new_file_line_ending {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Auto => self.sibling_line_ending(file_path),
+        };
+        let content = content.replace("\r\n", "\n");
+        if line_ending == "\r\n" {
+            content.replace('\n', "\r\n")
+        } else {
+            content
+        }
+    }
+    
+    /// The line ending most of the files in a new file's directory use, or in the nearest
+    /// directory above it that exists; `\n` when there are none to go by
+    fn sibling_line_ending(&self, file_path: &Path) -> &'static str {
+        let Some(dir) = self.root.join(file_path).ancestors().skip(1).find(|dir| dir.is_dir()).map(Path::to_path_buf) else {
+            return "\n";
+        };
+        let Ok(entries) = fs::read_dir(&dir) else {
+            return "\n";
+        };
+        
+        let (mut crlf_files, mut lf_files) = (0, 0);
+        // A few files are enough to go by in a large directory
+        for entry in entries.flatten().take(50) {
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+            // The start of a file shows its line endings
+            let mut head = Vec::new();
+            if fs::File::open(&path).and_then(|file| file.take(8192).read_to_end(&mut head)).is_err() {
+                continue;
+            }
+            let content = String::from_utf8_lossy(&head);
+            if !content.contains('\n') {
+                continue;
+            }
+            match detect_line_ending(&content) {
+                "\r\n" => crlf_files += 1,
+                _ => lf_files += 1,
+            }
+        }
+        verbose!("Line endings in {}: {} file(s) with \\r\\n, {} with \\n", dir.display(), crlf_files, lf_files);
+        
+        if crlf_files > lf_files {
+            "\r\n"
+        } else {
+            "\n"
+        }
+    }
+    
+    /// Apply a diff block's hunks to the content: with `git apply` when `use_external_patch`
+    /// is set and git can handle the block, and with `apply_hunks` otherwise. The flag in the
+    /// result is true when git applied the hunks.
+    fn patch_content(&self, content: &str, lines: &[&str], prior: &[HunkReport]) -> Result<(String, Vec<HunkReport>, bool)> {
+        if self.use_external_patch {
+            if let Some(patched) = git_apply(content, lines)? {
+                return Ok((patched, Vec::new(), true));
+            }
+            verbose!("Not using git apply (git not found or the diff has no hunks)");
+        }
+        
+        let (patched, reports) = self.apply_hunks(content, lines, prior)?;
+        Ok((patched, reports, false))
+    }
+
+    /// Apply the hunks in a diff's lines to the given content, returning the new content.
+    /// Hunks whose context and removed lines don't match at the header's position are
+    /// searched for within `hunk_search_window` lines; if they can't be found, this fails.
+    /// `prior` holds the hunks already applied to the content from earlier diff blocks for the
+    /// same file, whose line changes move the hunks of this block.
+    fn apply_hunks(&self, old_content: &str, lines: &[&str], prior: &[HunkReport]) -> Result<(String, Vec<HunkReport>)> {
+        let old_lines: Vec<&str> = old_content.lines().collect();
+        let mut new_lines = old_lines.iter().map(|&s| s.to_string()).collect::<Vec<String>>();
+        let mut reports = Vec::new();
+        
+        // Earlier hunks shift the position of later ones, since headers use original line numbers
+        let mut offset: isize = 0;
+        let mut missing_newline = MissingNewline::default();
+        
+        // Process hunks with line numbers
+        let mut i = 0;
+        while i < lines.len() {
+            let line = lines[i];
+            
+            // Look for hunk headers
+            if line.starts_with("@@ -") && line.contains(" @@") {
+                // Parse the hunk header
+                let header_parts: Vec<&str> = line
+                    .trim_matches(|c| c == '@' || c == ' ')
+                    .split(' ')
+                    .collect();
+                
+                if !header_parts.is_empty() {
+                    let old_info = header_parts[0].trim_start_matches('-');
+                    
+                    // Parse old line number: -X,Y where X = start line (1-based), Y = line count
+                    let old_start = old_info.split(',').next()
+                        .and_then(|start| start.parse::<usize>().ok())
+                        .unwrap_or(1);
+                    
+                    // Collect hunk content
+                    let (old_hunk_content, new_hunk_content, next, hunk_missing_newline) = Self::collect_hunk(lines, i + 1);
+                    i = next;
+                    missing_newline.old |= hunk_missing_newline.old;
+                    missing_newline.new |= hunk_missing_newline.new;
+                    
+                    let header_idx = old_start.saturating_sub(1); // Convert to 0-based
+                    let prior_shift = Self::prior_shift(prior, header_idx);
+                    let expected_idx = (header_idx as isize + prior_shift + offset).max(0) as usize;
+                    
+                    let (start_idx, loose) = if old_hunk_content.is_empty() {
+                        // Nothing to anchor on, so trust the header; a purely added hunk that
+                        // points past the end is appended, and its report shows the offset
+                        (expected_idx.min(new_lines.len()), false)
+                    } else if let Some(found) = self.find_hunk_position(&new_lines, &old_hunk_content, expected_idx) {
+                        found
+                    } else {
+                        // The header is too far off, so fall back to the one place the hunk's
+                        // context and removed lines appear in the file
+                        match self.find_unique_position(&new_lines, &old_hunk_content) {
+                            Ok(found) => found,
+                            // A miscounted header is likelier than a mismatch with the file then
+                            Err(0) if expected_idx >= new_lines.len() => return Err(anyhow!(DiffError::PastEndOfFile {
+                                hunk: reports.len() + 1,
+                                line: old_start,
+                                len: new_lines.len(),
+                            })),
+                            // The file changed where the hunk goes, so merge the change in there
+                            Err(_) if self.merge_on_conflict => {
+                                let region = self.merge_region(&new_lines, &old_hunk_content, expected_idx);
+                                let (merged, conflicts) = merge_lines(&old_hunk_content, &new_lines[region.clone()], &new_hunk_content);
+                                let merged_count = merged.len();
+                                new_lines.splice(region.clone(), merged);
+                                
+                                reports.push(HunkReport {
+                                    old_start: header_idx + 1,
+                                    old_count: region.len(),
+                                    new_start: region.start + 1,
+                                    new_count: merged_count,
+                                    offset: region.start as isize - header_idx as isize,
+                                    loose: true,
+                                    merged: Some(conflicts),
+                                });
+                                offset = region.start as isize - header_idx as isize - prior_shift
+                                    + merged_count as isize - region.len() as isize;
+                                continue;
+                            },
+                            Err(0) => return Err(anyhow!(DiffError::InvalidFormat(format!(
+                                "hunk {} doesn't match the file within {} lines of line {}: {}",
+                                reports.len() + 1, self.hunk_search_window, old_start,
+                                Self::describe_mismatch(&new_lines, &old_hunk_content, expected_idx)
+                            )))),
+                            Err(count) => return Err(anyhow!(DiffError::InvalidFormat(format!(
+                                "hunk {} doesn't match the file within {} lines of line {}, and its lines appear {} times elsewhere",
+                                reports.len() + 1, self.hunk_search_window, old_start, count
+                            )))),
+                        }
+                    };
+                    let end_idx = start_idx + old_hunk_content.len();
+                    
+                    // Replace the old lines with new lines
+                    let replacement = Self::replacement_lines(&new_lines[start_idx..end_idx], &old_hunk_content, &new_hunk_content);
+                    new_lines.splice(start_idx..end_idx, replacement);
+                    
+                    reports.push(HunkReport {
+                        old_start: header_idx + 1,
+                        old_count: old_hunk_content.len(),
+                        new_start: start_idx + 1,
+                        new_count: new_hunk_content.len(),
+                        offset: start_idx as isize - header_idx as isize,
+                        loose,
+                        merged: None,
+                    });
+                    offset = start_idx as isize - header_idx as isize - prior_shift
+                        + new_hunk_content.len() as isize - old_hunk_content.len() as isize;
+                    
+                    // The line that ended the hunk is examined by the outer loop
+                    continue;
+                }
+            }
+            
+            i += 1;
+        }
+        
+        // Without hunk headers, the context and removed lines are the only anchor. Models
+        // sometimes elide unchanged code between changes (with `...` or a comment), which
+        // splits the diff into groups; each is placed after the one before it.
+        if reports.is_empty() {
+            let groups = Self::headerless_groups(lines);
+            if groups.is_empty() {
+                return Err(anyhow!(DiffError::InvalidFormat("diff has no changes".to_string())));
+            }
+            
+            let mut search_from = 0;
+            let mut shift: isize = 0;
+            for (n, (old_hunk_content, new_hunk_content)) in groups.iter().enumerate() {
+                let label = if groups.len() > 1 {
+                    format!("diff without hunk headers, change {}", n + 1)
+                } else {
+                    "diff without hunk headers".to_string()
+                };
+                
+                if old_hunk_content.is_empty() {
+                    return Err(anyhow!(DiffError::InvalidFormat(format!(
+                        "{}: added lines have no hunk header or context lines to place them by", label
+                    ))));
+                }
+                
+                let start_idx = match self.find_unique_position(&new_lines[search_from..], old_hunk_content) {
+                    Ok((position, _)) => search_from + position,
+                    Err(0) => {
+                        let missing = old_hunk_content.iter()
+                            .find(|line| !old_lines.iter().any(|old| old.trim_end() == line.trim_end()))
+                            .map(|line| format!("line `{}` doesn't match the file", line))
+                            .unwrap_or_else(|| "its lines don't appear together in the file".to_string());
+                        return Err(anyhow!(DiffError::InvalidFormat(format!("{}: {}", label, missing))));
+                    },
+                    Err(count) => return Err(anyhow!(DiffError::InvalidFormat(format!(
+                        "{}: its context and removed lines appear {} times in the file, so the change can't be placed",
+                        label, count
+                    )))),
+                };
+                
+                let end_idx = start_idx + old_hunk_content.len();
+                let replacement = Self::replacement_lines(&new_lines[start_idx..end_idx], old_hunk_content, new_hunk_content);
+                new_lines.splice(start_idx..end_idx, replacement);
+                
+                let old_start = (start_idx as isize - shift) as usize;
+                reports.push(HunkReport {
+                    old_start: old_start + 1,
+                    old_count: old_hunk_content.len(),
+                    new_start: start_idx + 1,
+                    new_count: new_hunk_content.len(),
+                    offset: 0,
+                    loose: true,
+                    merged: None,
+                });
+                search_from = start_idx + new_hunk_content.len();
+                shift += new_hunk_content.len() as isize - old_hunk_content.len() as isize;
+            }
+        }
+        
+        // Combine the lines, keeping the file's original line endings
+        let line_ending = detect_line_ending(old_content);
+        let mut content = new_lines.join(line_ending);
+        
+        // The file keeps its final newline, or lack of one, unless a `\ No newline at end
+        // of file` marker says the change removes or adds it
+        let trailing_newline = if missing_newline.new {
+            false
+        } else {
+            missing_newline.old || old_content.ends_with('\n')
+        };
+        if trailing_newline {
+            content.push_str(line_ending);
+        }
+        
+        Ok((content, reports))
+    }
+    
+    /// Split the lines of a hunk starting at `start` into its old lines (context and removed)
+    /// and new lines (context and added). Returns them with the index of the first line
+    /// after the hunk, and which side a `\ No newline at end of file` marker applies to.
+    fn collect_hunk<'a>(lines: &[&'a str], start: usize) -> (Vec<&'a str>, Vec<&'a str>, usize, MissingNewline) {
+        let mut old_hunk_content = Vec::new();
+        let mut new_hunk_content = Vec::new();
+        let mut missing_newline = MissingNewline::default();
+        
+        let mut i = start;
+        while i < lines.len() {
+            let hunk_line = lines[i];
+            
+            if hunk_line.starts_with('\\') {
+                // The marker is about the line before it: the file's last line, on the side
+                // (or sides, for context) that line belongs to
+                match lines[i - 1].chars().next() {
+                    Some('-') => missing_newline.old = true,
+                    Some('+') => missing_newline.new = true,
+                    _ => {
+                        missing_newline.old = true;
+                        missing_newline.new = true;
+                    },
+                }
+            } else if let Some(removed) = hunk_line.strip_prefix('-') {
+                old_hunk_content.push(removed);
+            } else if let Some(added) = hunk_line.strip_prefix('+') {
+                new_hunk_content.push(added);
+            } else if let Some(context) = hunk_line.strip_prefix(' ') {
+                // Context lines are the same in both
+                old_hunk_content.push(context);
+                new_hunk_content.push(context);
+            } else if hunk_line.is_empty() {
+                // Models often drop the space prefix of blank context lines
+                old_hunk_content.push("");
+                new_hunk_content.push("");
+            } else {
+                // Next hunk header or end of hunk
+                break;
+            }
+            
+            i += 1;
+        }
+        
+        // Blank lines trailing the hunk are separators, not context
+        while old_hunk_content.last() == Some(&"") && new_hunk_content.last() == Some(&"") {
+            old_hunk_content.pop();
+            new_hunk_content.pop();
+        }
+        
+        (old_hunk_content, new_hunk_content, i, missing_newline)
+    }
+    
+    /// Index of the first content line of a diff without hunk headers, after the file
+    /// headers and any file name line
+    fn headerless_hunk_start(lines: &[&str]) -> usize {
+        lines.iter()
+            .position(|line| {
+                !line.is_empty() && !line.starts_with("--- ") && !line.starts_with("+++ ")
+                    && (line.starts_with(' ') || line.starts_with('-') || line.starts_with('+'))
+            })
+            .unwrap_or(lines.len())
+    }
+    
+    /// Split a diff without hunk headers into its groups of diff lines, as (old lines, new
+    /// lines), skipping the lines between groups and groups with only blank lines
+    fn headerless_groups<'a>(lines: &[&'a str]) -> Vec<(Vec<&'a str>, Vec<&'a str>)> {
+        let mut groups = Vec::new();
+        let mut start = Self::headerless_hunk_start(lines);
+        
+        while start < lines.len() {
+            let (old_hunk_content, new_hunk_content, next, _) = Self::collect_hunk(lines, start);
+            if !old_hunk_content.is_empty() || !new_hunk_content.is_empty() {
+                groups.push((old_hunk_content, new_hunk_content));
+            }
+            
+            // collect_hunk always consumes the line it starts on
+            let next = next.max(start + 1);
+            start = next + Self::headerless_hunk_start(&lines[next..]);
+        }
+        
+        groups
+    }
+    
+    /// Ways of comparing a file line with a hunk line, strictest first: exactly, ignoring
+    /// trailing whitespace (which models often drop), and with `ignore_whitespace_in_match`,
+    /// ignoring indentation as well
+    fn line_comparisons(&self) -> Vec<fn(&str, &str) -> bool> {
+        let mut comparisons: Vec<fn(&str, &str) -> bool> = vec![
+            |line, hunk_line| line == hunk_line,
+            |line, hunk_line| line.trim_end() == hunk_line.trim_end(),
+        ];
+        if self.ignore_whitespace_in_match {
+            comparisons.push(|line, hunk_line| line.trim() == hunk_line.trim());
+        }
+        comparisons
+    }
+    
+    /// Find the only position where a hunk's old lines occur in the file, using the
+    /// strictest comparison that finds any, and whether that was looser than an exact one.
+    /// Fails with the number of matches when there are none or several.
+    fn find_unique_position(&self, lines: &[String], hunk: &[&str]) -> std::result::Result<(usize, bool), usize> {
+        let mut matches = Vec::new();
+        let mut loose = false;
+        for (strictness, same) in self.line_comparisons().into_iter().enumerate() {
+            loose = strictness > 0;
+            matches = (0..lines.len().saturating_sub(hunk.len()) + 1)
+                .filter(|&start| start + hunk.len() <= lines.len()
+                    && lines[start..start + hunk.len()].iter().zip(hunk).all(|(line, hunk_line)| same(line, hunk_line)))
+                .collect();
+            if !matches.is_empty() {
+                break;
+            }
+        }
+        
+        match matches.as_slice() {
+            [position] => Ok((*position, loose)),
+            _ => Err(matches.len()),
+        }
+    }
+    
+    /// The lines that replace the file lines a hunk matched: the hunk's new lines, with
+    /// context lines taken from the file so its whitespace is kept when they only matched
+    /// loosely, and added lines re-indented when the file and the hunk disagree on tabs
+    /// and spaces
+    fn replacement_lines(matched: &[String], old_hunk: &[&str], new_hunk: &[&str]) -> Vec<String> {
+        let indent = IndentConversion::between(matched, old_hunk);
+        let mut next_old = 0;
+        
+        new_hunk.iter()
+            .map(|&line| match old_hunk[next_old..].iter().position(|&old| old == line) {
+                Some(k) => {
+                    next_old += k + 1;
+                    matched[next_old - 1].clone()
+                },
+                None => match &indent {
+                    Some(indent) => indent.convert(line),
+                    None => line.to_string(),
+                },
+            })
+            .collect()
+    }
+    
+    /// Net number of lines added by earlier blocks' hunks that start above the given
+    /// (0-based) line of the original file
+    fn prior_shift(prior: &[HunkReport], line_idx: usize) -> isize {
+        prior.iter()
+            .filter(|report| report.old_start <= line_idx)
+            .map(|report| report.new_count as isize - report.old_count as isize)
+            .sum()
+    }
+    
+    /// Describe the first hunk line that differs from the file at the given position
+    fn describe_mismatch(lines: &[String], hunk: &[&str], start: usize) -> String {
+        for (j, hunk_line) in hunk.iter().enumerate() {
+            match lines.get(start + j) {
+                Some(line) if line == hunk_line => continue,
+                Some(line) => return format!("expected `{}` at line {}, found `{}`", hunk_line, start + j + 1, line),
+                None => return format!("expected `{}` at line {}, past the end of the file", hunk_line, start + j + 1),
+            }
+        }
+        
+        "no match".to_string()
+    }
+    
+    /// The lines of the file that most likely stand for the old lines of a hunk that
+    /// doesn't match: of the ranges within the search window, the one most like the hunk's
+    /// lines for its length, nearest the expected position on a tie
+    fn merge_region(&self, lines: &[String], hunk: &[&str], expected: usize) -> Range<usize> {
+        let hunk: Vec<&str> = hunk.iter().map(|line| line.trim_end()).collect();
+        let expected = expected.min(lines.len());
+        let window = self.hunk_search_window;
+        
+        let mut best = expected..(expected + hunk.len()).min(lines.len());
+        let mut best_score = (isize::MIN, 0);
+        for start in expected.saturating_sub(window)..=(expected + window).min(lines.len()) {
+            let shortest = (start + hunk.len()).saturating_sub(window).max(start);
+            let longest = (start + hunk.len() + window).min(lines.len());
+            for end in shortest..=longest {
+                let region: Vec<&str> = lines[start..end].iter().map(|line| line.trim_end()).collect();
+                // Matching lines count the most and lines that replace a hunk line a little,
+                // while other lines count against the range, so it doesn't grow past the hunk
+                let matched: isize = similar::capture_diff_slices(similar::Algorithm::Myers, &hunk, &region).iter()
+                    .map(|op| match op {
+                        DiffOp::Equal { len, .. } => 3 * *len as isize,
+                        DiffOp::Replace { old_len, new_len, .. } => (*old_len).min(*new_len) as isize,
+                        _ => 0,
+                    })
+                    .sum();
+                let score = (matched - region.len() as isize, -(start.abs_diff(expected) as isize));
+                if score > best_score {
+                    best_score = score;
+                    best = start..end;
+                }
+            }
+        }
+        best
+    }
+    
+    /// Find where a hunk's old lines occur, preferring the expected position and then the
+    /// nearest position within the search window. The flag is set when only a comparison
+    /// looser than an exact one matched.
+    fn find_hunk_position(&self, lines: &[String], hunk: &[&str], expected: usize) -> Option<(usize, bool)> {
+        for (strictness, same) in self.line_comparisons().into_iter().enumerate() {
+            let matches_at = |start: usize| {
+                start + hunk.len() <= lines.len()
+                    && lines[start..start + hunk.len()].iter().zip(hunk).all(|(line, hunk_line)| same(line, hunk_line))
+            };
+            
+            for distance in 0..=self.hunk_search_window {
+                if matches_at(expected + distance) {
+                    return Some((expected + distance, strictness > 0));
+                }
+                if distance > 0 && distance <= expected && matches_at(expected - distance) {
+                    return Some((expected - distance, strictness > 0));
+                }
+            }
+        }
+        
+        None
+    }
+}
+
+/// Marker lines around a merge conflict: the file's lines come first, then the change's
+const CONFLICT_START: &str = "<<<<<<< current file";
+const CONFLICT_SEPARATOR: &str = "=======";
+const CONFLICT_END: &str = ">>>>>>> suggested change";
+
+/// Three-way merge of a hunk into the lines of the file it no longer matches: the hunk's
+/// old lines are the base, the file's lines are ours and the hunk's new lines are theirs.
+/// Where only one side changed the base, its change is taken; where both did differently,
+/// both are kept between conflict markers. Returns the merged lines and the conflict count.
+fn merge_lines(base: &[&str], ours: &[String], theirs: &[&str]) -> (Vec<String>, usize) {
+    // Trailing whitespace, which models often drop, isn't a change
+    let base_keys: Vec<&str> = base.iter().map(|line| line.trim_end()).collect();
+    let ours_keys: Vec<&str> = ours.iter().map(|line| line.trim_end()).collect();
+    let theirs_keys: Vec<&str> = theirs.iter().map(|line| line.trim_end()).collect();
+    
+    // Each side's changes as (changed base lines, lines they became, is ours), by position
+    let mut changes: Vec<(Range<usize>, Range<usize>, bool)> = Vec::new();
+    for (keys, is_ours) in [(&ours_keys, true), (&theirs_keys, false)] {
+        for op in similar::capture_diff_slices(similar::Algorithm::Myers, &base_keys, keys) {
+            let (tag, base_range, side_range) = op.as_tag_tuple();
+            if tag != similar::DiffTag::Equal {
+                changes.push((base_range, side_range, is_ours));
+            }
+        }
+    }
+    changes.sort_by_key(|(base_range, _, is_ours)| (base_range.start, base_range.end, !is_ours));
+    
+    let mut merged: Vec<String> = Vec::new();
+    let mut conflicts = 0;
+    let mut base_pos = 0;
+    let mut ours_pos = 0;
+    let mut i = 0;
+    while i < changes.len() {
+        // Changes that overlap are resolved together, as are lines inserted where the other
+        // side changed lines, since which goes first is unclear then
+        let start = changes[i].0.start;
+        let mut end = changes[i].0.end;
+        let mut group_end = i + 1;
+        while group_end < changes.len() && (changes[group_end].0.start < end
+            || (changes[group_end].0.start == end && (changes[group_end].0.is_empty() || start == end)))
+        {
+            end = end.max(changes[group_end].0.end);
+            group_end += 1;
+        }
+        let group = &changes[i..group_end];
+        i = group_end;
+        
+        // Lines neither side changed, as they are in the file
+        merged.extend(ours[ours_pos..ours_pos + (start - base_pos)].iter().cloned());
+        ours_pos += start - base_pos;
+        base_pos = end;
+        
+        // What each side made of the base lines in the group
+        let ours_len = (end - start) as isize + group.iter()
+            .filter(|(_, _, is_ours)| *is_ours)
+            .map(|(base_range, side_range, _)| side_range.len() as isize - base_range.len() as isize)
+            .sum::<isize>();
+        let ours_lines = &ours[ours_pos..ours_pos + ours_len as usize];
+        ours_pos += ours_len as usize;
+        let mut theirs_lines: Vec<String> = Vec::new();
+        let mut pos = start;
+        for (base_range, side_range, _) in group.iter().filter(|(_, _, is_ours)| !is_ours) {
+            theirs_lines.extend(base[pos..base_range.start].iter().map(|line| line.to_string()));
+            theirs_lines.extend(theirs[side_range.clone()].iter().map(|line| line.to_string()));
+            pos = base_range.end;
+        }
+        theirs_lines.extend(base[pos..end].iter().map(|line| line.to_string()));
+        
+        let ours_changed = group.iter().any(|(_, _, is_ours)| *is_ours);
+        let theirs_changed = group.iter().any(|(_, _, is_ours)| !is_ours);
+        let same = ours_lines.iter().map(|line| line.trim_end()).eq(theirs_lines.iter().map(|line| line.trim_end()));
+        if !theirs_changed || same {
+            merged.extend(ours_lines.iter().cloned());
+        } else if !ours_changed {
+            merged.extend(theirs_lines);
+        } else {
+            merged.push(CONFLICT_START.to_string());
+            merged.extend(ours_lines.iter().cloned());
+            merged.push(CONFLICT_SEPARATOR.to_string());
+            merged.extend(theirs_lines);
+            merged.push(CONFLICT_END.to_string());
+            conflicts += 1;
+        }
+    }
+    merged.extend(ours[ours_pos..].iter().cloned());
+    
+    (merged, conflicts)
+}
+
+/// How to turn a hunk's indentation into the file's when one indents with tabs and the
+/// other with spaces, with the number of spaces per tab
+enum IndentConversion {
+    SpacesToTabs(usize),
+    TabsToSpaces(usize),
+}
+
+impl IndentConversion {
+    /// Work out the conversion from the first matched line whose indentation differs from
+    /// the file's only in tabs versus spaces
+    fn between(matched: &[String], old_hunk: &[&str]) -> Option<Self> {
+        matched.iter().zip(old_hunk).find_map(|(line, hunk_line)| {
+            let file_indent = leading_whitespace(line);
+            let hunk_indent = leading_whitespace(hunk_line);
+            let all = |indent: &str, c: char| !indent.is_empty() && indent.chars().all(|ch| ch == c);
+            
+            if all(file_indent, '\t') && all(hunk_indent, ' ') && hunk_indent.len().is_multiple_of(file_indent.len()) {
+                Some(Self::SpacesToTabs(hunk_indent.len() / file_indent.len()))
+            } else if all(file_indent, ' ') && all(hunk_indent, '\t') && file_indent.len().is_multiple_of(hunk_indent.len()) {
+                Some(Self::TabsToSpaces(file_indent.len() / hunk_indent.len()))
+            } else {
+                None
+            }
+        })
+    }
+    
+    fn convert(&self, line: &str) -> String {
+        let indent = leading_whitespace(line);
+        let rest = &line[indent.len()..];
+        match *self {
+            Self::SpacesToTabs(width) => {
+                let columns: usize = indent.chars().map(|c| if c == '\t' { width } else { 1 }).sum();
+                format!("{}{}{}", "\t".repeat(columns / width), " ".repeat(columns % width), rest)
+            },
+            Self::TabsToSpaces(width) => format!("{}{}", indent.replace('\t', &" ".repeat(width)), rest),
+        }
+    }
+}
+
+fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn generator(root: &Path) -> DiffGenerator {
+        DiffGenerator::new(root, &Config::default())
+    }
+    
+    #[test]
+    fn new_file_diff_for_existing_file_changes_it() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/lib.rs"), "a\nb\n").unwrap();
+        
+        let diff = generator(dir.path())
+            .parse_diff("--- /dev/null\n+++ b/src/lib.rs\n@@ -1,2 +1,3 @@\n a\n+x\n b\n")
+            .unwrap();
+        
+        assert!(!diff.is_new_file());
+        assert_eq!(diff.old_content(), "a\nb\n");
+        assert_eq!(diff.new_content(), "a\nx\nb\n");
+    }
+    
+    #[test]
+    fn new_file_diff_for_file_found_by_name_changes_it() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "a\nb\n").unwrap();
+        
+        let diff = generator(dir.path())
+            .parse_diff("--- /dev/null\n+++ b/src/lib.rs\n@@ -1,2 +1,3 @@\n a\n+x\n b\n")
+            .unwrap();
+        
+        assert!(!diff.is_new_file());
+        assert_eq!(diff.new_content(), "a\nx\nb\n");
+        
+        // The file keeps its lines instead of being replaced by the added one
+        diff.apply(&mut BackupSet::create(dir.path()).unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("lib.rs")).unwrap(), "a\nx\nb\n");
+        assert!(!dir.path().join("src/lib.rs").exists());
+    }
+    
+    /// Parse the diff texts and apply them together, as accepting them in review does
+    fn apply_together(root: &Path, diff_texts: &[&str]) {
+        let generator = generator(root);
+        let diffs: Vec<FileDiff> = diff_texts.iter().map(|text| generator.parse_diff(text).unwrap()).collect();
+        generator.apply_all(&diffs).unwrap();
+    }
+    
+    #[test]
+    fn undo_restores_every_file_of_the_last_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "b\n").unwrap();
+        fs::write(dir.path().join("c.txt"), "c\n").unwrap();
+        
+        apply_together(dir.path(), &["--- a/c.txt\n+++ b/c.txt\n@@ -1 +1 @@\n-c\n+c1\n"]);
+        apply_together(dir.path(), &[
+            "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-a\n+a2\n",
+            "--- a/b.txt\n+++ b/b.txt\n@@ -1 +1 @@\n-b\n+b2\n",
+        ]);
+        
+        let steps = undo_last(dir.path()).unwrap().unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(fs::read_to_string(dir.path().join("a.txt")).unwrap(), "a\n");
+        assert_eq!(fs::read_to_string(dir.path().join("b.txt")).unwrap(), "b\n");
+        // The earlier apply is left for the next undo
+        assert_eq!(fs::read_to_string(dir.path().join("c.txt")).unwrap(), "c1\n");
+        
+        undo_last(dir.path()).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("c.txt")).unwrap(), "c\n");
+        assert!(undo_last(dir.path()).unwrap().is_none());
+    }
+    
+    #[test]
+    fn undo_removes_a_created_file_without_touching_older_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("old.txt"), "old\n").unwrap();
+        apply_together(dir.path(), &["--- a/old.txt\n+++ b/old.txt\n@@ -1 +1 @@\n-old\n+older\n"]);
+        apply_together(dir.path(), &["--- /dev/null\n+++ b/src/new.txt\n@@ -0,0 +1 @@\n+new\n"]);
+        
+        let steps = undo_last(dir.path()).unwrap().unwrap();
+        assert_eq!(steps, vec![UndoStep::Remove { path: PathBuf::from("src/new.txt") }]);
+        assert!(!dir.path().join("src/new.txt").exists());
+        assert_eq!(fs::read_to_string(dir.path().join("old.txt")).unwrap(), "older\n");
+    }
+    
+    #[test]
+    fn undo_moves_a_renamed_file_back() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("from.txt"), "x\n").unwrap();
+        apply_together(dir.path(), &["diff --git a/from.txt b/to.txt\nsimilarity index 100%\nrename from from.txt\nrename to to.txt\n"]);
+        assert!(dir.path().join("to.txt").exists());
+        
+        undo_last(dir.path()).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("from.txt")).unwrap(), "x\n");
+        assert!(!dir.path().join("to.txt").exists());
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn undo_restores_through_a_followed_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("real")).unwrap();
+        fs::write(dir.path().join("real/target.txt"), "t\n").unwrap();
+        std::os::unix::fs::symlink("real/target.txt", dir.path().join("link.txt")).unwrap();
+        
+        let generator = DiffGenerator::new(dir.path(), &Config { follow_symlinks: true, ..Config::default() });
+        let diff = generator.parse_diff("--- a/link.txt\n+++ b/link.txt\n@@ -1 +1 @@\n-t\n+t2\n").unwrap();
+        generator.apply_all(&[diff]).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("real/target.txt")).unwrap(), "t2\n");
+        
+        undo_last(dir.path()).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("real/target.txt")).unwrap(), "t\n");
+        assert!(fs::symlink_metadata(dir.path().join("link.txt")).unwrap().file_type().is_symlink());
+    }
+    
+    #[test]
+    fn crlf_line_endings_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("win.txt"), "one\r\ntwo\r\nthree\r\n").unwrap();
+        
+        let diff = generator(dir.path())
+            .parse_diff("--- a/win.txt\n+++ b/win.txt\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n")
+            .unwrap();
+        assert_eq!(diff.new_content(), "one\r\n2\r\nthree\r\n");
+        
+        diff.apply(&mut BackupSet::create(dir.path()).unwrap()).unwrap();
+        assert_eq!(fs::read(dir.path().join("win.txt")).unwrap(), b"one\r\n2\r\nthree\r\n");
+    }
+    
+    #[test]
+    fn lf_files_stay_lf() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("unix.txt"), "one\ntwo\n").unwrap();
+        
+        let diff = generator(dir.path())
+            .parse_diff("--- a/unix.txt\n+++ b/unix.txt\n@@ -1,2 +1,2 @@\n one\n-two\n+2\n")
+            .unwrap();
+        assert_eq!(diff.new_content(), "one\n2\n");
+    }
+    
+    #[test]
+    fn blocks_for_one_file_apply_together() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines: Vec<String> = (1..=20).map(|n| format!("line {}", n)).collect();
+        fs::write(dir.path().join("notes.txt"), lines.join("\n") + "\n").unwrap();
+        
+        // Both headers count from the original file; the first block adds two lines
+        let response = "First:\n```diff\n--- a/notes.txt\n+++ b/notes.txt\n@@ -2,3 +2,5 @@\n line 2\n+added A\n+added B\n line 3\n line 4\n```\n\
+            Then:\n```diff\n--- a/notes.txt\n+++ b/notes.txt\n@@ -15,3 +15,3 @@\n line 15\n-line 16\n+changed 16\n line 17\n```\n";
+        let extraction = generator(dir.path()).extract_diffs(response);
+        
+        assert!(extraction.skipped.is_empty(), "{:?}", extraction.skipped);
+        assert_eq!(extraction.diffs.len(), 1);
+        let diff = &extraction.diffs[0];
+        assert_eq!(diff.block_count(), 2);
+        
+        let new_lines: Vec<&str> = diff.new_content().lines().collect();
+        assert_eq!(&new_lines[1..5], ["line 2", "added A", "added B", "line 3"]);
+        assert_eq!(new_lines[17], "changed 16");
+        assert_eq!(new_lines.len(), 22);
+        assert!(!diff.new_content().contains("line 16\n"));
+    }
+    
+    /// Example output in prose: a shopping list and a changelog with +/- lines, no headers
+    const FAKE_DIFF_RESPONSE: &str = "The command prints something like:\n```\n- eggs\n- milk\n+ bread\n+ butter\n```\n\
+        and the changelog reads:\n```\n--- Version 2 ---\n+ faster startup\n- old flag\n```\n";
+    
+    #[test]
+    fn strict_diffs_ignore_prose_that_looks_like_a_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let strict = DiffGenerator::new(dir.path(), &Config { strict_diffs: true, ..Config::default() });
+        
+        assert!(!strict.has_diff_blocks(FAKE_DIFF_RESPONSE));
+        assert!(strict.extract_raw_diff_blocks(FAKE_DIFF_RESPONSE).is_empty());
+        // The loose check is what used to offer these blocks for application
+        assert!(generator(dir.path()).has_diff_blocks(FAKE_DIFF_RESPONSE));
+        // Shown as plain code rather than hidden as a diff
+        assert!(DiffBlockFilter::filter_all(FAKE_DIFF_RESPONSE, true).contains("+ butter"));
+    }
+    
+    #[test]
+    fn strict_diffs_still_accept_real_diffs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a\n").unwrap();
+        let strict = DiffGenerator::new(dir.path(), &Config { strict_diffs: true, ..Config::default() });
+        
+        let response = "Change it:\n```diff\n--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-a\n+b\n```\n";
+        let extraction = strict.extract_diffs(response);
+        assert_eq!(extraction.diffs.len(), 1);
+        assert_eq!(extraction.diffs[0].new_content(), "b\n");
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn executable_bit_survives_an_edit() {
+        use std::os::unix::fs::PermissionsExt;
+        
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("run.sh");
+        fs::write(&script, "#!/bin/sh\necho one\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        
+        let diff = generator(dir.path())
+            .parse_diff("--- a/run.sh\n+++ b/run.sh\n@@ -1,2 +1,2 @@\n #!/bin/sh\n-echo one\n+echo two\n")
+            .unwrap();
+        diff.apply(&mut BackupSet::create(dir.path()).unwrap()).unwrap();
+        
+        assert_eq!(fs::read_to_string(&script).unwrap(), "#!/bin/sh\necho two\n");
+        assert_eq!(fs::metadata(&script).unwrap().permissions().mode() & 0o777, 0o755);
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_refused_without_follow_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("target.txt"), "a\n").unwrap();
+        std::os::unix::fs::symlink("target.txt", dir.path().join("link.txt")).unwrap();
+        
+        let generator = generator(dir.path());
+        let diff = generator.parse_diff("--- a/link.txt\n+++ b/link.txt\n@@ -1 +1 @@\n-a\n+b\n").unwrap();
+        let error = format!("{:#}", generator.apply_all(&[diff]).unwrap_err());
+        
+        assert!(error.contains("link.txt is a symlink"), "{}", error);
+        assert_eq!(fs::read_to_string(dir.path().join("target.txt")).unwrap(), "a\n");
+    }
+    
+    #[test]
+    fn apply_all_in_read_only_mode_leaves_the_tree_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a\n").unwrap();
+        
+        let generator = generator(dir.path());
+        let diffs = vec![
+            generator.parse_diff("--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-a\n+b\n").unwrap(),
+            generator.parse_diff("--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1 @@\n+new\n").unwrap(),
+        ];
+        set_read_only(true);
+        let result = generator.apply_all(&diffs);
+        set_read_only(false);
+        
+        let error = result.unwrap_err();
+        assert!(matches!(error.downcast_ref::<DiffError>(), Some(DiffError::ReadOnly(_))), "{:#}", error);
+        assert_eq!(fs::read_to_string(dir.path().join("a.txt")).unwrap(), "a\n");
+        assert!(!dir.path().join("new.txt").exists());
+        assert!(!dir.path().join(BACKUP_DIR).exists());
+    }
+    
+    #[test]
+    fn diffs_apply_under_a_custom_root() {
+        // The project is somewhere other than the current directory, as with --project-root
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+        assert_ne!(std::env::current_dir().unwrap(), root);
+        
+        apply_together(&root, &[
+            "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1 +1 @@\n-fn main() {}\n+fn main() { run() }\n",
+            "--- /dev/null\n+++ b/src/run.rs\n@@ -0,0 +1 @@\n+pub fn run() {}\n",
+        ]);
+        
+        assert_eq!(fs::read_to_string(root.join("src/main.rs")).unwrap(), "fn main() { run() }\n");
+        assert_eq!(fs::read_to_string(root.join("src/run.rs")).unwrap(), "pub fn run() {}\n");
+        assert!(!Path::new("src/run.rs").exists());
+        assert!(root.join(BACKUP_DIR).is_dir());
+    }
+    
+    #[test]
+    fn diff_without_hunk_headers_is_placed_by_its_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "use std::fs;\n\nfn one() {}\nfn two() {}\nfn three() {}\n").unwrap();
+        
+        let diff = generator(dir.path())
+            .parse_diff("--- a/lib.rs\n+++ b/lib.rs\n fn one() {}\n-fn two() {}\n+fn two() { one() }\n fn three() {}\n")
+            .unwrap();
+        assert_eq!(diff.new_content(), "use std::fs;\n\nfn one() {}\nfn two() { one() }\nfn three() {}\n");
+    }
+    
+    #[test]
+    fn diff_without_hunk_headers_that_doesnt_match_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "fn one() {}\nfn two() {}\n").unwrap();
+        
+        let error = generator(dir.path())
+            .parse_diff("--- a/lib.rs\n+++ b/lib.rs\n fn one() {}\n-fn four() {}\n+fn four() { one() }\n")
+            .unwrap_err();
+        assert!(format!("{:#}", error).ends_with("diff without hunk headers: line `fn four() {}` doesn't match the file"), "{:#}", error);
+    }
+    
+    #[test]
+    fn whitespace_insensitive_matching_accepts_spaces_for_tabs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("main.go"), "func main() {\n\tstart()\n\tstop()\n}\n").unwrap();
+        // The model indented the context and removed lines with spaces; the file uses tabs
+        let diff_text = "--- a/main.go\n+++ b/main.go\n@@ -1,4 +1,4 @@\n func main() {\n     start()\n-    stop()\n+\tfinish()\n }\n";
+        
+        assert!(generator(dir.path()).parse_diff(diff_text).is_err());
+        
+        let lenient = DiffGenerator::new(dir.path(), &Config { ignore_whitespace_in_match: true, ..Config::default() });
+        let diff = lenient.parse_diff(diff_text).unwrap();
+        // The file's own tabs are kept on the context lines
+        assert_eq!(diff.new_content(), "func main() {\n\tstart()\n\tfinish()\n}\n");
+    }
+    
+    #[test]
+    fn render_diff_is_a_plain_unified_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        let generator = generator(dir.path());
+        
+        let edit = generator.parse_diff("--- a/a.txt\n+++ b/a.txt\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n").unwrap();
+        assert_eq!(edit.render_diff(), "--- a.txt\n+++ a.txt\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n");
+        
+        let new_file = generator.parse_diff("--- /dev/null\n+++ b/b.txt\n@@ -0,0 +1,2 @@\n+hello\n+world\n").unwrap();
+        assert_eq!(new_file.render_diff(), "--- /dev/null\n+++ b.txt\n@@ -0,0 +1,2 @@\n+hello\n+world\n");
+        
+        let deletion = generator.parse_diff("--- a/a.txt\n+++ /dev/null\n@@ -1,3 +0,0 @@\n-one\n-two\n-three\n").unwrap();
+        assert_eq!(deletion.render_diff(), "--- a.txt\n+++ /dev/null\n@@ -1,3 +0,0 @@\n-one\n-two\n-three\n");
+    }
+    
+    #[test]
+    fn rejected_hunks_keep_the_original_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines: Vec<String> = (1..=20).map(|n| format!("line {}", n)).collect();
+        let original = lines.join("\n") + "\n";
+        fs::write(dir.path().join("notes.txt"), &original).unwrap();
+        
+        let mut diff = generator(dir.path())
+            .parse_diff("--- a/notes.txt\n+++ b/notes.txt\n@@ -1,3 +1,3 @@\n line 1\n-line 2\n+changed 2\n line 3\n\
+                @@ -17,3 +17,3 @@\n line 17\n-line 18\n+changed 18\n line 19\n")
+            .unwrap();
+        assert_eq!(diff.hunk_count(), 2);
+        
+        diff.keep_hunks(&[true, false]);
+        assert_eq!(diff.new_content(), original.replace("line 2\n", "changed 2\n"));
+    }
+    
+    #[test]
+    fn paths_leaving_the_project_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir_all(root.join("src")).unwrap();
+        
+        assert!(check_confined(&root.join("src/main.rs"), &root).is_ok());
+        assert!(check_confined(&root.join("src/../main.rs"), &root).is_ok());
+        assert!(check_confined(&root.join("../escape.txt"), &root).is_err());
+        assert!(check_confined(&root.join("src/../../escape.txt"), &root).is_err());
+        assert!(check_confined(Path::new("/etc/passwd"), &root).is_err());
+        
+        // A diff naming such a path is refused as it's parsed
+        let error = generator(&root).parse_diff("--- /dev/null\n+++ b/../escape.txt\n@@ -0,0 +1 @@\n+owned\n").unwrap_err();
+        assert_eq!(format!("{:#}", error), "Refusing to touch ../escape.txt: it is outside the project");
+        // An absolute path in a header is taken as relative to the project
+        let diff = generator(&root).parse_diff("--- /dev/null\n+++ /tmp/escape.txt\n@@ -0,0 +1 @@\n+owned\n").unwrap();
+        assert_eq!(diff.file_path, Path::new("tmp/escape.txt"));
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories_leaving_the_project_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("linked")).unwrap();
+        
+        let error = check_confined(&root.join("linked/new.txt"), &root).unwrap_err();
+        assert!(matches!(error.downcast_ref::<DiffError>(), Some(DiffError::OutsideProject(_))));
+        
+        let error = generator(&root).parse_diff("--- /dev/null\n+++ b/linked/new.txt\n@@ -0,0 +1 @@\n+owned\n").unwrap_err();
+        assert_eq!(format!("{:#}", error), "Refusing to touch linked/new.txt: it is outside the project");
+    }
+    
+    /// A 10-line file, `line 1` to `line 10`
+    fn ten_line_file(dir: &Path) -> String {
+        let content: String = (1..=10).map(|n| format!("line {}\n", n)).collect();
+        fs::write(dir.join("short.txt"), &content).unwrap();
+        content
+    }
+    
+    #[test]
+    fn added_hunk_past_the_end_is_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = ten_line_file(dir.path());
+        
+        let diff = generator(dir.path())
+            .parse_diff("--- a/short.txt\n+++ b/short.txt\n@@ -500,0 +501,1 @@\n+line 11\n")
+            .unwrap();
+        assert_eq!(diff.new_content(), content + "line 11\n");
+    }
+    
+    #[test]
+    fn hunk_past_the_end_is_an_error_unless_its_lines_are_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = ten_line_file(dir.path());
+        let generator = generator(dir.path());
+        
+        let error = generator
+            .parse_diff("--- a/short.txt\n+++ b/short.txt\n@@ -500,2 +500,2 @@\n line 500\n-line 501\n+changed\n")
+            .unwrap_err();
+        assert!(matches!(error.downcast_ref::<DiffError>(), Some(DiffError::PastEndOfFile { hunk: 1, line: 500, len: 10 })), "{:#}", error);
+        
+        // A miscounted header whose lines are in the file still lands where they are
+        let diff = generator
+            .parse_diff("--- a/short.txt\n+++ b/short.txt\n@@ -500,2 +500,2 @@\n line 4\n-line 5\n+changed\n")
+            .unwrap();
+        assert_eq!(diff.new_content(), content.replace("line 5\n", "changed\n"));
+    }
+    
+    #[test]
+    fn cut_off_diff_parses_once_the_continuation_is_added() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        let generator = generator(dir.path());
+        
+        // The response stopped before the end of the hunk and the closing fence
+        let mut response = "Here you go:\n```diff\n--- a/a.txt\n+++ b/a.txt\n@@ -1,3 +1,3 @@\n one\n-two\n".to_string();
+        assert!(has_unterminated_fence(&response));
+        // Read as it is, the change would only delete a line
+        let cut_off = generator.extract_diffs(&response).diffs;
+        assert_eq!(cut_off.iter().map(|diff| diff.new_content()).collect::<Vec<_>>(), ["one\nthree\n"]);
+        
+        // What the model sends when asked to continue, appended as the CLI does
+        let continuation = "+2\n three\n```\n";
+        response.push_str(continuation);
+        assert!(!has_unterminated_fence(&response));
+        
+        let extraction = generator.extract_diffs(&response);
+        assert!(extraction.skipped.is_empty(), "{:?}", extraction.skipped);
+        assert_eq!(extraction.diffs.len(), 1);
+        assert_eq!(extraction.diffs[0].new_content(), "one\n2\nthree\n");
+    }
+    
+    #[test]
+    fn several_files_in_one_fence_are_all_parsed() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/a.rs"), "fn a() {}\n").unwrap();
+        fs::write(dir.path().join("src/b.rs"), "fn b() {}\n").unwrap();
+        
+        let response = "Both files:\n```diff\n--- a/src/a.rs\n+++ b/src/a.rs\n@@ -1 +1 @@\n-fn a() {}\n+fn a() { b() }\n\
+            --- a/src/b.rs\n+++ b/src/b.rs\n@@ -1 +1 @@\n-fn b() {}\n+pub fn b() {}\n```\n";
+        let extraction = generator(dir.path()).extract_diffs(response);
+        
+        assert!(extraction.skipped.is_empty(), "{:?}", extraction.skipped);
+        let changes: Vec<(&Path, &str)> = extraction.diffs.iter().map(|diff| (diff.file_path.as_path(), diff.new_content())).collect();
+        assert_eq!(changes, [
+            (Path::new("src/a.rs"), "fn a() { b() }\n"),
+            (Path::new("src/b.rs"), "pub fn b() {}\n"),
+        ]);
+    }
+    
+    #[test]
+    fn full_git_diff_output_is_parsed() {
+        let dir = tempfile::tempdir().unwrap();
+        // A directory really named `a`, whose prefix must survive
+        fs::create_dir_all(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("a/lib.rs"), "fn a() {}\n").unwrap();
+        fs::write(dir.path().join("my notes.txt"), "one\n").unwrap();
+        
+        let patch = "diff --git a/a/lib.rs b/a/lib.rs\nindex 3b18e51..4c5d2a1 100644\n--- a/a/lib.rs\n+++ b/a/lib.rs\n\
+            @@ -1 +1 @@\n-fn a() {}\n+fn a() -> u8 { 1 }\n\
+            diff --git a/my notes.txt b/my notes.txt\nindex 5626abf..f719efd 100644\n--- a/my notes.txt\n+++ b/my notes.txt\n\
+            @@ -1 +1,2 @@\n one\n+two\n";
+        let extraction = generator(dir.path()).extract_patch_diffs(patch);
+        
+        assert!(extraction.skipped.is_empty(), "{:?}", extraction.skipped);
+        let changes: Vec<(&Path, &str)> = extraction.diffs.iter().map(|diff| (diff.file_path.as_path(), diff.new_content())).collect();
+        assert_eq!(changes, [
+            (Path::new("a/lib.rs"), "fn a() -> u8 { 1 }\n"),
+            (Path::new("my notes.txt"), "one\ntwo\n"),
+        ]);
+    }
+    
+    #[test]
+    fn git_headers_give_both_paths() {
+        assert_eq!(parse_git_header("a/src/x.rs b/src/x.rs"), Some(("src/x.rs".to_string(), "src/x.rs".to_string())));
+        assert_eq!(parse_git_header("a/old name.rs b/new.rs"), Some(("old name.rs".to_string(), "new.rs".to_string())));
+        assert_eq!(parse_git_header("\"a/x y.rs\" \"b/x y.rs\""), Some(("x y.rs".to_string(), "x y.rs".to_string())));
+        assert_eq!(parse_git_header("src/x.rs src/x.rs"), None);
+    }
+    
+    #[test]
+    fn a_directory_named_a_is_kept_without_git_prefixes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("a/notes.txt"), "one\n").unwrap();
+        
+        // Both headers name `a/notes.txt`, so neither prefix is git's
+        let diff = generator(dir.path())
+            .parse_diff("--- a/notes.txt\n+++ a/notes.txt\n@@ -1 +1 @@\n-one\n+two\n")
+            .unwrap();
+        assert_eq!(diff.file_path, Path::new("a/notes.txt"));
+    }
+    
+    #[test]
+    fn missing_final_newline_is_kept_unless_the_diff_adds_one() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "one\ntwo").unwrap();
+        let generator = generator(dir.path());
+        
+        let kept = generator
+            .parse_diff("--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +1,2 @@\n-one\n+1\n two\n\\ No newline at end of file\n")
+            .unwrap();
+        assert_eq!(kept.new_content(), "1\ntwo");
+        
+        let added = generator
+            .parse_diff("--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +1,2 @@\n one\n-two\n\\ No newline at end of file\n+two\n")
+            .unwrap();
+        assert_eq!(added.new_content(), "one\ntwo\n");
+    }
+    
+    #[test]
+    fn diff_can_drop_the_final_newline() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "one\ntwo\n").unwrap();
+        
+        let diff = generator(dir.path())
+            .parse_diff("--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +1,2 @@\n one\n-two\n+two\n\\ No newline at end of file\n")
+            .unwrap();
+        assert_eq!(diff.new_content(), "one\ntwo");
+        
+        let new_file = generator(dir.path())
+            .parse_diff("--- /dev/null\n+++ b/b.txt\n@@ -0,0 +1 @@\n+only\n\\ No newline at end of file\n")
+            .unwrap();
+        assert_eq!(new_file.new_content(), "only");
+    }
+    
+    #[test]
+    fn mismatched_hunk_is_merged_with_conflict_markers() {
+        let dir = tempfile::tempdir().unwrap();
+        // The model saw `c` on line 3, but the file has since changed there
+        fs::write(dir.path().join("a.txt"), "a\nb\nmine\nd\ne\n").unwrap();
+        let diff_text = "--- a/a.txt\n+++ b/a.txt\n@@ -1,5 +1,5 @@\n a\n b\n-c\n+theirs\n d\n e\n";
+        
+        assert!(generator(dir.path()).parse_diff(diff_text).is_err());
+        
+        let merging = DiffGenerator::new(dir.path(), &Config { merge_on_conflict: true, ..Config::default() });
+        let diff = merging.parse_diff(diff_text).unwrap();
+        assert_eq!(diff.new_content(), format!("a\nb\n{}\nmine\n{}\ntheirs\n{}\nd\ne\n", CONFLICT_START, CONFLICT_SEPARATOR, CONFLICT_END));
+        assert!(diff.has_conflicts());
+    }
+    
+    #[test]
+    fn changes_on_different_lines_merge_cleanly() {
+        let base = ["a", "b", "c", "d"];
+        let ours = ["A".to_string(), "b".to_string(), "c".to_string(), "d".to_string()];
+        
+        let (merged, conflicts) = merge_lines(&base, &ours, &["a", "b", "C", "d"]);
+        assert_eq!(merged, ["A", "b", "C", "d"]);
+        assert_eq!(conflicts, 0);
+    }
+    
+    #[test]
+    fn diff_from_nothing_creates_a_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        
+        let diff = generator(dir.path())
+            .parse_diff("--- a/src/new.rs\n+++ b/src/new.rs\n@@ -0,0 +1,2 @@\n+fn new() {}\n+fn other() {}\n")
+            .unwrap();
+        assert!(diff.is_new_file());
+        assert_eq!(diff.new_content(), "fn new() {}\nfn other() {}\n");
+        
+        generator(dir.path()).apply_all(&[diff]).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("src/new.rs")).unwrap(), "fn new() {}\nfn other() {}\n");
+    }
+    
+    #[test]
+    fn pure_rename_moves_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("utils.rs"), "pub fn helper() {}\n").unwrap();
+        
+        let response = "Move it:\n```diff\ndiff --git a/utils.rs b/src/helpers.rs\nsimilarity index 100%\n\
+            rename from utils.rs\nrename to src/helpers.rs\n```\n";
+        let generator = generator(dir.path());
+        let extraction = generator.extract_diffs(response);
+        assert!(extraction.skipped.is_empty(), "{:?}", extraction.skipped);
+        
+        let diff = &extraction.diffs[0];
+        assert_eq!(diff.rename_from(), Some(&PathBuf::from("utils.rs")));
+        assert_eq!(diff.file_path, Path::new("src/helpers.rs"));
+        assert!(!diff.is_new_file());
+        assert_eq!(diff.hunk_count(), 0);
+        assert_eq!(diff.new_content(), "pub fn helper() {}\n");
+        assert_eq!(diff.render_diff(), "diff --git a/utils.rs b/src/helpers.rs\nsimilarity index 100%\nrename from utils.rs\nrename to src/helpers.rs\n");
+        
+        generator.apply_all(&extraction.diffs).unwrap();
+        assert!(!dir.path().join("utils.rs").exists());
+        assert_eq!(fs::read_to_string(dir.path().join("src/helpers.rs")).unwrap(), "pub fn helper() {}\n");
+    }
+    
+    #[test]
+    fn new_files_get_the_configured_line_endings() {
+        let dir = tempfile::tempdir().unwrap();
+        let new_file = "--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1,2 @@\n+one\n+two\n";
+        
+        let crlf = DiffGenerator::new(dir.path(), &Config { new_file_line_ending: LineEnding::Crlf, ..Config::default() });
+        let diff = crlf.parse_diff(new_file).unwrap();
+        crlf.apply_all(&[diff]).unwrap();
+        assert_eq!(fs::read(dir.path().join("new.txt")).unwrap(), b"one\r\ntwo\r\n");
+        
+        let lf = DiffGenerator::new(dir.path(), &Config { new_file_line_ending: LineEnding::Lf, ..Config::default() });
+        assert_eq!(lf.parse_diff(&new_file.replace("new.txt", "other.txt")).unwrap().new_content(), "one\ntwo\n");
+    }
+    
+    #[test]
+    fn auto_line_endings_follow_neighbouring_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("win")).unwrap();
+        fs::write(dir.path().join("win/a.txt"), "a\r\nb\r\n").unwrap();
+        fs::write(dir.path().join("win/b.txt"), "c\r\n").unwrap();
+        
+        let auto = DiffGenerator::new(dir.path(), &Config { new_file_line_ending: LineEnding::Auto, ..Config::default() });
+        let diff = auto.parse_diff("--- /dev/null\n+++ b/win/new.txt\n@@ -0,0 +1,2 @@\n+one\n+two\n").unwrap();
+        assert_eq!(diff.new_content(), "one\r\ntwo\r\n");
+        
+        let diff = auto.parse_diff("--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1 @@\n+one\n").unwrap();
+        assert_eq!(diff.new_content(), "one\n");
+    }
+    
+    #[test]
+    fn external_files_are_never_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let elsewhere = tempfile::tempdir().unwrap();
+        let shared = fs::canonicalize(elsewhere.path()).unwrap().join("shared.proto");
+        fs::write(&shared, "message Ping {}\n").unwrap();
+        let generator = DiffGenerator::new(dir.path(), &Config { external_files: vec![shared.clone()], ..Config::default() });
+        
+        let path = shared.display();
+        let response = format!("```diff\n--- {0}\n+++ {0}\n@@ -1 +1 @@\n-message Ping {{}}\n+message Pong {{}}\n```\n", path);
+        let extraction = generator.extract_diffs(&response);
+        
+        assert!(extraction.diffs.is_empty());
+        assert_eq!(extraction.skipped.len(), 1);
+        assert!(extraction.skipped[0].reason.contains("only given as reference"), "{}", extraction.skipped[0].reason);
+        assert_eq!(fs::read_to_string(&shared).unwrap(), "message Ping {}\n");
+    }
+}
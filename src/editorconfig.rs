@@ -0,0 +1,224 @@
+use anyhow::Result;
+use regex::Regex;
+use std::fs;
+use std::path::Path;
+
+/// Formatting settings resolved from `.editorconfig` for a single file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EditorSettings {
+    pub indent_style: Option<IndentStyle>,
+    pub indent_size: Option<usize>,
+    pub end_of_line: Option<String>,
+    pub insert_final_newline: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndentStyle {
+    Tab,
+    Space,
+}
+
+/// A parsed `.editorconfig` file from the project root
+#[derive(Debug, Clone, Default)]
+pub struct EditorConfig {
+    sections: Vec<(Regex, Vec<(String, String)>)>,
+}
+
+impl EditorConfig {
+    /// Load `.editorconfig` from the given root directory, returning None if it doesn't exist
+    pub fn load<P: AsRef<Path>>(root_dir: P) -> Result<Option<Self>> {
+        let path = root_dir.as_ref().join(".editorconfig");
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&path)?;
+        Ok(Some(Self::parse(&content)?))
+    }
+
+    /// Parse the contents of an `.editorconfig` file
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut sections: Vec<(Regex, Vec<(String, String)>)> = Vec::new();
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let glob = &line[1..line.len() - 1];
+                sections.push((glob_to_regex(glob)?, Vec::new()));
+            } else if let Some((key, value)) = line.split_once('=') {
+                // Properties before the first section (e.g. `root = true`) are ignored
+                if let Some((_, properties)) = sections.last_mut() {
+                    properties.push((key.trim().to_lowercase(), value.trim().to_lowercase()));
+                }
+            }
+        }
+
+        Ok(Self { sections })
+    }
+
+    /// Resolve the settings that apply to a path relative to the project root.
+    /// Later matching sections override earlier ones, as in the editorconfig spec.
+    pub fn settings_for(&self, rel_path: &Path) -> EditorSettings {
+        let path_str = rel_path.to_string_lossy().replace('\\', "/");
+        let mut settings = EditorSettings::default();
+
+        for (pattern, properties) in &self.sections {
+            if !pattern.is_match(&path_str) {
+                continue;
+            }
+
+            for (key, value) in properties {
+                match key.as_str() {
+                    "indent_style" => {
+                        settings.indent_style = match value.as_str() {
+                            "tab" => Some(IndentStyle::Tab),
+                            "space" => Some(IndentStyle::Space),
+                            _ => None,
+                        };
+                    },
+                    "indent_size" => settings.indent_size = value.parse().ok(),
+                    "end_of_line" => settings.end_of_line = Some(value.clone()),
+                    "insert_final_newline" => settings.insert_final_newline = value.parse().ok(),
+                    _ => {}
+                }
+            }
+        }
+
+        settings
+    }
+}
+
+impl EditorSettings {
+    /// Normalize indentation, line endings and the final newline of `content`
+    pub fn normalize(&self, content: &str) -> String {
+        let indent_size = self.indent_size.unwrap_or(4).max(1);
+        let had_final_newline = content.ends_with('\n');
+
+        let lines: Vec<String> = content
+            .lines()
+            .map(|line| match self.indent_style {
+                Some(style) => reindent_line(line, style, indent_size),
+                None => line.to_string(),
+            })
+            .collect();
+
+        let line_ending = match self.end_of_line.as_deref() {
+            Some("crlf") => "\r\n",
+            Some("cr") => "\r",
+            Some("lf") => "\n",
+            // Keep the endings the file was given (see `new_file_line_ending`)
+            _ if content.contains("\r\n") => "\r\n",
+            _ => "\n",
+        };
+
+        let mut normalized = lines.join(line_ending);
+        if self.insert_final_newline.unwrap_or(had_final_newline) && !normalized.is_empty() {
+            normalized.push_str(line_ending);
+        }
+
+        normalized
+    }
+}
+
+/// Convert the leading whitespace of a line to the requested indent style
+fn reindent_line(line: &str, style: IndentStyle, indent_size: usize) -> String {
+    let body = line.trim_start_matches([' ', '\t']);
+    let leading = &line[..line.len() - body.len()];
+
+    // Measure the indentation width in columns
+    let mut width = 0;
+    for c in leading.chars() {
+        if c == '\t' {
+            width += indent_size - (width % indent_size);
+        } else {
+            width += 1;
+        }
+    }
+
+    let indent = match style {
+        IndentStyle::Tab => format!("{}{}", "\t".repeat(width / indent_size), " ".repeat(width % indent_size)),
+        IndentStyle::Space => " ".repeat(width),
+    };
+
+    format!("{}{}", indent, body)
+}
+
+/// Convert an editorconfig section glob into a regex matching relative paths
+fn glob_to_regex(glob: &str) -> Result<Regex> {
+    let mut pattern = String::new();
+    let mut chars = glob.chars().peekable();
+    let mut in_braces = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => {
+                if chars.peek() == Some(&'*') {
+                    chars.next();
+                    pattern.push_str(".*");
+                } else {
+                    pattern.push_str("[^/]*");
+                }
+            },
+            '?' => pattern.push_str("[^/]"),
+            '{' => {
+                in_braces = true;
+                pattern.push_str("(?:");
+            },
+            '}' if in_braces => {
+                in_braces = false;
+                pattern.push(')');
+            },
+            ',' if in_braces => pattern.push('|'),
+            '[' | ']' => pattern.push(c),
+            _ => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    // Globs without a slash match the file name in any directory
+    let anchored = if glob.contains('/') {
+        format!("^{}$", pattern.trim_start_matches('/'))
+    } else {
+        format!("(?:^|/){}$", pattern)
+    };
+
+    Ok(Regex::new(&anchored)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "root = true\n\n[*]\nend_of_line = lf\ninsert_final_newline = true\n\n[*.rs]\nindent_style = space\nindent_size = 4\n\n[Makefile]\nindent_style = tab\n";
+
+    #[test]
+    fn tabs_become_spaces() {
+        let editorconfig = EditorConfig::parse(CONFIG).unwrap();
+        let settings = editorconfig.settings_for(Path::new("src/main.rs"));
+        assert_eq!(settings.indent_style, Some(IndentStyle::Space));
+
+        let normalized = settings.normalize("fn main() {\n\tif true {\n\t\tlet x = 1;\n\t}\n}");
+        assert_eq!(normalized, "fn main() {\n    if true {\n        let x = 1;\n    }\n}\n");
+    }
+
+    #[test]
+    fn spaces_become_tabs() {
+        let editorconfig = EditorConfig::parse(CONFIG).unwrap();
+        let settings = editorconfig.settings_for(Path::new("Makefile"));
+        assert_eq!(settings.indent_style, Some(IndentStyle::Tab));
+
+        // Spaces short of a full indent are kept after the tabs
+        let normalized = settings.normalize("build:\n    cargo build\n      --release\n");
+        assert_eq!(normalized, "build:\n\tcargo build\n\t  --release\n");
+    }
+
+    #[test]
+    fn unmatched_files_keep_their_indentation() {
+        let editorconfig = EditorConfig::parse(CONFIG).unwrap();
+        let settings = editorconfig.settings_for(Path::new("notes.txt"));
+        assert_eq!(settings.normalize("a\n\tb\n"), "a\n\tb\n");
+    }
+}
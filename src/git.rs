@@ -0,0 +1,139 @@
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::diff::FileDiff;
+
+/// Longest prompt summary used in a commit subject
+const MAX_SUBJECT_CHARS: usize = 72;
+
+/// Returns true if `dir` is inside a git work tree (false when git isn't installed)
+pub fn is_git_repo(dir: &Path) -> bool {
+    Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .output()
+        .map(|output| output.status.success() && String::from_utf8_lossy(&output.stdout).trim() == "true")
+        .unwrap_or(false)
+}
+
+/// Build a commit message from the first non-empty line of the user's prompt
+pub fn commit_message(prompt: &str) -> String {
+    let first_line = prompt.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or("apply suggested changes");
+
+    let mut subject: String = first_line.chars().take(MAX_SUBJECT_CHARS).collect();
+    if first_line.chars().count() > MAX_SUBJECT_CHARS {
+        subject.push_str("...");
+    }
+
+    format!("code-llm: {}", subject)
+}
+
+/// Stage the files touched by the applied diffs and commit only those files, leaving
+/// anything else the user has staged alone. Returns the short hash of the new commit,
+/// or None if the directory isn't a git repository.
+pub fn commit_applied(root: &Path, diffs: &[FileDiff], message: &str) -> Result<Option<String>> {
+    if !is_git_repo(root) {
+        return Ok(None);
+    }
+
+    let paths: Vec<PathBuf> = diffs.iter()
+        .flat_map(|diff| diff.touched_paths(root))
+        .collect();
+    if paths.is_empty() {
+        return Ok(None);
+    }
+
+    // -A stages deletions and the old side of moves too
+    run_git(root, |cmd| {
+        cmd.args(["add", "-A", "--"]).args(&paths);
+    })?;
+
+    run_git(root, |cmd| {
+        cmd.args(["commit", "--quiet", "-m", message, "--"]).args(&paths);
+    })?;
+
+    let hash = run_git(root, |cmd| {
+        cmd.args(["rev-parse", "--short", "HEAD"]);
+    })?;
+
+    Ok(Some(hash))
+}
+
+/// Where a session's changes went so they stay apart from the user's own work
+pub struct Isolation {
+    /// The branch the changes are applied on, like `code-llm/session-1700000000`
+    pub branch: String,
+    /// The stash holding the uncommitted work from before the session, if there was any
+    pub stash: Option<String>,
+}
+
+/// Move onto a new `code-llm/session-<timestamp>` branch so the changes that follow can be
+/// told apart from the branch the user was on. Uncommitted work is saved in a stash first
+/// and then brought along, since the suggested changes were made against it. Returns None
+/// if the directory isn't a git repository.
+pub fn isolate_in_branch(root: &Path) -> Result<Option<Isolation>> {
+    if !is_git_repo(root) {
+        return Ok(None);
+    }
+    run_git(root, |cmd| {
+        cmd.args(["rev-parse", "--verify", "--quiet", "HEAD"]);
+    }).map_err(|_| anyhow!("the repository has no commits to branch from yet"))?;
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let branch = format!("code-llm/session-{}", timestamp);
+
+    let dirty = !run_git(root, |cmd| {
+        cmd.args(["status", "--porcelain"]);
+    })?.is_empty();
+    let stash = if dirty {
+        let message = format!("code-llm: work before {}", branch);
+        run_git(root, |cmd| {
+            cmd.args(["stash", "push", "--quiet", "--include-untracked", "-m", &message]);
+        })?;
+        Some(message)
+    } else {
+        None
+    };
+
+    if let Err(e) = run_git(root, |cmd| {
+        cmd.args(["checkout", "--quiet", "-b", &branch]);
+    }) {
+        // Put the work back where it was rather than leaving it in the stash
+        if stash.is_some() {
+            run_git(root, |cmd| {
+                cmd.args(["stash", "pop", "--quiet", "--index"]);
+            })?;
+        }
+        return Err(e);
+    }
+    if stash.is_some() {
+        // Applied rather than popped, so the stash stays as a copy of the work
+        run_git(root, |cmd| {
+            cmd.args(["stash", "apply", "--quiet", "--index"]);
+        })?;
+    }
+
+    Ok(Some(Isolation { branch, stash }))
+}
+
+/// Run a git command in `dir`, returning its trimmed stdout or an error with its stderr
+fn run_git(dir: &Path, build: impl FnOnce(&mut Command)) -> Result<String> {
+    let mut cmd = Command::new("git");
+    cmd.arg("-C").arg(dir);
+    build(&mut cmd);
+
+    let output = cmd.output()
+        .map_err(|e| anyhow!("Failed to run git: {}", e))?;
+
+    if !output.status.success() {
+        return Err(anyhow!("git failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
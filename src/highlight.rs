@@ -0,0 +1,161 @@
+use colored::Colorize;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+/// Global switch for syntax highlighting in diff previews
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Syntax definitions and theme, loaded on first use since parsing them takes a moment
+static ASSETS: OnceLock<(SyntaxSet, Theme)> = OnceLock::new();
+
+/// A highlighted line as (foreground RGB, text) spans, without its line ending
+pub type HighlightedLine = Vec<((u8, u8, u8), String)>;
+
+/// How a line appears in a diff preview
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineKind {
+    Added,
+    Removed,
+    Context,
+}
+
+/// Enable or disable syntax highlighting of diff previews
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+fn assets() -> &'static (SyntaxSet, Theme) {
+    ASSETS.get_or_init(|| {
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+        let mut themes = ThemeSet::load_defaults().themes;
+        let theme = themes.remove("base16-ocean.dark").unwrap_or_default();
+        (syntax_set, theme)
+    })
+}
+
+/// Highlight a file's content based on its extension. Returns None when highlighting is
+/// disabled or there is no syntax for the file type.
+pub fn highlight_content(content: &str, path: &Path) -> Option<Vec<HighlightedLine>> {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return None;
+    }
+
+    let extension = path.extension()?.to_str()?;
+    let (syntax_set, theme) = assets();
+    let syntax = syntax_set.find_syntax_by_extension(extension)?;
+
+    // Highlight the whole file so multi-line constructs like block comments are colored right
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    let mut lines = Vec::new();
+    for line in LinesWithEndings::from(content) {
+        let ranges = highlighter.highlight_line(line, syntax_set).ok()?;
+        lines.push(
+            ranges
+                .into_iter()
+                .map(|(style, text)| {
+                    let color = (style.foreground.r, style.foreground.g, style.foreground.b);
+                    (color, text.trim_end_matches(['\r', '\n']).to_string())
+                })
+                .collect(),
+        );
+    }
+
+    Some(lines)
+}
+
+/// Render one line of a diff preview with its prefix, optional syntax colors, and the
+/// green/red background of added/removed lines. Every span carries its own reset code,
+/// so styling never leaks past the end of the line.
+pub fn render_line(kind: LineKind, text: &str, spans: Option<&HighlightedLine>) -> String {
+    let prefix = match kind {
+        LineKind::Added => "+",
+        LineKind::Removed => "-",
+        LineKind::Context => " ",
+    };
+
+    let Some(spans) = spans else {
+        return style_span(&format!("{}{}", prefix, text), kind, None);
+    };
+
+    let mut line = style_span(prefix, kind, None);
+    for (color, span) in spans.iter().filter(|(_, span)| !span.is_empty()) {
+        line.push_str(&style_span(span, kind, Some(*color)));
+    }
+    line
+}
+
+/// Render a removed or added line like `render_line`, but with only the `changed` byte
+/// ranges bold and the rest of the line dimmed, so that a small edit stands out
+pub fn render_line_with_changes(kind: LineKind, text: &str, spans: Option<&HighlightedLine>, changed: &[Range<usize>]) -> String {
+    let prefix = match kind {
+        LineKind::Added => "+",
+        LineKind::Removed => "-",
+        LineKind::Context => " ",
+    };
+
+    // Without syntax colors the whole line is one span
+    let plain = [(None, text)];
+    let spans: Vec<_> = match spans {
+        Some(spans) => spans.iter().map(|(color, span)| (Some(*color), span.as_str())).collect(),
+        None => plain.to_vec(),
+    };
+
+    let mut line = style_span(prefix, kind, None);
+    let mut offset = 0;
+    for (color, span) in spans {
+        // Split the span wherever a changed range starts or ends
+        let mut start = 0;
+        while start < span.len() {
+            let pos = offset + start;
+            let (is_changed, end) = match changed.iter().find(|range| range.end > pos) {
+                Some(range) if range.start <= pos => (true, range.end),
+                Some(range) => (false, range.start),
+                None => (false, offset + span.len()),
+            };
+            let end = (end - offset).min(span.len());
+            let piece = &span[start..end];
+            line.push_str(&if is_changed {
+                style_span(piece, kind, color)
+            } else {
+                style_dimmed_span(piece, kind, color)
+            });
+            start = end;
+        }
+        offset += span.len();
+    }
+    line
+}
+
+fn style_span(text: &str, kind: LineKind, color: Option<(u8, u8, u8)>) -> String {
+    let styled = match (kind, color) {
+        (LineKind::Context, None) => return text.to_string(),
+        (_, Some((r, g, b))) => text.truecolor(r, g, b),
+        (_, None) => text.white(),
+    };
+
+    match kind {
+        LineKind::Added => styled.on_green().bold().to_string(),
+        LineKind::Removed => styled.on_red().bold().to_string(),
+        LineKind::Context => styled.to_string(),
+    }
+}
+
+/// Style the unchanged part of a changed line: the line's background, but dimmed
+fn style_dimmed_span(text: &str, kind: LineKind, color: Option<(u8, u8, u8)>) -> String {
+    let styled = match color {
+        Some((r, g, b)) => text.truecolor(r, g, b),
+        None => text.white(),
+    };
+
+    match kind {
+        LineKind::Added => styled.on_green().dimmed().to_string(),
+        LineKind::Removed => styled.on_red().dimmed().to_string(),
+        LineKind::Context => styled.to_string(),
+    }
+}
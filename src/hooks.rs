@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Context, Result};
+use globset::{Glob, GlobMatcher};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Which files a hook runs on
+enum HookMatch {
+    /// Files with this extension, from a key like `rs`
+    Extension(String),
+    /// Files whose path relative to the project root matches, from a key like `web/**/*.ts`
+    Glob(GlobMatcher),
+}
+
+/// Commands from `post_apply_hooks` run on each file after changes are applied, like a
+/// formatter. Glob keys are checked before extension keys, so a glob can override the
+/// command for some of the files with an extension.
+pub struct PostApplyHooks {
+    hooks: Vec<(HookMatch, String)>,
+}
+
+impl PostApplyHooks {
+    pub fn new(config_hooks: &HashMap<String, String>) -> Result<Self> {
+        let mut keys: Vec<&String> = config_hooks.keys().collect();
+        // Globs first; alphabetical otherwise, so the same hook wins on every run
+        keys.sort_by_key(|key| (!is_glob(key), key.as_str()));
+
+        let mut hooks = Vec::new();
+        for key in keys {
+            let matcher = if is_glob(key) {
+                let glob = Glob::new(key)
+                    .with_context(|| format!("Invalid glob in post_apply_hooks: {}", key))?;
+                HookMatch::Glob(glob.compile_matcher())
+            } else {
+                HookMatch::Extension(key.trim_start_matches('.').to_string())
+            };
+            hooks.push((matcher, config_hooks[key].clone()));
+        }
+        Ok(Self { hooks })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// The command to run on a file, by its path relative to the project root
+    pub fn command_for(&self, rel_path: &Path) -> Option<&str> {
+        let extension = rel_path.extension().map(|extension| extension.to_string_lossy());
+        self.hooks.iter()
+            .find(|(matcher, _)| match matcher {
+                HookMatch::Extension(wanted) => extension.as_deref() == Some(wanted.as_str()),
+                HookMatch::Glob(glob) => glob.is_match(rel_path),
+            })
+            .map(|(_, command)| command.as_str())
+    }
+}
+
+/// Whether a `post_apply_hooks` key is a glob rather than a file extension
+fn is_glob(key: &str) -> bool {
+    key.contains(['*', '?', '[', '{', '/'])
+}
+
+/// Run a hook's command from `root` with the file's path as its last argument, capturing
+/// its output. Fails when the command can't be started or exits with an error.
+pub fn run_hook(command: &str, path: &Path, root: &Path) -> Result<Output> {
+    // The path is passed as an argument rather than pasted into the command, so spaces and
+    // quotes in it are safe
+    #[cfg(target_os = "windows")]
+    let mut process = {
+        let mut process = Command::new("cmd");
+        process.arg("/C").arg(format!("{} \"{}\"", command, path.display()));
+        process
+    };
+    #[cfg(not(target_os = "windows"))]
+    let mut process = {
+        let mut process = Command::new("sh");
+        process.arg("-c").arg(format!("{} \"$1\"", command)).arg("sh").arg(path);
+        process
+    };
+
+    let output = process.current_dir(root)
+        .output()
+        .with_context(|| format!("Failed to run `{}`", command))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let details = if stderr.trim().is_empty() { stdout.trim() } else { stderr.trim() };
+        return Err(anyhow!("`{}` failed ({}){}{}", command, output.status,
+            if details.is_empty() { "" } else { ":\n" }, details));
+    }
+    Ok(output)
+}
@@ -1,18 +1,24 @@
 mod api;
+mod audit;
+mod cache;
 mod cli;
 mod config;
 mod context;
 mod diff;
+mod editorconfig;
+mod git;
+mod highlight;
+mod hooks;
+mod project;
+mod secrets;
+mod session;
+mod shell;
 mod utils;
 
 use anyhow::Result;
 use cli::run_cli;
-use colored;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Enable colors globally, regardless of whether stdout is a terminal
-    colored::control::set_override(true);
-
     run_cli().await
 }
@@ -0,0 +1,130 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A bundle of type-specific behavior: context ignores, a language prompt and entry files
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProjectType {
+    /// Files at the project root whose presence identifies this project type
+    #[serde(default)]
+    pub markers: Vec<String>,
+
+    /// Regex patterns for paths (relative to the root) to exclude from the context
+    #[serde(default)]
+    pub ignore: Vec<String>,
+
+    /// Extra instructions appended to the system prompt
+    #[serde(default)]
+    pub prompt: Option<String>,
+
+    /// Files that are the usual entry points of this kind of project
+    #[serde(default)]
+    pub entry_files: Vec<String>,
+}
+
+/// A project type resolved for the current directory
+#[derive(Debug, Clone)]
+pub struct ResolvedProjectType {
+    pub name: String,
+    pub bundle: ProjectType,
+}
+
+impl ResolvedProjectType {
+    /// Entry files from the bundle that actually exist under the root
+    pub fn existing_entry_files(&self, root_dir: &Path) -> Vec<String> {
+        self.bundle
+            .entry_files
+            .iter()
+            .filter(|file| root_dir.join(file).is_file())
+            .cloned()
+            .collect()
+    }
+}
+
+fn bundle(markers: &[&str], ignore: &[&str], prompt: &str, entry_files: &[&str]) -> ProjectType {
+    ProjectType {
+        markers: markers.iter().map(|s| s.to_string()).collect(),
+        ignore: ignore.iter().map(|s| s.to_string()).collect(),
+        prompt: Some(prompt.to_string()),
+        entry_files: entry_files.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+/// The built-in project types, in detection order
+pub fn builtin_project_types() -> Vec<(&'static str, ProjectType)> {
+    vec![
+        ("rust", bundle(
+            &["Cargo.toml"],
+            &[r"(^|/)target/", r"Cargo\.lock$"],
+            "This is a Rust project. Follow idiomatic Rust and keep code compatible with the existing edition.",
+            &["src/main.rs", "src/lib.rs"],
+        )),
+        ("node", bundle(
+            &["package.json"],
+            &[r"(^|/)node_modules/", r"(^|/)dist/", r"(^|/)build/", r"package-lock\.json$", r"yarn\.lock$"],
+            "This is a JavaScript/TypeScript project. Match the existing module style and formatting.",
+            &["index.js", "index.ts", "src/index.js", "src/index.ts", "src/main.ts"],
+        )),
+        ("python", bundle(
+            &["pyproject.toml", "setup.py", "requirements.txt"],
+            &[r"(^|/)__pycache__/", r"(^|/)\.venv/", r"(^|/)venv/", r"\.pyc$", r"(^|/)\.pytest_cache/"],
+            "This is a Python project. Follow PEP 8 and match the existing code style.",
+            &["main.py", "app.py", "__main__.py", "manage.py"],
+        )),
+        ("go", bundle(
+            &["go.mod"],
+            &[r"(^|/)vendor/", r"go\.sum$"],
+            "This is a Go project. Write gofmt-formatted, idiomatic Go.",
+            &["main.go", "cmd/main.go"],
+        )),
+        ("java", bundle(
+            &["pom.xml", "build.gradle", "build.gradle.kts"],
+            &[r"(^|/)target/", r"(^|/)build/", r"(^|/)\.gradle/", r"\.class$"],
+            "This is a Java/JVM project. Match the existing package layout and code style.",
+            &["src/main/java/Main.java"],
+        )),
+    ]
+}
+
+/// Resolve the project type: an explicit name wins, otherwise custom bundles and then
+/// built-ins are detected by their marker files. Returns None if nothing matches.
+pub fn resolve_project_type(
+    explicit: Option<&str>,
+    custom: &HashMap<String, ProjectType>,
+    root_dir: &Path,
+) -> Result<Option<ResolvedProjectType>> {
+    let builtins = builtin_project_types();
+
+    if let Some(name) = explicit {
+        if let Some(bundle) = custom.get(name) {
+            return Ok(Some(ResolvedProjectType { name: name.to_string(), bundle: bundle.clone() }));
+        }
+
+        if let Some((_, bundle)) = builtins.into_iter().find(|(builtin, _)| *builtin == name) {
+            return Ok(Some(ResolvedProjectType { name: name.to_string(), bundle }));
+        }
+
+        let mut known: Vec<String> = builtin_project_types().iter().map(|(n, _)| n.to_string()).collect();
+        known.extend(custom.keys().cloned());
+        return Err(anyhow!("Unknown project type '{}'. Available types: {}", name, known.join(", ")));
+    }
+
+    // Custom bundles take precedence over built-ins during detection
+    let mut custom_names: Vec<&String> = custom.keys().collect();
+    custom_names.sort();
+    for name in custom_names {
+        let bundle = &custom[name];
+        if bundle.markers.iter().any(|marker| root_dir.join(marker).exists()) {
+            return Ok(Some(ResolvedProjectType { name: name.clone(), bundle: bundle.clone() }));
+        }
+    }
+
+    for (name, bundle) in builtins {
+        if bundle.markers.iter().any(|marker| root_dir.join(marker).exists()) {
+            return Ok(Some(ResolvedProjectType { name: name.to_string(), bundle }));
+        }
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,69 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Known credential formats, compiled on first use
+static KNOWN_SECRETS: OnceLock<Vec<(&'static str, Regex)>> = OnceLock::new();
+
+/// A quoted value assigned to a name like `api_key` or `password`, in most config and source
+/// formats, optionally with a type annotation in between
+static ASSIGNED_SECRET: OnceLock<Regex> = OnceLock::new();
+
+/// Values this short are too easy to mistake for ordinary strings
+const MIN_SECRET_LEN: usize = 16;
+
+/// Bits of entropy per character above which an assigned value looks randomly generated;
+/// words and identifiers stay well below it
+const MIN_SECRET_ENTROPY: f64 = 3.5;
+
+fn known_secrets() -> &'static [(&'static str, Regex)] {
+    KNOWN_SECRETS.get_or_init(|| {
+        [
+            ("a private key", r"-----BEGIN ([A-Z]+ )?PRIVATE KEY( BLOCK)?-----"),
+            ("an AWS access key", r"\b(AKIA|ASIA)[0-9A-Z]{16}\b"),
+            ("a GitHub token", r"\b(gh[pousr]_[A-Za-z0-9]{36}|github_pat_[A-Za-z0-9_]{40,})\b"),
+            ("a Slack token", r"\bxox[abprs]-[A-Za-z0-9-]{10,}"),
+            ("a Google API key", r"\bAIza[0-9A-Za-z_-]{35}\b"),
+            ("a Stripe key", r"\b[rs]k_live_[0-9A-Za-z]{20,}"),
+        ]
+        .into_iter()
+        .map(|(name, pattern)| (name, Regex::new(pattern).unwrap()))
+        .collect()
+    })
+}
+
+fn assigned_secret() -> &'static Regex {
+    ASSIGNED_SECRET.get_or_init(|| {
+        Regex::new(r#"(?i)(api[_-]?key|secret|token|passw(or)?d|credentials?|auth)[A-Za-z0-9_-]*["']?(\s*:\s*[&'\w]+)?\s*[:=]\s*["'](?P<value>[A-Za-z0-9+/=_.-]+)["']"#).unwrap()
+    })
+}
+
+/// What makes `content` look like it holds a credential (e.g. "a private key"), if anything
+pub fn find_secret(content: &str) -> Option<&'static str> {
+    if let Some((name, _)) = known_secrets().iter().find(|(_, regex)| regex.is_match(content)) {
+        return Some(name);
+    }
+
+    assigned_secret()
+        .captures_iter(content)
+        .filter_map(|captures| captures.name("value"))
+        .map(|value| value.as_str())
+        .any(|value| value.len() >= MIN_SECRET_LEN && shannon_entropy(value) >= MIN_SECRET_ENTROPY)
+        .then_some("a high-entropy value assigned to a secret-like name")
+}
+
+/// Shannon entropy of the string's characters, in bits per character
+fn shannon_entropy(value: &str) -> f64 {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in value.chars() {
+        *counts.entry(c).or_default() += 1;
+    }
+
+    let len = value.chars().count() as f64;
+    counts.values()
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
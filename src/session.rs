@@ -0,0 +1,79 @@
+use anyhow::{anyhow, Context as AnyhowContext, Result};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::get_config_dir;
+use crate::utils::ensure_directory_exists;
+
+/// Name of the session that is saved automatically when interactive mode exits
+pub const LAST_SESSION: &str = "last";
+
+/// A conversation that can be saved to disk and resumed later
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Session {
+    /// The model the conversation was held with
+    #[serde(default)]
+    pub model: Option<String>,
+
+    /// The conversation so far, as "User: ..." / "Assistant: ..." entries
+    #[serde(default)]
+    pub conversation_history: Vec<String>,
+}
+
+impl Session {
+    pub fn new(model: &str, conversation_history: &[String]) -> Self {
+        Self {
+            model: Some(model.to_string()),
+            conversation_history: conversation_history.to_vec(),
+        }
+    }
+
+    /// Write the session to `<config dir>/sessions/<name>.json`
+    pub fn save(&self, name: &str) -> Result<PathBuf> {
+        let path = get_session_path(name)?;
+        if let Some(parent) = path.parent() {
+            ensure_directory_exists(parent)?;
+        }
+
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(&path, json)
+            .with_context(|| format!("Failed to write session file: {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Read a saved session
+    pub fn load(name: &str) -> Result<Self> {
+        let path = get_session_path(name)?;
+        let json = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read session file: {}", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("Session file is corrupt: {}", path.display()))
+    }
+
+    /// Read a saved session, falling back to an empty one with a warning if it is
+    /// missing or can't be parsed
+    pub fn load_or_default(name: &str) -> Self {
+        match Self::load(name) {
+            Ok(session) => session,
+            Err(e) => {
+                println!("{}", format!("⚠️  Couldn't load session '{}' ({:#}). Starting fresh.", name, e).yellow());
+                Self::default()
+            }
+        }
+    }
+}
+
+/// Get the path of a named session file in the config directory
+pub fn get_session_path(name: &str) -> Result<PathBuf> {
+    // Session names become file names, so keep them from escaping the sessions directory
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(anyhow!("Invalid session name: '{}'", name));
+    }
+
+    let mut path = get_config_dir()?;
+    path.push("sessions");
+    path.push(format!("{}.json", name));
+    Ok(path)
+}
@@ -0,0 +1,138 @@
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use std::path::Path;
+use std::process::{Command, ExitStatus};
+use std::sync::Mutex;
+
+/// Languages of code blocks whose lines are commands to run
+const SHELL_LANGUAGES: &[&str] = &["bash", "sh", "shell", "zsh", "console", "shell-session"];
+
+/// Clipboard used for copying commands. On Linux the copied text is only available while
+/// it's open, so it stays open until code-llm exits.
+static CLIPBOARD: Mutex<Option<arboard::Clipboard>> = Mutex::new(None);
+
+/// A command the model suggested running, from a ```bash or ```sh block
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellCommand {
+    /// The command line, without a `$ ` prompt and with continued lines joined
+    pub command: String,
+}
+
+/// Finds the commands in the shell code blocks of a response, the way `DiffGenerator`
+/// finds its diffs. Nothing found is ever run without asking.
+pub struct ShellCommandExtractor {
+    block_regex: Regex,
+}
+
+impl Default for ShellCommandExtractor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShellCommandExtractor {
+    pub fn new() -> Self {
+        Self {
+            block_regex: Regex::new(r"(?ms)^[ \t]*```([a-zA-Z-]+)[ \t]*\n(.*?)^[ \t]*```[ \t]*$").unwrap(),
+        }
+    }
+
+    /// Every command in the response's shell blocks, in order. Comments and blank lines are
+    /// skipped, and in `console` transcripts only the lines after a `$ ` prompt are commands.
+    pub fn extract(&self, text: &str) -> Vec<ShellCommand> {
+        let mut commands: Vec<ShellCommand> = Vec::new();
+        for captures in self.block_regex.captures_iter(text) {
+            let language = captures[1].to_lowercase();
+            if !SHELL_LANGUAGES.contains(&language.as_str()) {
+                continue;
+            }
+            // The other lines of a transcript are the commands' output
+            let transcript = language == "console" || language == "shell-session";
+
+            // A command continued on the next line with a trailing backslash
+            let mut pending = String::new();
+            for line in captures[2].lines() {
+                let mut line = line.trim();
+                if pending.is_empty() {
+                    match line.strip_prefix('$') {
+                        Some(command) => line = command.trim_start(),
+                        None if transcript => continue,
+                        None => {},
+                    }
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                }
+                match line.strip_suffix('\\') {
+                    Some(start) => {
+                        pending.push_str(start.trim_end());
+                        pending.push(' ');
+                    },
+                    None => {
+                        pending.push_str(line);
+                        let command = std::mem::take(&mut pending);
+                        // The same setup step is often repeated in several blocks
+                        if !commands.iter().any(|existing| existing.command == command) {
+                            commands.push(ShellCommand { command });
+                        }
+                    },
+                }
+            }
+            if !pending.trim().is_empty() {
+                commands.push(ShellCommand { command: pending.trim().to_string() });
+            }
+        }
+        commands
+    }
+}
+
+impl ShellCommand {
+    /// Run the command with the system shell in `dir`, showing its output as it runs
+    pub fn run(&self, dir: &Path) -> Result<ExitStatus> {
+        #[cfg(target_os = "windows")]
+        let mut command = {
+            let mut command = Command::new("cmd");
+            command.arg("/C").arg(&self.command);
+            command
+        };
+        #[cfg(not(target_os = "windows"))]
+        let mut command = {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(&self.command);
+            command
+        };
+
+        command.current_dir(dir)
+            .status()
+            .with_context(|| format!("Failed to run '{}'", self.command))
+    }
+
+    /// Put the command on the system clipboard
+    pub fn copy_to_clipboard(&self) -> Result<()> {
+        with_clipboard(|clipboard| clipboard.set_text(self.command.clone())
+            .map_err(|e| anyhow!("Couldn't copy to the clipboard: {}", e)))
+    }
+}
+
+/// The text on the system clipboard. Fails when the clipboard can't be opened or holds
+/// something other than text, like an image.
+pub fn read_clipboard() -> Result<String> {
+    with_clipboard(|clipboard| match clipboard.get_text() {
+        Ok(text) => Ok(text),
+        Err(arboard::Error::ContentNotAvailable) => {
+            Err(anyhow!("The clipboard is empty or doesn't hold text. Copy a unified diff first."))
+        },
+        Err(e) => Err(anyhow!("Couldn't read the clipboard: {}", e)),
+    })
+}
+
+/// Run `f` with the clipboard, opening it the first time
+fn with_clipboard<T>(f: impl FnOnce(&mut arboard::Clipboard) -> Result<T>) -> Result<T> {
+    let mut guard = CLIPBOARD.lock().unwrap_or_else(|e| e.into_inner());
+    let clipboard = match &mut *guard {
+        Some(clipboard) => clipboard,
+        slot @ None => slot.insert(arboard::Clipboard::new()
+            .map_err(|e| anyhow!("Couldn't open the clipboard: {}", e))?),
+    };
+    f(clipboard)
+}
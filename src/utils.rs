@@ -1,6 +1,138 @@
-use anyhow::{Result, Context as AnyhowContext};
+use anyhow::{anyhow, Result, Context as AnyhowContext};
+use colored::Colorize;
 use std::fs;
+use std::io::{self, IsTerminal, Write};
 use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Global switch that sends status messages to stderr, keeping stdout machine-readable
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Send status messages printed with `status!` to stderr instead of stdout
+pub fn set_status_to_stderr(enabled: bool) {
+    STATUS_TO_STDERR.store(enabled, Ordering::SeqCst);
+}
+
+/// Returns true if status messages go to stderr
+pub fn status_to_stderr() -> bool {
+    STATUS_TO_STDERR.load(Ordering::SeqCst)
+}
+
+/// Global switch for logging requests, responses and parsing details
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable verbose logging to stderr
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::SeqCst);
+}
+
+/// Returns true if verbose logging is enabled
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::SeqCst)
+}
+
+/// Global switch for showing output taller than the terminal in a pager
+static PAGER: AtomicBool = AtomicBool::new(false);
+
+/// Pager used when `PAGER` isn't set; `-R` keeps the colors
+#[cfg(target_os = "windows")]
+const DEFAULT_PAGER: &str = "more";
+#[cfg(not(target_os = "windows"))]
+const DEFAULT_PAGER: &str = "less -R";
+
+/// Enable or disable paging of long output with `print_paged`
+pub fn set_pager(enabled: bool) {
+    PAGER.store(enabled, Ordering::SeqCst);
+}
+
+/// Print text, through the pager when paging is enabled and the text (with room for a
+/// prompt below it) doesn't fit in the terminal. Returns once the pager is closed. If the
+/// pager can't be started, the text is printed as usual.
+pub fn print_paged(text: &str) {
+    let too_tall = PAGER.load(Ordering::SeqCst)
+        && io::stdout().is_terminal()
+        && console::Term::stdout().size_checked()
+            .is_some_and(|(rows, _)| text.lines().count() + 3 > rows as usize);
+    
+    if too_tall {
+        match run_pager(text) {
+            Ok(()) => return,
+            Err(e) => println!("{}", format!("Warning: {:#}", e).yellow()),
+        }
+    }
+    println!("{}", text);
+}
+
+/// Show text in the pager even when paging is off, such as when the user asks to see all
+/// of something that was cut short. Falls back to printing it.
+pub fn show_in_pager(text: &str) {
+    if io::stdout().is_terminal() {
+        match run_pager(text) {
+            Ok(()) => return,
+            Err(e) => println!("{}", format!("Warning: {:#}", e).yellow()),
+        }
+    }
+    println!("{}", text);
+}
+
+/// Show text in the pager from `PAGER`, or the default one, and wait for it to close
+fn run_pager(text: &str) -> Result<()> {
+    let pager = std::env::var("PAGER").ok()
+        .map(|pager| pager.trim().to_string())
+        .filter(|pager| !pager.is_empty())
+        .unwrap_or_else(|| DEFAULT_PAGER.to_string());
+    let mut words = pager.split_whitespace();
+    let program = words.next().unwrap_or(DEFAULT_PAGER);
+    
+    // `more` is a `.com` program on Windows, which only the shell finds
+    #[cfg(target_os = "windows")]
+    let mut command = {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(program);
+        command
+    };
+    #[cfg(not(target_os = "windows"))]
+    let mut command = Command::new(program);
+    
+    let mut child = command.args(words)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Failed to start pager '{}': {}", pager, e))?;
+    
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager stops reading if it's closed before the end, which is fine
+        match writeln!(stdin, "{}", text) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {},
+        }
+    }
+    child.wait()?;
+    Ok(())
+}
+
+/// Print a debugging message to stderr, only when `--verbose` is set
+#[macro_export]
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::utils::is_verbose() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Print a progress or warning message: to stdout normally, or to stderr when the
+/// output is meant for other programs (e.g. `ask --format json`)
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::utils::status_to_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
 
 pub fn ensure_directory_exists<P: AsRef<Path>>(dir: P) -> Result<()> {
     let dir = dir.as_ref();
@@ -32,18 +164,267 @@ pub fn is_binary_file<P: AsRef<Path>>(path: P) -> Result<bool> {
         }
     }
     
-    // Check file content for null bytes, which is a common way to detect binary files
     let content = fs::read(path).with_context(|| {
         format!("Failed to read file: {:?}", path)
     })?;
     
-    // Check the first 8KB for null bytes
+    // Only look at the first 8KB
     let check_size = std::cmp::min(8192, content.len());
-    for i in 0..check_size {
-        if content[i] == 0 {
-            return Ok(true);
+    let sample = &content[..check_size];
+    
+    // UTF-16 text is full of null bytes, so recognize it before the null byte check
+    if utf16_byte_order(sample).is_some() {
+        return Ok(false);
+    }
+    
+    // Null bytes are a common way to detect binary files
+    if sample.contains(&0) {
+        return Ok(true);
+    }
+    
+    // Text rarely contains control characters other than whitespace and escape sequences
+    let control_bytes = sample
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+        .count();
+    
+    Ok(control_bytes * 10 > check_size)
+}
+
+/// Byte order of UTF-16 text, from its BOM or, without one, from null bytes consistently
+/// filling every other byte as they do for ASCII-range characters. True means little-endian.
+fn utf16_byte_order(sample: &[u8]) -> Option<bool> {
+    match sample {
+        [0xFF, 0xFE, ..] => return Some(true),
+        [0xFE, 0xFF, ..] => return Some(false),
+        _ => {}
+    }
+    
+    let pairs = sample.len() / 2;
+    if pairs < 2 {
+        return None;
+    }
+    
+    let even_nulls = sample.iter().step_by(2).filter(|&&b| b == 0).count();
+    let odd_nulls = sample.iter().skip(1).step_by(2).filter(|&&b| b == 0).count();
+    
+    // Mostly-ASCII UTF-16 has nulls in nearly every high byte and (almost) none in the low bytes
+    if odd_nulls * 10 >= pairs * 9 && even_nulls == 0 {
+        Some(true)
+    } else if even_nulls * 10 >= pairs * 9 && odd_nulls == 0 {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// The code fence language tag for a file, from its extension (or name, for files like
+/// `Makefile`), if it's a language models commonly know by that tag
+pub fn language_for_path<P: AsRef<Path>>(path: P) -> Option<&'static str> {
+    let path = path.as_ref();
+    match path.file_name()?.to_str()? {
+        "Dockerfile" => return Some("dockerfile"),
+        "Makefile" | "makefile" | "GNUmakefile" => return Some("makefile"),
+        "CMakeLists.txt" => return Some("cmake"),
+        _ => {}
+    }
+    
+    let language = match path.extension()?.to_str()?.to_lowercase().as_str() {
+        "rs" => "rust",
+        "py" | "pyi" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "jsx",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "tsx",
+        "go" => "go",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "scala" => "scala",
+        "swift" => "swift",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" | "hh" | "hxx" => "cpp",
+        "cs" => "csharp",
+        "rb" => "ruby",
+        "php" => "php",
+        "pl" | "pm" => "perl",
+        "lua" => "lua",
+        "dart" => "dart",
+        "ex" | "exs" => "elixir",
+        "erl" | "hrl" => "erlang",
+        "hs" => "haskell",
+        "ml" | "mli" => "ocaml",
+        "clj" | "cljs" => "clojure",
+        "zig" => "zig",
+        "sh" | "bash" | "zsh" => "bash",
+        "ps1" => "powershell",
+        "sql" => "sql",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "vue" => "vue",
+        "svelte" => "svelte",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "xml" => "xml",
+        "md" | "markdown" => "markdown",
+        "proto" => "protobuf",
+        "graphql" | "gql" => "graphql",
+        "tf" => "hcl",
+        _ => return None,
+    };
+    Some(language)
+}
+
+/// The part a file plays in a project, in the order changes to them are reviewed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileRole {
+    /// Manifests, build files and settings, like `package.json` or `Dockerfile`
+    Config,
+    /// Code in a programming language
+    Source,
+    /// Markup, styles, documentation, images and anything else
+    Asset,
+}
+
+impl FileRole {
+    /// Heading for a group of files with this role
+    pub fn heading(self) -> &'static str {
+        match self {
+            FileRole::Config => "Configuration files",
+            FileRole::Source => "Source files",
+            FileRole::Asset => "Markup, styles, docs and other files",
         }
     }
+}
+
+/// What part a file plays in the project, from its name and extension
+pub fn file_role<P: AsRef<Path>>(path: P) -> FileRole {
+    let path = path.as_ref();
+    let name = path.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+    
+    // Dotfiles like .gitignore, .env and .eslintrc configure tools
+    if name.starts_with('.') || name.ends_with(".lock")
+        || matches!(extension.as_str(), "json" | "yaml" | "yml" | "toml" | "ini" | "cfg" | "conf" | "env" | "xml" | "gradle" | "properties")
+    {
+        return FileRole::Config;
+    }
+    match language_for_path(path) {
+        Some("dockerfile" | "makefile" | "cmake") => FileRole::Config,
+        Some("html" | "css" | "scss" | "markdown") | None => FileRole::Asset,
+        Some(_) => FileRole::Source,
+    }
+}
+
+/// Read a text file as a string, decoding UTF-16 and dropping a UTF-8 BOM.
+/// Invalid UTF-8 sequences are replaced rather than treated as an error.
+pub fn read_text_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    let content = fs::read(path).with_context(|| {
+        format!("Failed to read file: {:?}", path)
+    })?;
+    
+    if let Some(little_endian) = utf16_byte_order(&content[..std::cmp::min(8192, content.len())]) {
+        let units: Vec<u16> = content
+            .chunks_exact(2)
+            .map(|pair| if little_endian {
+                u16::from_le_bytes([pair[0], pair[1]])
+            } else {
+                u16::from_be_bytes([pair[0], pair[1]])
+            })
+            .collect();
+        let text = String::from_utf16_lossy(&units);
+        return Ok(text.trim_start_matches('\u{FEFF}').to_string());
+    }
+    
+    let text = String::from_utf8_lossy(&content);
+    Ok(text.trim_start_matches('\u{FEFF}').to_string())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn utf16le(text: &str, bom: bool) -> Vec<u8> {
+        let mut bytes = if bom { vec![0xFF, 0xFE] } else { Vec::new() };
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        bytes
+    }
     
-    Ok(false)
-}
\ No newline at end of file
+    #[test]
+    fn utf16le_with_bom_is_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.ini");
+        fs::write(&path, utf16le("[main]\r\nname = café\r\n", true)).unwrap();
+        
+        assert!(!is_binary_file(&path).unwrap());
+        assert_eq!(read_text_file(&path).unwrap(), "[main]\r\nname = café\r\n");
+    }
+    
+    #[test]
+    fn utf16le_without_bom_is_text_when_mostly_ascii() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        // 3 of the 31 characters have a non-null high byte, within the 90% threshold
+        let text = "Hello, world! 日本語 is Japanese.\n";
+        fs::write(&path, utf16le(text, false)).unwrap();
+        
+        assert!(!is_binary_file(&path).unwrap());
+        assert_eq!(read_text_file(&path).unwrap(), text);
+    }
+    
+    #[test]
+    fn byte_order_needs_nulls_in_nine_of_ten_pairs() {
+        // 9 of 10 pairs with a null high byte
+        let mut sample = utf16le("abcdefghi", false);
+        sample.extend([0x41, 0x42]);
+        assert_eq!(utf16_byte_order(&sample), Some(true));
+        
+        // 8 of 10 isn't enough
+        let mut sample = utf16le("abcdefgh", false);
+        sample.extend([0x41, 0x42, 0x43, 0x44]);
+        assert_eq!(utf16_byte_order(&sample), None);
+        
+        // A null in a low byte rules it out
+        let mut sample = utf16le("abcdefghijk", false);
+        sample.extend([0x00, 0x00]);
+        assert_eq!(utf16_byte_order(&sample), None);
+        
+        assert_eq!(utf16_byte_order(&[0xFE, 0xFF, 0x00, 0x41]), Some(false));
+    }
+    
+    #[test]
+    fn real_binaries_are_binary() {
+        // The test executable itself
+        let executable = std::env::current_exe().unwrap();
+        assert!(is_binary_file(&executable).unwrap());
+        
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        fs::write(&path, [0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0x3E, 0]).unwrap();
+        assert!(is_binary_file(&path).unwrap());
+    }
+    
+    #[test]
+    fn plain_text_is_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.rs");
+        fs::write(&path, "\u{FEFF}fn main() {\n\tprintln!(\"\x1b[1mhi\x1b[0m\");\n}\n").unwrap();
+        
+        assert!(!is_binary_file(&path).unwrap());
+        assert!(read_text_file(&path).unwrap().starts_with("fn main()"));
+    }
+    
+    #[test]
+    fn files_are_sorted_into_roles() {
+        for path in ["package.json", "Cargo.toml", "Cargo.lock", ".gitignore", "Dockerfile", "config/app.yaml"] {
+            assert_eq!(file_role(path), FileRole::Config, "{}", path);
+        }
+        for path in ["src/main.rs", "index.js", "app/models.py"] {
+            assert_eq!(file_role(path), FileRole::Source, "{}", path);
+        }
+        for path in ["index.html", "styles/site.css", "README.md", "logo.svg"] {
+            assert_eq!(file_role(path), FileRole::Asset, "{}", path);
+        }
+    }
+}